@@ -101,6 +101,9 @@ pub enum Expr {
     AnyChar,
     /// Match any character.
     AnyCharNoNL,
+    /// Match one extended grapheme cluster, as defined by Unicode Standard
+    /// Annex #29.
+    AnyGrapheme,
     /// A character class.
     Class(CharClass),
     /// Match the start of a line or beginning of input.
@@ -111,6 +114,8 @@ pub enum Expr {
     StartText,
     /// Match the end of input.
     EndText,
+    /// Match the end of input, or just before a `\n` that ends the input.
+    EndTextNewline,
     /// Match a word boundary (word character on one side and a non-word
     /// character on the other).
     WordBoundary,
@@ -137,6 +142,13 @@ pub enum Expr {
         /// least).
         greedy: bool,
     },
+    /// Match the capture group with the given index again, so that it may
+    /// contain itself, or the whole expression if the index is `0`. e.g.,
+    /// `(?R)` or `(?1)`.
+    Recurse(usize),
+    /// Match the same text that the capture group with the given index
+    /// last matched. e.g., `\1` or `\k<name>`.
+    Backref(usize),
     /// A concatenation of expressions. Must be matched one after the other.
     ///
     /// N.B. A concat expression can only appear at the top-level or
@@ -223,8 +235,99 @@ pub struct ClassRange {
     pub end: char,
 }
 
+/// Options for parsing a regular expression.
+///
+/// `Expr::parse` parses with the default options. To change them, start with
+/// `ParserOptions::new()`, set the options and then call `parse`:
+///
+/// ```rust
+/// use regex_syntax::ParserOptions;
+///
+/// let opts = ParserOptions::new().depth_limit(1);
+/// assert!(opts.parse("(a)").is_ok());
+/// assert!(opts.parse("((a))").is_err());
+/// ```
+#[derive(Clone, Debug)]
+pub struct ParserOptions {
+    depth_limit: usize,
+    ascii_case_insensitive: bool,
+    dollar_newline: bool,
+    classes: Vec<(String, Vec<(char, char)>)>,
+}
+
+impl ParserOptions {
+    /// Create a new set of options with the defaults used by `Expr::parse`.
+    pub fn new() -> ParserOptions {
+        ParserOptions {
+            depth_limit: ::std::usize::MAX,
+            ascii_case_insensitive: false,
+            dollar_newline: false,
+            classes: vec![],
+        }
+    }
+
+    /// Fail with `ErrorKind::TooDeep` if groups are nested more than `limit`
+    /// levels deep.
+    ///
+    /// This should be used to parse untrusted regular expressions, since
+    /// processing a deeply nested syntax tree can overflow the stack.
+    ///
+    /// By default, there is no limit.
+    pub fn depth_limit(mut self, limit: usize) -> ParserOptions {
+        self.depth_limit = limit;
+        self
+    }
+
+    /// Make the `i` flag only make ASCII letters match case insensitively.
+    /// Every other character only matches itself, as if `i` weren't set.
+    ///
+    /// The expression returned then never contains a case insensitive
+    /// literal or class. Instead, an ASCII letter `a` becomes the class
+    /// `[Aa]`.
+    ///
+    /// This is disabled by default.
+    pub fn ascii_case_insensitive(mut self, yes: bool) -> ParserOptions {
+        self.ascii_case_insensitive = yes;
+        self
+    }
+
+    /// Parse `$` outside of multi-line mode as `\Z` instead of `\z`, like in
+    /// Perl.
+    ///
+    /// This is disabled by default.
+    pub fn dollar_newline(mut self, yes: bool) -> ParserOptions {
+        self.dollar_newline = yes;
+        self
+    }
+
+    /// Let `\p{name}` and `\P{name}` also refer to one of `classes`, each
+    /// given as a name and the inclusive ranges of characters in the class.
+    /// These take precedence over the Unicode classes of the same name.
+    pub fn classes(
+        mut self,
+        classes: Vec<(String, Vec<(char, char)>)>,
+    ) -> ParserOptions {
+        self.classes = classes;
+        self
+    }
+
+    /// Parses a string in a regular expression syntax tree with these
+    /// options.
+    pub fn parse(&self, s: &str) -> Result<Expr> {
+        parser::Parser::parse_with_options(s, self).map(|e| e.simplify())
+    }
+}
+
+impl Default for ParserOptions {
+    fn default() -> ParserOptions {
+        ParserOptions::new()
+    }
+}
+
 impl Expr {
     /// Parses a string in a regular expression syntax tree.
+    ///
+    /// This is a shorthand for `ParserOptions::new().parse(s)`.
     pub fn parse(s: &str) -> Result<Expr> {
         parser::Parser::parse(s).map(|e| e.simplify())
     }
@@ -235,10 +338,13 @@ impl Expr {
             Literal{..}
             | AnyChar
             | AnyCharNoNL
+            | AnyGrapheme
             | Class(_)
-            | StartLine | EndLine | StartText | EndText
+            | StartLine | EndLine | StartText | EndText | EndTextNewline
             | WordBoundary | NotWordBoundary
             | Group{..}
+            | Recurse(_)
+            | Backref(_)
             => true,
             _ => false,
         }
@@ -316,8 +422,19 @@ impl<'a> IntoIterator for &'a CharClass {
 
 impl CharClass {
     /// Create a new class from an existing set of ranges.
-    fn new(ranges: Vec<ClassRange>) -> CharClass {
-        CharClass { ranges: ranges, casei: false }
+    ///
+    /// The ranges may be given in any order and may overlap. They are
+    /// sorted and merged into the canonical format described above.
+    pub fn new(ranges: Vec<ClassRange>) -> CharClass {
+        CharClass { ranges: ranges, casei: false }.canonicalize()
+    }
+
+    /// Returns the class of a Unicode general category, script or property,
+    /// e.g., `M` or `Greek`, as matched by `\p{..}`.
+    ///
+    /// If there's no class with the given name, `None` is returned.
+    pub fn unicode(name: &str) -> Option<CharClass> {
+        parser::unicode_class(name)
     }
 
     /// Create an empty class.
@@ -398,7 +515,10 @@ impl CharClass {
         // Never allow negating of a class that has been case folded!
         assert!(!self.casei);
 
-        if self.is_empty() { return self; }
+        if self.is_empty() {
+            self.ranges.push(range('\x00', char::MAX));
+            return self;
+        }
         self = self.canonicalize();
         let mut inv = self.to_empty();
         if self[0].start > '\x00' {
@@ -415,6 +535,31 @@ impl CharClass {
         inv
     }
 
+    /// Returns the class of characters matched by both `self` and `other`.
+    ///
+    /// Like `negate`, this cannot be called on a case folded class.
+    fn intersect(self, other: CharClass) -> CharClass {
+        assert!(!self.casei && !other.casei);
+
+        let (a, b) = (self.canonicalize(), other.canonicalize());
+        let mut both = a.to_empty();
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() && j < b.len() {
+            let start = max(a[i].start, b[j].start);
+            let end = min(a[i].end, b[j].end);
+            if start <= end {
+                both.ranges.push(ClassRange::new(start, end));
+            }
+            if a[i].end < b[j].end { i += 1; } else { j += 1; }
+        }
+        both
+    }
+
+    /// Returns the class of characters matched by `self` but not `other`.
+    fn subtract(self, other: CharClass) -> CharClass {
+        self.intersect(other.negate())
+    }
+
     /// Apply case folding to this character class.
     ///
     /// One a class had been case folded, it cannot be negated.
@@ -434,6 +579,24 @@ impl CharClass {
         }
         folded.canonicalize()
     }
+
+    /// Adds the other case of every ASCII letter in this class.
+    ///
+    /// Unlike `case_fold`, the class stays case sensitive.
+    fn ascii_case_fold(self) -> CharClass {
+        let swap = |c: char| ((c as u8) ^ 0x20) as char;
+        let mut folded = self.clone();
+        for r in self {
+            for &(lower, upper) in &[('a', 'z'), ('A', 'Z')] {
+                let (start, end) = (max(r.start, lower), min(r.end, upper));
+                if start <= end {
+                    let r = ClassRange::new(swap(start), swap(end));
+                    folded.ranges.push(r);
+                }
+            }
+        }
+        folded.canonicalize()
+    }
 }
 
 impl ClassRange {
@@ -441,7 +604,7 @@ impl ClassRange {
     ///
     /// If `end < start`, then the two values are swapped so that
     /// the invariant `start <= end` is preserved.
-    fn new(start: char, end: char) -> ClassRange {
+    pub fn new(start: char, end: char) -> ClassRange {
         if start <= end {
             ClassRange { start: start, end: end }
         } else {
@@ -561,11 +724,13 @@ impl fmt::Display for Expr {
             }
             AnyChar => write!(f, "(?s:.)"),
             AnyCharNoNL => write!(f, "."),
+            AnyGrapheme => write!(f, r"\X"),
             Class(ref cls) => write!(f, "{}", cls),
             StartLine => write!(f, "(?m:^)"),
             EndLine => write!(f, "(?m:$)"),
             StartText => write!(f, r"^"),
             EndText => write!(f, r"$"),
+            EndTextNewline => write!(f, r"\Z"),
             WordBoundary => write!(f, r"\b"),
             NotWordBoundary => write!(f, r"\B"),
             Group { ref e, i: None, name: None } => write!(f, "(?:{})", e),
@@ -573,6 +738,9 @@ impl fmt::Display for Expr {
             Group { ref e, name: Some(ref n), .. } => {
                 write!(f, "(?P<{}>{})", n, e)
             }
+            Recurse(0) => write!(f, "(?R)"),
+            Recurse(i) => write!(f, "(?{})", i),
+            Backref(i) => write!(f, r"\k<{}>", i),
             Repeat { ref e, r, greedy } => {
                 match &**e {
                     &Literal { ref chars, .. } if chars.len() > 1 => {
@@ -618,6 +786,14 @@ impl fmt::Display for CharClass {
         if self.casei {
             try!(write!(f, "(?i:"));
         }
+        if self.is_empty() {
+            // `[]` isn't a valid class, so write one that matches nothing.
+            try!(write!(f, "[^\\x00-\\x{{10FFFF}}]"));
+            if self.casei {
+                try!(write!(f, ")"));
+            }
+            return Ok(());
+        }
         try!(write!(f, "["));
         for range in self.iter() {
             try!(write!(f, "{}", range));
@@ -646,6 +822,7 @@ pub type Result<T> = ::std::result::Result<T, Error>;
 #[derive(Clone, Debug, PartialEq)]
 pub struct Error {
     pos: usize,
+    span: (usize, usize),
     surround: String,
     kind: ErrorKind,
 }
@@ -685,6 +862,9 @@ pub enum ErrorKind {
     /// An escape sequence was used in a character class where it is not
     /// allowed. e.g., `[a-\pN]` or `[\A]`.
     InvalidClassEscape(Expr),
+    /// A control character escape was not followed by an ASCII letter.
+    /// e.g., `\c1`.
+    InvalidControlEscape(char),
     /// An invalid counted repetition min/max was given. e.g., `a{2,1}`.
     InvalidRepeatRange {
         /// The first number specified in the repetition.
@@ -702,6 +882,9 @@ pub enum ErrorKind {
     /// A repetition operator was applied to an expression that cannot be
     /// repeated. e.g., `a+*` or `a|*`.
     RepeaterUnexpectedExpr(Expr),
+    /// Groups are nested more deeply than the limit given.
+    /// e.g., `((a))` with a limit of `1`.
+    TooDeep(usize),
     /// A capture group name that is never closed. e.g., `(?P<a`.
     UnclosedCaptureName(String),
     /// An unclosed hexadecimal literal. e.g., `\x{a`.
@@ -720,6 +903,15 @@ pub enum ErrorKind {
     UnexpectedFlagEof,
     /// Saw end of regex before two hexadecimal digits were seen. e.g., `\xA`.
     UnexpectedTwoDigitHexEof,
+    /// A recursion refers to a capture group that doesn't exist. e.g.,
+    /// `(?2)(a)`.
+    UnknownRecursionGroup(usize),
+    /// A backreference refers to a capture group that doesn't exist. e.g.,
+    /// `(a)\2`.
+    UnknownBackrefGroup(usize),
+    /// A backreference refers to a capture name that isn't defined before
+    /// it. e.g., `\k<a>(?P<a>.)`.
+    UnknownBackrefName(String),
     /// Unopened parenthesis. e.g., `)`.
     UnopenedParen,
     /// Unrecognized escape sequence. e.g., `\q`.
@@ -747,6 +939,14 @@ impl Error {
         self.pos
     }
 
+    /// Returns the *byte* offsets of the start and end of the character at
+    /// which the error occurred.
+    ///
+    /// The span is empty when the error points to the end of the regex.
+    pub fn span(&self) -> (usize, usize) {
+        self.span
+    }
+
     /// Returns the type of the regex parse error.
     pub fn kind(&self) -> &ErrorKind {
         &self.kind
@@ -768,11 +968,13 @@ impl ErrorKind {
             InvalidCaptureName(_) => "invalid capture name",
             InvalidClassRange{..} => "invalid character class range",
             InvalidClassEscape(_) => "invalid escape sequence in class",
+            InvalidControlEscape(_) => "invalid control character escape",
             InvalidRepeatRange{..} => "invalid counted repetition range",
             InvalidScalarValue(_) => "invalid Unicode scalar value",
             MissingBase10 => "missing count in repetition operator",
             RepeaterExpectsExpr => "repetition operator missing expression",
             RepeaterUnexpectedExpr(_) => "expression cannot be repeated",
+            TooDeep(_) => "groups nested too deeply",
             UnclosedCaptureName(_) => "unclosed capture group name",
             UnclosedHex => "unclosed hexadecimal literal",
             UnclosedParen => "unclosed parenthesis",
@@ -782,6 +984,9 @@ impl ErrorKind {
             UnexpectedEscapeEof => "unexpected EOF in escape sequence",
             UnexpectedFlagEof => "unexpected EOF in flags",
             UnexpectedTwoDigitHexEof => "unexpected EOF in hex literal",
+            UnknownRecursionGroup(_) => "recursion into unknown group",
+            UnknownBackrefGroup(_) => "backreference to unknown group",
+            UnknownBackrefName(_) => "backreference to unknown group name",
             UnopenedParen => "unopened parenthesis",
             UnrecognizedEscape(_) => "unrecognized escape sequence",
             UnrecognizedFlag(_) => "unrecognized flag",
@@ -835,6 +1040,9 @@ impl fmt::Display for ErrorKind {
             InvalidClassEscape(ref e) =>
                 write!(f, "Invalid escape sequence in character \
                            class: '{}'.", e),
+            InvalidControlEscape(c) =>
+                write!(f, "Control character escapes must be followed by \
+                           an ASCII letter, but got: '{}'.", c),
             InvalidRepeatRange { min, max } =>
                 write!(f, "Invalid counted repetition range: {{{}, {}}}. \
                            Counted repetition ranges must start with the \
@@ -849,6 +1057,9 @@ impl fmt::Display for ErrorKind {
             RepeaterUnexpectedExpr(ref e) =>
                 write!(f, "Invalid application of reptition operator to: \
                           '{}'.", e),
+            TooDeep(limit) =>
+                write!(f, "Groups are nested more than {} levels deep.",
+                       limit),
             UnclosedCaptureName(ref s) =>
                 write!(f, "Capture name group for '{}' is not closed. \
                            (Missing a '>'.)", s),
@@ -871,6 +1082,15 @@ impl fmt::Display for ErrorKind {
                            of the regex (missing a ')' or ':')."),
             UnexpectedTwoDigitHexEof =>
                 write!(f, "Unexpected end of two digit hexadecimal literal."),
+            UnknownRecursionGroup(i) =>
+                write!(f, "Recursion into capture group {}, which doesn't \
+                           exist.", i),
+            UnknownBackrefGroup(i) =>
+                write!(f, "Backreference to capture group {}, which doesn't \
+                           exist.", i),
+            UnknownBackrefName(ref n) =>
+                write!(f, "Backreference to capture group name '{}', which \
+                           isn't defined before it.", n),
             UnopenedParen =>
                 write!(f, "Unopened parenthesis."),
             UnrecognizedEscape(c) =>
@@ -973,7 +1193,8 @@ mod tests {
     fn class(ranges: &[(char, char)]) -> CharClass {
         let ranges = ranges.iter().cloned()
                            .map(|(c1, c2)| ClassRange::new(c1, c2)).collect();
-        CharClass::new(ranges)
+        // Not `CharClass::new`, which would canonicalize the ranges up front.
+        CharClass { ranges: ranges, casei: false }
     }
 
     fn classi(ranges: &[(char, char)]) -> CharClass {
@@ -1048,6 +1269,31 @@ mod tests {
         assert_eq!(cls.canonicalize(), class(&[('a', 'b')]));
     }
 
+    #[test]
+    fn class_intersect() {
+        let cls1 = class(&[('a', 'f'), ('h', 'k'), ('x', 'z')]);
+        let cls2 = class(&[('c', 'i'), ('k', 'y')]);
+        assert_eq!(cls1.intersect(cls2), class(&[
+            ('c', 'f'), ('h', 'i'), ('k', 'k'), ('x', 'y'),
+        ]));
+        assert_eq!(class(&[('a', 'c')]).intersect(class(&[('d', 'f')])),
+                   class(&[]));
+    }
+
+    #[test]
+    fn class_subtract() {
+        let cls = class(&[('a', 'z')]).subtract(class(&[
+            ('a', 'a'), ('e', 'e'), ('i', 'i'), ('o', 'o'), ('u', 'u'),
+        ]));
+        assert_eq!(cls, class(&[
+            ('b', 'd'), ('f', 'h'), ('j', 'n'), ('p', 't'), ('v', 'z'),
+        ]));
+        assert_eq!(class(&[('a', 'c')]).subtract(class(&[('a', 'z')])),
+                   class(&[]));
+        assert_eq!(class(&[('a', 'c')]).subtract(class(&[])),
+                   class(&[('a', 'c')]));
+    }
+
     #[test]
     fn class_negate_single() {
         let cls = class(&[('a', 'a')]);
@@ -14,7 +14,7 @@ use unicode::regex::UNICODE_CLASSES;
 
 use {
     Expr, Repeater, CharClass, ClassRange, CaptureIndex, CaptureName,
-    Error, ErrorKind, Result,
+    Error, ErrorKind, ParserOptions, Result,
 };
 
 /// Parser state.
@@ -29,8 +29,15 @@ pub struct Parser {
     chari: usize,
     stack: Vec<Build>,
     caps: usize,
-    names: Vec<String>, // to check for duplicates
+    names: Vec<(String, usize)>, // to check for duplicates, with indices
     flags: Flags,
+    depth: usize, // number of open groups
+    depth_limit: usize,
+    ascii_casei: bool, // only fold the case of ASCII letters
+    dollar_newline: bool, // `$` is `\Z` outside of multi-line mode
+    classes: Vec<(String, CharClass)>, // named classes besides Unicode's
+    recursions: Vec<(usize, usize)>, // group index and position of each
+    backrefs: Vec<(usize, usize)>, // group index and position of each
 }
 
 /// An empheral type for representing the expression stack.
@@ -63,6 +70,17 @@ struct Flags {
 // Primary expression parsing routines.
 impl Parser {
     pub fn parse(s: &str) -> Result<Expr> {
+        Parser::parse_with_options(s, &ParserOptions::new())
+    }
+
+    pub fn parse_with_options(
+        s: &str,
+        opts: &ParserOptions,
+    ) -> Result<Expr> {
+        let classes = opts.classes.iter().map(|&(ref name, ref ranges)| {
+            let ranges = ranges.iter().map(|&(s, e)| ClassRange::new(s, e));
+            (name.clone(), CharClass::new(ranges.collect()))
+        }).collect();
         Parser {
             chars: s.chars().collect(),
             chari: 0,
@@ -76,6 +94,13 @@ impl Parser {
                 swap_greed: false,
                 ignore_space: false,
             },
+            depth: 0,
+            depth_limit: opts.depth_limit,
+            ascii_casei: opts.ascii_case_insensitive,
+            dollar_newline: opts.dollar_newline,
+            classes: classes,
+            recursions: vec![],
+            backrefs: vec![],
         }.parse_expr()
     }
 
@@ -86,6 +111,7 @@ impl Parser {
     fn parse_expr(mut self) -> Result<Expr> {
         while !self.eof() {
             let build_expr = match self.cur() {
+                '\\' if self.is_backref() => try!(self.parse_backref()),
                 '\\' => try!(self.parse_escape()),
                 '|' => { let e = try!(self.alternate()); self.bump(); e }
                 '?' => try!(self.parse_simple_repeat(Repeater::ZeroOrOne)),
@@ -106,6 +132,8 @@ impl Parser {
                 '$' => {
                     if self.flags.multi {
                         self.parse_one(Expr::EndLine)
+                    } else if self.dollar_newline {
+                        self.parse_one(Expr::EndTextNewline)
                     } else {
                         self.parse_one(Expr::EndText)
                     }
@@ -117,9 +145,20 @@ impl Parser {
                         self.parse_one(Expr::AnyCharNoNL)
                     }
                 }
-                '(' => try!(self.parse_group()),
+                '(' => {
+                    let build = try!(self.parse_group());
+                    if let Build::LeftParen { chari, .. } = build {
+                        self.depth += 1;
+                        if self.depth > self.depth_limit {
+                            let kind = ErrorKind::TooDeep(self.depth_limit);
+                            return Err(self.errat(chari, kind));
+                        }
+                    }
+                    build
+                }
                 ')' => {
                     let (old_flags, e) = try!(self.close_paren());
+                    self.depth -= 1;
                     self.bump();
                     self.flags = old_flags;
                     e
@@ -134,7 +173,21 @@ impl Parser {
                 self.stack.push(build_expr);
             }
         }
-        self.finish_concat()
+        let e = try!(self.finish_concat());
+        // A recursion may refer to a group that's opened after it.
+        for &(i, chari) in &self.recursions {
+            if i > self.caps {
+                return Err(self.errat(chari,
+                                      ErrorKind::UnknownRecursionGroup(i)));
+            }
+        }
+        for &(i, chari) in &self.backrefs {
+            if i == 0 || i > self.caps {
+                return Err(self.errat(chari,
+                                      ErrorKind::UnknownBackrefGroup(i)));
+            }
+        }
+        Ok(e)
     }
 
     // Parses an escape sequence, e.g., \Ax
@@ -159,23 +212,27 @@ impl Parser {
         }
         match c {
             'a' => { self.bump(); Ok(lit('\x07')) }
+            'e' => { self.bump(); Ok(lit('\x1B')) }
             'f' => { self.bump(); Ok(lit('\x0C')) }
             't' => { self.bump(); Ok(lit('\t')) }
             'n' => { self.bump(); Ok(lit('\n')) }
             'r' => { self.bump(); Ok(lit('\r')) }
-            'v' => { self.bump(); Ok(lit('\x0B')) }
             'A' => { self.bump(); Ok(Build::Expr(Expr::StartText)) }
             'z' => { self.bump(); Ok(Build::Expr(Expr::EndText)) }
+            'Z' => { self.bump(); Ok(Build::Expr(Expr::EndTextNewline)) }
             'b' => { self.bump(); Ok(Build::Expr(Expr::WordBoundary)) }
             'B' => { self.bump(); Ok(Build::Expr(Expr::NotWordBoundary)) }
+            'R' => { self.bump(); Ok(Build::Expr(linebreak())) }
+            'X' => { self.bump(); Ok(Build::Expr(Expr::AnyGrapheme)) }
             '0'|'1'|'2'|'3'|'4'|'5'|'6'|'7' => self.parse_octal(),
             'x' => { self.bump(); self.parse_hex() }
+            'c' => { self.bump(); self.parse_control() }
             'p'|'P' => {
                 self.bump();
                 self.parse_unicode_class(c == 'P')
                     .map(|cls| Build::Expr(Expr::Class(cls)))
             }
-            'd'|'s'|'w'|'D'|'S'|'W' => {
+            'd'|'s'|'w'|'h'|'v'|'D'|'S'|'W'|'H'|'V' => {
                 self.bump();
                 Ok(Build::Expr(Expr::Class(self.parse_perl_class(c))))
             }
@@ -198,12 +255,19 @@ impl Parser {
         self.bump();
         if self.bump_if("?P<") {
             let n = try!(self.parse_group_name());
-            if self.names.iter().any(|n2| n2 == &n) {
+            if self.names.iter().any(|&(ref n2, _)| n2 == &n) {
                 return Err(self.err(ErrorKind::DuplicateCaptureName(n)));
             }
-            self.names.push(n.clone());
+            // The group is numbered below.
+            self.names.push((n.clone(), self.caps + 1));
             name = Some(n);
+        } else if self.bump_if("?R)") {
+            self.recursions.push((0, chari));
+            return Ok(Build::Expr(Expr::Recurse(0)));
         } else if self.bump_if("?") {
+            if self.peek_is(|c| c >= '0' && c <= '9') {
+                return self.parse_recursion(chari);
+            }
             // This can never be capturing. It's either setting flags for
             // the current group, or it's opening a non-capturing group or
             // it's opening a group with a specific set of flags (which is
@@ -291,6 +355,66 @@ impl Parser {
         }
     }
 
+    // Parses a recursion into a group by its index, e.g., `(?1)`.
+    //
+    // Start: `1`
+    // End:   `a` in `(?1)a`
+    fn parse_recursion(&mut self, opening_chari: usize) -> Result<Build> {
+        let i = try!(self.parse_decimal(|c| c != ')')) as usize;
+        if self.eof() {
+            // e.g., (?1
+            return Err(self.errat(opening_chari, ErrorKind::UnclosedParen));
+        }
+        self.bump();
+        self.recursions.push((i, opening_chari));
+        Ok(Build::Expr(Expr::Recurse(i)))
+    }
+
+    // Returns true if the cursor is at a backreference, e.g., `\1` or
+    // `\k<name>`. A digit is only a backreference when no other digit
+    // follows it, so that `\123` is still an octal escape.
+    fn is_backref(&self) -> bool {
+        let is_digit = |i: usize, lo: char| {
+            self.chars.get(self.chari + i).map_or(false, |&c| {
+                c >= lo && c <= '9'
+            })
+        };
+        self.peek_is("\\k<") || (is_digit(1, '1') && !is_digit(2, '0'))
+    }
+
+    // Parses a backreference by index or by name, e.g., `\1`, `\k<1>` or
+    // `\k<foo>`.
+    //
+    // Start: `\`
+    // End:   `a` in `\1a`
+    fn parse_backref(&mut self) -> Result<Build> {
+        let chari = self.chari;
+        let i = if !self.bump_if("\\k<") {
+            self.bump();
+            self.bump().to_digit(10).unwrap() as usize
+        } else if self.peek_is(|c| c >= '0' && c <= '9') {
+            let i = try!(self.parse_decimal(|c| c != '>')) as usize;
+            if self.eof() {
+                // e.g., \k<1
+                let name = i.to_string();
+                return Err(self.err(ErrorKind::UnclosedCaptureName(name)));
+            }
+            self.bump();
+            i
+        } else {
+            let name = try!(self.parse_group_name());
+            match self.names.iter().find(|&&(ref n, _)| n == &name) {
+                Some(&(_, i)) => i,
+                None => {
+                    let kind = ErrorKind::UnknownBackrefName(name);
+                    return Err(self.errat(chari, kind));
+                }
+            }
+        };
+        self.backrefs.push((i, chari));
+        Ok(Build::Expr(Expr::Backref(i)))
+    }
+
     // Parses a group name, e.g., `foo` in `(?P<foo>abc)`.
     //
     // Start: `f`
@@ -484,6 +608,26 @@ impl Parser {
         }))
     }
 
+    // Parses a control character, e.g., `a\cAb`.
+    //
+    // Start: `A`
+    // End:   `b`
+    fn parse_control(&mut self) -> Result<Build> {
+        if self.eof() {
+            return Err(self.err(ErrorKind::UnexpectedEscapeEof));
+        }
+        let c = self.cur();
+        if !(c >= 'a' && c <= 'z') && !(c >= 'A' && c <= 'Z') {
+            return Err(self.err(ErrorKind::InvalidControlEscape(c)));
+        }
+        self.bump();
+        Ok(Build::Expr(Expr::Literal {
+            // The letter's position in the alphabet, regardless of case.
+            chars: vec![((c as u8) & 0x1F) as char],
+            casei: false,
+        }))
+    }
+
     // Parses a character class, e.g., `[^a-zA-Z0-9]+`.
     //
     // Start: `[`
@@ -492,6 +636,9 @@ impl Parser {
         self.bump();
         let negated = self.bump_if('^');
         let mut class = CharClass::empty();
+        // Set when an operator has been applied, since its result may be
+        // empty.
+        let mut combined = false;
         while self.bump_if('-') {
             class.ranges.push(ClassRange::one('-'));
         }
@@ -500,11 +647,27 @@ impl Parser {
                 // e.g., [a
                 return Err(self.err(ErrorKind::UnexpectedClassEof));
             }
+            if class.len() > 0 || combined {
+                if self.peek_is("&&[") {
+                    self.bump_if("&&");
+                    let other = try!(self.parse_class_operand());
+                    class = class.intersect(other);
+                    combined = true;
+                    continue;
+                }
+                if self.peek_is("--[") {
+                    self.bump_if("--");
+                    let other = try!(self.parse_class_operand());
+                    class = class.subtract(other);
+                    combined = true;
+                    continue;
+                }
+            }
             match self.cur() {
                 // If no ranges have been added, then `]` is the first
                 // character (sans, perhaps, the `^` symbol), so it should
                 // be interpreted as a `]` instead of a closing class bracket.
-                ']' if class.len() > 0 => { self.bump(); break }
+                ']' if class.len() > 0 || combined => { self.bump(); break }
                 '[' => match self.maybe_parse_ascii() {
                     Some(class2) => class.ranges.extend(class2),
                     None => {
@@ -538,6 +701,32 @@ impl Parser {
         Ok(Build::Expr(Expr::Class(class.canonicalize())))
     }
 
+    // Parses the right hand side of a class intersection or subtraction,
+    // which is a nested class, e.g., `[^aeiou]` in `[a-z&&[^aeiou]]`.
+    //
+    // The operator is only recognized when it is followed by a nested
+    // class, so that `[a&&b]` still matches `a`, `&` or `b`. Nested classes
+    // count towards the depth limit, just like groups.
+    //
+    // Start: `[`
+    // End:   `]`
+    fn parse_class_operand(&mut self) -> Result<CharClass> {
+        if let Some(class) = self.maybe_parse_ascii() {
+            return Ok(class);
+        }
+        self.depth += 1;
+        if self.depth > self.depth_limit {
+            return Err(self.err(ErrorKind::TooDeep(self.depth_limit)));
+        }
+        let class = match try!(self.parse_class()) {
+            Build::Expr(Expr::Class(class)) => class,
+            // Because `parse_class` always returns a class.
+            _ => unreachable!(),
+        };
+        self.depth -= 1;
+        Ok(class)
+    }
+
     // Parses a single range in a character class.
     //
     // Since this is a helper for `parse_class`, its signature sticks out.
@@ -550,7 +739,7 @@ impl Parser {
     // End:   `]`
     fn parse_class_range(&mut self, class: &mut CharClass, start: char)
                         -> Result<()> {
-        if !self.bump_if('-') {
+        if self.peek_is("--[") || !self.bump_if('-') {
             // Not a range, so just push a singleton range.
             class.ranges.push(ClassRange::one(start));
             return Ok(());
@@ -650,7 +839,10 @@ impl Parser {
                 }
                 self.bump().to_string()
             };
-        match unicode_class(&name) {
+        let custom = self.classes.iter()
+                                 .find(|&&(ref n, _)| *n == name)
+                                 .map(|&(_, ref cls)| cls.clone());
+        match custom.or_else(|| unicode_class(&name)) {
             None => Err(self.err(ErrorKind::UnrecognizedUnicodeClass(name))),
             Some(cls) => if neg { Ok(cls.negate()) } else { Ok(cls) },
         }
@@ -658,7 +850,8 @@ impl Parser {
 
     // Parses a perl character class with Unicode support.
     //
-    // `name` must be one of d, s, w, D, S, W. If not, this function panics.
+    // `name` must be one of d, s, w, h, v, D, S, W, H, V. If not, this
+    // function panics.
     //
     // No parser state is changed.
     fn parse_perl_class(&mut self, name: char) -> CharClass {
@@ -670,6 +863,10 @@ impl Parser {
             'S' => raw_class_to_expr(PERLS).negate(),
             'w' => raw_class_to_expr(PERLW),
             'W' => raw_class_to_expr(PERLW).negate(),
+            'h' => raw_class_to_expr(PERLH),
+            'H' => raw_class_to_expr(PERLH).negate(),
+            'v' => raw_class_to_expr(PERLV),
+            'V' => raw_class_to_expr(PERLV).negate(),
             _ => unreachable!(),
         }
     }
@@ -733,7 +930,15 @@ impl Parser {
     }
 
     fn errat(&self, pos: usize, kind: ErrorKind) -> Error {
-        Error { pos: pos, surround: self.windowat(pos), kind: kind }
+        let pos = min(pos, self.chars.len());
+        let start = self.chars[..pos].iter().fold(0, |n, c| n + c.len_utf8());
+        let end = start + self.chars.get(pos).map_or(0, |c| c.len_utf8());
+        Error {
+            pos: pos,
+            span: (start, end),
+            surround: self.windowat(pos),
+            kind: kind,
+        }
     }
 
     fn windowat(&self, pos: usize) -> String {
@@ -755,6 +960,9 @@ impl Parser {
     //
     // Otherwise, return the expression unchanged.
     fn maybe_class_case_fold(&mut self, bexpr: Build) -> Build {
+        if self.ascii_casei {
+            return self.ascii_case_fold(bexpr);
+        }
         match bexpr {
             Build::Expr(Expr::Class(cls)) => {
                 Build::Expr(Expr::Class(
@@ -768,6 +976,35 @@ impl Parser {
             bexpr => bexpr,
         }
     }
+
+    // Like `maybe_class_case_fold`, but only folds ASCII letters, and
+    // rewrites case insensitive literals too. A literal ASCII letter becomes
+    // a class of both of its cases.
+    fn ascii_case_fold(&mut self, bexpr: Build) -> Build {
+        match bexpr {
+            Build::Expr(Expr::Class(cls)) => {
+                Build::Expr(Expr::Class(
+                    if self.flags.casei {
+                        cls.ascii_case_fold()
+                    } else {
+                        cls
+                    }
+                ))
+            }
+            Build::Expr(Expr::Literal { chars, casei: true }) => {
+                // The parser only builds literals of one character.
+                let c = chars[0];
+                let letter = (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z');
+                Build::Expr(if letter {
+                    let r = ClassRange::new(c, c);
+                    Expr::Class(CharClass::new(vec![r]).ascii_case_fold())
+                } else {
+                    Expr::Literal { chars: chars, casei: false }
+                })
+            }
+            bexpr => bexpr,
+        }
+    }
 }
 
 struct Chars<'a> {
@@ -1080,7 +1317,7 @@ fn checkadd(x: usize, y: usize) -> usize {
     x.checked_add(y).expect("regex length overflow")
 }
 
-fn unicode_class(name: &str) -> Option<CharClass> {
+pub fn unicode_class(name: &str) -> Option<CharClass> {
     UNICODE_CLASSES.binary_search_by(|&(s, _)| s.cmp(name)).ok().map(|i| {
         raw_class_to_expr(UNICODE_CLASSES[i].1)
     })
@@ -1149,18 +1386,48 @@ const UPPER: Class = &[('A', 'Z')];
 const WORD: Class = &[('0', '9'), ('A', 'Z'), ('a', 'z'), ('_', '_')];
 const XDIGIT: Class = &[('0', '9'), ('A', 'F'), ('a', 'f')];
 
+// Any line break, i.e., `(?:\r\n|\v)`. `\r\n` comes first so that it is
+// never split in two.
+fn linebreak() -> Expr {
+    Expr::Group {
+        e: Box::new(Expr::Alternate(vec![
+            Expr::Literal { chars: vec!['\r', '\n'], casei: false },
+            Expr::Class(raw_class_to_expr(PERLV)),
+        ])),
+        i: None,
+        name: None,
+    }
+}
+
+// Horizontal and vertical whitespace, as defined by PCRE.
+const PERLH: Class = &[
+    ('\t', '\t'), (' ', ' '), ('\u{a0}', '\u{a0}'), ('\u{1680}', '\u{1680}'),
+    ('\u{180e}', '\u{180e}'), ('\u{2000}', '\u{200a}'),
+    ('\u{202f}', '\u{202f}'), ('\u{205f}', '\u{205f}'),
+    ('\u{3000}', '\u{3000}'),
+];
+const PERLV: Class = &[
+    ('\n', '\r'), ('\u{85}', '\u{85}'), ('\u{2028}', '\u{2029}'),
+];
+
 #[cfg(test)]
 mod tests {
-    use { CharClass, ClassRange, Expr, Repeater, ErrorKind };
+    use {
+        CharClass, ClassRange, Expr, Repeater, ErrorKind, ParserOptions,
+        Result,
+    };
     use unicode::regex::{PERLD, PERLS, PERLW};
     use super::Parser;
-    use super::{LOWER, UPPER};
+    use super::{LOWER, UPPER, PERLH, PERLV};
 
     static YI: &'static [(char, char)] = &[
         ('\u{a000}', '\u{a48c}'), ('\u{a490}', '\u{a4c6}'),
     ];
 
     fn p(s: &str) -> Expr { Parser::parse(s).unwrap() }
+    fn pl(s: &str, limit: usize) -> Result<Expr> {
+        Parser::parse_with_options(s, &ParserOptions::new().depth_limit(limit))
+    }
     fn lit(c: char) -> Expr { Expr::Literal { chars: vec![c], casei: false } }
     fn liti(c: char) -> Expr { Expr::Literal { chars: vec![c], casei: true } }
     fn b<T>(v: T) -> Box<T> { Box::new(v) }
@@ -1448,6 +1715,52 @@ mod tests {
         });
     }
 
+    #[test]
+    fn group_recursion() {
+        assert_eq!(p("a(?R)?"), c(&[lit('a'), Expr::Repeat {
+            e: b(Expr::Recurse(0)),
+            r: Repeater::ZeroOrOne,
+            greedy: true,
+        }]));
+        assert_eq!(p("(?1)(a)"), c(&[Expr::Recurse(1), Expr::Group {
+            e: b(lit('a')),
+            i: Some(1),
+            name: None,
+        }]));
+        assert_eq!(p("(?0)"), Expr::Recurse(0));
+    }
+
+    #[test]
+    fn backref() {
+        assert_eq!(p(r"(a)\1"), c(&[Expr::Group {
+            e: b(lit('a')),
+            i: Some(1),
+            name: None,
+        }, Expr::Backref(1)]));
+        assert_eq!(p(r"\k<1>(a)"), c(&[Expr::Backref(1), Expr::Group {
+            e: b(lit('a')),
+            i: Some(1),
+            name: None,
+        }]));
+        assert_eq!(p(r"(a)(?P<b>c)\k<b>"), c(&[Expr::Group {
+            e: b(lit('a')),
+            i: Some(1),
+            name: None,
+        }, Expr::Group {
+            e: b(lit('c')),
+            i: Some(2),
+            name: Some("b".into()),
+        }, Expr::Backref(2)]));
+        // More than one digit is still an octal escape, as is any digit in
+        // a class.
+        assert_eq!(p(r"(a)\12"), c(&[Expr::Group {
+            e: b(lit('a')),
+            i: Some(1),
+            name: None,
+        }, lit('\n')]));
+        assert_eq!(p(r"[\1]"), Expr::Class(class(&[('\x01', '\x01')])));
+    }
+
     #[test]
     fn group_flags() {
         assert_eq!(p("(?i:a)"), Expr::Group {
@@ -1486,6 +1799,30 @@ mod tests {
         assert_eq!(p("(?i)a"), liti('a'));
     }
 
+    #[test]
+    fn flags_inline_ascii_casei() {
+        fn pa(s: &str) -> Expr {
+            let opts = ParserOptions::new().ascii_case_insensitive(true);
+            Parser::parse_with_options(s, &opts).unwrap()
+        }
+        assert_eq!(pa("(?i)a"), Expr::Class(class(&[('A', 'A'), ('a', 'a')])));
+        assert_eq!(pa("(?i)δ"), lit('δ'));
+        assert_eq!(pa("(?i)[k-mδ]"), Expr::Class(class(&[
+            ('K', 'M'), ('k', 'm'), ('δ', 'δ'),
+        ])));
+        assert_eq!(pa("(?i)[X-c]"), Expr::Class(class(&[
+            ('A', 'C'), ('X', 'c'), ('x', 'z'),
+        ])));
+        assert_eq!(pa("a(?i:b)"), c(&[
+            lit('a'),
+            Expr::Group {
+                e: b(Expr::Class(class(&[('B', 'B'), ('b', 'b')]))),
+                i: None,
+                name: None,
+            },
+        ]));
+    }
+
     #[test]
     fn flags_inline_multiple() {
         assert_eq!(p("(?is)a."), c(&[liti('a'), Expr::AnyChar]));
@@ -1496,6 +1833,19 @@ mod tests {
         assert_eq!(p("(?m)^(?-m)$"), c(&[Expr::StartLine, Expr::EndText]));
     }
 
+    #[test]
+    fn dollar_newline() {
+        fn pd(s: &str) -> Expr {
+            let opts = ParserOptions::new().dollar_newline(true);
+            Parser::parse_with_options(s, &opts).unwrap()
+        }
+        assert_eq!(p(r"\Z"), Expr::EndTextNewline);
+        assert_eq!(pd(r"$\z\Z"), c(&[
+            Expr::EndTextNewline, Expr::EndText, Expr::EndTextNewline,
+        ]));
+        assert_eq!(pd("(?m)$"), Expr::EndLine);
+    }
+
     #[test]
     fn flags_inline_swap_greed() {
         assert_eq!(p("(?U)a*a*?(?i-U)a*a*?"), c(&[
@@ -1560,9 +1910,9 @@ mod tests {
 
     #[test]
     fn escape_simple() {
-        assert_eq!(p(r"\a\f\t\n\r\v"), c(&[
-            lit('\x07'), lit('\x0C'), lit('\t'),
-            lit('\n'), lit('\r'), lit('\x0B'),
+        assert_eq!(p(r"\a\e\f\t\n\r"), c(&[
+            lit('\x07'), lit('\x1B'), lit('\x0C'), lit('\t'),
+            lit('\n'), lit('\r'),
         ]));
     }
 
@@ -1602,6 +1952,35 @@ mod tests {
         assert_eq!(p(r"\x{2603}"), lit('\u{2603}'));
     }
 
+    #[test]
+    fn escape_linebreak() {
+        assert_eq!(p(r"\R"), Expr::Group {
+            e: b(Expr::Alternate(vec![
+                Expr::Literal { chars: vec!['\r', '\n'], casei: false },
+                Expr::Class(class(PERLV)),
+            ])),
+            i: None,
+            name: None,
+        });
+    }
+
+    #[test]
+    fn escape_grapheme() {
+        assert_eq!(p(r"\X"), Expr::AnyGrapheme);
+        assert_eq!(p(r"\X+"), Expr::Repeat {
+            e: b(Expr::AnyGrapheme),
+            r: Repeater::OneOrMore,
+            greedy: true,
+        });
+    }
+
+    #[test]
+    fn escape_control() {
+        assert_eq!(p(r"\cA"), lit('\x01'));
+        assert_eq!(p(r"\cz"), lit('\x1A'));
+        assert_eq!(p(r"\cJ\cm"), c(&[lit('\n'), lit('\r')]));
+    }
+
     #[test]
     fn escape_unicode_name() {
         assert_eq!(p(r"\p{Yi}"), Expr::Class(class(YI)));
@@ -1663,6 +2042,24 @@ mod tests {
         ]).negate().case_fold()));
     }
 
+    #[test]
+    fn escape_unicode_custom() {
+        let classes = vec![
+            ("Block=Runic".to_owned(), vec![('\u{16f0}', '\u{16a0}')]),
+            ("V".to_owned(), vec![('e', 'e'), ('a', 'a'), ('b', 'f')]),
+            ("Yi".to_owned(), vec![('y', 'y')]),
+        ];
+        let opts = ParserOptions::new().classes(classes);
+        let pc = |s: &str| Parser::parse_with_options(s, &opts).unwrap();
+        assert_eq!(pc(r"\p{Block=Runic}"),
+                   Expr::Class(class(&[('\u{16a0}', '\u{16f0}')])));
+        assert_eq!(pc(r"\pV"), Expr::Class(class(&[('a', 'f')])));
+        assert_eq!(pc(r"\PV"),
+                   Expr::Class(class(&[('a', 'f')]).negate()));
+        assert_eq!(pc(r"\p{Yi}"), Expr::Class(class(&[('y', 'y')])));
+        assert_eq!(pc(r"\p{Greek}"), p(r"\p{Greek}"));
+    }
+
     #[test]
     fn escape_perl_d() {
         assert_eq!(p(r"\d"), Expr::Class(class(PERLD)));
@@ -1678,6 +2075,19 @@ mod tests {
         assert_eq!(p(r"\w"), Expr::Class(class(PERLW)));
     }
 
+    #[test]
+    fn escape_perl_h() {
+        assert_eq!(p(r"\h"), Expr::Class(class(PERLH)));
+        assert_eq!(p(r"\H"), Expr::Class(class(PERLH).negate()));
+    }
+
+    #[test]
+    fn escape_perl_v() {
+        assert_eq!(p(r"\v"), Expr::Class(class(PERLV)));
+        assert_eq!(p(r"\V"), Expr::Class(class(PERLV).negate()));
+        assert_eq!(p(r"[\v]"), Expr::Class(class(PERLV)));
+    }
+
     #[test]
     fn escape_perl_d_negate() {
         assert_eq!(p(r"\D"), Expr::Class(class(PERLD).negate()));
@@ -1867,6 +2277,44 @@ mod tests {
         ]));
     }
 
+    #[test]
+    fn class_intersect() {
+        assert_eq!(p(r"[a-z&&[^aeiou]]"), Expr::Class(class(&[
+            ('b', 'd'), ('f', 'h'), ('j', 'n'), ('p', 't'), ('v', 'z'),
+        ])));
+        assert_eq!(p(r"[\d&&[0-4a-z]]"), Expr::Class(class(&[('0', '4')])));
+        assert_eq!(p(r"[a-f&&[:upper:]]"), Expr::Class(class(&[])));
+        assert_eq!(p(&p(r"[a&&[b]]").to_string()), Expr::Class(class(&[])));
+        assert_eq!(p(r"[^a-z&&[c-x]]"), Expr::Class(class(&[
+            ('\x00', 'b'), ('y', '\u{10FFFF}'),
+        ])));
+    }
+
+    #[test]
+    fn class_subtract() {
+        assert_eq!(p(r"[a-z--[aeiou]]"), Expr::Class(class(&[
+            ('b', 'd'), ('f', 'h'), ('j', 'n'), ('p', 't'), ('v', 'z'),
+        ])));
+        assert_eq!(p(r"[a--[a]]"), Expr::Class(class(&[])));
+        assert_eq!(p(r"[a-z--[b-y]--[a]0]"), Expr::Class(class(&[
+            ('0', '0'), ('z', 'z'),
+        ])));
+        assert_eq!(p(r"(?i)[a-c--[b]]"), Expr::Class(class(&[
+            ('a', 'a'), ('c', 'c'),
+        ]).case_fold()));
+    }
+
+    #[test]
+    fn class_operators_need_class() {
+        assert_eq!(p(r"[a&&b]"), Expr::Class(class(&[
+            ('&', '&'), ('a', 'b'),
+        ])));
+        assert_eq!(p(r"[&&[a]]"), Expr::Concat(vec![
+            Expr::Class(class(&[('&', '&'), ('[', '['), ('a', 'a')])),
+            lit(']'),
+        ]));
+    }
+
     #[test]
     fn class_overlapping() {
         assert_eq!(p("[a-fd-h]"), Expr::Class(class(&[('a', 'h')])));
@@ -2012,6 +2460,15 @@ mod tests {
         }}
     }
 
+    #[test]
+    fn error_span() {
+        let span = |re: &str| Parser::parse(re).unwrap_err().span();
+        assert_eq!(span("a("), (1, 2));
+        assert_eq!(span("é("), (2, 3));
+        assert_eq!(span("☃(?P<é"), (9, 9));
+        assert_eq!(span("a)"), (1, 2));
+    }
+
     #[test]
     fn error_repeat_no_expr_simple() {
         test_err!("(*", 1, ErrorKind::RepeaterExpectsExpr);
@@ -2137,6 +2594,15 @@ mod tests {
         test_err!("ab(xy", 2, ErrorKind::UnclosedParen);
     }
 
+    #[test]
+    fn error_too_deep() {
+        let err = pl("(a)((?i:(b)))", 2).unwrap_err();
+        assert_eq!(8, err.pos);
+        assert_eq!(ErrorKind::TooDeep(2), err.kind);
+        assert!(pl("(a)((?i:b))", 2).is_ok());
+        assert!(pl("(?i)a", 0).is_ok());
+    }
+
     #[test]
     fn error_finish_concat_empty_alt() {
         test_err!("a|", 2, ErrorKind::EmptyAlternate);
@@ -2162,6 +2628,22 @@ mod tests {
         test_err!("(?P<>a)", 4, ErrorKind::EmptyCaptureName);
     }
 
+    #[test]
+    fn error_group_recursion() {
+        test_err!("(a)(?2)", 3, ErrorKind::UnknownRecursionGroup(2));
+        test_err!("(?1", 0, ErrorKind::UnclosedParen);
+        test_err!("(?1a)", 4, ErrorKind::InvalidBase10("1a".into()));
+    }
+
+    #[test]
+    fn error_backref() {
+        test_err!(r"(a)\2", 3, ErrorKind::UnknownBackrefGroup(2));
+        test_err!(r"\k<0>", 0, ErrorKind::UnknownBackrefGroup(0));
+        test_err!(r"\k<a>(?P<a>b)", 0,
+                  ErrorKind::UnknownBackrefName("a".into()));
+        test_err!(r"(a)\k<1", 7, ErrorKind::UnclosedCaptureName("1".into()));
+    }
+
     #[test]
     fn error_group_opts_unrecognized_flag() {
         test_err!("(?z:a)", 2, ErrorKind::UnrecognizedFlag('z'));
@@ -2197,6 +2679,16 @@ mod tests {
         test_err!(r"\m", 1, ErrorKind::UnrecognizedEscape('m'));
     }
 
+    #[test]
+    fn error_escape_control_eof() {
+        test_err!(r"\c", 2, ErrorKind::UnexpectedEscapeEof);
+    }
+
+    #[test]
+    fn error_escape_control_invalid() {
+        test_err!(r"\c1", 2, ErrorKind::InvalidControlEscape('1'));
+    }
+
     #[test]
     fn error_escape_hex2_eof0() {
         test_err!(r"\x", 2, ErrorKind::UnexpectedTwoDigitHexEof);
@@ -2263,6 +2755,8 @@ mod tests {
     fn error_unicode_unknown_name() {
         test_err!(r"\p{Yii}", 7,
                   ErrorKind::UnrecognizedUnicodeClass("Yii".into()));
+        test_err!(r"\p{Block=Runic}", 15,
+                  ErrorKind::UnrecognizedUnicodeClass("Block=Runic".into()));
     }
 
     #[test]
@@ -2293,6 +2787,10 @@ mod tests {
     #[test]
     fn error_class_valid_escape_not_allowed() {
         test_err!(r"[\A]", 3, ErrorKind::InvalidClassEscape(Expr::StartText));
+        test_err!(r"[\R]", 3, ErrorKind::InvalidClassEscape(
+            super::linebreak()));
+        test_err!(r"[\X]", 3, ErrorKind::InvalidClassEscape(
+            Expr::AnyGrapheme));
     }
 
     #[test]
@@ -2313,6 +2811,13 @@ mod tests {
         });
     }
 
+    #[test]
+    fn error_class_operand_too_deep() {
+        let err = pl("[a&&[a&&[a]]]", 1).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::TooDeep(1));
+        assert!(pl("[a&&[a]--[b]]", 1).is_ok());
+    }
+
     #[test]
     fn error_class_empty_range() {
         test_err!("[]", 2, ErrorKind::UnexpectedClassEof);
@@ -2323,5 +2828,7 @@ mod tests {
     fn error_duplicate_capture_name() {
         test_err!("(?P<a>.)(?P<a>.)", 14,
                   ErrorKind::DuplicateCaptureName("a".into()));
+        test_err!("(?P<a>.)|(?P<a>.)", 15,
+                  ErrorKind::DuplicateCaptureName("a".into()));
     }
 }
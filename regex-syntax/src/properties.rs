@@ -23,7 +23,8 @@ fn qc<T: Testable>(t: T) {
 fn class(ranges: &[(char, char)]) -> CharClass {
     let ranges = ranges.iter().cloned()
                        .map(|(c1, c2)| ClassRange::new(c1, c2)).collect();
-    CharClass::new(ranges)
+    // Not `CharClass::new`, which would canonicalize the ranges up front.
+    CharClass { ranges: ranges, casei: false }
 }
 
 // Test invariants for canonicalizing character classes.
@@ -134,9 +135,10 @@ impl Arbitrary for Expr {
 
         let nada = || Box::new(None.into_iter());
         let es: Box<Iterator<Item=Expr>> = match *self {
-            Empty | AnyChar | AnyCharNoNL
-            | StartLine | EndLine | StartText | EndText
-            | WordBoundary | NotWordBoundary => nada(),
+            Empty | AnyChar | AnyCharNoNL | AnyGrapheme
+            | StartLine | EndLine | StartText | EndText | EndTextNewline
+            | WordBoundary | NotWordBoundary
+            | Recurse(_) | Backref(_) => nada(),
             Literal { ref chars, .. } if chars.len() == 1 => nada(),
             Literal { ref chars, casei } => {
                 Box::new((chars.clone(), casei)
@@ -12,7 +12,7 @@
 use std::iter::repeat;
 use test::Bencher;
 use rand::{Rng, thread_rng};
-use regex::{Regex, NoExpand};
+use regex::{Regex, NoExpand, LiteralSearcher};
 
 fn bench_assert_match(b: &mut Bencher, re: Regex, text: &str) {
     b.iter(|| if !re.is_match(text) { panic!("no match") });
@@ -37,6 +37,13 @@ fn literal(b: &mut Bencher) {
     bench_assert_match(b, re, &text);
 }
 
+#[bench]
+fn literal_searcher(b: &mut Bencher) {
+    let s = LiteralSearcher::new(&["foo", "bar", "quux"]);
+    let text = format!("{}quux", repeat("x").take(50).collect::<String>());
+    b.iter(|| if s.find(&text).is_none() { panic!("no match") });
+}
+
 #[bench]
 fn not_literal(b: &mut Bencher) {
     let re = regex!(".y");
@@ -59,6 +66,28 @@ fn match_class_in_range(b: &mut Bencher) {
     bench_assert_match(b, re, &text);
 }
 
+#[bench]
+fn match_class_single_range(b: &mut Bencher) {
+    let re = regex!("[0-9]+");
+    let text = format!("{}9", repeat("xxxx").take(20).collect::<String>());
+    bench_assert_match(b, re, &text);
+}
+
+#[bench]
+fn match_class_ascii_word(b: &mut Bencher) {
+    let re = regex!("[a-zA-Z0-9_]+");
+    let text = format!("{}a", repeat("-+-!").take(20).collect::<String>());
+    bench_assert_match(b, re, &text);
+}
+
+#[bench]
+fn match_class_word(b: &mut Bencher) {
+    // `\w` is Unicode aware, so it can't use an ASCII bitmap.
+    let re = regex!(r"\w+");
+    let text = format!("{}a", repeat("-+-!").take(20).collect::<String>());
+    bench_assert_match(b, re, &text);
+}
+
 #[bench]
 fn match_class_unicode(b: &mut Bencher) {
     let re = regex!(r"\pL");
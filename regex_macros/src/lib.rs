@@ -34,7 +34,7 @@ use rustc::plugin::Registry;
 
 use regex::Regex;
 use regex::internal::{
-    Inst, LookInst, OneChar, CharRanges, Program, Dynamic, Native,
+    Inst, LookInst, CharRanges, Program, Dynamic, Native, MatchEngine,
 };
 
 /// For the `regex!` syntax extension. Do not use.
@@ -63,14 +63,16 @@ pub fn plugin_registrar(reg: &mut Registry) {
 /// strategy is identical and vm.rs has comments and will be easier to follow.
 fn native(cx: &mut ExtCtxt, sp: codemap::Span, tts: &[ast::TokenTree])
           -> Box<MacResult+'static> {
-    let regex = match parse(cx, tts) {
-        Some(r) => r,
+    let args = match parse(cx, tts) {
+        Some(args) => args,
         // error is logged in 'parse' with cx.span_err
         None => return DummyResult::any(sp),
     };
+    let regex = args.regex;
     // We use the largest possible size limit because this is happening at
-    // compile time. We trust the programmer.
-    let re = match Regex::with_size_limit(::std::usize::MAX, &regex) {
+    // compile time. We trust the programmer, unless they gave a limit.
+    let limit = args.size_limit.unwrap_or(::std::usize::MAX);
+    let re = match Regex::with_engine(args.engine, limit, &regex) {
         Ok(re) => re,
         Err(err) => {
             cx.span_err(sp, &err.to_string());
@@ -81,10 +83,26 @@ fn native(cx: &mut ExtCtxt, sp: codemap::Span, tts: &[ast::TokenTree])
         Dynamic(ref prog) => prog.clone(),
         Native(_) => unreachable!(),
     };
+    if args.engine == Some(MatchEngine::Literals) && !prog.prefixes_complete {
+        cx.span_err(sp, "the Literals engine can only run a regex that \
+                         is a set of literals.");
+        return DummyResult::any(sp)
+    }
+    if prog.recursive {
+        cx.span_err(sp, "regex! does not support recursion, since it \
+                         compiles to an NFA. Use Regex::new instead.");
+        return DummyResult::any(sp)
+    }
+    if !prog.backrefs.is_empty() {
+        cx.span_err(sp, "regex! does not support backreferences, since it \
+                         compiles to an NFA. Use Regex::new instead.");
+        return DummyResult::any(sp)
+    }
 
     let mut gen = NfaGen {
         cx: &*cx, sp: sp, prog: prog,
         names: re.names_iter().collect(), original: re.as_str().to_string(),
+        engine: args.engine,
     };
     MacEager::expr(gen.code())
 }
@@ -95,6 +113,9 @@ struct NfaGen<'a> {
     prog: Program,
     names: Vec<Option<String>>,
     original: String,
+    /// The engine given with `engine = ...`. The generated code is an NFA,
+    /// so a regex pinned to another engine searches with a dynamic copy.
+    engine: Option<MatchEngine>,
 }
 
 impl<'a> NfaGen<'a> {
@@ -113,6 +134,15 @@ impl<'a> NfaGen<'a> {
             }
         );
         let prefix_anchor = self.prog.anchored_begin;
+        let matches_empty = self.prog.matches_empty;
+        let engine = match self.engine {
+            Some(engine) => {
+                let engine = self.cx.ident_of(&format!("{:?}", engine));
+                quote_expr!(self.cx,
+                            Some(::regex::internal::MatchEngine::$engine))
+            }
+            None => self.cx.expr_none(self.sp),
+        };
 
         // let prefix_lit = Rc::new(self.prog.prefix.as_bytes().to_vec());
         // let prefix_bytes = self.cx.expr_lit(self.sp, ast::LitBinary(prefix_lit));
@@ -316,6 +346,8 @@ fn exec<'t>(
     original: $regex,
     names: &CAP_NAMES,
     prog: exec,
+    matches_empty: $matches_empty,
+    engine: $engine,
 })
         })
     }
@@ -341,6 +373,41 @@ fn exec<'t>(
                         }
                     })
                 }
+                Inst::EmptyLook(LookInst::StartLineCRLF) => {
+                    quote_expr!(self.cx, {
+                        let prev = self.input.previous_at(at.pos());
+                        if prev.char().is_none()
+                           || prev.char() == '\n'
+                           || (prev.char() == '\r' && at.char() != '\n') {
+                            self.add(nlist, thread_caps, $nextpc, at);
+                        }
+                    })
+                }
+                Inst::EmptyLook(LookInst::EndLineCRLF) => {
+                    quote_expr!(self.cx, {
+                        let prev = self.input.previous_at(at.pos());
+                        if at.char().is_none()
+                           || at.char() == '\r'
+                           || (at.char() == '\n' && prev.char() != '\r') {
+                            self.add(nlist, thread_caps, $nextpc, at);
+                        }
+                    })
+                }
+                Inst::EmptyLook(LookInst::StartLineWith(c)) => {
+                    quote_expr!(self.cx, {
+                        let prev = self.input.previous_at(at.pos());
+                        if prev.char().is_none() || prev.char() == $c {
+                            self.add(nlist, thread_caps, $nextpc, at);
+                        }
+                    })
+                }
+                Inst::EmptyLook(LookInst::EndLineWith(c)) => {
+                    quote_expr!(self.cx, {
+                        if at.char().is_none() || at.char() == $c {
+                            self.add(nlist, thread_caps, $nextpc, at);
+                        }
+                    })
+                }
                 Inst::EmptyLook(LookInst::StartText) => {
                     quote_expr!(self.cx, {
                         let prev = self.input.previous_at(at.pos());
@@ -356,6 +423,29 @@ fn exec<'t>(
                         }
                     })
                 }
+                Inst::EmptyLook(LookInst::EndTextNewline) => {
+                    quote_expr!(self.cx, {
+                        let next = self.input.at(at.next_pos());
+                        if at.char().is_none()
+                           || (at.char() == '\n' && next.char().is_none()) {
+                            self.add(nlist, thread_caps, $nextpc, at);
+                        }
+                    })
+                }
+                Inst::EmptyLook(LookInst::GraphemeBoundary) => {
+                    quote_expr!(self.cx, {
+                        if self.input.is_grapheme_boundary(at.pos()) {
+                            self.add(nlist, thread_caps, $nextpc, at);
+                        }
+                    })
+                }
+                Inst::EmptyLook(LookInst::NotGraphemeBoundary) => {
+                    quote_expr!(self.cx, {
+                        if !self.input.is_grapheme_boundary(at.pos()) {
+                            self.add(nlist, thread_caps, $nextpc, at);
+                        }
+                    })
+                }
                 Inst::EmptyLook(ref wbty) => {
                     let m = if *wbty == LookInst::WordBoundary {
                         quote_expr!(self.cx, { w1 ^ w2 })
@@ -381,6 +471,10 @@ fn exec<'t>(
                         thread_caps[$slot] = old;
                     }
                 }),
+                // Native regexes are never compiled with branch marks.
+                Inst::BranchMark(_) => quote_expr!(self.cx, {
+                    self.add(nlist, thread_caps, $nextpc, at);
+                }),
                 Inst::Jump(to) => quote_expr!(self.cx, {
                     self.add(nlist, thread_caps, $to, at);
                 }),
@@ -388,7 +482,7 @@ fn exec<'t>(
                     self.add(nlist, thread_caps, $x, at);
                     self.add(nlist, thread_caps, $y, at);
                 }),
-                // For Match, OneChar, CharClass, Any, AnyNoNL
+                // For Match, Char, CharClass, Any, AnyNoNL
                 _ => quote_expr!(self.cx, {
                     let mut t = &mut nlist.thread(ti);
                     for (slot, val) in t.caps.iter_mut().zip(thread_caps.iter()) {
@@ -413,13 +507,13 @@ fn exec<'t>(
                     }
                     return true;
                 }),
-                Inst::Char(OneChar { c, casei }) => quote_expr!(self.cx, {
-                    if $c == at.char() || ($casei && $c == at.char().case_fold()) {
+                Inst::Char(c) => quote_expr!(self.cx, {
+                    if $c == at.char() {
                         self.add(nlist, thread_caps, $nextpc, at_next);
                     }
                     return false;
                 }),
-                Inst::Ranges(CharRanges { ref ranges, casei }) => {
+                Inst::Ranges(CharRanges { ref ranges, casei, .. }) => {
                     let match_class = self.match_class(ranges);
                     quote_expr!(self.cx, {
                         let mut c = at.char();
@@ -434,7 +528,7 @@ fn exec<'t>(
                         return false;
                     })
                 }
-                // EmptyLook, Save, Jump, Split
+                // EmptyLook, Save, Jump, Split, BranchMark
                 _ => quote_expr!(self.cx, { return false; }),
             };
             self.arm_inst(pc, body)
@@ -527,9 +621,19 @@ fn exec<'t>(
     }
 }
 
-/// Looks for a single string literal and returns it.
-/// Otherwise, logs an error with cx.span_err and returns None.
-fn parse(cx: &mut ExtCtxt, tts: &[ast::TokenTree]) -> Option<String> {
+/// The arguments of `regex!`.
+struct Args {
+    regex: String,
+    /// The engine given by `engine = Nfa`, if any.
+    engine: Option<MatchEngine>,
+    /// The size limit given by `size_limit = 1000`, if any.
+    size_limit: Option<usize>,
+}
+
+/// Looks for a single string literal followed by optional `name = value`
+/// settings, and returns them.
+/// Issues an error and returns None if the arguments are invalid.
+fn parse(cx: &mut ExtCtxt, tts: &[ast::TokenTree]) -> Option<Args> {
     let mut parser = cx.new_parser_from_tts(tts);
     let entry = cx.expander().fold_expr(parser.parse_expr());
     let regex = match entry.node {
@@ -551,9 +655,61 @@ fn parse(cx: &mut ExtCtxt, tts: &[ast::TokenTree]) -> Option<String> {
             return None
         }
     };
+    let mut args = Args { regex: regex, engine: None, size_limit: None };
+    while parser.eat(&token::Comma).ok().unwrap() {
+        if parser.eat(&token::Eof).ok().unwrap() {
+            return Some(args);
+        }
+        // `name = value` parses as an assignment.
+        let setting = cx.expander().fold_expr(parser.parse_expr());
+        let (name, value) = match setting.node {
+            ast::ExprAssign(ref name, ref value) => {
+                (pprust::expr_to_string(&**name), value.clone())
+            }
+            _ => {
+                cx.span_err(setting.span, &format!(
+                    "expected `name = value` but got `{}`",
+                    pprust::expr_to_string(&*setting)));
+                return None
+            }
+        };
+        match (&*name, &value.node) {
+            ("engine", &ast::ExprPath(..)) => {
+                args.engine = match &*pprust::expr_to_string(&*value) {
+                    "Backtrack" => Some(MatchEngine::Backtrack),
+                    "Nfa" => Some(MatchEngine::Nfa),
+                    "Literals" => Some(MatchEngine::Literals),
+                    other => {
+                        cx.span_err(value.span, &format!(
+                            "unknown engine `{}`, expected `Backtrack`, \
+                             `Nfa` or `Literals`", other));
+                        return None
+                    }
+                };
+            }
+            ("size_limit", &ast::ExprLit(ref lit)) => {
+                args.size_limit = match lit.node {
+                    ast::LitInt(n, _) => Some(n as usize),
+                    _ => {
+                        cx.span_err(value.span, &format!(
+                            "expected integer literal but got `{}`",
+                            pprust::lit_to_string(&**lit)));
+                        return None
+                    }
+                };
+            }
+            _ => {
+                cx.span_err(setting.span, &format!(
+                    "expected `engine = <engine>` or \
+                     `size_limit = <integer>` but got `{}`",
+                    pprust::expr_to_string(&*setting)));
+                return None
+            }
+        }
+    }
     if !parser.eat(&token::Eof).ok().unwrap() {
         cx.span_err(parser.span, "only one string literal allowed");
         return None;
     }
-    Some(regex)
+    Some(args)
 }
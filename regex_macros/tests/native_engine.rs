@@ -0,0 +1,52 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use regex::Regex;
+use regex::internal::{MatchEngine, Native};
+
+static PINNED: Regex = regex!(r"(\w+)@(\w+)\.com|(\d+)", engine = Nfa);
+
+fn caps(re: &Regex, text: &str) -> Vec<Vec<Option<(usize, usize)>>> {
+    re.captures_iter(text).map(|caps| caps.iter_pos().collect()).collect()
+}
+
+fn is_native(re: &Regex) -> bool {
+    match *re {
+        Native(_) => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn pinned_nfa_matches_dynamic() {
+    let re = r"(\w+)@(\w+)\.com|(\d+)";
+    let dynamic = Regex::with_engine(
+        Some(MatchEngine::Nfa), 10 * (1 << 20), re).unwrap();
+    assert!(is_native(&PINNED));
+    assert_eq!(PINNED.chosen_engine(""), Some(MatchEngine::Nfa));
+    assert_eq!(PINNED.as_str(), re);
+
+    let text = "a@b.com, c@d.org 42 and e@f.com";
+    assert_eq!(caps(&PINNED, text), caps(&dynamic, text));
+    assert_eq!(caps(&PINNED, text),
+               caps(&regex!(r"(\w+)@(\w+)\.com|(\d+)"), text));
+}
+
+#[test]
+fn pinned_size_limit() {
+    let re = regex!(r"\d+", engine = Backtrack, size_limit = 1000);
+    assert!(is_native(&re));
+    assert_eq!(re.chosen_engine(""), Some(MatchEngine::Backtrack));
+    assert_eq!(re.find("ab12"), Some((2, 4)));
+
+    let re = regex!(r"\d+", size_limit = 1000,);
+    assert_eq!(re.chosen_engine(""), None);
+    assert_eq!(re.find("ab12"), Some((2, 4)));
+}
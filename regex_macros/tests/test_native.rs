@@ -18,3 +18,4 @@ macro_rules! searcher_expr { ($e:expr) => ($e) }
 
 mod tests;
 mod native_static;
+mod native_engine;
@@ -8,7 +8,9 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use regex::{Regex, NoExpand};
+use regex::{
+    Regex, RegexBuilder, RegexSet, AlternationBuilder, Anchored, NoExpand,
+};
 
 #[test]
 fn eq() {
@@ -31,6 +33,1185 @@ fn split() {
     assert_eq!(subs, vec!("cauchy", "plato", "tyler", "binx"));
 }
 
+#[test]
+fn branch_marks() {
+    let re = RegexBuilder::new(r"(?:(\d+)|([a-z]+)|\s)")
+                          .branch_marks(true)
+                          .compile().unwrap();
+    let branches: Vec<_> = re.captures_iter("ab 12")
+                             .map(|caps| caps.branch())
+                             .collect();
+    assert_eq!(branches, vec![Some(1), Some(2), Some(0)]);
+
+    let caps = re.captures("12").unwrap();
+    assert_eq!(caps.len(), 3);
+    assert_eq!(caps.at(1), Some("12"));
+    assert_eq!(regex!("a|b").captures("b").unwrap().branch(), None);
+}
+
+#[test]
+fn regex_set() {
+    let set = RegexSet::new(&[r"\d+", r"[a-z]+", r"^bar"]).unwrap();
+    assert_eq!(set.matches("foo123"), vec![true, true, false]);
+    assert_eq!(set.matches("bar"), vec![false, true, true]);
+    assert_eq!(set.matches("--"), vec![false, false, false]);
+    assert!(!set.is_match(""));
+
+    // Every pattern can match at the same place.
+    let set = RegexSet::new(&[r"a", r"a", r"a*", r"(a)\1"]).unwrap();
+    assert_eq!(set.matches("xaa"), vec![true, true, true, true]);
+    assert_eq!(set.matches("xa"), vec![true, true, true, false]);
+
+    assert!(RegexSet::new(&[]).unwrap().matches("a").is_empty());
+    assert!(RegexSet::new(&["a", "("]).is_err());
+}
+
+#[test]
+fn alternation_builder() {
+    let keywords: Vec<String> = (0..50).map(|i| format!("kw{}.", i)).collect();
+    let re = keywords.iter()
+                     .fold(AlternationBuilder::new(), |b, k| b.add_literal(k))
+                     .compile().unwrap();
+    let caps = re.captures("x kw37. kw3x").unwrap();
+    assert_eq!(caps.branch(), Some(37));
+    assert_eq!(caps.pos(0), Some((2, 7)));
+    // `.` is quoted, so `kw3x` doesn't match.
+    let branches: Vec<_> = re.captures_iter("kw3x kw3. kw49.")
+                             .map(|caps| caps.branch())
+                             .collect();
+    assert_eq!(branches, vec![Some(3), Some(49)]);
+
+    let re = AlternationBuilder::new().add(r"(?i)if").unwrap()
+                                      .add(r"\d+").unwrap()
+                                      .compile().unwrap();
+    assert_eq!(re.captures("IF").unwrap().branch(), Some(0));
+    assert_eq!(re.captures("42").unwrap().branch(), Some(1));
+    assert!(AlternationBuilder::new().add("(").is_err());
+
+    // A branch can't close the group around it or open another one.
+    assert!(AlternationBuilder::new().add("a)|(?:b").is_err());
+    let re = AlternationBuilder::new().add("(?x)a # comment").unwrap()
+                                      .add("b").unwrap()
+                                      .compile().unwrap();
+    assert_eq!(re.captures("b").unwrap().branch(), Some(1));
+    assert!(!re.is_match("#"));
+    let re = AlternationBuilder::new().add("a").unwrap()
+                                      .add("b ").unwrap()
+                                      .compile().unwrap();
+    assert!(re.is_match("b "));
+    assert!(!re.is_match("b\n"));
+
+    // Groups are numbered across branches.
+    let re = AlternationBuilder::new().add("(a)").unwrap()
+                                      .add(r"(b)\1").unwrap()
+                                      .compile().unwrap();
+    assert!(!re.is_match("bb"));
+}
+
+#[test]
+fn regex_hash_set() {
+    use regex::MatchEngine;
+    use std::collections::HashSet;
+
+    let mut set = HashSet::new();
+    assert!(set.insert(regex!(r"\d+")));
+    assert!(!set.insert(Regex::new(r"\d+").unwrap()));
+    assert!(set.insert(Regex::new(r"\d\d*").unwrap()));
+    assert!(set.insert(RegexBuilder::new(r"\d+")
+                                    .crlf(true)
+                                    .compile().unwrap()));
+    assert!(!set.insert(RegexBuilder::new(r"\d+")
+                                     .engine(Some(MatchEngine::Nfa))
+                                     .size_limit(1 << 20)
+                                     .compile().unwrap()));
+    assert_eq!(set.len(), 3);
+    assert!(set.contains(&Regex::new(r"\d\d*").unwrap()));
+}
+
+#[test]
+fn end_text_before_newline() {
+    let text = "foo\n";
+    assert_eq!(regex!(r"foo$").find(text), None);
+    assert_eq!(regex!(r"foo\z").find(text), None);
+    assert_eq!(regex!(r"foo\Z").find(text), Some((0, 3)));
+    assert_eq!(regex!(r"foo\n\Z").find(text), Some((0, 4)));
+    assert_eq!(regex!(r"(?m)foo$").find(text), Some((0, 3)));
+    assert_eq!(regex!(r"\Z").find_iter("a\n").collect::<Vec<_>>(),
+               vec![(1, 1), (2, 2)]);
+    assert!(!regex!(r"foo\Z").is_match("foo\n\n"));
+    assert!(!regex!(r"foo\Z").is_match("foo\nbar"));
+
+    let perl = |re: &str| {
+        RegexBuilder::new(re).dollar_newline(true).compile().unwrap()
+    };
+    assert_eq!(perl(r"foo$").find(text), Some((0, 3)));
+    assert_eq!(perl(r"foo$").find("foo"), Some((0, 3)));
+    assert_eq!(perl(r"foo\z").find(text), None);
+    assert_eq!(perl(r"foo\Z").find(text), Some((0, 3)));
+    assert!(!perl(r"foo$").is_match("foo\n\n"));
+    assert!(perl(r"foo$") != Regex::new(r"foo$").unwrap());
+}
+
+#[test]
+fn big_counted_repetition_too_big() {
+    use regex::Error::CompiledTooBig;
+
+    match Regex::new("a{100000000}") {
+        Err(CompiledTooBig(_)) => {}
+        r => panic!("expected CompiledTooBig, got {:?}", r),
+    }
+    match Regex::new("(?:a{1000}){1000,}") {
+        Err(CompiledTooBig(_)) => {}
+        r => panic!("expected CompiledTooBig, got {:?}", r),
+    }
+}
+
+#[test]
+fn big_counted_repetition_switch_to_counted() {
+    use regex::{MatchEngine, SizeLimitPolicy};
+
+    let counted = |re: &str| {
+        RegexBuilder::new(re)
+            .size_limit_policy(SizeLimitPolicy::SwitchToCounted)
+            .compile().unwrap()
+    };
+    let tests = &[
+        ("a{0,1000000}", "a*"),
+        ("a{0,1000000}?", "a*?"),
+        ("a{2,1000000}", "a{2,}"),
+        ("(a|ab){1,1000000}c", "(a|ab)+c"),
+        ("(a{1,1000000}?)(a*)", "(a+?)(a*)"),
+        ("^(?:(?:ab){0,1000000}c){0,1000000}$", "^(?:(?:ab)*c)*$"),
+        ("(?:x(?:ab){0,1000000}){2}", "(?:x(?:ab)*){2}"),
+    ];
+    let texts = &["", "a", "aaa", "abc", "aababc", "ababcabcc", "xabxab"];
+    for &(re, unrolled) in tests {
+        let (re, unrolled) = (counted(re), Regex::new(unrolled).unwrap());
+        for text in texts {
+            let caps = |re: &Regex| {
+                re.captures(text).map(|c| (0..3).map(|i| c.pos(i))
+                                                .collect::<Vec<_>>())
+            };
+            assert_eq!(caps(&re), caps(&unrolled), "{} on {:?}", re, text);
+        }
+    }
+    assert!(!counted("a{1000000,}").is_match("aaa"));
+
+    // Both the backtracking engine and the NFA support counted
+    // repetitions.
+    for &engine in &[MatchEngine::Backtrack, MatchEngine::Nfa] {
+        let mut re = counted("a{0,1000000}b");
+        re.set_default_engine(Some(engine));
+        assert_eq!(re.chosen_engine("aab"), Some(engine));
+        assert_eq!(re.find("xaab"), Some((1, 4)));
+    }
+}
+
+#[test]
+fn big_class_too_big() {
+    use regex::Error::CompiledTooBig;
+
+    // Only a few instructions, but hundreds of ranges.
+    assert!(Regex::with_size_limit(1000, r"\w").is_err());
+    match Regex::with_size_limit(1000, r"\pL") {
+        Err(CompiledTooBig(1000)) => {}
+        r => panic!("expected CompiledTooBig, got {:?}", r),
+    }
+    assert!(Regex::with_size_limit(1000, r"[a-z]").is_ok());
+    match Regex::with_size_limit(100000, r"\pL{1000}") {
+        Err(CompiledTooBig(100000)) => {}
+        r => panic!("expected CompiledTooBig, got {:?}", r),
+    }
+}
+
+#[test]
+fn captures_span_of() {
+    let re = regex!(r"(\d+)-(\d+)?-(\d+)(x)?");
+    let caps = re.captures("a 12--345 b").unwrap();
+    assert_eq!(caps.span_of(1..4), Some((2, 9)));
+    assert_eq!(caps.span_of(2..3), None);
+    assert_eq!(caps.span_of(2..4), Some((6, 9)));
+    assert_eq!(caps.span_of(4..9), None);
+    assert_eq!(caps.span_of(0..1), caps.pos(0));
+}
+
+#[test]
+fn find_prefix() {
+    let re = Regex::new("foo|bar").unwrap();
+    assert_eq!(re.find_prefix("xxbaryy"), Some((2, 5, 1)));
+    assert_eq!(re.find("xxbaryy"), Some((2, 5)));
+    assert_eq!(Regex::new(r"\w+").unwrap().find_prefix("abc"), None);
+}
+
+#[test]
+fn too_deep() {
+    use std::iter::repeat;
+    use regex::Error::TooDeep;
+
+    let re = format!("{}a{}", repeat("(").take(10000).collect::<String>(),
+                     repeat(")").take(10000).collect::<String>());
+    match Regex::new(&re) {
+        Err(TooDeep(250)) => {}
+        r => panic!("expected TooDeep, got {:?}", r),
+    }
+    let re = format!("{}a{}", repeat("(?:").take(10).collect::<String>(),
+                     repeat(")").take(10).collect::<String>());
+    assert!(Regex::new(&re).unwrap().is_match("a"));
+    match RegexBuilder::new(&re).depth_limit(5).compile() {
+        Err(TooDeep(5)) => {}
+        r => panic!("expected TooDeep, got {:?}", r),
+    }
+}
+
+#[test]
+fn nested_repeats() {
+    use regex::Error::NestedUnboundedRepeat;
+
+    let reject = |re: &str| {
+        RegexBuilder::new(re).reject_nested_repeats(true).compile()
+    };
+    for re in &[r"(a*)*", r"(a+)+", r"(?:a?)+", r"(?:a|b*){2,}", r"x(\b)*"] {
+        match reject(re) {
+            Err(NestedUnboundedRepeat) => {}
+            r => panic!("expected NestedUnboundedRepeat, got {:?}", r),
+        }
+    }
+    for re in &[r"a*", r"(?:ab*)+", r"(a*){3}", r"(?:a?b)*"] {
+        assert!(reject(re).is_ok());
+    }
+    assert!(Regex::new(r"(a*)*").is_ok());
+
+    let simplify = |re: &str| {
+        RegexBuilder::new(re).simplify_nested_repeats(true).compile()
+                             .unwrap()
+    };
+    let re = simplify(r"^(a+)+$");
+    assert!(re.is_match("aaa"));
+    assert!(!re.is_match(""));
+    assert!(simplify(r"^(?:a+)*$").is_match(""));
+    assert!(simplify(r"^(?:a*)+$").is_match(""));
+    assert!(!simplify(r"^(?:a+){2,}$").is_match("a"));
+
+    // Only what can't be simplified is rejected.
+    let both = |re: &str| {
+        RegexBuilder::new(re).simplify_nested_repeats(true)
+                             .reject_nested_repeats(true).compile()
+    };
+    assert!(both(r"(?:a+)+").is_ok());
+    assert!(both(r"(?:a?)+").is_err());
+    // These can't be merged without changing the matches or captures.
+    assert!(both(r"(a+)+").is_err());
+    assert!(both(r"(?:a+)*?").is_err());
+
+    let res = &[
+        r"(?:a+)*?", r"(?:a+?)+", r"(a+)*", r"(?:a+)*", r"(?:a*)+",
+        r"(?:a+?)*?", r"x(?:(?:ab)+)+y", r"((?:a+)*)b", r"(?:(a)+)+",
+    ];
+    let texts = &["", "b", "aaa", "aaab", "xababy"];
+    for re in res {
+        let plain = Regex::new(re).unwrap();
+        let merged = simplify(re);
+        for text in texts {
+            let locs = |re: &Regex| re.captures(text).map(|caps| {
+                (0..caps.len()).map(|i| caps.pos(i)).collect::<Vec<_>>()
+            });
+            assert_eq!(locs(&merged), locs(&plain), "{} on {:?}", re, text);
+        }
+    }
+}
+
+#[test]
+fn simplify() {
+    let cases = &[
+        (r"a{1}b", "ab"),
+        (r"[a]+b[c]", "a+bc"),
+        (r"(?:a{1}[b])+c", "(?:ab)+c"),
+        (r"x(?:a*b){1}y", "xa*by"),
+        (r"x(?:a|b)y", "x(?:a|b)y"),
+        (r"a|(?:b|(?:c|d))", "a|b|c|d"),
+        (r"(?:a|b){1}", "a|b"),
+        (r"((?:a*){1})+", "(a*)+"),
+        (r"(?P<n>[.])(?:x{1})*", r"(?P<n>\.)x*"),
+        (r"(?:a+)?", "(?:a+)?"),
+    ];
+    let texts = &["", "ab", "abc", "aaabcc", "xay xaaby", "dcba", ".xx.x"];
+    for &(re, expected) in cases {
+        let simple = ::regex::simplify(re).unwrap();
+        assert_eq!(simple, expected);
+        let re1 = Regex::new(re).unwrap();
+        let re2 = Regex::new(&simple).unwrap();
+        let re3 = RegexBuilder::new(re).simplify(true).compile().unwrap();
+        for text in texts {
+            let caps = |re: &Regex| {
+                re.captures_iter(text)
+                  .map(|caps| caps.iter_pos().collect::<Vec<_>>())
+                  .collect::<Vec<_>>()
+            };
+            assert_eq!(caps(&re1), caps(&re2), "{} on {:?}", re, text);
+            assert_eq!(caps(&re1), caps(&re3), "{} on {:?}", re, text);
+        }
+    }
+    assert!(::regex::simplify(r"a{1").is_err());
+}
+
+#[test]
+fn replacen_count() {
+    let re = regex!(r"(\w)(\d)");
+    let count = |text: &str, limit: usize| {
+        let (new, n) = re.replacen_count(text, limit, "$2$1");
+        assert_eq!(new, re.replacen(text, limit, "$2$1"));
+        (new, n)
+    };
+    assert_eq!(count("a1 b2 c3", 2), ("1a 2b c3".to_owned(), 2));
+    assert_eq!(count("a1 b2 c3", 0), ("1a 2b 3c".to_owned(), 3));
+    assert_eq!(count("a1 b2 c3", 5), ("1a 2b 3c".to_owned(), 3));
+    assert_eq!(count("abc", 1), ("abc".to_owned(), 0));
+
+    // Empty matches count too, and the rest of the text is kept.
+    let (new, n) = regex!(r"x*").replacen_count("axb", 3, NoExpand("-"));
+    assert_eq!((&*new, n), ("-a-b-", 3));
+    let (new, n) = regex!(r"x*").replacen_count("axbc", 2, NoExpand("-"));
+    assert_eq!((&*new, n), ("-a-bc", 2));
+}
+
+#[test]
+fn replace_all_to() {
+    let re = regex!(r"(?P<first>\w+)\s+(?P<last>\w+)");
+    let text = "Bruce Springsteen, Patti Scialfa, x";
+    let mut out = vec![];
+    re.replace_all_to(text, "$last $first", &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               re.replace_all(text, "$last $first"));
+
+    let mut out = vec![];
+    re.replace_all_to(text, NoExpand("$last"), &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               re.replace_all(text, NoExpand("$last")));
+
+    let upper = |caps: &::regex::Captures| {
+        caps.at(0).unwrap().to_uppercase()
+    };
+    let mut out = vec![];
+    re.replace_all_to(text, &upper, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), re.replace_all(text, &upper));
+}
+
+#[test]
+fn compile_replacement() {
+    let re = regex!(r"(?P<first>\w+)\s+(?P<last>\w+)(x)?");
+    let text = "Bruce Springsteen, Patti Scialfa";
+    let templates = &[
+        "$last $first", "${last}0$1", "$$1 $ $3", "${1", "$", "", "lit",
+    ];
+    for template in templates {
+        let rep = re.compile_replacement(template, true).unwrap();
+        assert_eq!(re.replace_all(text, &rep),
+                   re.replace_all(text, *template));
+        for caps in re.captures_iter(text) {
+            assert_eq!(rep.apply(&caps), caps.expand(template));
+        }
+    }
+
+    // Unknown groups expand to nothing unless the template is strict.
+    for template in &["$1 $4", "${frist}$last"] {
+        let rep = re.compile_replacement(template, false).unwrap();
+        assert_eq!(re.replace_all(text, &rep),
+                   re.replace_all(text, *template));
+    }
+    match re.compile_replacement("$1 $4", true) {
+        Err(::regex::Error::UnknownGroup(ref name)) if name == "4" => {}
+        r => panic!("expected UnknownGroup, got {:?}", r),
+    }
+    match re.compile_replacement("${frist}", true) {
+        Err(::regex::Error::UnknownGroup(ref name)) if name == "frist" => {}
+        r => panic!("expected UnknownGroup, got {:?}", r),
+    }
+}
+
+#[test]
+fn empty_matches_flagged() {
+    let re = regex!(r"x+|\b");
+    let ms: Vec<_> = re.find_iter("ab xx").flag_empty().collect();
+    assert_eq!(ms, vec![((0, 0), true), ((2, 2), true), ((3, 5), false)]);
+
+    let empty: Vec<_> = re.captures_iter("ab xx")
+                          .map(|caps| caps.is_empty_match())
+                          .collect();
+    assert_eq!(empty, vec![true, true, false]);
+}
+
+#[test]
+fn exec_into_reuse() {
+    let re = regex!(r"(\d+),(\w+)?");
+    let mut locs = vec![];
+    let mut cap = 0;
+    for i in 0..1000 {
+        let line = if i % 3 == 0 {
+            format!("{},", i)
+        } else {
+            format!("{},x{}", i, i)
+        };
+        assert!(re.exec_into(&line, &mut locs));
+        if i == 0 {
+            cap = locs.capacity();
+        }
+        assert_eq!(locs.capacity(), cap);
+        assert_eq!(locs.len(), 6);
+
+        let caps = re.captures(&line).unwrap();
+        for g in 0..3 {
+            let pos = locs[2 * g].map(|s| (s, locs[2 * g + 1].unwrap()));
+            assert_eq!(pos, caps.pos(g));
+        }
+    }
+    assert!(!re.exec_into("x", &mut locs));
+    assert_eq!(locs, vec![None; 6]);
+}
+
+#[test]
+fn captures_all() {
+    fn locs(caps: &[::regex::Captures]) -> Vec<Vec<Option<(usize, usize)>>> {
+        caps.iter().map(|c| c.iter_pos().collect()).collect()
+    }
+
+    for &(re, text) in &[(r"(a)|b", "abxb"), (r"a*", "baaxa"), ("", "ab")] {
+        let re = Regex::new(re).unwrap();
+        let all = re.captures_all(text);
+        let iter: Vec<_> = re.captures_iter(text).collect();
+        assert_eq!(locs(&all), locs(&iter));
+    }
+    assert!(regex!("x").captures_all("abc").is_empty());
+}
+
+#[test]
+fn regex_is_send_sync() {
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+    assert_send_sync(&regex!("a"));
+}
+
+#[test]
+fn shared_across_threads() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let re = Arc::new(regex!(r"(\d+)-(\d+)"));
+    let handles: Vec<_> = (0..8).map(|t| {
+        let re = re.clone();
+        thread::spawn(move || {
+            for i in 0..500 {
+                let text = format!("x{}-{}y", t, i);
+                let end = text.len() - 1;
+                assert!(re.is_match(&text));
+                assert_eq!(re.find(&text), Some((1, end)));
+                let caps = re.captures(&text).unwrap();
+                assert_eq!(caps.at(1), Some(&*t.to_string()));
+                assert_eq!(caps.at(2), Some(&*i.to_string()));
+                assert!(!re.is_match(&text[end..]));
+            }
+        })
+    }).collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+}
+
+#[test]
+fn engine_observer() {
+    use std::sync::{Arc, Mutex};
+    use regex::MatchEngine::{Backtrack, Literals, Nfa};
+
+    let seen = Arc::new(Mutex::new(vec![]));
+    let compile = |re: &str| {
+        let seen = seen.clone();
+        RegexBuilder::new(re)
+            .engine_observer(move |e, len| seen.lock().unwrap().push((e, len)))
+            .compile().unwrap()
+    };
+
+    assert!(compile("abc").is_match("xabc"));
+    assert!(compile(r"\w(\d)").captures("a1").is_some());
+    let big = ::std::iter::repeat("a").take(1 << 20).collect::<String>();
+    assert!(!compile(r"\w+\d").is_match(&big));
+    assert_eq!(*seen.lock().unwrap(),
+               vec![(Literals, 4), (Backtrack, 2), (Nfa, 1 << 20)]);
+}
+
+#[test]
+fn set_default_engine() {
+    use regex::MatchEngine::{Backtrack, Nfa};
+
+    let text = "a1 b22 c333";
+    let mut re = Regex::new(r"[a-z](\d+)").unwrap();
+    let expected: Vec<_> = re.find_iter(text).collect();
+    assert_eq!(re.chosen_engine(text), Some(Backtrack));
+
+    re.set_default_engine(Some(Nfa));
+    assert_eq!(re.chosen_engine(text), Some(Nfa));
+    assert_eq!(re.find_iter(text).collect::<Vec<_>>(), expected);
+    assert_eq!(re.captures(text).unwrap().pos(1), Some((1, 2)));
+
+    re.set_default_engine(None);
+    assert_eq!(re.chosen_engine(text), Some(Backtrack));
+
+    let re = RegexBuilder::new(r"[a-z](\d+)").engine(Some(Nfa)).compile();
+    assert_eq!(re.unwrap().chosen_engine(text), Some(Nfa));
+}
+
+#[test]
+fn is_full_match() {
+    assert!(regex!(r"\d+").is_full_match("123"));
+    assert!(!regex!(r"\d+").is_full_match("123a"));
+    assert!(!regex!(r"\d+").is_full_match("a123"));
+    assert!(regex!(r"a|ab").is_full_match("ab"));
+    assert!(regex!(r"a*").is_full_match(""));
+    assert!(!regex!(r"(?m)^\d+$").is_full_match("1\n2"));
+    assert!(regex!(r"\w+\b").is_full_match("abc"));
+
+    // Only the backtracking engine can run these.
+    let re = Regex::new(r"(a|ab)\1").unwrap();
+    assert!(re.is_full_match("abab"));
+    assert!(!re.is_full_match("aba"));
+    let re = Regex::new(r"\((?:[^()]|(?R))*\)").unwrap();
+    assert!(re.is_full_match("(a(b)())"));
+    assert!(!re.is_full_match("(a(b)()"));
+}
+
+#[cfg(feature = "normalize")]
+#[test]
+fn normalize() {
+    use regex::NormalForm::{Nfc, Nfd};
+
+    let nfc = "caf\u{e9}";
+    let nfd = "cafe\u{301}";
+    let compile = |re: &str, form| {
+        RegexBuilder::new(re).normalize(form).compile().unwrap()
+    };
+    for &form in &[Nfc, Nfd] {
+        assert_eq!(compile(nfc, form).find(nfd), Some((0, 6)));
+        assert_eq!(compile(nfd, form).find(nfc), Some((0, 5)));
+        assert_eq!(compile(nfd, form).find(nfd), Some((0, 6)));
+        assert_eq!(compile(r"\w+", form).find_iter("a \u{e9}t\u{e9} e\u{301}")
+                                         .collect::<Vec<_>>(),
+                   vec![(0, 1), (2, 7), (8, 11)]);
+        assert_eq!(compile(r"(\w)\w*", form).captures_iter("\u{e9}a e\u{301}")
+                                            .map(|c| c.pos(1).unwrap())
+                                            .collect::<Vec<_>>(),
+                   vec![(0, 2), (4, 7)]);
+        assert_eq!(compile(r"x*", form).find_iter("\u{e9}x")
+                                       .collect::<Vec<_>>(),
+                   vec![(0, 0), (2, 3)]);
+    }
+    assert!(!regex!(nfc).is_match(nfd));
+    // A match ending inside a composed character is widened.
+    assert_eq!(compile("e", Nfd).find(nfc), Some((3, 5)));
+}
+
+#[cfg(feature = "profile")]
+#[test]
+fn last_exec_stats() {
+    use regex::MatchEngine;
+
+    let re = RegexBuilder::new(r"a+b")
+        .engine(Some(MatchEngine::Nfa))
+        .compile().unwrap();
+    assert_eq!(re.last_exec_stats(), None);
+    assert!(re.is_match("xaab"));
+    let stats = re.last_exec_stats().unwrap();
+    assert_eq!(stats.engine, MatchEngine::Nfa);
+    assert!(stats.steps > 0);
+
+    let re = Regex::new(r"foo").unwrap();
+    assert!(re.is_match("xfoo"));
+    let stats = re.last_exec_stats().unwrap();
+    assert_eq!(stats.engine, MatchEngine::Literals);
+    assert_eq!(stats.steps, 0);
+}
+
+#[test]
+fn might_match() {
+    let res = &[
+        r"\w+ needle\d*", r"(?:foo|bar)baz+", r"a(b)c\b", r"(?m)^x\.y$",
+        r"[a-z]{2}", r"(?:ab)+c",
+    ];
+    let texts = &[
+        "", "needle", "a needle", "foobaz", "barbazz", "baz", "abc",
+        "abc d", "q\nx.y\n", "ababc", "ab", "xy",
+    ];
+    for re in res {
+        let re = Regex::new(re).unwrap();
+        for text in texts {
+            // A text that matches must never be skipped.
+            if re.is_match(text) {
+                assert!(re.might_match(text), "{} on {:?}", re, text);
+            }
+        }
+    }
+    assert!(!Regex::new(r"\w+ needle").unwrap().might_match("a haystack"));
+    assert!(!Regex::new(r"(?:foo|bar)baz+").unwrap().might_match("foo"));
+    assert!(!Regex::new(r"(?:ab)+c").unwrap().might_match("abab"));
+    assert!(Regex::new(r"[a-z]{2}").unwrap().might_match(""));
+}
+
+#[test]
+fn matches_empty() {
+    assert!(regex!(r"a*").matches_empty());
+    assert!(regex!(r"a?").matches_empty());
+    assert!(regex!(r"\b").matches_empty());
+    assert!(!regex!(r"a+").matches_empty());
+    assert!(!regex!(r"a|b*c").matches_empty());
+
+    let split = |re: &Regex, text| re.split(text).collect::<Vec<_>>();
+    assert_eq!(split(&regex!(r"a+"), "baab"), vec!["b", "b"]);
+    assert_eq!(split(&regex!(r"a+"), "aba"), vec!["", "b"]);
+    assert_eq!(split(&regex!(r"a*"), "baab"), vec!["", "b", "b"]);
+    assert_eq!(split(&regex!(r"a*"), "bc"), vec!["", "b", "c"]);
+}
+
+#[test]
+fn captures_len() {
+    assert_eq!(regex!(r"(a)(b)").captures_len(), 3);
+    assert_eq!(regex!(r"(?:a)b").captures_len(), 1);
+    assert_eq!(regex!(r"(?P<x>a)|(b)").captures_len(), 3);
+}
+
+#[test]
+fn count_matches() {
+    macro_rules! same {
+        ($re:expr, $text:expr) => {{
+            let re = regex!($re);
+            assert_eq!(re.count_matches($text), re.find_iter($text).count());
+        }}
+    }
+    same!(r"a*", "");
+    same!(r"a*", "aabaa");
+    same!(r"a*?", "aab");
+    same!(r"\d+", "a1b22c333");
+    same!(r"foo|bar", "foobarfoo");
+    same!(r"", "☃a☃");
+    same!(r"\b", "ab cd");
+    assert_eq!(regex!(r"x").count_matches("abc"), 0);
+    assert_eq!(regex!(r"a*").count_matches("aabaa"), 2);
+    assert_eq!(regex!(r"a*").count_matches("aab"), 2);
+}
+
+#[test]
+fn find_all_into() {
+    let re = regex!(r"\d+");
+    let mut out = vec![(0, 0)];
+    assert_eq!(re.find_all_into("a1b22c333d4", &mut out, 3), 3);
+    assert_eq!(out, vec![(0, 0), (1, 2), (3, 5), (6, 9)]);
+    out.clear();
+    let cap = out.capacity();
+    assert_eq!(re.find_all_into("a1b22", &mut out, 3), 2);
+    assert_eq!(out, vec![(1, 2), (3, 5)]);
+    assert_eq!(out.capacity(), cap);
+    assert_eq!(re.find_all_into("a1", &mut out, 0), 0);
+    assert_eq!(out, vec![(1, 2), (3, 5)]);
+}
+
+#[test]
+fn first_chars() {
+    let ranges = |re: &Regex| re.first_chars().map(|cls| cls.ranges);
+    assert_eq!(ranges(&regex!(r"[abc]x|def")), Some(vec![('a', 'd')]));
+    assert_eq!(ranges(&regex!(r"\b(?:x|[0-3])")),
+               Some(vec![('0', '3'), ('x', 'x')]));
+    assert_eq!(ranges(&regex!(r"^$")), Some(vec![]));
+    assert_eq!(ranges(&regex!(r"(?s).*x")), None);
+}
+
+#[test]
+fn find_anchored() {
+    use regex::MatchEngine;
+
+    let re = regex!(r"\w+");
+    assert_eq!(re.find_anchored("ab cd", 0, Anchored::Start), Some((0, 2)));
+    assert_eq!(re.find_anchored("ab cd", 2, Anchored::Start), None);
+    assert_eq!(re.find_anchored("ab cd", 3, Anchored::Start), Some((3, 5)));
+    assert_eq!(re.find_anchored("ab cd", 2, Anchored::No), Some((3, 5)));
+    assert_eq!(re.find_anchored("ab cd", 0, Anchored::Both), None);
+    assert_eq!(re.find_anchored("ab cd", 3, Anchored::Both), Some((3, 5)));
+
+    // The highest priority match that ends at the end of the text.
+    let re = regex!(r"a|ab|abc");
+    assert_eq!(re.find_anchored("abc", 0, Anchored::Start), Some((0, 1)));
+    assert_eq!(re.find_anchored("abc", 0, Anchored::Both), Some((0, 3)));
+    assert_eq!(re.find_anchored("abcd", 0, Anchored::Both), None);
+
+    // Assertions still see the text before `start`.
+    assert_eq!(regex!(r"\bb").find_anchored("ab", 1, Anchored::Start), None);
+    assert_eq!(regex!(r"^b").find_anchored("ab", 1, Anchored::Both), None);
+    assert_eq!(regex!(r"^foo").find_anchored("foo", 0, Anchored::Both),
+               Some((0, 3)));
+    assert_eq!(regex!(r"").find_anchored("ab", 2, Anchored::Both),
+               Some((2, 2)));
+
+    // The literal that a search for literals finds first may not start at
+    // `start`.
+    for &(re, text) in &[("abcd|bc", "abcd"), ("abcde|cd", "abcde"),
+                         ("foobar|oba", "foobar")] {
+        let mut re = Regex::new(re).unwrap();
+        re.set_default_engine(Some(MatchEngine::Literals));
+        let end = text.len();
+        assert_eq!(re.find_anchored(text, 0, Anchored::Start),
+                   Some((0, end)));
+        assert_eq!(re.find_anchored(text, 0, Anchored::Both),
+                   Some((0, end)));
+    }
+}
+
+#[test]
+fn find_at() {
+    let re = regex!(r"\w+");
+    assert_eq!(re.find_at("ab cd", 0), Some((0, 2)));
+    assert_eq!(re.find_at("ab cd", 1), Some((1, 2)));
+    assert_eq!(re.find_at("ab cd", 2), Some((3, 5)));
+    assert_eq!(re.find_at("ab cd", 5), None);
+    assert_eq!(regex!(r"^b").find_at("ab", 1), None);
+    assert_eq!(regex!(r"\bb").find_at("ab", 1), None);
+
+    // Every offset inside of a character starts the search at the start
+    // of that character.
+    let text = "a\u{2603}b";
+    for start in 1..4 {
+        assert_eq!(regex!(r".").find_at(text, start), Some((1, 4)));
+        let caps = regex!(r"(.)(b)").captures_at(text, start).unwrap();
+        assert_eq!(caps.pos(1), Some((1, 4)));
+    }
+    assert_eq!(regex!(r".").find_at(text, 4), Some((4, 5)));
+    assert!(regex!(r"a").captures_at(text, 2).is_none());
+}
+
+#[test]
+#[should_panic]
+fn find_at_past_end() {
+    regex!(r"a").find_at("a", 2);
+}
+
+/// A reader that hands out at most `size` bytes at a time.
+struct ChunkReader<'a> {
+    bytes: &'a [u8],
+    size: usize,
+}
+
+impl<'a> ::std::io::Read for ChunkReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        let n = ::std::cmp::min(::std::cmp::min(self.size, buf.len()),
+                                self.bytes.len());
+        buf[..n].clone_from_slice(&self.bytes[..n]);
+        self.bytes = &self.bytes[n..];
+        Ok(n)
+    }
+}
+
+#[test]
+fn stream_matches() {
+    let tests = &[
+        (regex!(r"\d+"), "a 12 b 345 c 6"),
+        (regex!(r"a+"), "aaaaaaaaaaaaaaaaaaaab aaa"),
+        (regex!(r"a*"), "baaab"),
+        (regex!(r"\bab\b"), "ab abab ab"),
+        (regex!(r"b$"), "abab"),
+        (regex!(r"(?m)^x"), "xax\nxx\nx"),
+        (regex!(r"^a"), "aaa"),
+        (regex!(r"\w\w"), "\u{2603}\u{e9}\u{e9}\u{e9}x \u{2603}"),
+        (regex!(r"a.*b"), "xa b a bx a"),
+        (regex!(r"ab|abcdefg"), "abcdefabcdefgh"),
+        (regex!(r""), "\u{2603}a"),
+        (regex!(r"x{3}"), "xxxxxxx"),
+    ];
+    for &(ref re, text) in tests {
+        let expected: Vec<_> = re.find_iter(text).collect();
+        for size in 1..6 {
+            let rdr = ChunkReader { bytes: text.as_bytes(), size: size };
+            let got: Vec<_> = re.stream_matches(rdr)
+                                .map(|m| m.unwrap())
+                                .collect();
+            assert_eq!((re.as_str(), size, got),
+                       (re.as_str(), size, expected.clone()));
+        }
+    }
+
+    // The window only holds as much text as the longest match needs.
+    let text = ::std::iter::repeat("abc ").take(10000).collect::<String>();
+    let rdr = ChunkReader { bytes: text.as_bytes(), size: 100 };
+    assert_eq!(regex!(r"c a").stream_matches(rdr).count(), 9999);
+
+    let rdr = ChunkReader { bytes: b"abc\xff", size: 1 };
+    let got: Vec<_> = regex!(r"a").stream_matches(rdr).collect();
+    assert_eq!(got.len(), 2);
+    assert_eq!(got[0].as_ref().unwrap(), &(0, 1));
+    assert!(got[1].is_err());
+}
+
+#[test]
+fn find_last() {
+    assert_eq!(regex!(r"\d").find_last("a1b2c3"), Some((5, 6)));
+    assert_eq!(regex!(r"\d").find_last("abc"), None);
+    assert_eq!(regex!(r"aa").find_last("aaa"), Some((0, 2)));
+    assert_eq!(regex!(r"a*").find_last("ab"), Some((2, 2)));
+
+    // These are searched backwards.
+    let tests = &[
+        (r"\d+$", "12 34"),
+        (r"a{2}$", "aaa"),
+        (r"(?:a|ab)b*$", "xabbb"),
+        (r"$", "ab"),
+        (r"\bx$", "ax"),
+        (r"a|b$", "ab a"),
+        (r"\d$", "12a"),
+    ];
+    for &(re, text) in tests {
+        let re = Regex::new(re).unwrap();
+        assert_eq!(re.find_last(text), re.find_iter(text).last(),
+                   "{} on {:?}", re, text);
+    }
+}
+
+#[test]
+fn is_match_os() {
+    use std::ffi::OsStr;
+
+    let re = regex!(r"\.rs$");
+    assert_eq!(re.is_match_os(OsStr::new("lib.rs")).unwrap(), true);
+    assert_eq!(re.is_match_os(OsStr::new("lib.rc")).unwrap(), false);
+    assert!(regex!("é").is_match_os(OsStr::new("é")).unwrap());
+    let re = regex!(r"\x{FFFD}");
+    assert!(re.is_match_os(OsStr::new("\u{FFFD}")).is_ok() != cfg!(unix));
+}
+
+#[cfg(unix)]
+#[test]
+fn is_match_os_bytes() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = OsStr::from_bytes(b"a\xFF\xFEb.txt");
+    assert!(regex!(r"^a..b\.txt$").is_match_os(name).unwrap());
+    assert!(!regex!(r"^a.b\.txt$").is_match_os(name).unwrap());
+    assert!(regex!(r"^a[^b]+b").is_match_os(name).unwrap());
+    assert!(!regex!(r"^a\w").is_match_os(name).unwrap());
+}
+
+#[test]
+fn too_many_captures() {
+    use std::iter::repeat;
+    use regex::Error::TooManyCaptures;
+
+    let re = repeat("(a)").take(1001).collect::<String>();
+    match Regex::new(&re) {
+        Err(TooManyCaptures(1000)) => {}
+        r => panic!("expected TooManyCaptures, got {:?}", r),
+    }
+    assert!(RegexBuilder::new("(a)(b)").capture_limit(2).compile().is_ok());
+    match RegexBuilder::new("(a)(?:b)(c)(d)").capture_limit(2).compile() {
+        Err(TooManyCaptures(2)) => {}
+        r => panic!("expected TooManyCaptures, got {:?}", r),
+    }
+}
+
+#[test]
+fn find_match() {
+    let m = regex!(r"\d+").find_match("ab123cd").unwrap();
+    assert_eq!((m.start(), m.end()), (2, 5));
+    assert_eq!(m.range(), 2..5);
+    assert_eq!(m.as_str(), "123");
+
+    let m = regex!(r"x*").find_match("ab").unwrap();
+    assert_eq!((m.range(), m.as_str()), (0..0, ""));
+    assert_eq!(regex!(r"\d").find_match("ab"), None);
+}
+
+#[test]
+fn matches_view() {
+    let re = regex!(r"[a-z]+");
+    let matches = re.matches("ab 12 cde f");
+    assert_eq!(matches.count(), 3);
+    assert_eq!(matches.first(), Some((0, 2)));
+    assert_eq!(matches.last(), Some((10, 11)));
+    assert_eq!(matches.iter().collect::<Vec<_>>(),
+               vec![(0, 2), (6, 9), (10, 11)]);
+    let mut n = 0;
+    for _ in matches {
+        n += 1;
+    }
+    assert_eq!(n, 3);
+
+    let matches = re.matches("123");
+    assert_eq!((matches.count(), matches.first(), matches.last()),
+               (0, None, None));
+}
+
+#[test]
+fn split_captures() {
+    let fields: Vec<_> = regex!(r"(\d)").split_captures("a1b2c").collect();
+    assert_eq!(fields, vec![
+        Some("a"), Some("1"), Some("b"), Some("2"), Some("c"),
+    ]);
+
+    let fields: Vec<_> = regex!(r"-").split_captures("a-b").collect();
+    assert_eq!(fields, vec![Some("a"), Some("b")]);
+
+    let re = regex!(r"(,)|(;)");
+    let fields: Vec<_> = re.split_captures(",a;").collect();
+    assert_eq!(fields, vec![
+        Some(""), Some(","), None, Some("a"), None, Some(";"),
+    ]);
+}
+
+#[test]
+fn dot_matches_new_line_and_single_line() {
+    let text = "a\nb";
+    for &dot in &[false, true] {
+        for &single in &[false, true] {
+            let compile = |re: &str| {
+                RegexBuilder::new(re)
+                    .dot_matches_new_line(dot)
+                    .single_line(single)
+                    .compile().unwrap()
+            };
+            assert_eq!(compile(r"a.b").is_match(text), dot);
+            assert!(compile(r"(?s)a.b").is_match(text));
+            assert_eq!(compile(r"(?m)^b").is_match(text), !single);
+            assert_eq!(compile(r"(?m)a$").is_match(text), !single);
+            assert!(compile(r"(?m)^a").is_match(text));
+            assert!(compile(r"(?m)b$").is_match(text));
+            assert_eq!(compile(r"(?m)^.+$").find(text), match (dot, single) {
+                (true, _) => Some((0, 3)),
+                (false, false) => Some((0, 1)),
+                (false, true) => None,
+            });
+            assert_eq!(compile(r"(?m)^.$").find_iter(text).count(),
+                       if single { 0 } else { 2 });
+        }
+    }
+}
+
+#[test]
+fn line_terminator() {
+    let compile = |re: &str| {
+        RegexBuilder::new(re).line_terminator(b'\x1e').compile().unwrap()
+    };
+    assert!(compile(r"(?m)^b").is_match("a\x1eb"));
+    assert!(!compile(r"(?m)^b").is_match("a\nb"));
+    assert!(compile(r"(?m)a$").is_match("a\x1eb"));
+    assert!(!compile(r"(?m)a$").is_match("a\nb"));
+    assert!(compile(r"a.b").is_match("a\nb"));
+    assert!(!compile(r"a.b").is_match("a\x1eb"));
+    assert!(compile(r"(?s)a.b").is_match("a\x1eb"));
+    assert_eq!(compile(r"(?m)^\w+$").find_iter("ab\x1ecd\x1e").count(), 2);
+}
+
+#[test]
+fn ascii_case_insensitive() {
+    let compile = |re: &str| {
+        RegexBuilder::new(re).ascii_case_insensitive(true).compile().unwrap()
+    };
+    assert!(compile(r"(?i)abc").is_match("xAbC"));
+    assert!(compile(r"(?i)[a-c]+").is_match("BcA"));
+    assert!(compile(r"(?i)[^x]").is_match("a"));
+    assert!(!compile(r"(?i)k").is_match("\u{212A}"));
+    assert!(!compile(r"(?i)[k]").is_match("\u{212A}"));
+    assert!(!compile(r"(?i)δ").is_match("Δ"));
+    assert!(compile(r"(?i)δ").is_match("δ"));
+    assert!(!compile(r"(?i)[δ-ε]").is_match("Δ"));
+    assert!(!compile(r"a(?i:b)c").is_match("aBC"));
+    assert!(Regex::new(r"(?i)δ").unwrap().is_match("Δ"));
+}
+
+// `regex!` rejects recursion, so these always compile with `Regex::new`.
+#[test]
+fn recursion() {
+    use regex::MatchEngine;
+    use std::iter::repeat;
+
+    let re = Regex::new(r"\((?:[^()]|(?R))*\)").unwrap();
+    assert_eq!(re.find("x(a(b)(c(d))e)y"), Some((1, 14)));
+    assert_eq!(re.find("((a)"), Some((1, 4)));
+    assert_eq!(re.find(")("), None);
+    let nested = format!("{}{}", repeat("(").take(200).collect::<String>(),
+                         repeat(")").take(200).collect::<String>());
+    assert_eq!(re.find(&nested), Some((0, 400)));
+    // The recursion limit stops a deeper match.
+    let deep = format!("{}{}", repeat("(").take(300).collect::<String>(),
+                       repeat(")").take(300).collect::<String>());
+    assert!(re.find(&deep) != Some((0, 600)));
+
+    let re = Regex::new(r"^(a|b(?1)c)$").unwrap();
+    assert!(re.is_match("bbacc"));
+    assert!(!re.is_match("bbac"));
+
+    // Groups captured inside of a recursion are reset when it returns.
+    let caps = Regex::new(r"(\w)(?1)").unwrap().captures("ab").unwrap();
+    assert_eq!(caps.at(1), Some("a"));
+    let caps = Regex::new(r"(?1)(\w)").unwrap().captures("ab").unwrap();
+    assert_eq!(caps.at(1), Some("b"));
+
+    // Left recursion ends at the recursion limit.
+    assert_eq!(Regex::new(r"(?R)a|b").unwrap().find("baa"), Some((0, 3)));
+
+    // Only the backtracking engine supports recursion.
+    let mut re = Regex::new(r"a(?R)?b").unwrap();
+    re.set_default_engine(Some(MatchEngine::Nfa));
+    assert_eq!(re.chosen_engine("aabb"), Some(MatchEngine::Backtrack));
+    assert_eq!(re.find("xaabbb"), Some((1, 5)));
+
+    assert!(Regex::new(r"(?2)(a)").is_err());
+}
+
+// `regex!` rejects backreferences too.
+#[test]
+fn backrefs() {
+    use regex::MatchEngine;
+
+    let re = Regex::new(r"(\w+) \1").unwrap();
+    assert!(re.is_match("hi hi"));
+    assert!(!re.is_match("hi bye"));
+    assert_eq!(re.find("oh, hi hi!"), Some((4, 9)));
+    let caps = re.captures("a bb bb").unwrap();
+    assert_eq!(caps.at(0), Some("bb bb"));
+    assert_eq!(caps.at(1), Some("bb"));
+
+    let re = Regex::new(r#"(?P<q>['"]).*?\k<q>"#).unwrap();
+    assert_eq!(re.find(r#"a "b'c" d"#), Some((2, 7)));
+    // A group that hasn't matched fails the backreference.
+    assert!(!Regex::new(r"(a)?b\1").unwrap().is_match("b"));
+    assert!(Regex::new(r"(a*)b\1").unwrap().is_match("b"));
+    // The text must match exactly.
+    assert!(!Regex::new(r"(?i)(a)\1").unwrap().is_match("aA"));
+
+    // Only the backtracking engine supports backreferences.
+    let mut re = Regex::new(r"(a+)b\1").unwrap();
+    re.set_default_engine(Some(MatchEngine::Nfa));
+    assert_eq!(re.chosen_engine("aabaa"), Some(MatchEngine::Backtrack));
+    assert_eq!(re.find("aaaba"), Some((2, 5)));
+
+    assert!(Regex::new(r"(a)\2").is_err());
+}
+
+#[test]
+fn custom_class() {
+    let compile = |re: &str| {
+        RegexBuilder::new(re)
+            .custom_class("Block=Cyrillic", &[('\u{400}', '\u{4FF}')])
+            .custom_class("V", &[('a', 'a'), ('e', 'e'), ('i', 'i')])
+            .compile().unwrap()
+    };
+    let re = compile(r"^\p{Block=Cyrillic}$");
+    for c in &["\u{400}", "\u{416}", "\u{42F}", "\u{4FF}"] {
+        assert!(re.is_match(c), "{:?}", c);
+    }
+    for c in &["\u{3FF}", "\u{500}", "A", "\u{1D2B}"] {
+        assert!(!re.is_match(c), "{:?}", c);
+    }
+    assert!(compile(r"^\P{Block=Cyrillic}$").is_match("\u{500}"));
+    assert_eq!(compile(r"[\p{Block=Cyrillic}\d]+").find("ab Щ1 c"),
+               Some((3, 6)));
+    assert_eq!(compile(r"\p{Block=Cyrillic}+$").find_last("Да и нет"),
+               Some((8, 14)));
+    assert_eq!(compile(r"\pV+").find("xyzaei"), Some((3, 6)));
+    assert!(Regex::new(r"\p{Block=Cyrillic}").is_err());
+}
+
+#[test]
+fn grapheme_dot() {
+    let re = RegexBuilder::new(r"^.$").grapheme_dot(true).compile().unwrap();
+    // Thumbs up with a skin tone modifier.
+    assert!(re.is_match("\u{1F44D}\u{1F3FD}"));
+    assert!(!regex!(r"^.$").is_match("\u{1F44D}\u{1F3FD}"));
+    // Family: man, zero width joiner, woman, zero width joiner, girl.
+    assert!(re.is_match("\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}"));
+    // Heart with an emoji variation selector.
+    assert!(re.is_match("\u{2764}\u{FE0F}"));
+    assert!(re.is_match("e\u{301}"));
+    assert!(!re.is_match("ab"));
+    assert!(!re.is_match("\n\u{301}"));
+
+    let re = RegexBuilder::new(r".").grapheme_dot(true).compile().unwrap();
+    let text = "a\u{1F44D}\u{1F3FD}b";
+    assert_eq!(re.find_iter(text).collect::<Vec<_>>(),
+               vec![(0, 1), (1, 9), (9, 10)]);
+    assert_eq!(re.find_last(text), Some((9, 10)));
+    let re = RegexBuilder::new(r".$").grapheme_dot(true).compile().unwrap();
+    assert_eq!(re.find_last("ab\u{1F44D}\u{1F3FD}"), Some((2, 10)));
+}
+
+#[cfg(feature = "grapheme")]
+#[test]
+fn grapheme_cluster() {
+    // The flag of Canada is a pair of regional indicators.
+    let flag = "\u{1F1E8}\u{1F1E6}";
+    assert!(regex!(r"^\X$").is_match(flag));
+    assert!(!regex!(r"^.$").is_match(flag));
+    assert_eq!(regex!(r".").find(flag), Some((0, 4)));
+
+    let text = "a\u{1F1E8}\u{1F1E6}\u{1F1EF}\u{1F1F5}b";
+    assert_eq!(regex!(r"\X").find_iter(text).collect::<Vec<_>>(),
+               vec![(0, 1), (1, 9), (9, 17), (17, 18)]);
+    assert_eq!(regex!(r"\X").find_last(text), Some((17, 18)));
+    assert_eq!(regex!(r"\X\X$").find(text), Some((9, 18)));
+    assert!(regex!(r"^\X$").is_match("\u{1F44D}\u{1F3FD}"));
+    assert!(regex!(r"^\X$").is_match("e\u{301}"));
+    assert!(regex!(r"^\X$").is_match("\r\n"));
+    assert!(!regex!(r"^\X$").is_match("ab"));
+    assert!(!regex!(r"^\X$").is_match(""));
+}
+
+#[cfg(not(feature = "grapheme"))]
+#[test]
+fn grapheme_cluster() {
+    use regex::Error::NoGraphemeTables;
+
+    match Regex::new(r"\X") {
+        Err(NoGraphemeTables) => {}
+        r => panic!("expected NoGraphemeTables, got {:?}", r),
+    }
+}
+
+#[test]
+fn literal() {
+    let re = Regex::literal("a.b");
+    assert_eq!(re, Regex::new(r"a\.b").unwrap());
+    assert_eq!(re.find("axb a.b"), Some((4, 7)));
+    assert!(!re.is_match("axb"));
+    let ms: Vec<_> = re.find_iter("a.ba.b").collect();
+    assert_eq!(ms, vec![(0, 3), (3, 6)]);
+    let caps = re.captures("xa.b").unwrap();
+    assert_eq!(caps.pos(0), Some((1, 4)));
+    assert_eq!(re.replace_all("a.b!a.b", "c"), "c!c");
+
+    let re = Regex::literal("☃");
+    assert_eq!(re.find("a☃"), Some((1, 4)));
+    let re = Regex::literal("");
+    let ms: Vec<_> = re.find_iter("ab").collect();
+    assert_eq!(ms, vec![(0, 0), (1, 1), (2, 2)]);
+}
+
+#[test]
+fn multi_line_crlf_off() {
+    let re = regex!(r"(?m)a$");
+    assert_eq!(re.find("a\r\nb"), None);
+    let re = regex!(r"(?m)a\r$");
+    assert_eq!(re.find("a\r\nb"), Some((0, 2)));
+    let re = regex!(r"(?m)^b");
+    assert_eq!(re.find("a\r\nb"), Some((3, 4)));
+}
+
+#[test]
+fn multi_line_crlf_on() {
+    let crlf = |re: &str| RegexBuilder::new(re).crlf(true).compile().unwrap();
+    assert_eq!(crlf(r"(?m)a$").find("a\r\nb"), Some((0, 1)));
+    assert_eq!(crlf(r"(?m)a\r$").find("a\r\nb"), None);
+    assert_eq!(crlf(r"(?m)^b").find("a\r\nb"), Some((3, 4)));
+    assert_eq!(crlf(r"(?m)^\nb").find("a\r\nb"), None);
+
+    let ms: Vec<_> = crlf(r"(?m)^$").find_iter("a\r\n\r\nb").collect();
+    assert_eq!(ms, vec![(3, 3)]);
+    let ms: Vec<_> = crlf(r"(?m)^\w+$").find_iter("a\r\nbc\nd").collect();
+    assert_eq!(ms, vec![(0, 1), (3, 5), (6, 7)]);
+    // Without multi-line mode, `$` still only matches at the end.
+    assert_eq!(crlf(r"a$").find("a\r\nb"), None);
+}
+
 #[test]
 fn empty_regex_empty_match() {
     let re = regex!("");
@@ -101,6 +1282,53 @@ fn empty_match_unicode_captures_iter() {
     assert_eq!(ms, vec![(0, 0), (3, 3), (4, 4), (7, 7), (8, 8)]);
 }
 
+#[test]
+fn empty_match_word_boundary_find_iter() {
+    let re = regex!(r"\b");
+    let ms: Vec<_> = re.find_iter("ab cd").collect();
+    assert_eq!(ms, vec![(0, 0), (2, 2), (3, 3), (5, 5)]);
+}
+
+#[test]
+fn empty_match_unicode_word_boundary_find_iter() {
+    let re = regex!(r"\b");
+    let ms: Vec<_> = re.find_iter("δδ ☃δ").collect();
+    assert_eq!(ms, vec![(0, 0), (4, 4), (8, 8), (10, 10)]);
+}
+
+#[test]
+fn empty_match_then_nonempty_find_iter() {
+    // An empty match must not cause the real match that starts at the next
+    // character to be skipped.
+    let re = regex!(r"a*");
+    let ms: Vec<_> = re.find_iter("baa").collect();
+    assert_eq!(ms, vec![(0, 0), (1, 3)]);
+    let ms: Vec<_> = re.find_iter("bab").collect();
+    assert_eq!(ms, vec![(0, 0), (1, 2), (3, 3)]);
+}
+
+#[test]
+fn error_kinds() {
+    use regex::Error;
+
+    match Regex::new("a(") {
+        Err(ref err @ Error::Syntax(_)) => {
+            assert_eq!(err.span(), Some((1, 2)));
+        }
+        r => panic!("expected a syntax error, got {:?}", r),
+    }
+    match Regex::with_size_limit(100, "a{100}") {
+        Err(ref err @ Error::CompiledTooBig(100)) => {
+            assert_eq!(err.span(), None);
+        }
+        r => panic!("expected CompiledTooBig, got {:?}", r),
+    }
+    match RegexBuilder::new("((a))").depth_limit(1).compile() {
+        Err(Error::TooDeep(1)) => {}
+        r => panic!("expected TooDeep, got {:?}", r),
+    }
+}
+
 #[test]
 fn invalid_regexes_no_crash() {
     // See: https://github.com/rust-lang/regex/issues/48
@@ -177,6 +1405,46 @@ searcher!(searcher_many_zero_length_matches, r"\d*", "a1bbb2",
 searcher!(searcher_unicode, r".+?", "Ⅰ1Ⅱ2",
           Match(0, 3), Match(3, 4), Match(4, 7), Match(7, 8));
 
+#[test]
+#[allow(unused_imports)]
+fn pattern_str_methods() {
+    searcher_expr! {{
+        let re = regex!(r"\d+");
+        let haystack = "a12b3c";
+        assert!(haystack.contains(&re));
+        assert!(!"abc".contains(&re));
+        assert_eq!(haystack.find(&re), Some(1));
+        assert_eq!(haystack.split(&re).collect::<Vec<_>>(),
+                   vec!["a", "b", "c"]);
+        assert_eq!(haystack.splitn(2, &re).collect::<Vec<_>>(),
+                   vec!["a", "b3c"]);
+        assert_eq!(haystack.matches(&re).collect::<Vec<_>>(),
+                   vec!["12", "3"]);
+        assert_eq!(haystack.match_indices(&re).collect::<Vec<_>>(),
+                   vec![(1, "12"), (4, "3")]);
+        assert_eq!(haystack.replace(&re, "#"), "a#b#c");
+        assert_eq!("12ab".trim_left_matches(&re), "ab");
+        assert!("12ab".starts_with(&re));
+    }}
+}
+
+#[test]
+#[allow(unused_imports)]
+fn pattern_next_match() {
+    searcher_expr! {{
+        use std::str::pattern::{Pattern, Searcher};
+        use std::str::pattern::SearchStep::{Match, Reject, Done};
+
+        let re = regex!(r"\d");
+        let mut se = (&re).into_searcher("a1b2c");
+        assert_eq!(se.next_match(), Some((1, 2)));
+        assert_eq!(se.next(), Reject(2, 3));
+        assert_eq!(se.next_match(), Some((3, 4)));
+        assert_eq!(se.next_match(), None);
+        assert_eq!(se.next(), Done);
+    }}
+}
+
 macro_rules! replace(
     ($name:ident, $which:ident, $re:expr,
      $search:expr, $replace:expr, $result:expr) => (
@@ -205,6 +1473,17 @@ replace!(rep_trim, replace_all, "^[ \t]+|[ \t]+$", " \t  trim me\t   \t",
          "", "trim me");
 replace!(rep_number_hypen, replace, r"(.)(.)", "ab", "$1-$2", "a-b");
 replace!(rep_number_underscore, replace, r"(.)(.)", "ab", "$1_$2", "a_b");
+replace!(rep_named_braces, replace,
+         r"(?P<first>\S+)\s+(?P<last>\S+)", "w1 w2", "${last}_${first}",
+         "w2_w1");
+replace!(rep_index_braces, replace, r"(.)(.)", "ab", "${1}0", "a0");
+replace!(rep_index_longest, replace, r"(.)(.)", "ab", "$10", "");
+replace!(rep_name_longest, replace,
+         r"(?P<a>.)(?P<ab>.)", "xy", "$ab-$a", "y-x");
+replace!(rep_braces_unclosed, replace, r"(.)(.)", "ab", "${1", "${1");
+replace!(rep_braces_invalid, replace, r"(.)(.)", "ab", "${1a}", "${1a}");
+replace!(rep_braces_dollar, replace, r"(.)(.)", "ab", "$${1}", "${1}");
+replace!(rep_lone_dollar, replace, r"(.)(.)", "ab", "$ $", "$ $");
 
 macro_rules! noparse(
     ($name:ident, $re:expr) => (
@@ -244,12 +1523,17 @@ noparse!(fail_octal_digit, r"\8");
 noparse!(fail_hex_digit, r"\xG0");
 noparse!(fail_hex_short, r"\xF");
 noparse!(fail_hex_long_digits, r"\x{fffg}");
+noparse!(fail_hex_too_big, r"\x{110000}");
+noparse!(fail_hex_surrogate, r"\x{D800}");
+noparse!(fail_control_digit, r"\c1");
+noparse!(fail_control_eof, r"\c");
 noparse!(fail_flag_bad, "(?a)");
 noparse!(fail_flag_empty, "(?)");
 noparse!(fail_double_neg, "(?-i-i)");
 noparse!(fail_neg_empty, "(?i-)");
 noparse!(fail_empty_group, "()");
 noparse!(fail_dupe_named, "(?P<a>.)(?P<a>.)");
+noparse!(fail_dupe_named_alternate, r"(?P<v>\d+)|(?P<v>\w+)");
 noparse!(fail_range_end_no_class, "[a-[:lower:]]");
 noparse!(fail_range_end_no_begin, r"[a-\A]");
 noparse!(fail_range_end_no_end, r"[a-\z]");
@@ -281,6 +1565,22 @@ macro_rules! mat(
     );
 );
 
+// Escape sequences.
+mat!(escape_hex2, r"\x41", "xA", Some((1, 2)));
+mat!(escape_hex, r"\x{1F600}", "x\u{1F600}", Some((1, 5)));
+mat!(escape_hex_class, r"[\x{1F600}-\x{1F64F}]", "\u{1F64F}", Some((0, 4)));
+mat!(escape_octal_nul, r"a\0", "a\0", Some((0, 2)));
+mat!(escape_control, r"\cA\cz", "\x01\x1A", Some((0, 2)));
+mat!(escape_control_class, r"[\cI\cJ]+", "a\t\nb", Some((1, 3)));
+mat!(escape_nul, r"\x00", "a\0b", Some((1, 2)));
+mat!(escape_nul_class, r"[\x00-\x02]+", "a\0\x01\x02b", Some((1, 4)));
+mat!(escape_esc, r"\e\[0m", "x\x1B[0m", Some((1, 5)));
+mat!(escape_esc_class, r"[\e]", "x\x1B", Some((1, 2)));
+mat!(escape_simple, r"\a\e\f\t\n\r\v", "\x07\x1B\x0C\t\n\r\x0B",
+     Some((0, 7)));
+mat!(escape_simple_class, r"[\a\e\f\t\n\r\v\x00]+",
+     "x\0\x07\x1B\x0C\t\n\r\x0Bx", Some((1, 9)));
+
 // Some crazy expressions from regular-expressions.info.
 mat!(match_ranges,
      r"\b(?:[0-9]|[1-9][0-9]|1[0-9][0-9]|2[0-4][0-9]|25[0-5])\b",
@@ -312,6 +1612,17 @@ mat!(match_date3,
 mat!(match_flag_case, "(?i)abc", "ABC", Some((0, 3)));
 mat!(match_flag_weird_case, "(?i)a(?-i)bc", "Abc", Some((0, 3)));
 mat!(match_flag_weird_case_not, "(?i)a(?-i)bc", "ABC", None);
+mat!(match_class_intersect, r"[\w&&[^_]]+", "__ab_c", Some((2, 4)));
+mat!(match_class_subtract, r"[a-z--[aeiou]]+", "aeibcdo", Some((3, 6)));
+mat!(match_class_subtract_ascii, r"[\w--[:digit:]]+", "12ab3", Some((2, 4)));
+mat!(match_class_empty, r"a[a&&[b]]|b", "aab", Some((2, 3)));
+mat!(match_flag_case_scoped, "foo(?i:bar)baz", "fooBARbaz", Some((0, 9)));
+mat!(match_flag_case_scoped_before, "foo(?i:bar)baz", "FOObarbaz", None);
+mat!(match_flag_case_scoped_after, "foo(?i:bar)baz", "foobarBAZ", None);
+mat!(match_flag_case_scoped_class, "a(?i:[b-c]d)e", "aCDe", Some((0, 4)));
+mat!(match_flag_case_scoped_class_not, "a(?i:[b-c]d)e", "aCDE", None);
+mat!(match_flag_case_scoped_nested, "(?i)a(?-i:b(?i:c))", "AbC", Some((0, 3)));
+mat!(match_flag_case_scoped_nested_not, "(?i)a(?-i:b(?i:c))", "ABC", None);
 mat!(match_flag_case_dotnl, "(?is)a.", "A\n", Some((0, 2)));
 mat!(match_flag_case_dotnl_toggle, "(?is)a.(?-is)a.", "A\nab", Some((0, 4)));
 mat!(match_flag_case_dotnl_toggle_not, "(?is)a.(?-is)a.", "A\na\n", None);
@@ -351,10 +1662,30 @@ mat!(uni_perl_s, r"\s+", " ", Some((0, 3)));
 mat!(uni_perl_s_not, r"\s+", "☃", None);
 mat!(uni_perl_s_neg, r"\S+", "☃", Some((0, 3)));
 
+mat!(uni_perl_h, r"\h+", "a \t\u{a0}\u{2003}\u{3000}b", Some((1, 11)));
+mat!(uni_perl_h_not_vertical, r"\h", "\n\r\x0B", None);
+mat!(uni_perl_h_neg, r"\H+", "\n\x0B ", Some((0, 2)));
+mat!(uni_perl_v, r"\v+", "a\n\x0B\x0C\r\u{85}\u{2028}\u{2029}b",
+     Some((1, 13)));
+mat!(uni_perl_v_not_horizontal, r"\v", " \t\u{a0}", None);
+mat!(uni_perl_v_neg, r"\V+", " \t\n", Some((0, 2)));
+mat!(linebreak_crlf, r"\R", "a\r\nb", Some((1, 3)));
+mat!(linebreak_crlf_anchored, r"^\R$", "\r\n", Some((0, 2)));
+mat!(linebreak_lf_cr, r"\R\R", "\n\r", Some((0, 2)));
+mat!(linebreak_uni, r"\R", "a\u{2028}", Some((1, 4)));
+
 // And do the same for word boundaries.
 mat!(uni_boundary_none, r"\d\b", "6δ", None);
 mat!(uni_boundary_ogham, r"\d\b", "6 ", Some((0, 1)));
 
+// Test the boundaries of a single ASCII range.
+mat!(single_range_below, r"[0-9]", "/", None);
+mat!(single_range_start, r"[0-9]", "/0", Some((1, 2)));
+mat!(single_range_end, r"[0-9]", ":9", Some((1, 2)));
+mat!(single_range_above, r"[0-9]", ":", None);
+mat!(single_range_many, r"[0-9]+", "/09:", Some((1, 3)));
+mat!(single_range_casei, r"(?i)[a-c]+", "zAbCd", Some((1, 4)));
+
 // Test negated character classes.
 mat!(negclass_letters, r"[^ac]", "acx", Some((2, 3)));
 mat!(negclass_letter_comma, r"[^a,]", "a,x", Some((2, 3)));
@@ -365,6 +1696,21 @@ mat!(negclass_space_comma, r"[^,\s]", ", a", Some((2, 3)));
 mat!(negclass_comma_space, r"[^\s,]", " ,a", Some((2, 3)));
 mat!(negclass_ascii, r"[^[:alpha:]Z]", "A1", Some((1, 2)));
 
+// When more than one branch of an alternation matches, the captures of the
+// first branch win, like Perl.
+mat!(alt_captures_first, r"(a)(b)|(ab)", "ab",
+     Some((0, 2)), Some((0, 1)), Some((1, 2)), None);
+mat!(alt_captures_second, r"(ab)|(a)(b)", "ab",
+     Some((0, 2)), Some((0, 2)), None, None);
+mat!(alt_captures_fallback, r"(a)(c)|(ab)", "ab",
+     Some((0, 2)), None, None, Some((0, 2)));
+mat!(alt_captures_nested, r"((a)|(ab))(b?)", "ab",
+     Some((0, 2)), Some((0, 1)), Some((0, 1)), None, Some((1, 2)));
+mat!(alt_captures_repeat, r"(?:(a)|(ab))+", "abab",
+     Some((0, 1)), Some((0, 1)), None);
+mat!(alt_captures_concat, r"(a|ab)(c|bcd)", "abcd",
+     Some((0, 4)), Some((0, 1)), Some((1, 4)));
+
 // Regression test for https://github.com/rust-lang/regex/issues/75
 mat!(regression_unsorted_binary_search_1, r"(?i)[a_]+", "A_", Some((0, 2)));
 mat!(regression_unsorted_binary_search_2, r"(?i)[A_]+", "a_", Some((0, 2)));
@@ -22,15 +22,51 @@
 // regexes/inputs because it doesn't have to keep track of multiple copies of
 // the capture groups. In benchmarks, the backtracking engine is roughly twice
 // as fast as the full NFA simulation.
+//
+// The backtracking engine can also be used on large inputs, provided that
+// the regex has a literal prefix and a bounded match length. In that case,
+// the prefix is used to find candidate match starts, and each candidate is
+// tried in a window that is only as large as the longest possible match.
+// The visited states only need to be tracked for the window, but the input
+// is still read in full, so that zero-width assertions at the edges of a
+// window see the surrounding characters.
+//
+// This is also the only engine that runs recursive regexes, like `\((?R)*\)`.
+// A `Call` instruction pushes a frame onto a call stack, which `Return` pops.
+// Frames are never freed during a search, so a job can refer to the stack it
+// runs with by the index of its top frame. The state of a job then includes
+// its frame, so visited states are kept in a set instead of the bitmap, and
+// the time complexity is no longer bounded by `O(mn)`. Calls nest at most
+// `MAX_CALL_DEPTH` deep, which keeps left recursion like `(?R)a|b` finite.
+//
+// Counted repetitions, which the compiler emits for repetitions too big to
+// unroll, only run here too. The value of every counter is part of a job's
+// state like its frame, and is restored on backtracking like a capture.
+//
+// So are backreferences, like `(\w+) \1`. Whether a state leads to a match
+// then also depends on the captures of the groups that are referred to, so
+// those are part of its state too. They're always recorded, even when the
+// caller asked for fewer captures.
+//
+// Since the states of a program with recursion or backreferences aren't
+// bounded by `O(mn)`, a search with one gives up after visiting
+// `MAX_STATES` of them, and reports that there's no match.
 
 use input::{Input, InputAt, CharInput};
-use program::{Inst, InstIdx, Program};
+use program::{Anchored, Inst, InstIdx, Program};
+use std::cmp;
+use std::collections::HashSet;
+use std::mem;
 use re::CaptureIdxs;
 
 type Bits = u32;
 const BIT_SIZE: usize = 32;
 const MAX_PROG_SIZE: usize = 100;
 const MAX_INPUT_SIZE: usize = 256 * (1 << 10);
+const MAX_CALL_DEPTH: usize = 256;
+// Each visited state of a program with recursion or backreferences takes
+// on the order of a hundred bytes, so this caps a search at tens of MB.
+const MAX_STATES: usize = 1 << 18;
 
 // Total memory usage in bytes is determined by:
 //
@@ -46,6 +82,14 @@ pub struct Backtrack<'r, 't, 'c> {
     input: CharInput<'t>,
     caps: &'c mut CaptureIdxs,
     m: BackMachine,
+    /// Where a match must be, besides what the program requires.
+    anchored: Anchored,
+    /// The byte offset at which the window of visited states starts.
+    offset: usize,
+    /// The length, in bytes, of the window of visited states.
+    len: usize,
+    /// Set once a search has visited `MAX_STATES` states, which ends it.
+    exhausted: bool,
 }
 
 /// Shared cached state between multiple invocations of a backtracking engine
@@ -56,6 +100,16 @@ pub struct Backtrack<'r, 't, 'c> {
 pub struct BackMachine {
     jobs: Vec<Job>,
     visited: Vec<Bits>,
+    /// The frames of every call made in a recursive program. The first
+    /// frame stands for the top level, outside of any call.
+    frames: Vec<Frame>,
+    /// The visited states of a program that only this engine can run or
+    /// that has counters, keyed by instruction, input position, frame,
+    /// counters and the captures of groups that are referred to by
+    /// backreferences.
+    seen: HashSet<(InstIdx, usize, usize, Vec<u32>, Vec<Option<usize>>)>,
+    /// The current value of every counter of a counted program.
+    counts: Vec<u32>,
 }
 
 impl BackMachine {
@@ -64,10 +118,28 @@ impl BackMachine {
         BackMachine {
             jobs: vec![],
             visited: vec![],
+            frames: vec![],
+            seen: HashSet::new(),
+            counts: vec![],
         }
     }
 }
 
+/// A call to a subroutine.
+#[derive(Clone, Debug)]
+struct Frame {
+    /// The instruction to continue with when the subroutine returns.
+    ret: InstIdx,
+    /// The frame of the caller.
+    parent: usize,
+    /// The number of calls that this one is nested in, plus one.
+    depth: usize,
+    /// The capture locations at the time of the call.
+    caps: Vec<Option<usize>>,
+    /// The counters at the time of the call.
+    counts: Vec<u32>,
+}
+
 /// A job is an explicit unit of stack space in the backtracking engine.
 ///
 /// The "normal" representation is a single state transition, which corresponds
@@ -76,8 +148,12 @@ impl BackMachine {
 /// stack to do it.
 #[derive(Clone, Copy, Debug)]
 enum Job {
-    Inst { pc: InstIdx, at: InputAt },
+    Inst { pc: InstIdx, at: InputAt, frame: usize },
     SaveRestore { slot: usize, old_pos: Option<usize> },
+    CountRestore { counter: usize, old: u32 },
+    /// Increment the counter and start another iteration of its loop at
+    /// `pc`.
+    CountEnter { pc: InstIdx, at: InputAt, frame: usize, counter: usize },
 }
 
 impl<'r, 't, 'c> Backtrack<'r, 't, 'c> {
@@ -87,34 +163,138 @@ impl<'r, 't, 'c> Backtrack<'r, 't, 'c> {
     /// captures accordingly.
     pub fn exec(
         prog: &'r Program,
+        caps: &mut CaptureIdxs,
+        text: &'t str,
+        start: usize,
+        anchored: Anchored,
+    ) -> bool {
+        let mut m = prog.backtrack.get();
+        let matched =
+            Backtrack::exec_with(prog, &mut m, caps, text, start, anchored);
+        prog.backtrack.put(m);
+        matched
+    }
+
+    /// Execute the backtracking matching engine like `exec`, but with
+    /// memory provided by the caller instead of taken from the program's
+    /// pool.
+    pub fn exec_with(
+        prog: &'r Program,
+        m: &mut BackMachine,
         mut caps: &mut CaptureIdxs,
         text: &'t str,
         start: usize,
+        anchored: Anchored,
     ) -> bool {
+        let nslots = prog.num_slots();
+        if !prog.backrefs.is_empty() && caps.len() < nslots {
+            let mut all = vec![None; nslots];
+            let matched = Backtrack::exec_with(
+                prog, m, &mut all, text, start, anchored);
+            for (slot, pos) in caps.iter_mut().zip(all) {
+                *slot = pos;
+            }
+            return matched;
+        }
+        if text.len() > MAX_INPUT_SIZE {
+            if let Some(window) = window_len(prog) {
+                return Backtrack::exec_windows(
+                    prog, m, caps, text, start, window, anchored);
+            }
+        }
         let input = CharInput::new(text);
         let start = input.at(start);
-        let m = prog.backtrack.get();
         let mut b = Backtrack {
             prog: prog,
             input: input,
             caps: caps,
-            m: m,
+            m: mem::replace(m, BackMachine::new()),
+            anchored: anchored,
+            offset: 0,
+            len: text.len(),
+            exhausted: false,
         };
         let matched = b.exec_(start);
-        prog.backtrack.put(b.m);
+        *m = b.m;
+        matched
+    }
+
+    /// Execute the backtracking matching engine on a window of `window`
+    /// bytes at each occurrence of a literal prefix of the regex.
+    ///
+    /// `prog` must have a non-empty prefix and no match of it may be longer
+    /// than `window` bytes.
+    fn exec_windows(
+        prog: &'r Program,
+        m: &mut BackMachine,
+        caps: &mut CaptureIdxs,
+        text: &'t str,
+        start: usize,
+        window: usize,
+        anchored: Anchored,
+    ) -> bool {
+        let input = CharInput::new(text);
+        let mut at = input.at(start);
+        let mut b = Backtrack {
+            prog: prog,
+            input: input,
+            caps: caps,
+            m: mem::replace(m, BackMachine::new()),
+            anchored: anchored,
+            offset: 0,
+            len: window,
+            exhausted: false,
+        };
+        let matched = loop {
+            at = match b.input.prefix_at(&prog.prefixes, at) {
+                None => break false,
+                Some(at) => at,
+            };
+            if (prog.anchored_begin && !at.is_beginning())
+               || (anchored != Anchored::No && at.pos() != start) {
+                break false;
+            }
+            b.offset = at.pos();
+            b.len = cmp::min(window, text.len() - at.pos());
+            b.clear();
+            if b.backtrack(at) {
+                break true;
+            }
+            if b.exhausted || at.char().is_none() {
+                break false;
+            }
+            at = b.input.at(at.next_pos());
+        };
+        *m = b.m;
         matched
     }
 
     /// Returns true iff the given regex and input can be executed by this
     /// engine with reasonable memory usage.
     pub fn should_exec(prog: &'r Program, input: &str) -> bool {
-        prog.insts.len() <= MAX_PROG_SIZE && input.len() <= MAX_INPUT_SIZE
+        prog.needs_backtrack()
+        || prog.insts.len() <= MAX_PROG_SIZE
+           && (input.len() <= MAX_INPUT_SIZE || window_len(prog).is_some())
     }
 
     fn clear(&mut self) {
         // Reset the job memory so that we start fresh.
         self.m.jobs.truncate(0);
 
+        if keeps_states(self.prog) {
+            self.m.seen.clear();
+            self.m.counts = vec![0; self.prog.counters];
+            self.m.frames.truncate(0);
+            self.m.frames.push(Frame {
+                ret: 0,
+                parent: 0,
+                depth: 0,
+                caps: vec![],
+                counts: vec![],
+            });
+            return;
+        }
+
         // Now we need to clear the bit state set.
         // We do this by figuring out how much space we need to keep track
         // of the states we've visited.
@@ -126,10 +306,10 @@ impl<'r, 't, 'c> Backtrack<'r, 't, 'c> {
         // (Probably because backtracking is limited to such small
         // inputs/regexes in the first place.)
         let visited_len =
-            (self.prog.insts.len() * (self.input.len() + 1) + BIT_SIZE - 1)
+            (self.prog.insts.len() * (self.len + 1) + BIT_SIZE - 1)
             /
             BIT_SIZE;
-        for v in &mut self.m.visited {
+        for v in self.m.visited.iter_mut().take(visited_len) {
             *v = 0;
         }
         let cur_visited_cap = self.m.visited.capacity();
@@ -143,6 +323,9 @@ impl<'r, 't, 'c> Backtrack<'r, 't, 'c> {
 
     fn exec_(&mut self, mut at: InputAt) -> bool {
         self.clear();
+        if self.anchored != Anchored::No {
+            return self.backtrack(at);
+        }
         if self.prog.anchored_begin {
             return if !at.is_beginning() {
                 false
@@ -159,11 +342,16 @@ impl<'r, 't, 'c> Backtrack<'r, 't, 'c> {
                     None => return false,
                     Some(at) => at,
                 };
+            } else if let Some(ref bytes) = self.prog.first_bytes {
+                at = match self.input.first_byte_at(bytes, at) {
+                    None => return false,
+                    Some(at) => at,
+                };
             }
             if self.backtrack(at) {
                 return true;
             }
-            if at.char().is_none() {
+            if self.exhausted || at.char().is_none() {
                 return false;
             }
             at = self.input.at(at.next_pos());
@@ -174,31 +362,53 @@ impl<'r, 't, 'c> Backtrack<'r, 't, 'c> {
     // throughput on the `hard` benchmarks (over a standard `inline`). ---AG
     #[inline(always)]
     fn backtrack(&mut self, start: InputAt) -> bool {
-        self.push(0, start);
+        self.push(0, start, 0);
         while let Some(job) = self.m.jobs.pop() {
+            if self.exhausted {
+                return false;
+            }
             match job {
-                Job::Inst { pc, at } => {
-                    if self.step(pc, at) {
+                Job::Inst { pc, at, frame } => {
+                    if self.step(pc, at, frame) {
                         return true;
                     }
                 }
                 Job::SaveRestore { slot, old_pos } => {
                     self.caps[slot] = old_pos;
                 }
+                Job::CountRestore { counter, old } => {
+                    self.m.counts[counter] = old;
+                }
+                Job::CountEnter { pc, at, frame, counter } => {
+                    let count = self.m.counts[counter];
+                    self.set_count(counter, count + 1);
+                    if self.step(pc, at, frame) {
+                        return true;
+                    }
+                }
             }
         }
         false
     }
 
-    fn step(&mut self, mut pc: InstIdx, mut at: InputAt) -> bool {
+    fn step(
+        &mut self,
+        mut pc: InstIdx,
+        mut at: InputAt,
+        mut frame: usize,
+    ) -> bool {
         use program::Inst::*;
         loop {
             // This loop is an optimization to avoid constantly pushing/popping
             // from the stack. Namely, if we're pushing a job only to run it
             // next, avoid the push and just mutate `pc` (and possibly `at`)
             // in place.
+            self.prog.hits.hit(pc);
             match self.prog.insts[pc] {
-                Match => return true,
+                Match => {
+                    return self.anchored != Anchored::Both
+                           || at.char().is_none();
+                }
                 Save(slot) => {
                     if slot < self.caps.len() {
                         // If this path doesn't work out, then we save the old
@@ -211,21 +421,111 @@ impl<'r, 't, 'c> Backtrack<'r, 't, 'c> {
                     }
                     pc += 1;
                 }
+                BranchMark(branch) => {
+                    match self.prog.branch_slot {
+                        Some(slot) if slot < self.caps.len() => {
+                            let old = self.caps[slot];
+                            self.push_save_restore(slot, old);
+                            self.caps[slot] = Some(branch);
+                        }
+                        _ => {}
+                    }
+                    pc += 1;
+                }
                 Jump(pc2) => pc = pc2,
                 Split(x, y) => {
-                    self.push(y, at);
+                    self.push(y, at, frame);
                     pc = x;
                 }
+                Call(to) => {
+                    let depth = self.m.frames[frame].depth + 1;
+                    if depth > MAX_CALL_DEPTH {
+                        return false;
+                    }
+                    self.m.frames.push(Frame {
+                        ret: pc + 1,
+                        parent: frame,
+                        depth: depth,
+                        caps: self.caps.to_vec(),
+                        counts: self.m.counts.clone(),
+                    });
+                    frame = self.m.frames.len() - 1;
+                    pc = to;
+                }
+                Return => {
+                    // Captures and counters changed by the subroutine are
+                    // undone, but only until this path fails, just like
+                    // `Save`.
+                    let f = &self.m.frames[frame];
+                    for (slot, &old_pos) in f.caps.iter().enumerate() {
+                        if self.caps[slot] != old_pos {
+                            self.m.jobs.push(Job::SaveRestore {
+                                slot: slot,
+                                old_pos: self.caps[slot],
+                            });
+                            self.caps[slot] = old_pos;
+                        }
+                    }
+                    for (counter, &old) in f.counts.iter().enumerate() {
+                        if self.m.counts[counter] != old {
+                            self.m.jobs.push(Job::CountRestore {
+                                counter: counter,
+                                old: self.m.counts[counter],
+                            });
+                            self.m.counts[counter] = old;
+                        }
+                    }
+                    pc = f.ret;
+                    frame = f.parent;
+                }
+                CountReset(counter) => {
+                    self.set_count(counter, 0);
+                    pc += 1;
+                }
+                CountLoop(l) => {
+                    let count = self.m.counts[l.counter];
+                    if count < l.min {
+                        self.set_count(l.counter, count + 1);
+                        pc += 1;
+                    } else if count == l.max {
+                        pc = l.exit;
+                    } else if l.greedy {
+                        self.push(l.exit, at, frame);
+                        self.set_count(l.counter, count + 1);
+                        pc += 1;
+                    } else {
+                        self.m.jobs.push(Job::CountEnter {
+                            pc: pc + 1,
+                            at: at,
+                            frame: frame,
+                            counter: l.counter,
+                        });
+                        pc = l.exit;
+                    }
+                }
+                Backref(group) => {
+                    let (s, e) = match (self.caps[2 * group],
+                                        self.caps[2 * group + 1]) {
+                        (Some(s), Some(e)) => (s, e),
+                        _ => return false,
+                    };
+                    let next = at.pos() + (e - s);
+                    let text: &str = &self.input;
+                    if !text[at.pos()..].starts_with(&text[s..e]) {
+                        return false;
+                    }
+                    pc += 1;
+                    at = self.input.at(next);
+                }
                 EmptyLook(ref inst) => {
-                    let prev = self.input.previous_at(at.pos());
-                    if inst.matches(prev.char(), at.char()) {
+                    if inst.matches(&self.input, at) {
                         pc += 1;
                     } else {
                         return false;
                     }
                 }
-                Char(ref inst) => {
-                    if inst.matches(at.char()) {
+                Char(c) => {
+                    if c == at.char() {
                         pc += 1;
                         at = self.input.at(at.next_pos());
                     } else {
@@ -233,7 +533,7 @@ impl<'r, 't, 'c> Backtrack<'r, 't, 'c> {
                     }
                 }
                 Ranges(ref inst) => {
-                    if inst.matches(at.char()).is_some() {
+                    if inst.matches(at.char()) {
                         pc += 1;
                         at = self.input.at(at.next_pos());
                     } else {
@@ -241,22 +541,45 @@ impl<'r, 't, 'c> Backtrack<'r, 't, 'c> {
                     }
                 }
             }
-            if self.has_visited(pc, at) {
+            if self.has_visited(pc, at, frame) {
                 return false;
             }
         }
     }
 
-    fn push(&mut self, pc: InstIdx, at: InputAt) {
-        self.m.jobs.push(Job::Inst { pc: pc, at: at });
+    fn push(&mut self, pc: InstIdx, at: InputAt, frame: usize) {
+        self.m.jobs.push(Job::Inst { pc: pc, at: at, frame: frame });
     }
 
     fn push_save_restore(&mut self, slot: usize, old_pos: Option<usize>) {
         self.m.jobs.push(Job::SaveRestore { slot: slot, old_pos: old_pos });
     }
 
-    fn has_visited(&mut self, pc: InstIdx, at: InputAt) -> bool {
-        let k = pc * (self.input.len() + 1) + at.pos();
+    /// Sets the counter to `count`, and restores its old value once this
+    /// path fails.
+    fn set_count(&mut self, counter: usize, count: u32) {
+        let old = self.m.counts[counter];
+        if old != count {
+            self.m.jobs.push(Job::CountRestore { counter: counter, old: old });
+            self.m.counts[counter] = count;
+        }
+    }
+
+    fn has_visited(&mut self, pc: InstIdx, at: InputAt, frame: usize) -> bool {
+        if keeps_states(self.prog) {
+            if self.prog.needs_backtrack() && self.m.seen.len() >= MAX_STATES {
+                self.exhausted = true;
+                return true;
+            }
+            let mut refs = Vec::with_capacity(2 * self.prog.backrefs.len());
+            for &i in &self.prog.backrefs {
+                refs.push(self.caps[2 * i]);
+                refs.push(self.caps[2 * i + 1]);
+            }
+            let state = (pc, at.pos(), frame, self.m.counts.clone(), refs);
+            return !self.m.seen.insert(state);
+        }
+        let k = pc * (self.len + 1) + (at.pos() - self.offset);
         let k1 = k / BIT_SIZE;
         let k2 = (1 << (k & (BIT_SIZE - 1))) as Bits;
         if self.m.visited[k1] & k2 == 0 {
@@ -267,3 +590,87 @@ impl<'r, 't, 'c> Backtrack<'r, 't, 'c> {
         }
     }
 }
+
+/// Returns true if the visited states of `prog` can't be told apart by
+/// instruction and input position alone, so they're kept in a set instead
+/// of a bitmap.
+fn keeps_states(prog: &Program) -> bool {
+    prog.needs_backtrack() || prog.counters > 0
+}
+
+/// Returns the size of the window needed to run the backtracking engine at
+/// each occurrence of a prefix of `prog`, if it can be done at all.
+///
+/// This requires that `prog` has a literal prefix and that the length of
+/// its matches is bounded. The window is the maximum length of a match, in
+/// bytes, which must not exceed the input size limit.
+fn window_len(prog: &Program) -> Option<usize> {
+    if prog.prefixes.is_empty() || prog.insts.len() > MAX_PROG_SIZE {
+        return None;
+    }
+    match prog.max_match_len() {
+        Some(n) if n <= MAX_INPUT_SIZE => Some(n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nfa::Nfa;
+    use program::{Anchored, Program, RegexOptions};
+    use super::{Backtrack, BackMachine, MAX_STATES, window_len};
+
+    #[test]
+    fn windows_agree_with_nfa() {
+        let tests = &[
+            ("foo", "xxfooxx"),
+            ("fo[a-z]", "fo1 fox"),
+            (r"x\b", "xy x"),
+            (r"foo\b", "foox foo"),
+            ("a(b|bc)?d", "abcd abd"),
+            ("ab[0-9]{2}", "ab1 ab12"),
+            (r"ab$", "abab"),
+            ("xyz", "xy"),
+        ];
+        for &(re, text) in tests {
+            let prog = Program::new(re, &RegexOptions::default()).unwrap();
+            let window = window_len(&prog).expect(re);
+            let mut m = BackMachine::new();
+            for start in 0..text.len() + 1 {
+                let mut caps1 = prog.alloc_captures();
+                let mut caps2 = prog.alloc_captures();
+                let m1 = Backtrack::exec_windows(
+                    &prog, &mut m, &mut caps1, text, start, window,
+                    Anchored::No);
+                let m2 = Nfa::exec(&prog, &mut caps2, text, start,
+                                   Anchored::No);
+                assert_eq!((m1, &caps1), (m2, &caps2),
+                           "{} on {:?} at {}", re, text, start);
+            }
+        }
+    }
+
+    #[test]
+    fn no_windows() {
+        let prog = |re| Program::new(re, &RegexOptions::default()).unwrap();
+        assert!(window_len(&prog("a+b")).is_none());
+        assert!(window_len(&prog("[^a]b")).is_none());
+        assert_eq!(window_len(&prog("ab?c")), Some(3));
+        assert_eq!(window_len(&prog("a☃")), Some(4));
+    }
+
+    #[test]
+    fn states_are_capped() {
+        let prog = Program::new(r"(\w+) \1",
+                                &RegexOptions::default()).unwrap();
+        let a: String = ::std::iter::repeat('a').take(3000).collect();
+        let text = &format!("{} b b", a);
+        let mut m = BackMachine::new();
+        let mut caps = prog.alloc_captures();
+        assert!(Backtrack::exec_with(&prog, &mut m, &mut caps, "aa aa", 0,
+                                     Anchored::No));
+        assert!(!Backtrack::exec_with(&prog, &mut m, &mut caps, text, 0,
+                                      Anchored::No));
+        assert_eq!(m.seen.len(), MAX_STATES);
+    }
+}
@@ -62,6 +62,14 @@ impl Char {
         char::from_u32(self.0).map(syntax::is_word_char).unwrap_or(false)
     }
 
+    /// Returns the character as a byte if it is ASCII.
+    ///
+    /// If the character is absent, then `None` is returned.
+    #[inline]
+    pub fn as_ascii(self) -> Option<u8> {
+        if self.0 < 0x80 { Some(self.0 as u8) } else { None }
+    }
+
     /// Converts the character to a real primitive `char`.
     ///
     /// If the character is absent, then `None` is returned.
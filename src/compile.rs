@@ -8,12 +8,14 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use syntax::{self, Expr, Repeater};
+use syntax::{self, CharClass, ClassRange, Expr, Repeater};
 
 use Error;
-use program::{CharRanges, Inst, InstIdx, OneChar};
+use program::{
+    CharRanges, CountLoop, Inst, InstIdx, RegexOptions, SizeLimitPolicy,
+};
 
-type Compiled = (Vec<Inst>, Vec<Option<String>>);
+type Compiled = (Vec<Inst>, Vec<Option<String>>, Vec<(InstIdx, bool)>);
 
 /// A regex compiler.
 ///
@@ -21,110 +23,281 @@ type Compiled = (Vec<Inst>, Vec<Option<String>>);
 /// of instructions.
 pub struct Compiler {
     size_limit: usize,
+    depth_limit: usize,
+    depth: usize,
+    capture_limit: usize,
+    branch_marks: bool,
+    crlf: bool,
+    grapheme_dot: bool,
+    dot_matches_new_line: bool,
+    single_line: bool,
+    line_terminator: char,
+    reject_nested_repeats: bool,
+    simplify_nested_repeats: bool,
+    reverse: bool,
     insts: Vec<Inst>,
+    /// The number of bytes allocated on the heap by `insts`.
+    heap_size: usize,
     cap_names: Vec<Option<String>>,
+    quantifiers: Vec<(InstIdx, bool)>,
+    /// True while compiling the body of a group as a subroutine, whose
+    /// capture groups have already been named.
+    subroutine: bool,
+    /// The group index and first instruction of each subroutine.
+    subroutines: Vec<(usize, InstIdx)>,
+    /// Each `Call` instruction along with the group index it calls, so that
+    /// it can be patched once every subroutine is compiled.
+    calls: Vec<(InstIdx, usize)>,
+    size_limit_policy: SizeLimitPolicy,
+    /// The number of counters allocated to counted repetitions so far.
+    counters: usize,
+}
+
+/// How much the compiler had emitted at some point, so that it can back up
+/// to that point.
+#[derive(Clone, Copy)]
+struct Mark {
+    insts: usize,
+    cap_names: usize,
+    quantifiers: usize,
+    calls: usize,
+    counters: usize,
 }
 
 impl Compiler {
     /// Creates a new compiler that limits the size of the regex program
-    /// to the size given (in bytes).
-    pub fn new(size_limit: usize) -> Compiler {
+    /// to the size given in `opts` (in bytes).
+    pub fn new(opts: &RegexOptions) -> Compiler {
         Compiler {
-            size_limit: size_limit,
+            size_limit: opts.size_limit,
+            depth_limit: opts.depth_limit,
+            depth: 0,
+            capture_limit: opts.capture_limit,
+            branch_marks: opts.branch_marks,
+            crlf: opts.crlf,
+            grapheme_dot: opts.grapheme_dot,
+            dot_matches_new_line: opts.dot_matches_new_line,
+            single_line: opts.single_line,
+            line_terminator: opts.line_terminator as char,
+            reject_nested_repeats: opts.reject_nested_repeats,
+            simplify_nested_repeats: opts.simplify_nested_repeats,
+            reverse: false,
             insts: vec![],
+            heap_size: 0,
             cap_names: vec![None],
+            quantifiers: vec![],
+            subroutine: false,
+            subroutines: vec![],
+            calls: vec![],
+            size_limit_policy: opts.size_limit_policy,
+            counters: 0,
         }
     }
 
     /// Compiles the given regex AST into a tuple of a sequence of
     /// instructions and a sequence of capture groups, optionally named.
     pub fn compile(mut self, ast: Expr) -> Result<Compiled, Error> {
+        let ast = if self.reject_nested_repeats
+                     || self.simplify_nested_repeats {
+            try!(self.nested_repeats(ast))
+        } else {
+            ast
+        };
+        // Branch marks number the branches of the top-level alternation as
+        // written, so it must not be rewritten.
+        let ast = if self.branch_marks { ast } else { factor_alternates(ast) };
         self.insts.push(Inst::Save(0));
-        try!(self.c(ast));
+        try!(self.c_subroutines(&ast));
+        if self.branch_marks {
+            try!(self.c_branches(ast));
+        } else {
+            try!(self.c(ast));
+        }
+        self.insts.push(Inst::Save(1));
+        self.insts.push(Inst::Match);
+        for &(pc, i) in &self.calls {
+            let &(_, start) =
+                self.subroutines.iter().find(|&&(i2, _)| i2 == i).unwrap();
+            self.insts[pc] = Inst::Call(start);
+        }
+        Ok((self.insts, self.cap_names, self.quantifiers))
+    }
+
+    /// Compiles the body of every group that `ast` recurses into as a
+    /// subroutine that ends with `Return`, where group `0` is all of `ast`.
+    ///
+    /// The subroutines are placed right after the leading `Save(0)` and are
+    /// jumped over, so that the program still ends with the body of `ast`
+    /// followed by `Save(1)` and `Match`.
+    fn c_subroutines(&mut self, ast: &Expr) -> Result<(), Error> {
+        let mut groups = vec![];
+        recursions(ast, &mut groups);
+        if groups.is_empty() {
+            return Ok(());
+        }
+        groups.sort();
+        groups.dedup();
+        let jmp = self.empty_jump();
+        self.subroutine = true;
+        for i in groups {
+            let body = if i == 0 {
+                ast.clone()
+            } else {
+                group_body(ast, i).expect("recursion into a known group")
+                                  .clone()
+            };
+            self.subroutines.push((i, self.insts.len()));
+            try!(self.c(body));
+            self.push(Inst::Return);
+        }
+        self.subroutine = false;
+        let end = self.insts.len();
+        self.set_jump(jmp, end);
+        Ok(())
+    }
+
+    /// Compiles the given regex AST into a program that matches the reverse
+    /// of the strings matched by the AST.
+    ///
+    /// The program is meant to be executed from right to left (see
+    /// `Nfa::exec_reverse`), so concatenations and literals are emitted
+    /// backwards while the topology of `Split` and `Jump` instructions is
+    /// otherwise the same. Capture groups are not recorded and branch marks
+    /// are never emitted.
+    pub fn compile_reverse(mut self, ast: Expr) -> Result<Compiled, Error> {
+        self.reverse = true;
         self.insts.push(Inst::Save(1));
+        try!(self.c(ast));
+        self.insts.push(Inst::Save(0));
         self.insts.push(Inst::Match);
-        Ok((self.insts, self.cap_names))
+        Ok((self.insts, self.cap_names, self.quantifiers))
     }
 
     fn c(&mut self, ast: Expr) -> Result<(), Error> {
+        self.depth += 1;
+        if self.depth > self.depth_limit {
+            return Err(Error::TooDeep(self.depth_limit));
+        }
+        let result = self.c_expr(ast);
+        self.depth -= 1;
+        result
+    }
+
+    fn c_expr(&mut self, ast: Expr) -> Result<(), Error> {
         use program::Inst::*;
         use program::LookInst::*;
 
         match ast {
             Expr::Empty => {},
-            Expr::Literal { chars, casei } => {
-                for mut c in chars {
+            Expr::Literal { mut chars, casei } => {
+                if self.reverse {
+                    chars.reverse();
+                }
+                for c in chars {
                     if casei {
-                        c = syntax::simple_case_fold(c);
+                        let c = syntax::simple_case_fold(c);
+                        self.push(Ranges(CharRanges::casei(c)));
+                    } else {
+                        self.push(Char(c));
                     }
-                    self.push(Char(OneChar { c: c, casei: casei }));
                 }
             }
+            Expr::AnyCharNoNL if self.dot_matches_new_line => {
+                try!(self.c(Expr::AnyChar))
+            }
+            Expr::AnyChar if self.grapheme_dot => {
+                let any = ClassRange::new('\x00', '\u{10FFFF}');
+                try!(self.c(grapheme(CharClass::new(vec![any]))))
+            }
+            Expr::AnyCharNoNL if self.grapheme_dot => {
+                let t = self.line_terminator as u8;
+                let mut ranges = vec![];
+                if t != 0 {
+                    ranges.push(ClassRange::new('\x00', (t - 1) as char));
+                }
+                ranges.push(ClassRange::new((t + 1) as char, '\u{10FFFF}'));
+                try!(self.c(grapheme(CharClass::new(ranges))))
+            }
+            Expr::AnyGrapheme if !cfg!(feature = "grapheme") => {
+                return Err(Error::NoGraphemeTables);
+            }
+            Expr::AnyGrapheme => self.c_grapheme(),
             Expr::AnyChar => self.push(Ranges(CharRanges::any())),
-            Expr::AnyCharNoNL => self.push(Ranges(CharRanges::any_nonl())),
+            Expr::AnyCharNoNL => {
+                let t = self.line_terminator;
+                self.push(Ranges(CharRanges::any_except(t)))
+            }
             Expr::Class(cls) => {
-                if cls.len() == 1 && cls[0].start == cls[0].end {
-                    self.push(Char(OneChar {
-                        c: cls[0].start,
-                        casei: cls.is_case_insensitive(),
-                    }));
+                if cls.len() == 1 && cls[0].start == cls[0].end
+                   && !cls.is_case_insensitive() {
+                    self.push(Char(cls[0].start));
                 } else {
                     self.push(Ranges(CharRanges::from_class(cls)));
                 }
             }
+            Expr::StartLine if self.single_line => {
+                self.push(EmptyLook(StartText))
+            }
+            Expr::EndLine if self.single_line => self.push(EmptyLook(EndText)),
+            Expr::StartLine if self.crlf => {
+                self.push(EmptyLook(StartLineCRLF))
+            }
+            Expr::EndLine if self.crlf => self.push(EmptyLook(EndLineCRLF)),
+            Expr::StartLine if self.line_terminator != '\n' => {
+                self.push(EmptyLook(StartLineWith(self.line_terminator)))
+            }
+            Expr::EndLine if self.line_terminator != '\n' => {
+                self.push(EmptyLook(EndLineWith(self.line_terminator)))
+            }
             Expr::StartLine => self.push(EmptyLook(StartLine)),
             Expr::EndLine => self.push(EmptyLook(EndLine)),
             Expr::StartText => self.push(EmptyLook(StartText)),
             Expr::EndText => self.push(EmptyLook(EndText)),
+            Expr::EndTextNewline => self.push(EmptyLook(EndTextNewline)),
             Expr::WordBoundary => self.push(EmptyLook(WordBoundary)),
             Expr::NotWordBoundary => self.push(EmptyLook(NotWordBoundary)),
             Expr::Group { e, i: None, name: None } => try!(self.c(*e)),
+            Expr::Group { e, .. } if self.reverse => try!(self.c(*e)),
             Expr::Group { e, i, name } => {
                 let i = i.expect("capture index");
-                self.cap_names.push(name);
+                if i > self.capture_limit {
+                    return Err(Error::TooManyCaptures(self.capture_limit));
+                }
+                if !self.subroutine {
+                    self.cap_names.push(name);
+                }
                 self.push(Save(2 * i));
                 try!(self.c(*e));
                 self.push(Save(2 * i + 1));
             }
-            Expr::Concat(es) => {
+            Expr::Concat(mut es) => {
+                if self.reverse {
+                    es.reverse();
+                }
                 for e in es {
                     try!(self.c(e));
                 }
             }
-            Expr::Alternate(mut es) => {
-                // TODO: Don't use recursion here. ---AG
-                if es.len() == 0 {
-                    return Ok(());
-                }
-                let e1 = es.remove(0);
-                if es.len() == 0 {
-                    try!(self.c(e1));
-                    return Ok(());
-                }
-                let e2 = Expr::Alternate(es); // this causes recursion
-
-                let split = self.empty_split();
-                let j1 = self.insts.len();
-                try!(self.c(e1));
-                let jmp = self.empty_jump();
-                let j2 = self.insts.len();
-                try!(self.c(e2));
-                let j3 = self.insts.len();
-
-                self.set_split(split, j1, j2);
-                self.set_jump(jmp, j3);
+            Expr::Alternate(es) => try!(self.c_alternate(es, false)),
+            Expr::Recurse(_) if self.reverse => {
+                unreachable!("recursive regexes can't be compiled in reverse")
+            }
+            Expr::Recurse(i) => {
+                // The subroutine's address is patched in at the end.
+                self.calls.push((self.insts.len(), i));
+                self.push(Call(0));
             }
+            Expr::Backref(_) if self.reverse => {
+                unreachable!("backreferences can't be compiled in reverse")
+            }
+            Expr::Backref(i) => self.push(Backref(i)),
             Expr::Repeat { e, r: Repeater::ZeroOrOne, greedy } => {
                 let split = self.empty_split();
                 let j1 = self.insts.len();
                 try!(self.c(*e));
                 let j2 = self.insts.len();
 
-                if greedy {
-                    self.set_split(split, j1, j2);
-                } else {
-                    self.set_split(split, j2, j1);
-                }
+                self.set_quantifier_split(split, j1, j2, greedy);
             }
             Expr::Repeat { e, r: Repeater::ZeroOrMore, greedy } => {
                 let j1 = self.insts.len();
@@ -135,11 +308,7 @@ impl Compiler {
                 let j3 = self.insts.len();
 
                 self.set_jump(jmp, j1);
-                if greedy {
-                    self.set_split(split, j2, j3);
-                } else {
-                    self.set_split(split, j3, j2);
-                }
+                self.set_quantifier_split(split, j2, j3, greedy);
             }
             Expr::Repeat { e, r: Repeater::OneOrMore, greedy } => {
                 let j1 = self.insts.len();
@@ -147,11 +316,7 @@ impl Compiler {
                 let split = self.empty_split();
                 let j2 = self.insts.len();
 
-                if greedy {
-                    self.set_split(split, j1, j2);
-                } else {
-                    self.set_split(split, j2, j1);
-                }
+                self.set_quantifier_split(split, j1, j2, greedy);
             }
             Expr::Repeat {
                 e,
@@ -159,8 +324,16 @@ impl Compiler {
                 greedy,
             } => {
                 let e = *e;
-                for _ in 0..min {
+                let mark = self.mark();
+                for i in 0..min {
+                    let start = self.insts.len();
                     try!(self.c(e.clone()));
+                    let unrolled =
+                        i > 0 || try!(self.check_unroll(mark, start, min - 1));
+                    if !unrolled {
+                        try!(self.c_counted(e.clone(), min, min, greedy));
+                        break;
+                    }
                 }
                 try!(self.c(Expr::Repeat {
                     e: Box::new(e),
@@ -174,34 +347,240 @@ impl Compiler {
                 greedy,
             } => {
                 let e = *e;
-                for _ in 0..min {
-                    try!(self.c(e.clone()));
-                }
-                for _ in min..max {
-                    try!(self.c(Expr::Repeat {
-                        e: Box::new(e.clone()),
-                        r: Repeater::ZeroOrOne,
-                        greedy: greedy,
-                    }));
+                let mark = self.mark();
+                for i in 0..max {
+                    let start = self.insts.len();
+                    if i < min {
+                        try!(self.c(e.clone()));
+                    } else {
+                        try!(self.c(Expr::Repeat {
+                            e: Box::new(e.clone()),
+                            r: Repeater::ZeroOrOne,
+                            greedy: greedy,
+                        }));
+                    }
+                    let unrolled =
+                        i > 0 || try!(self.check_unroll(mark, start, max - 1));
+                    if !unrolled {
+                        try!(self.c_counted(e.clone(), min, max, greedy));
+                        break;
+                    }
                 }
             }
         }
         self.check_size()
     }
 
-    fn check_size(&self) -> Result<(), Error> {
-        use std::mem::size_of;
+    /// Compiles the top-level alternation of `ast` such that each branch
+    /// starts with a `BranchMark` instruction. Non-capturing groups around
+    /// the alternation are seen through.
+    ///
+    /// If `ast` isn't an alternation, then the whole expression is marked as
+    /// the branch `0`.
+    fn c_branches(&mut self, ast: Expr) -> Result<(), Error> {
+        match ast {
+            Expr::Group { e, i: None, name: None } => self.c_branches(*e),
+            Expr::Alternate(es) => self.c_alternate(es, true),
+            ast => {
+                self.push(Inst::BranchMark(0));
+                self.c(ast)
+            }
+        }
+    }
+
+    /// Compiles `\X`, which matches one extended grapheme cluster.
+    ///
+    /// That's a character followed by as many characters as possible that
+    /// don't start a new cluster, and then a cluster boundary. Since the
+    /// characters are consumed one at a time, every thread of the NFA
+    /// still moves in lockstep.
+    fn c_grapheme(&mut self) {
+        use program::Inst::*;
+        use program::LookInst::*;
+
+        let (first, second) = if self.reverse {
+            (EmptyLook(GraphemeBoundary), Ranges(CharRanges::any()))
+        } else {
+            (Ranges(CharRanges::any()), EmptyLook(GraphemeBoundary))
+        };
+        self.push(first);
+        let j1 = self.insts.len();
+        let split = self.empty_split();
+        let j2 = self.insts.len();
+        if self.reverse {
+            self.push(Ranges(CharRanges::any()));
+            self.push(EmptyLook(NotGraphemeBoundary));
+        } else {
+            self.push(EmptyLook(NotGraphemeBoundary));
+            self.push(Ranges(CharRanges::any()));
+        }
+        let jmp = self.empty_jump();
+        let j3 = self.insts.len();
+        self.set_jump(jmp, j1);
+        self.set_split(split, j2, j3);
+        self.push(second);
+    }
+
+    /// Compiles an alternation. Every branch but the last is preceded by a
+    /// `Split` to the next branch and followed by a `Jump` past the last
+    /// branch.
+    ///
+    /// When `marked` is true, each branch starts with a `BranchMark`
+    /// instruction carrying its index.
+    fn c_alternate(
+        &mut self,
+        es: Vec<Expr>,
+        marked: bool,
+    ) -> Result<(), Error> {
+        let n = es.len();
+        let mut jmps = Vec::with_capacity(n);
+        for (i, e) in es.into_iter().enumerate() {
+            let split =
+                if i + 1 < n { Some(self.empty_split()) } else { None };
+            let j1 = self.insts.len();
+            if marked {
+                self.push(Inst::BranchMark(i));
+            }
+            try!(self.c(e));
+            if let Some(split) = split {
+                jmps.push(self.empty_jump());
+                let j2 = self.insts.len();
+                self.set_split(split, j1, j2);
+            }
+        }
+        let end = self.insts.len();
+        for jmp in jmps {
+            self.set_jump(jmp, end);
+        }
+        Ok(())
+    }
+
+    /// Compiles `e{min,max}` as a loop that counts its iterations:
+    ///
+    /// ```text
+    ///     CountReset(k)
+    /// L:  CountLoop { counter: k, min, max, greedy, exit: E }
+    ///     e
+    ///     Jump(L)
+    /// E:
+    /// ```
+    fn c_counted(
+        &mut self,
+        e: Expr,
+        min: u32,
+        max: u32,
+        greedy: bool,
+    ) -> Result<(), Error> {
+        let counter = self.counters;
+        self.counters += 1;
+        self.push(Inst::CountReset(counter));
+        let head = self.insts.len();
+        self.push(Inst::CountLoop(CountLoop {
+            counter: counter,
+            min: min,
+            max: max,
+            greedy: greedy,
+            exit: 0,
+        }));
+        try!(self.c(e));
+        self.push(Inst::Jump(head));
+        let exit = self.insts.len();
+        if let Inst::CountLoop(ref mut l) = self.insts[head] {
+            l.exit = exit;
+        }
+        Ok(())
+    }
 
-        if self.insts.len() * size_of::<Inst>() > self.size_limit {
+    /// Checks that the program compiled so far fits in the size limit.
+    ///
+    /// This includes the ranges of character classes, since a single class
+    /// like `\pL` can hold hundreds of them.
+    fn check_size(&self) -> Result<(), Error> {
+        if self.size() > self.size_limit {
             Err(Error::CompiledTooBig(self.size_limit))
         } else {
             Ok(())
         }
     }
 
+    /// Checks that `more` copies of the instructions compiled since `start`
+    /// would fit in the size limit.
+    ///
+    /// This is called after compiling the first copy of a counted
+    /// repetition, so that something like `a{100000000}` fails without
+    /// first trying to expand it.
+    fn check_repeat_size(&self, start: usize, more: u32) -> Result<(), Error> {
+        use std::mem::size_of;
+
+        let n = self.insts[start..].iter().fold(0, |n, inst| {
+            n + size_of::<Inst>() + inst_heap_size(inst)
+        });
+        let size = n.checked_mul(more as usize)
+                    .and_then(|m| m.checked_add(self.size()));
+        match size {
+            Some(size) if size <= self.size_limit => Ok(()),
+            _ => Err(Error::CompiledTooBig(self.size_limit)),
+        }
+    }
+
+    /// Checks that `more` copies of the instructions compiled since `start`
+    /// fit in the size limit, like `check_repeat_size`.
+    ///
+    /// If they don't and the size limit policy allows it, everything
+    /// compiled since `mark` is discarded and false is returned, so that
+    /// the repetition can be compiled as a counted loop instead.
+    fn check_unroll(
+        &mut self,
+        mark: Mark,
+        start: usize,
+        more: u32,
+    ) -> Result<bool, Error> {
+        match self.check_repeat_size(start, more) {
+            Ok(()) => Ok(true),
+            Err(err) => match self.size_limit_policy {
+                SizeLimitPolicy::Error => Err(err),
+                SizeLimitPolicy::SwitchToCounted => {
+                    self.rollback(mark);
+                    Ok(false)
+                }
+            },
+        }
+    }
+
+    fn mark(&self) -> Mark {
+        Mark {
+            insts: self.insts.len(),
+            cap_names: self.cap_names.len(),
+            quantifiers: self.quantifiers.len(),
+            calls: self.calls.len(),
+            counters: self.counters,
+        }
+    }
+
+    /// Discards everything that was compiled since `mark` was taken.
+    fn rollback(&mut self, mark: Mark) {
+        let freed = self.insts[mark.insts..].iter().fold(0, |n, inst| {
+            n + inst_heap_size(inst)
+        });
+        self.heap_size -= freed;
+        self.insts.truncate(mark.insts);
+        self.cap_names.truncate(mark.cap_names);
+        self.quantifiers.truncate(mark.quantifiers);
+        self.calls.truncate(mark.calls);
+        self.counters = mark.counters;
+    }
+
+    /// Returns the number of bytes used by the program compiled so far.
+    fn size(&self) -> usize {
+        use std::mem::size_of;
+
+        self.insts.len() * size_of::<Inst>() + self.heap_size
+    }
+
     /// Appends the given instruction to the program.
     #[inline]
     fn push(&mut self, x: Inst) {
+        self.heap_size += inst_heap_size(&x);
         self.insts.push(x)
     }
 
@@ -227,6 +606,27 @@ impl Compiler {
         }
     }
 
+    /// Sets the locations of a `Split` instruction emitted by a quantifier,
+    /// where `body` is the start of the repeated expression and `exit` is
+    /// the instruction after the quantifier. The body is preferred when
+    /// `greedy` is true.
+    ///
+    /// The split is recorded so that its priority can be checked later.
+    fn set_quantifier_split(
+        &mut self,
+        i: InstIdx,
+        body: InstIdx,
+        exit: InstIdx,
+        greedy: bool,
+    ) {
+        if greedy {
+            self.set_split(i, body, exit);
+        } else {
+            self.set_split(i, exit, body);
+        }
+        self.quantifiers.push((i, greedy));
+    }
+
     /// Appends an *empty* `Jump` instruction to the program and returns the
     /// index of that instruction.
     #[inline]
@@ -235,6 +635,43 @@ impl Compiler {
         self.insts.len() - 1
     }
 
+    /// Finds unbounded repetitions of expressions that can match the empty
+    /// string or that are unbounded repetitions themselves, like `(a*)*` or
+    /// `(a+)+`. These are simplified or rejected, depending on the options.
+    ///
+    /// A repetition of a repetition is simplified to a single repetition
+    /// that matches the same strings, e.g., `(a+)*` becomes `(a*)`.
+    fn nested_repeats(&self, ast: Expr) -> Result<Expr, Error> {
+        Ok(match ast {
+            Expr::Group { e, i, name } => Expr::Group {
+                e: Box::new(try!(self.nested_repeats(*e))),
+                i: i,
+                name: name,
+            },
+            Expr::Concat(es) => Expr::Concat(try!(
+                es.into_iter().map(|e| self.nested_repeats(e)).collect())),
+            Expr::Alternate(es) => Expr::Alternate(try!(
+                es.into_iter().map(|e| self.nested_repeats(e)).collect())),
+            Expr::Repeat { e, r, greedy } => {
+                let e = try!(self.nested_repeats(*e));
+                let star_or_plus = match r {
+                    Repeater::ZeroOrMore | Repeater::OneOrMore => true,
+                    _ => false,
+                };
+                if self.simplify_nested_repeats && star_or_plus
+                   && can_merge_repeats(&e, greedy) {
+                    return Ok(merge_repeats(e, r));
+                }
+                if is_unbounded(r) && self.reject_nested_repeats
+                   && (inner_repeat(&e).is_some() || can_match_empty(&e)) {
+                    return Err(Error::NestedUnboundedRepeat);
+                }
+                Expr::Repeat { e: Box::new(e), r: r, greedy: greedy }
+            }
+            ast => ast,
+        })
+    }
+
     /// Sets the location of a `Jump` instruction at index `i` to `pc`.
     /// If the instruction at index `i` isn't a `Jump` instruction, then
     /// `panic!` is called.
@@ -247,3 +684,339 @@ impl Compiler {
         }
     }
 }
+
+/// Returns an expression matching a character of the class `base`, followed
+/// by any combining marks, emoji modifiers and zero width joined characters.
+///
+/// This approximates a grapheme cluster, and is what `.` compiles to when
+/// the `grapheme_dot` option is set.
+fn grapheme(base: CharClass) -> Expr {
+    // `base(?:[\pM\x{1F3FB}-\x{1F3FF}]|\x{200D}base)*`
+    let mut extend = CharClass::unicode("M").expect("the M category").to_vec();
+    extend.push(ClassRange::new('\u{1F3FB}', '\u{1F3FF}'));
+    let joined = Expr::Concat(vec![
+        Expr::Literal { chars: vec!['\u{200D}'], casei: false },
+        Expr::Class(base.clone()),
+    ]);
+    let rest = Expr::Alternate(vec![
+        Expr::Class(CharClass::new(extend)),
+        joined,
+    ]);
+    Expr::Concat(vec![
+        Expr::Class(base),
+        Expr::Repeat {
+            e: Box::new(Expr::Group {
+                e: Box::new(rest),
+                i: None,
+                name: None,
+            }),
+            r: Repeater::ZeroOrMore,
+            greedy: true,
+        },
+    ])
+}
+
+/// Factors the literal prefix and suffix shared by every branch of each
+/// alternation in `e` out of the alternation, e.g., `foobar|foobaz`
+/// becomes `fooba[rz]`.
+///
+/// Only literals that are directly part of a branch are factored, never
+/// ones inside of a group, so captures are unaffected. Since a prefix (or
+/// suffix) is common to every branch, the branches are still tried in the
+/// same order.
+fn factor_alternates(e: Expr) -> Expr {
+    match e {
+        Expr::Group { e, i, name } => Expr::Group {
+            e: Box::new(factor_alternates(*e)),
+            i: i,
+            name: name,
+        },
+        Expr::Repeat { e, r, greedy } => Expr::Repeat {
+            e: Box::new(factor_alternates(*e)),
+            r: r,
+            greedy: greedy,
+        },
+        Expr::Concat(es) => {
+            Expr::Concat(es.into_iter().map(factor_alternates).collect())
+        }
+        Expr::Alternate(es) => {
+            let es = es.into_iter().map(factor_alternates).collect();
+            let mut es = merge_single_chars(es);
+            if es.len() == 1 {
+                return es.pop().unwrap();
+            }
+            factor_alternate(es)
+        }
+        e => e,
+    }
+}
+
+/// Merges each run of two or more branches that are single characters
+/// into a class, e.g., `x|a|b|c|foo` becomes `x|[abc]|foo`, which saves a
+/// `Split` and a `Jump` for every character but one.
+///
+/// At most one of the characters can match at any position, so the order
+/// they are tried in doesn't matter, and the class takes the place of the
+/// run among the other branches.
+fn merge_single_chars(es: Vec<Expr>) -> Vec<Expr> {
+    let mut merged = Vec::with_capacity(es.len());
+    let mut run = vec![];
+    for e in es {
+        match e {
+            Expr::Literal { ref chars, casei: false } if chars.len() == 1 => {
+                run.push(chars[0]);
+                continue;
+            }
+            _ => {}
+        }
+        push_run(&mut merged, &mut run);
+        merged.push(e);
+    }
+    push_run(&mut merged, &mut run);
+    merged
+}
+
+/// Pushes the characters in `run` as one branch and clears it.
+fn push_run(es: &mut Vec<Expr>, run: &mut Vec<char>) {
+    match run.len() {
+        0 => {}
+        1 => es.push(Expr::Literal { chars: run.clone(), casei: false }),
+        _ => es.push(class_of_chars(run)),
+    }
+    run.clear();
+}
+
+/// Returns a class matching any of the given characters.
+fn class_of_chars(chars: &[char]) -> Expr {
+    let ranges = chars.iter().map(|&c| ClassRange::new(c, c)).collect();
+    Expr::Class(CharClass::new(ranges))
+}
+
+fn factor_alternate(es: Vec<Expr>) -> Expr {
+    // An alternation of literals is searched for all at once by the literal
+    // optimizer, which only works as long as it stays one.
+    let literals = es.iter().all(|e| match *e {
+        Expr::Literal { casei: false, .. } => true,
+        _ => false,
+    });
+    let mut branches: Vec<Vec<Expr>> = es.iter().cloned().map(|e| match e {
+        Expr::Concat(es) => es,
+        e => vec![e],
+    }).collect();
+    let prefix = common_literal(&mut branches, false);
+    let suffix = common_literal(&mut branches, true);
+    if prefix.is_none() && suffix.is_none() {
+        return Expr::Alternate(es);
+    }
+    let mut chars = vec![];
+    let single_chars = branches.iter().all(|b| match b.first() {
+        Some(&Expr::Literal { chars: ref cs, casei: false })
+        if b.len() == 1 && cs.len() == 1 => {
+            chars.push(cs[0]);
+            true
+        }
+        _ => false,
+    });
+    if literals && !single_chars {
+        return Expr::Alternate(es);
+    }
+    let middle = if single_chars {
+        class_of_chars(&chars)
+    } else {
+        Expr::Alternate(branches.into_iter().map(|mut b| match b.len() {
+            0 => Expr::Empty,
+            1 => b.pop().unwrap(),
+            _ => Expr::Concat(b),
+        }).collect())
+    };
+    let mut es = vec![];
+    es.extend(prefix);
+    es.push(middle);
+    es.extend(suffix);
+    Expr::Concat(es)
+}
+
+/// Removes the longest literal shared by the start of every branch, or the
+/// end if `end` is true, and returns it.
+fn common_literal(branches: &mut [Vec<Expr>], end: bool) -> Option<Expr> {
+    let mut shared: Option<(Vec<char>, bool)> = None;
+    for b in branches.iter() {
+        let lit = if end { b.last() } else { b.first() };
+        let (chars, casei) = match lit {
+            Some(&Expr::Literal { ref chars, casei }) => (chars, casei),
+            _ => return None,
+        };
+        shared = Some(match shared {
+            None => (chars.clone(), casei),
+            Some((_, casei2)) if casei != casei2 => return None,
+            Some((mut lit, _)) => {
+                let n = if end {
+                    lit.iter().rev().zip(chars.iter().rev())
+                       .take_while(|&(a, b)| a == b).count()
+                } else {
+                    lit.iter().zip(chars).take_while(|&(a, b)| a == b)
+                       .count()
+                };
+                if end {
+                    lit = lit[lit.len() - n..].to_vec();
+                } else {
+                    lit.truncate(n);
+                }
+                (lit, casei)
+            }
+        });
+    }
+    let (chars, casei) = match shared {
+        Some((ref chars, _)) if chars.is_empty() => return None,
+        None => return None,
+        Some(common) => common,
+    };
+    for b in branches.iter_mut() {
+        let i = if end { b.len() - 1 } else { 0 };
+        let emptied = match b[i] {
+            Expr::Literal { chars: ref mut cs, .. } => {
+                if end {
+                    let len = cs.len();
+                    cs.truncate(len - chars.len());
+                } else {
+                    *cs = cs[chars.len()..].to_vec();
+                }
+                cs.is_empty()
+            }
+            _ => unreachable!(),
+        };
+        if emptied {
+            b.remove(i);
+        }
+    }
+    Some(Expr::Literal { chars: chars, casei: casei })
+}
+
+/// Returns true if `r` has no upper bound.
+fn is_unbounded(r: Repeater) -> bool {
+    match r {
+        Repeater::ZeroOrMore | Repeater::OneOrMore => true,
+        Repeater::Range { max: None, .. } => true,
+        _ => false,
+    }
+}
+
+/// Returns the repetition operator of `e` if it is an unbounded `*` or `+`
+/// repetition, possibly inside of groups.
+fn inner_repeat(e: &Expr) -> Option<Repeater> {
+    match *e {
+        Expr::Group { ref e, .. } => inner_repeat(e),
+        Expr::Repeat { r: Repeater::ZeroOrMore, .. } => {
+            Some(Repeater::ZeroOrMore)
+        }
+        Expr::Repeat { r: Repeater::OneOrMore, .. } => {
+            Some(Repeater::OneOrMore)
+        }
+        _ => None,
+    }
+}
+
+/// Returns true if `e` is an unbounded `*` or `+` repetition, possibly
+/// inside of non-capturing groups, that `merge_repeats` can merge into an
+/// outer repetition with the given greediness.
+///
+/// Merging a lazy repetition into a greedy one, or the other way around,
+/// would change which match is preferred. A capture group inside of the
+/// outer repetition captures only its last iteration, or nothing if there
+/// is none, so merging through one would change what it captures.
+fn can_merge_repeats(e: &Expr, greedy: bool) -> bool {
+    match *e {
+        Expr::Group { ref e, i: None, .. } => can_merge_repeats(e, greedy),
+        Expr::Repeat { r: Repeater::ZeroOrMore, greedy: g, .. }
+        | Expr::Repeat { r: Repeater::OneOrMore, greedy: g, .. } => {
+            g == greedy
+        }
+        _ => false,
+    }
+}
+
+/// Replaces the repetition found by `can_merge_repeats` in `e` with one
+/// that matches the same strings as repeating all of `e` with `outer`,
+/// which must be `*` or `+`.
+fn merge_repeats(e: Expr, outer: Repeater) -> Expr {
+    match e {
+        Expr::Group { e, i, name } => Expr::Group {
+            e: Box::new(merge_repeats(*e, outer)),
+            i: i,
+            name: name,
+        },
+        Expr::Repeat { e, r, greedy } => {
+            let at_least_once = match (r, outer) {
+                (Repeater::OneOrMore, Repeater::OneOrMore) => true,
+                _ => false,
+            };
+            Expr::Repeat {
+                e: e,
+                r: if at_least_once {
+                    Repeater::OneOrMore
+                } else {
+                    Repeater::ZeroOrMore
+                },
+                greedy: greedy,
+            }
+        }
+        e => e,
+    }
+}
+
+/// Returns true if `e` can match the empty string, e.g., `a*` or `(?:a|)`.
+/// Zero-width assertions are assumed to succeed.
+fn can_match_empty(e: &Expr) -> bool {
+    match *e {
+        Expr::Literal { .. } | Expr::AnyChar | Expr::AnyCharNoNL
+        | Expr::AnyGrapheme | Expr::Class(_) => false,
+        Expr::Group { ref e, .. } => can_match_empty(e),
+        Expr::Repeat { r: Repeater::ZeroOrOne, .. }
+        | Expr::Repeat { r: Repeater::ZeroOrMore, .. }
+        | Expr::Repeat { r: Repeater::Range { min: 0, .. }, .. } => true,
+        Expr::Repeat { ref e, .. } => can_match_empty(e),
+        Expr::Concat(ref es) => es.iter().all(can_match_empty),
+        Expr::Alternate(ref es) => es.iter().any(can_match_empty),
+        _ => true,
+    }
+}
+
+/// Adds the index of every group that `e` recurses into to `groups`.
+fn recursions(e: &Expr, groups: &mut Vec<usize>) {
+    match *e {
+        Expr::Recurse(i) => groups.push(i),
+        Expr::Group { ref e, .. } | Expr::Repeat { ref e, .. } => {
+            recursions(e, groups)
+        }
+        Expr::Concat(ref es) | Expr::Alternate(ref es) => {
+            for e in es {
+                recursions(e, groups);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns the expression inside of the capture group with index `i`.
+fn group_body(e: &Expr, i: usize) -> Option<&Expr> {
+    match *e {
+        Expr::Group { ref e, i: Some(i2), .. } if i2 == i => Some(e),
+        Expr::Group { ref e, .. } | Expr::Repeat { ref e, .. } => {
+            group_body(e, i)
+        }
+        Expr::Concat(ref es) | Expr::Alternate(ref es) => {
+            es.iter().filter_map(|e| group_body(e, i)).next()
+        }
+        _ => None,
+    }
+}
+
+/// Returns the number of bytes `inst` allocates on the heap.
+pub fn inst_heap_size(inst: &Inst) -> usize {
+    use std::mem::size_of;
+
+    match *inst {
+        Inst::Ranges(ref rs) => rs.ranges.len() * size_of::<(char, char)>(),
+        _ => 0,
+    }
+}
@@ -59,6 +59,12 @@ pub trait Input {
     fn previous_at(&self, i: usize) -> InputAt;
     /// Scan the input for a matching prefix.
     fn prefix_at(&self, prefixes: &Prefix, at: InputAt) -> Option<InputAt>;
+    /// Scan the input for a character whose first UTF-8 byte `b` has
+    /// `bytes[b]` set.
+    fn first_byte_at(&self, bytes: &[bool], at: InputAt) -> Option<InputAt>;
+    /// Return true if byte offset `i` is the boundary of an extended
+    /// grapheme cluster.
+    fn is_grapheme_boundary(&self, i: usize) -> bool;
 }
 
 /// An input reader over characters.
@@ -111,4 +117,27 @@ impl<'t> Input for CharInput<'t> {
     fn prefix_at(&self, prefixes: &Prefix, at: InputAt) -> Option<InputAt> {
         prefixes.find(&self[at.pos()..]).map(|(s, _)| self.at(at.pos() + s))
     }
+
+    fn first_byte_at(&self, bytes: &[bool], at: InputAt) -> Option<InputAt> {
+        self[at.pos()..].bytes()
+                        .position(|b| bytes[b as usize])
+                        .map(|i| self.at(at.pos() + i))
+    }
+
+    fn is_grapheme_boundary(&self, i: usize) -> bool {
+        is_grapheme_boundary(self, i)
+    }
+}
+
+#[cfg(feature = "grapheme")]
+fn is_grapheme_boundary(text: &str, i: usize) -> bool {
+    use unicode_segmentation::GraphemeCursor;
+    // The cursor is given all of the text, so it never asks for more.
+    let mut cursor = GraphemeCursor::new(i, text.len(), true);
+    cursor.is_boundary(text, 0) == Ok(true)
+}
+
+#[cfg(not(feature = "grapheme"))]
+fn is_grapheme_boundary(_: &str, _: usize) -> bool {
+    unreachable!("grapheme clusters require the `grapheme` feature")
 }
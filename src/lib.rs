@@ -10,10 +10,10 @@
 
 //! This crate provides a native implementation of regular expressions that is
 //! heavily based on RE2 both in syntax and in implementation. Notably,
-//! backreferences and arbitrary lookahead/lookbehind assertions are not
-//! provided. In return, regular expression searching provided by this package
-//! has excellent worst-case performance. The specific syntax supported is
-//! documented further down.
+//! arbitrary lookahead/lookbehind assertions are not provided. In return,
+//! regular expression searching provided by this package has excellent
+//! worst-case performance, as long as a regex doesn't use backreferences or
+//! recursion. The specific syntax supported is documented further down.
 //!
 //! This crate's documentation provides some simple examples, describes Unicode
 //! support and exhaustively lists the supported syntax. For more specific
@@ -90,6 +90,14 @@
 //! Secondly, the `regex` crate *must* be linked with the name `regex` since
 //! the generated code depends on finding symbols in the `regex` crate.
 //!
+//! The literal may be followed by settings. `size_limit = 1000` sets the
+//! size limit of the compiled regex, which is otherwise unlimited, and
+//! `engine = Nfa` pins the matching engine used for every search, like
+//! `Regex::with_engine`. The generated code is itself an NFA. A regex
+//! pinned to another engine searches with a copy compiled for that engine
+//! on first use, but it can still initialize a `static`, and a regex that
+//! the engine can't run is an error when your program compiles.
+//!
 //! One downside of using the `regex!` macro is that it can increase the
 //! size of your program's binary since it generates specialized Rust code.
 //! The extra size probably won't be significant for a small number of
@@ -220,6 +228,17 @@
 //! # }
 //! ```
 //!
+//! Text is not normalized by default, so `é` written as one code point
+//! doesn't match `e` followed by a combining acute accent. With the
+//! `normalize` feature enabled, `RegexBuilder::normalize` normalizes both
+//! the literals in the regex and the text being searched.
+//!
+//! Similarly, `.` matches a single code point, which may only be part of
+//! what a reader sees as one character, like a flag made of two regional
+//! indicators. With the `grapheme` feature enabled, `\X` matches one
+//! extended grapheme cluster, as defined by Unicode Standard Annex #29.
+//! Without it, compiling a regex that uses `\X` returns an error.
+//!
 //! # Syntax
 //!
 //! The syntax supported in this crate is almost in an exact correspondence
@@ -244,11 +263,19 @@
 //! \p{Greek}   Unicode character class (general category or script)
 //! \PN         Negated one-letter name Unicode character class
 //! \P{Greek}   negated Unicode character class (general category or script)
+//! \X          one extended grapheme cluster (needs the `grapheme` feature)
 //! </pre>
 //!
 //! Any named character class may appear inside a bracketed `[...]` character
 //! class. For example, `[\p{Greek}\pN]` matches any Greek or numeral
-//! character.
+//! character. Classes that aren't built in, like Unicode blocks, can be
+//! named with `RegexBuilder::custom_class`.
+//!
+//! A bracketed class may also be intersected with or subtracted by a nested
+//! class, using `&&` or `--` followed by the nested class. Each operator
+//! applies to everything before it in the class. For example,
+//! `[\w&&[^_]]` matches any word character except `_` and `[a-z--[aeiou]]`
+//! matches any lowercase ASCII consonant.
 //!
 //! ## Composites
 //!
@@ -281,10 +308,15 @@
 //! $     the end of text (or end-of-line with multi-line mode)
 //! \A    only the beginning of text (even with multi-line mode enabled)
 //! \z    only the end of text (even with multi-line mode enabled)
+//! \Z    the end of text, or just before a \n that ends the text
 //! \b    a Unicode word boundary (\w on one side and \W, \A, or \z on other)
 //! \B    not a Unicode word boundary
 //! </pre>
 //!
+//! Unlike in Perl, `$` without multi-line mode is the same as `\z`, so
+//! `foo$` doesn't match `"foo\n"`. Use `\Z`, or
+//! `RegexBuilder::dollar_newline`, to also match before a final newline.
+//!
 //! ## Grouping and flags
 //!
 //! <pre class="rust">
@@ -293,8 +325,35 @@
 //! (?:exp)        non-capturing group
 //! (?flags)       set flags within current group
 //! (?flags:exp)   set flags for exp (non-capturing)
+//! (?R)           match the whole regex again, recursively
+//! (?1)           match capture group 1 again, recursively
+//! \1             match the text that capture group 1 matched (\1 to \9)
+//! \k&lt;name&gt;       match the text that the named capture group matched
+//! \k&lt;10&gt;         match the text that capture group 10 matched
 //! </pre>
 //!
+//! Recursion makes it possible to match nested structures, like balanced
+//! parentheses with `\((?:[^()]|(?R))*\)`. Groups captured inside of a
+//! recursion are reset once it returns. Only the backtracking engine can
+//! run a regex that recurses, so it is always used to search with one, and
+//! it may visit many states for each position in the text. Calls nest at
+//! most 256 levels deep, and a search visits at most 262,144 states; a match
+//! that needs more isn't found, so the search reports no match.
+//! `execution_coverage`, which always searches with another engine, returns
+//! an empty vector for a regex that recurses, and `regex!` rejects one.
+//!
+//! A backreference matches exactly the text that its group matched most
+//! recently, even with the `i` flag, and fails if the group hasn't matched.
+//! For example, `(\w+) \1` matches `hi hi` but not `hi bye`. A named group
+//! must be opened before a backreference to it. Backreferences are subject
+//! to the same restrictions as recursion. A digit followed by more digits
+//! is an octal escape, so use `\k<10>` for groups past 9.
+//!
+//! Each capture group name may only be used once in a regex, even in
+//! different branches of an alternation. For example, `(?P<v>\d+)|(?P<v>\w+)`
+//! is rejected with a `DuplicateCaptureName` syntax error. Use a single group
+//! around the alternation instead, like `(?P<v>\d+|\w+)`.
+//!
 //! Flags are each a single character. For example, `(?x)` sets the flag `x`
 //! and `(?-x)` clears the flag `x`. Multiple flags can be set or cleared at
 //! the same time: `(?xy)` sets both the `x` and `y` flags and `(?x-y)` sets
@@ -330,14 +389,16 @@
 //! <pre class="rust">
 //! \*         literal *, works for any punctuation character: \.+*?()|[]{}^$
 //! \a         bell (\x07)
+//! \e         escape (\x1B)
 //! \f         form feed (\x0C)
 //! \t         horizontal tab
 //! \n         new line
 //! \r         carriage return
-//! \v         vertical tab (\x0B)
-//! \123       octal character code (up to three digits)
+//! \123       octal character code (two or three digits, or \0)
 //! \x7F       hex character code (exactly two digits)
 //! \x{10FFFF} any hex character code corresponding to a Unicode code point
+//! \cA        control character (\cA through \cZ are \x01 through \x1A)
+//! \R         any line break, equivalent to (?:\r\n|\v)
 //! </pre>
 //!
 //! ## Perl character classes (Unicode friendly)
@@ -352,8 +413,15 @@
 //! \S     not whitespace
 //! \w     word character (\p{Alphabetic} + \p{M} + \d + \p{Pc} + \p{Join_Control})
 //! \W     not word character
+//! \h     horizontal whitespace (tab, space and Unicode space separators)
+//! \H     not horizontal whitespace
+//! \v     vertical whitespace ([\n\x0B\f\r\x85\x{2028}\x{2029}])
+//! \V     not vertical whitespace
 //! </pre>
 //!
+//! Note that `\h` and `\v` follow PCRE, so `\v` is a class that includes
+//! the vertical tab (`\x0B`) rather than only the vertical tab.
+//!
 //! ## ASCII character classes
 //!
 //! <pre class="rust">
@@ -381,13 +449,27 @@
 //! Untrusted regular expressions are handled by capping the size of a compiled
 //! regular expression. (See `Regex::with_size_limit`.) Without this, it would
 //! be trivial for an attacker to exhaust your system's memory with expressions
-//! like `a{100}{100}{100}`.
-//!
-//! Untrusted search text is allowed because the matching engine(s) in this
-//! crate have time complexity `O(mn)` (with `m ~ regex` and `n ~ search
-//! text`), which means there's no way to cause exponential blow-up like with
-//! some other regular expression engines. (We pay for this by disallowing
-//! features like arbitrary look-ahead and back-references.)
+//! like `a{100}{100}{100}`. Similarly, the nesting depth of a regular
+//! expression is capped so that compiling it can't overflow the stack. (See
+//! `RegexBuilder::depth_limit`.) The number of capture groups is capped too,
+//! since each search needs space for every group's location. (See
+//! `RegexBuilder::capture_limit`.)
+//!
+//! Untrusted search text is allowed for a regex without backreferences or
+//! recursion, because the matching engine(s) in this crate search with one
+//! in time `O(mn)` (with `m ~ regex` and `n ~ search text`), which means
+//! there's no way to cause exponential blow-up like with some other regular
+//! expression engines. (We pay for this by disallowing features like
+//! arbitrary look-ahead.)
+//!
+//! A regex with backreferences or recursion has no such bound. Only the
+//! backtracking engine can search with one, and the states it visits may
+//! grow much faster than the text. A search gives up after visiting 262,144
+//! states and reports no match, so its memory use is bounded, but it may
+//! still take far longer than a search without these features, and it may
+//! miss a match that a longer search would find. Don't search untrusted text
+//! with such a regex unless that's acceptable, and don't accept untrusted
+//! regexes that use these features if it isn't.
 
 #![deny(missing_docs)]
 #![cfg_attr(test, deny(warnings))]
@@ -399,12 +481,26 @@
 extern crate aho_corasick;
 extern crate memchr;
 extern crate regex_syntax as syntax;
+#[cfg(feature = "normalize")]
+extern crate unicode_normalization;
+#[cfg(feature = "grapheme")]
+extern crate unicode_segmentation;
 
+pub use prefix::LiteralSearcher;
+pub use program::{Anchored, MatchEngine};
+pub use program::SizeLimitPolicy;
+#[cfg(feature = "normalize")]
+pub use program::NormalForm;
+#[cfg(feature = "profile")]
+pub use profile::ExecStats;
+pub use set::RegexSet;
 pub use re::{
-    Regex, Error, Captures, SubCaptures, SubCapturesPos, SubCapturesNamed,
-    FindCaptures, FindMatches,
-    Replacer, NoExpand, RegexSplits, RegexSplitsN,
-    quote, is_match,
+    Regex, RegexBuilder, AlternationBuilder, Error, Match,
+    Captures, SubCaptures, SubCapturesPos, SubCapturesNamed,
+    FindCaptures, FindMatches, FlagEmptyMatches, Matches, StreamMatches,
+    Replacer, NoExpand, Replacement,
+    RegexSplits, RegexSplitsN, RegexSplitCaptures,
+    quote, is_match, simplify,
 };
 
 mod backtrack;
@@ -413,9 +509,13 @@ mod compile;
 mod input;
 mod pool;
 mod prefix;
+mod profile;
 mod program;
 mod nfa;
+mod normalize;
 mod re;
+mod set;
+mod simplify;
 
 /// The `internal` module exists to support the `regex!` macro and other
 /// suspicious activity, such as testing different matching engines.
@@ -424,7 +524,9 @@ pub mod internal {
     pub use char::Char;
     pub use input::{Input, CharInput, InputAt};
     pub use program::{
-        Program, MatchEngine, CharRanges, Inst, LookInst, OneChar,
+        Program, MatchEngine, CharRanges, AsciiSet, Inst, LookInst, CountLoop,
+        PartialResult, RegexOptions, Flags, ProgramStats, Scratch,
+        NormalForm, CapturesIterReuse, FirstSet,
     };
     pub use re::ExNative;
     pub use re::Regex::{Dynamic, Native};
@@ -37,18 +37,54 @@
 //
 // [1] - http://swtch.com/~rsc/regex/regex3.html
 
+use std::collections::HashSet;
+
 use input::{Input, InputAt, CharInput};
-use program::Program;
+use program::{Anchored, CountLoop, Inst, LookInst, PartialResult, Program};
 use re::CaptureIdxs;
 
 /// An NFA simulation matching engine.
+///
+/// Counted repetitions are followed with a counter per thread, instead of
+/// being unrolled. Two threads at the same instruction are only the same
+/// thread if their counters are equal too, so a thread list may hold more
+/// than one thread per instruction, and each thread carries a copy of every
+/// counter. The lists also keep a set of the instructions and counters of
+/// their threads, which takes as much memory again.
+///
+/// To keep the number of threads down, a counter is reset when its
+/// repetition is left, and a count past the minimum is replaced by the
+/// minimum when the maximum can't be reached in the rest of the text. e.g.,
+/// `a{0,1000}b` has at most one thread per instruction when searching a
+/// text shorter than 1000 bytes. Counts below the minimum are kept, so
+/// `(?:a?){1000}b` may have a thread for every count at each position,
+/// which is as many as the unrolled program has instructions.
 #[derive(Debug)]
 pub struct Nfa<'r, 't> {
     prog: &'r Program,
     input: CharInput<'t>,
+    /// When set, records which instructions made progress in a search.
+    coverage: Option<Vec<bool>>,
+    /// The counters of the thread being followed.
+    counts: Vec<u32>,
+    /// Whether the thread being followed started an iteration of each
+    /// counted repetition at the current position.
+    entered: Vec<bool>,
 }
 
 impl<'r, 't> Nfa<'r, 't> {
+    fn new(prog: &'r Program, input: CharInput<'t>) -> Nfa<'r, 't> {
+        debug_assert!(!prog.needs_backtrack(),
+                      "the NFA can't run {:?}", prog.original);
+        Nfa {
+            prog: prog,
+            input: input,
+            coverage: None,
+            counts: vec![0; prog.counters],
+            entered: vec![false; prog.counters],
+        }
+    }
+
     /// Execute the NFA matching engine.
     ///
     /// If there's a match, `exec` returns `true` and populates the given
@@ -58,24 +94,353 @@ impl<'r, 't> Nfa<'r, 't> {
         mut caps: &mut CaptureIdxs,
         text: &'t str,
         start: usize,
+        anchored: Anchored,
     ) -> bool {
         let mut q = prog.nfa_threads.get();
+        let matched =
+            Nfa::exec_with(prog, &mut q, caps, text, start, anchored);
+        prog.nfa_threads.put(q);
+        matched
+    }
+
+    /// Execute the NFA matching engine like `exec`, but with thread lists
+    /// provided by the caller instead of taken from the program's pool.
+    ///
+    /// `q` must have been created for `prog`.
+    pub fn exec_with(
+        prog: &'r Program,
+        q: &mut NfaThreads,
+        mut caps: &mut CaptureIdxs,
+        text: &'t str,
+        start: usize,
+        anchored: Anchored,
+    ) -> bool {
         let input = CharInput::new(text);
         let at = input.at(start);
-        let matched = Nfa {
-            prog: prog,
-            input: input,
-        }.exec_(&mut q, &mut caps, at);
+        Nfa::new(prog, input).exec_(q, &mut caps, at, true, anchored).0
+    }
+
+    /// Execute the NFA matching engine like `exec`, on a `text` that may be
+    /// followed by more input.
+    ///
+    /// Returns whether there is a match if that can't change no matter what
+    /// input follows `text`. Otherwise, more input is needed to tell, and
+    /// the earliest position where a match may still start is returned as
+    /// an error. The text before it doesn't need to be searched again.
+    pub fn exec_stream(
+        prog: &'r Program,
+        mut caps: &mut CaptureIdxs,
+        text: &'t str,
+        start: usize,
+    ) -> Result<bool, usize> {
+        let mut q = prog.nfa_threads.get();
+        let input = CharInput::new(text);
+        let at = input.at(start);
+        // Prefix scanning can't be used here, since a prefix may be cut off
+        // at the end of the input.
+        let mut nfa = Nfa::new(prog, input);
+        let (matched, stop) =
+            nfa.exec_(&mut q, &mut caps, at, false, Anchored::No);
+        // Every thread died before the end of `text`, and none of them saw
+        // past the character at `stop`.
+        if stop < text.len() {
+            prog.nfa_threads.put(q);
+            return Ok(matched);
+        }
+        // Otherwise, the match found so far may still be replaced by one
+        // of the threads left, which started no later than it.
+        let mut earliest = if matched { caps[0].unwrap() } else { stop };
+        for i in 0..q.clist.size {
+            if nfa.needs_input(q.clist.pc(i)) {
+                if let Some(s) = q.clist.caps(i).get(0).and_then(|&s| s) {
+                    earliest = ::std::cmp::min(earliest, s);
+                }
+            }
+        }
+        prog.nfa_threads.put(q);
+        Err(earliest)
+    }
+
+    /// Execute the NFA matching engine to find the first match in `text`,
+    /// and return which instructions made progress during the search.
+    ///
+    /// An instruction makes progress when a thread gets past it. e.g., a
+    /// `Char` instruction is only covered if a thread at that instruction
+    /// saw a matching character, and an `EmptyLook` instruction is only
+    /// covered if its assertion held somewhere.
+    pub fn exec_coverage(prog: &'r Program, text: &'t str) -> Vec<bool> {
+        let mut q = prog.nfa_threads.get();
+        let mut caps = [None, None];
+        let mut nfa = Nfa::new(prog, CharInput::new(text));
+        nfa.coverage = Some(vec![false; prog.insts.len()]);
+        let at = nfa.input.at(0);
+        nfa.exec_(&mut q, &mut caps, at, true, Anchored::No);
+        prog.nfa_threads.put(q);
+        nfa.coverage.unwrap()
+    }
+
+    /// Execute the NFA matching engine and report whether there is a match
+    /// in `text`, or if not, whether there could be a match if more input
+    /// were appended to `text`.
+    ///
+    /// The latter is conservative: `Partial` may be reported even though no
+    /// amount of additional input would lead to a match.
+    pub fn exec_partial(prog: &'r Program, text: &'t str) -> PartialResult {
+        let mut q = prog.nfa_threads.get();
+        let mut caps = [None, None];
+        let mut nfa = Nfa::new(prog, CharInput::new(text));
+        let at = nfa.input.at(0);
+        // Prefix scanning can't be used here, since a prefix may be cut off
+        // at the end of the input.
+        let result = if nfa.exec_(&mut q, &mut caps, at, false,
+                                  Anchored::No).0 {
+            PartialResult::Full((caps[0].unwrap(), caps[1].unwrap()))
+        } else if (0..q.clist.size).any(|i| nfa.needs_input(q.clist.pc(i))) {
+            PartialResult::Partial
+        } else {
+            PartialResult::None
+        };
+        prog.nfa_threads.put(q);
+        result
+    }
+
+    /// Returns true if the thread at `pc` could make progress if there were
+    /// more input, given that it is at the end of the input.
+    fn needs_input(&self, pc: usize) -> bool {
+        use program::Inst::*;
+        match self.prog.insts[pc] {
+            Char(_) | Ranges(_) => true,
+            // These could succeed depending on the next character.
+            EmptyLook(LookInst::EndLine)
+            | EmptyLook(LookInst::EndLineCRLF)
+            | EmptyLook(LookInst::EndLineWith(_))
+            | EmptyLook(LookInst::WordBoundary)
+            | EmptyLook(LookInst::NotWordBoundary)
+            | EmptyLook(LookInst::GraphemeBoundary)
+            | EmptyLook(LookInst::NotGraphemeBoundary) => true,
+            _ => false,
+        }
+    }
+
+    /// Execute the NFA matching engine backwards from `end`.
+    ///
+    /// `prog` must have been compiled with `Program::new_reverse`. If there
+    /// is a match of the regex that ends at `end`, then the smallest
+    /// position at which such a match starts is returned.
+    ///
+    /// Paired with the end of a leftmost-first match found by a forward
+    /// search, this finds the start of that match.
+    pub fn exec_reverse(
+        prog: &'r Program,
+        text: &'t str,
+        end: usize,
+    ) -> Option<usize> {
+        let mut q = prog.nfa_threads.get();
+        let start = Nfa::new(prog, CharInput::new(text))
+            .exec_reverse_(&mut q, end);
+        prog.nfa_threads.put(q);
+        start
+    }
+
+    fn exec_reverse_(
+        &mut self,
+        q: &mut NfaThreads,
+        end: usize,
+    ) -> Option<usize> {
+        use program::Inst::*;
+
+        let mut start = None;
+        let mut at = self.input.at(end);
+        q.clist.empty(); q.nlist.empty();
+        self.add(&mut q.clist, &mut [], 0, at);
+        loop {
+            // Stepping backwards means consuming the character that precedes
+            // the current position.
+            let prev = self.input.previous_at(at.pos());
+            let at_next = self.input.at(prev.pos());
+            for i in 0..q.clist.size {
+                let pc = q.clist.pc(i);
+                self.load_counts(&q.clist, i);
+                match self.prog.insts[pc] {
+                    // Unlike a forward search, lower priority threads can't
+                    // be dropped on a match. We want the smallest start.
+                    Match => start = Some(at.pos()),
+                    Char(c) => {
+                        if c == prev.char() {
+                            self.add(&mut q.nlist, &mut [], pc+1, at_next);
+                        }
+                    }
+                    Ranges(ref inst) => {
+                        if inst.matches(prev.char()) {
+                            self.add(&mut q.nlist, &mut [], pc+1, at_next);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if prev.char().is_none() || q.nlist.size == 0 {
+                break;
+            }
+            at = at_next;
+            q.swap();
+            q.nlist.empty();
+        }
+        start
+    }
+
+    /// Execute the NFA matching engine on all of `text` and report, for each
+    /// `Match` instruction of `prog` in order, whether any match reaches it.
+    ///
+    /// `prog` must have been built with `Program::new_set`. Unlike `exec`,
+    /// finding a match doesn't stop the search, since another `Match`
+    /// instruction may still be reached.
+    pub fn exec_set(prog: &'r Program, text: &'t str) -> Vec<bool> {
+        let mut q = prog.nfa_threads.get();
+        let matched =
+            Nfa::new(prog, CharInput::new(text)).exec_set_(&mut q);
         prog.nfa_threads.put(q);
         matched
     }
 
+    fn exec_set_(&mut self, q: &mut NfaThreads) -> Vec<bool> {
+        use program::Inst::*;
+
+        let match_pcs: Vec<usize> = (0..self.prog.insts.len()).filter(|&pc| {
+            self.prog.insts[pc] == Match
+        }).collect();
+        let mut matched = vec![false; match_pcs.len()];
+        let mut left = matched.len();
+        let mut at = self.input.at(0);
+        q.clist.empty(); q.nlist.empty();
+        loop {
+            // A match may start anywhere, so start a new thread at every
+            // position.
+            self.reset_counts();
+            self.add(&mut q.clist, &mut [], 0, at);
+            let at_next = self.input.at(at.next_pos());
+            for i in 0..q.clist.size {
+                let pc = q.clist.pc(i);
+                self.load_counts(&q.clist, i);
+                match self.prog.insts[pc] {
+                    Match => {
+                        let m = match_pcs.binary_search(&pc).unwrap();
+                        if !matched[m] {
+                            matched[m] = true;
+                            left -= 1;
+                        }
+                    }
+                    Char(c) => {
+                        if c == at.char() {
+                            self.add(&mut q.nlist, &mut [], pc+1, at_next);
+                        }
+                    }
+                    Ranges(ref inst) => {
+                        if inst.matches(at.char()) {
+                            self.add(&mut q.nlist, &mut [], pc+1, at_next);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if left == 0 || at.char().is_none() {
+                break;
+            }
+            at = at_next;
+            q.swap();
+            q.nlist.empty();
+        }
+        matched
+    }
+
+    /// Execute the NFA matching engine to find the shortest match in `text`
+    /// that starts at `start`, returning where it ends.
+    pub fn exec_shortest(
+        prog: &'r Program,
+        text: &'t str,
+        start: usize,
+    ) -> Option<usize> {
+        let mut q = prog.nfa_threads.get();
+        let end = Nfa::new(prog, CharInput::new(text))
+            .exec_anchored_(&mut q, start, true);
+        prog.nfa_threads.put(q);
+        end
+    }
+
+    /// Execute the NFA matching engine to find the longest match in `text`
+    /// that starts at `start`, returning where it ends.
+    pub fn exec_longest(
+        prog: &'r Program,
+        text: &'t str,
+        start: usize,
+    ) -> Option<usize> {
+        let mut q = prog.nfa_threads.get();
+        let end = Nfa::new(prog, CharInput::new(text))
+            .exec_anchored_(&mut q, start, false);
+        prog.nfa_threads.put(q);
+        end
+    }
+
+    fn exec_anchored_(
+        &mut self,
+        q: &mut NfaThreads,
+        start: usize,
+        shortest: bool,
+    ) -> Option<usize> {
+        use program::Inst::*;
+
+        let mut end = None;
+        let mut at = self.input.at(start);
+        q.clist.empty(); q.nlist.empty();
+        self.add(&mut q.clist, &mut [], 0, at);
+        loop {
+            let at_next = self.input.at(at.next_pos());
+            for i in 0..q.clist.size {
+                let pc = q.clist.pc(i);
+                self.load_counts(&q.clist, i);
+                match self.prog.insts[pc] {
+                    // Every thread started at `start`, so the match with the
+                    // highest priority doesn't matter, only where it ends.
+                    Match => {
+                        end = Some(at.pos());
+                        if shortest {
+                            return end;
+                        }
+                    }
+                    Char(c) => {
+                        if c == at.char() {
+                            self.add(&mut q.nlist, &mut [], pc+1, at_next);
+                        }
+                    }
+                    Ranges(ref inst) => {
+                        if inst.matches(at.char()) {
+                            self.add(&mut q.nlist, &mut [], pc+1, at_next);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if at.char().is_none() || q.nlist.size == 0 {
+                return end;
+            }
+            at = at_next;
+            q.swap();
+            q.nlist.empty();
+        }
+    }
+
+    /// Returns whether there is a match, along with the position where the
+    /// search stopped.
     fn exec_(
         &mut self,
         mut q: &mut NfaThreads,
         mut caps: &mut CaptureIdxs,
         mut at: InputAt,
-    ) -> bool {
+        use_prefixes: bool,
+        anchored: Anchored,
+    ) -> (bool, usize) {
+        let start = at.pos();
+        // An anchored search can't skip ahead to where a match may start.
+        let use_prefixes = use_prefixes && anchored == Anchored::No;
         let mut matched = false;
         q.clist.empty(); q.nlist.empty();
 'LOOP:  loop {
@@ -89,25 +454,38 @@ impl<'r, 't> Nfa<'r, 't> {
                 // 2. If the expression starts with a '^' we can terminate as
                 //    soon as the last thread dies.
                 if matched
-                   || (!at.is_beginning() && self.prog.anchored_begin) {
+                   || (!at.is_beginning() && self.prog.anchored_begin)
+                   || (at.pos() != start && anchored != Anchored::No) {
                     break;
                 }
 
                 // 3. If there's a literal prefix for the program, try to
                 //    jump ahead quickly. If it can't be found, then we can
                 //    bail out early.
-                if !self.prog.prefixes.is_empty() {
+                if use_prefixes && !self.prog.prefixes.is_empty() {
                     at = match self.input.prefix_at(&self.prog.prefixes, at) {
                         None => break,
                         Some(at) => at,
                     };
+                } else if use_prefixes {
+                    // Otherwise, skip to a character that can start a match.
+                    if let Some(ref bytes) = self.prog.first_bytes {
+                        at = match self.input.first_byte_at(bytes, at) {
+                            None => break,
+                            Some(at) => at,
+                        };
+                    }
                 }
             }
 
             // This simulates a preceding '.*?' for every regex by adding
             // a state starting at the current position in the input for the
             // beginning of the program only if we don't already have a match.
-            if q.clist.size == 0 || (!self.prog.anchored_begin && !matched) {
+            if at.pos() == start
+               || (anchored == Anchored::No
+                   && (q.clist.size == 0
+                       || (!self.prog.anchored_begin && !matched))) {
+                self.reset_counts();
                 self.add(&mut q.clist, &mut caps, 0, at)
             }
             // The previous call to "add" actually inspects the position just
@@ -117,6 +495,13 @@ impl<'r, 't> Nfa<'r, 't> {
             let at_next = self.input.at(at.next_pos());
             for i in 0..q.clist.size {
                 let pc = q.clist.pc(i);
+                if anchored == Anchored::Both && !at.char().is_none()
+                   && self.prog.insts[pc] == Inst::Match {
+                    // A match must end at the end of the text, so lower
+                    // priority threads may still find one.
+                    continue;
+                }
+                self.load_counts(&q.clist, i);
                 let tcaps = q.clist.caps(i);
                 if self.step(&mut q.nlist, caps, tcaps, pc, at, at_next) {
                     matched = true;
@@ -139,11 +524,11 @@ impl<'r, 't> Nfa<'r, 't> {
             q.swap();
             q.nlist.empty();
         }
-        matched
+        (matched, at.pos())
     }
 
     fn step(
-        &self,
+        &mut self,
         nlist: &mut Threads,
         caps: &mut [Option<usize>],
         thread_caps: &mut [Option<usize>],
@@ -152,31 +537,37 @@ impl<'r, 't> Nfa<'r, 't> {
         at_next: InputAt,
     ) -> bool {
         use program::Inst::*;
-        match self.prog.insts[pc] {
+        let prog = self.prog;
+        match prog.insts[pc] {
             Match => {
+                self.cover(pc);
                 for (slot, val) in caps.iter_mut().zip(thread_caps.iter()) {
                     *slot = *val;
                 }
                 true
             }
-            Char(ref inst) => {
-                if inst.matches(at.char()) {
+            Char(c) => {
+                if c == at.char() {
+                    self.cover(pc);
                     self.add(nlist, thread_caps, pc+1, at_next);
                 }
                 false
             }
             Ranges(ref inst) => {
-                if inst.matches(at.char()).is_some() {
+                if inst.matches(at.char()) {
+                    self.cover(pc);
                     self.add(nlist, thread_caps, pc+1, at_next);
                 }
                 false
             }
-            EmptyLook(_) | Save(_) | Jump(_) | Split(_, _) => false,
+            EmptyLook(_) | Save(_) | Jump(_) | Split(_, _) | BranchMark(_)
+            | Call(_) | Return | CountReset(_) | CountLoop(_)
+            | Backref(_) => false,
         }
     }
 
     fn add(
-        &self,
+        &mut self,
         nlist: &mut Threads,
         thread_caps: &mut [Option<usize>],
         pc: usize,
@@ -184,18 +575,35 @@ impl<'r, 't> Nfa<'r, 't> {
     ) {
         use program::Inst::*;
 
-        if nlist.contains(pc) {
-            return
-        }
-        let ti = nlist.add(pc);
-        match self.prog.insts[pc] {
+        // Each instruction is followed at most once per input position.
+        // Any later thread reaching it has a lower priority, so it can be
+        // dropped. This keeps the closure over `Split`, `Jump`, `Save` and
+        // `EmptyLook` linear in the size of the program, even for regexes
+        // like `(a?){30}` with exponentially many paths. With counters,
+        // that's once per instruction and counter values.
+        let ti = match nlist.add(pc, &self.counts, &self.entered) {
+            None => return,
+            Some(ti) => ti,
+        };
+        self.prog.hits.hit(pc);
+        let prog = self.prog;
+        match prog.insts[pc] {
             EmptyLook(ref inst) => {
-                let prev = self.input.previous_at(at.pos());
-                if inst.matches(prev.char(), at.char()) {
+                if inst.matches(&self.input, at) {
+                    self.cover(pc);
                     self.add(nlist, thread_caps, pc+1, at);
+                } else {
+                    // The assertion may hold once more input follows, so
+                    // `exec_stream` needs to know where the thread started.
+                    let t = &mut nlist.thread(ti);
+                    for (slot, val) in t.caps.iter_mut()
+                                             .zip(thread_caps.iter()) {
+                        *slot = *val;
+                    }
                 }
             }
             Save(slot) => {
+                self.cover(pc);
                 if slot >= thread_caps.len() {
                     self.add(nlist, thread_caps, pc+1, at);
                 } else {
@@ -205,21 +613,138 @@ impl<'r, 't> Nfa<'r, 't> {
                     thread_caps[slot] = old;
                 }
             }
+            BranchMark(branch) => {
+                self.cover(pc);
+                match self.prog.branch_slot {
+                    Some(slot) if slot < thread_caps.len() => {
+                        let old = thread_caps[slot];
+                        thread_caps[slot] = Some(branch);
+                        self.add(nlist, thread_caps, pc+1, at);
+                        thread_caps[slot] = old;
+                    }
+                    _ => self.add(nlist, thread_caps, pc+1, at),
+                }
+            }
             Jump(to) => {
+                self.cover(pc);
                 self.add(nlist, thread_caps, to, at)
             }
             Split(x, y) => {
+                self.cover(pc);
                 self.add(nlist, thread_caps, x, at);
                 self.add(nlist, thread_caps, y, at);
             }
+            Call(_) | Return => {
+                panic!("recursive regexes need the backtracking engine")
+            }
+            CountReset(counter) => {
+                self.cover(pc);
+                let old = (self.counts[counter], self.entered[counter]);
+                self.counts[counter] = 0;
+                self.entered[counter] = false;
+                self.add(nlist, thread_caps, pc+1, at);
+                self.counts[counter] = old.0;
+                self.entered[counter] = old.1;
+            }
+            CountLoop(l) => {
+                self.cover(pc);
+                let count = self.counts[l.counter];
+                if self.entered[l.counter] {
+                    // The iteration just started matched the empty string.
+                    // The iterations left would only do the same, so go
+                    // straight to the exit.
+                    self.add_exit(nlist, thread_caps, l, at);
+                } else if count < l.min {
+                    self.add_iteration(nlist, thread_caps, pc, l, at);
+                } else if count == l.max {
+                    self.add_exit(nlist, thread_caps, l, at);
+                } else if l.greedy {
+                    self.add_iteration(nlist, thread_caps, pc, l, at);
+                    self.add_exit(nlist, thread_caps, l, at);
+                } else {
+                    self.add_exit(nlist, thread_caps, l, at);
+                    self.add_iteration(nlist, thread_caps, pc, l, at);
+                }
+            }
+            Backref(_) => {
+                panic!("backreferences need the backtracking engine")
+            }
             Match | Char(_) | Ranges(_) => {
-                let mut t = &mut nlist.thread(ti);
+                let t = &mut nlist.thread(ti);
                 for (slot, val) in t.caps.iter_mut().zip(thread_caps.iter()) {
                     *slot = *val;
                 }
             }
         }
     }
+
+    /// Follows the body of the counted repetition `l`, whose head is at
+    /// `pc`, for one more iteration.
+    fn add_iteration(
+        &mut self,
+        nlist: &mut Threads,
+        thread_caps: &mut [Option<usize>],
+        pc: usize,
+        l: CountLoop,
+        at: InputAt,
+    ) {
+        let old = (self.counts[l.counter], self.entered[l.counter]);
+        let mut count = old.0 + 1;
+        // Every iteration that gets back here has matched a character, so
+        // if there aren't enough of them left for `max` iterations, then
+        // the count only matters until it reaches `min`. Threads that are
+        // past it are given the same count, so that they're merged.
+        if count >= l.min && (l.max - count) as usize > self.input.len() {
+            count = l.min;
+        }
+        self.counts[l.counter] = count;
+        self.entered[l.counter] = true;
+        self.add(nlist, thread_caps, pc+1, at);
+        self.counts[l.counter] = old.0;
+        self.entered[l.counter] = old.1;
+    }
+
+    /// Follows the instruction after the counted repetition `l`.
+    fn add_exit(
+        &mut self,
+        nlist: &mut Threads,
+        thread_caps: &mut [Option<usize>],
+        l: CountLoop,
+        at: InputAt,
+    ) {
+        // The counter isn't read again until it's reset, so it's cleared
+        // to keep threads that only differ in it from being told apart.
+        let old = (self.counts[l.counter], self.entered[l.counter]);
+        self.counts[l.counter] = 0;
+        self.entered[l.counter] = false;
+        self.add(nlist, thread_caps, l.exit, at);
+        self.counts[l.counter] = old.0;
+        self.entered[l.counter] = old.1;
+    }
+
+    /// Makes the counters of the `i`th thread of `threads` the current
+    /// ones.
+    fn load_counts(&mut self, threads: &Threads, i: usize) {
+        if self.prog.counters > 0 {
+            self.counts.clear();
+            self.counts.extend(threads.dense[i].counts.iter().cloned());
+        }
+    }
+
+    /// Resets the current counters, before starting a new thread at the
+    /// start of the program.
+    fn reset_counts(&mut self) {
+        for count in &mut self.counts {
+            *count = 0;
+        }
+    }
+
+    #[inline]
+    fn cover(&mut self, pc: usize) {
+        if let Some(ref mut coverage) = self.coverage {
+            coverage[pc] = true;
+        }
+    }
 }
 
 /// Shared cached state between multiple invocations of a NFA engine
@@ -237,20 +762,28 @@ struct Threads {
     dense: Vec<Thread>,
     sparse: Vec<usize>,
     size: usize,
+    nslots: usize,
+    /// The instructions and counters of the threads, for a program with
+    /// counters. Since there may be more than one thread per instruction,
+    /// `dense` grows as needed and `sparse` isn't used.
+    seen: HashSet<(usize, Vec<u32>, Vec<bool>)>,
 }
 
 #[derive(Clone, Debug)]
 struct Thread {
     pc: usize,
     caps: Vec<Option<usize>>,
+    counts: Vec<u32>,
 }
 
 impl NfaThreads {
     /// Create new empty state for the NFA engine.
-    pub fn new(num_insts: usize, ncaps: usize) -> NfaThreads {
+    ///
+    /// `nslots` is the number of capture slots each thread keeps track of.
+    pub fn new(num_insts: usize, nslots: usize) -> NfaThreads {
         NfaThreads {
-            clist: Threads::new(num_insts, ncaps),
-            nlist: Threads::new(num_insts, ncaps),
+            clist: Threads::new(num_insts, nslots),
+            nlist: Threads::new(num_insts, nslots),
         }
     }
 
@@ -260,21 +793,46 @@ impl NfaThreads {
 }
 
 impl Threads {
-    fn new(num_insts: usize, ncaps: usize) -> Threads {
-        let t = Thread { pc: 0, caps: vec![None; ncaps * 2] };
+    fn new(num_insts: usize, nslots: usize) -> Threads {
+        let t = Thread { pc: 0, caps: vec![None; nslots], counts: vec![] };
         Threads {
             dense: vec![t; num_insts],
             sparse: vec![0; num_insts],
             size: 0,
+            nslots: nslots,
+            seen: HashSet::new(),
         }
     }
 
-    fn add(&mut self, pc: usize) -> usize {
+    /// Adds a thread at `pc` with the given counters and returns its
+    /// index, unless there already is such a thread.
+    fn add(
+        &mut self,
+        pc: usize,
+        counts: &[u32],
+        entered: &[bool],
+    ) -> Option<usize> {
+        if counts.is_empty() {
+            if self.contains(pc) {
+                return None;
+            }
+        } else if !self.seen.insert((pc, counts.to_vec(), entered.to_vec())) {
+            return None;
+        }
         let i = self.size;
+        if i == self.dense.len() {
+            self.dense.push(Thread {
+                pc: 0,
+                caps: vec![None; self.nslots],
+                counts: vec![],
+            });
+        }
         self.dense[i].pc = pc;
+        self.dense[i].counts.clear();
+        self.dense[i].counts.extend(counts.iter().cloned());
         self.sparse[pc] = i;
         self.size += 1;
-        i
+        Some(i)
     }
 
     fn thread(&mut self, i: usize) -> &mut Thread {
@@ -288,6 +846,7 @@ impl Threads {
 
     fn empty(&mut self) {
         self.size = 0;
+        self.seen.clear();
     }
 
     fn pc(&self, i: usize) -> usize {
@@ -298,3 +857,201 @@ impl Threads {
         &mut self.dense[i].caps
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use program::{Anchored, Program, RegexOptions};
+    use super::Nfa;
+
+    #[test]
+    fn reverse_finds_forward_start() {
+        let opts = RegexOptions::default();
+        let tests = &[
+            ("abc", "xxabcxx"),
+            ("a+b", "caaab"),
+            ("a*", "baa"),
+            ("a|ab", "xab"),
+            ("(a+)(b+)?", "zzaabb"),
+            (r"\bfoo\b", "afoo foo"),
+            (r"^\d+$", "123"),
+            (r"(?m)^\w+$", "ab\ncd"),
+            (r"[a-c]{2,3}d", "aaaacd"),
+            (r"(?i)δx?", "aΔx"),
+            (r"x*?y", "xxy"),
+        ];
+        for &(re, text) in tests {
+            let fwd = Program::new(re, &opts).unwrap();
+            let rev = Program::new_reverse(re, &opts).unwrap();
+            let mut caps = fwd.alloc_captures();
+            assert!(fwd.exec(&mut caps, text, 0), "{} on {:?}", re, text);
+            let (s, e) = (caps[0].unwrap(), caps[1].unwrap());
+            assert_eq!(Nfa::exec_reverse(&rev, text, e), Some(s),
+                       "{} on {:?}", re, text);
+        }
+    }
+
+    #[test]
+    fn many_empty_paths() {
+        use program::MatchEngine;
+
+        let opts = |engine| RegexOptions {
+            engine: Some(engine),
+            ..RegexOptions::default()
+        };
+        let re = "(a?){30}a{30}";
+        let text: String = ::std::iter::repeat('a').take(30).collect();
+        let nfa = Program::new(re, &opts(MatchEngine::Nfa)).unwrap();
+        let mut caps = nfa.alloc_captures();
+        assert!(nfa.exec(&mut caps, &text, 0));
+        assert_eq!((caps[0], caps[1]), (Some(0), Some(30)));
+
+        let re = "(a?){5}a{5}";
+        let nfa = Program::new(re, &opts(MatchEngine::Nfa)).unwrap();
+        let back = Program::new(re, &opts(MatchEngine::Backtrack)).unwrap();
+        for n in 0..12 {
+            let text: String = ::std::iter::repeat('a').take(n).collect();
+            let mut caps1 = nfa.alloc_captures();
+            let mut caps2 = back.alloc_captures();
+            assert_eq!(nfa.exec(&mut caps1, &text, 0),
+                       back.exec(&mut caps2, &text, 0));
+            assert_eq!(caps1, caps2, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn ambiguous_captures_match_backtrack() {
+        use program::MatchEngine;
+
+        let opts = |engine| RegexOptions {
+            engine: Some(engine),
+            ..RegexOptions::default()
+        };
+        let tests = &[
+            ("(a)(b)|(ab)", "ab"),
+            ("(ab)|(a)(b)", "xab"),
+            ("(a|ab)(c|bcd)(d*)", "abcd"),
+            ("(a*)(a*)", "aaa"),
+            ("(a*?)(a*)", "aaa"),
+            ("(?:(a)|(b)|(ab))+", "abab"),
+            ("((a)|b)*", "abab"),
+            ("(a?)((ab)?)(b?)", "ab"),
+            (r"(\w+)(\s*)|(\s+)", " ab "),
+        ];
+        for &(re, text) in tests {
+            let nfa = Program::new(re, &opts(MatchEngine::Nfa));
+            let back = Program::new(re, &opts(MatchEngine::Backtrack));
+            let (nfa, back) = (nfa.unwrap(), back.unwrap());
+            let mut caps1 = nfa.alloc_captures();
+            let mut caps2 = back.alloc_captures();
+            assert_eq!(nfa.exec(&mut caps1, text, 0),
+                       back.exec(&mut caps2, text, 0));
+            assert_eq!(caps1, caps2, "{} on {:?}", re, text);
+        }
+    }
+
+    #[test]
+    fn coverage() {
+        use program::Inst;
+
+        let p = Program::new("(?:a|bd)c", &RegexOptions::default()).unwrap();
+        let pc = |c| p.insts.iter().position(|inst| match *inst {
+            Inst::Char(c2) => c == c2,
+            _ => false,
+        }).unwrap();
+        let cov = Nfa::exec_coverage(&p, "ac");
+        assert!(cov[pc('a')]);
+        assert!(!cov[pc('b')]);
+        assert!(cov[pc('c')]);
+        assert!(cov[p.insts.len() - 1]);
+
+        let cov = Nfa::exec_coverage(&p, "xx");
+        assert!(!cov[pc('a')] && !cov[pc('b')] && !cov[pc('c')]);
+    }
+
+    #[test]
+    fn first_bytes_skip_positions() {
+        use std::iter::repeat;
+
+        // No thread is ever started, since no byte can start a match.
+        let p = Program::new(r"[A-Z0-9]+!", &RegexOptions::default()).unwrap();
+        let text = repeat("xyz ").take(1000).collect::<String>();
+        let cov = Nfa::exec_coverage(&p, &text);
+        assert!(!cov[0]);
+
+        let mut caps = p.alloc_captures();
+        let text = format!("{}é B12!", text);
+        assert!(Nfa::exec(&p, &mut caps, &text, 0, Anchored::No));
+        assert_eq!(caps, vec![Some(4003), Some(4007)]);
+    }
+
+    #[test]
+    fn partial() {
+        use program::PartialResult::{Full, Partial};
+        use program::PartialResult::None as NoMatch;
+
+        let p = |re: &str| Program::new(re, &RegexOptions::default()).unwrap();
+        assert_eq!(p("abc").partial_match("xabcx"), Full((1, 4)));
+        assert_eq!(p("abc").partial_match("xxab"), Partial);
+        assert_eq!(p("^abc").partial_match("ab"), Partial);
+        assert_eq!(p("^abc").partial_match("ax"), NoMatch);
+        assert_eq!(p("^abc").partial_match(""), Partial);
+        assert_eq!(p(r"^a\b").partial_match("a"), Full((0, 1)));
+        assert_eq!(p(r"^a\B").partial_match("a"), Partial);
+        assert_eq!(p(r"^a\z").partial_match("ab"), NoMatch);
+        assert_eq!(p(r"^\d{3}-\d{4}$").partial_match("555-12"), Partial);
+        assert_eq!(p(r"^\d{3}-\d{4}$").partial_match("555-1x"), NoMatch);
+    }
+
+    #[test]
+    fn counted_matches_unrolled() {
+        use program::{MatchEngine, SizeLimitPolicy};
+
+        let unrolled = RegexOptions {
+            engine: Some(MatchEngine::Nfa),
+            ..RegexOptions::default()
+        };
+        // Small enough that none of these repetitions are unrolled.
+        let counted = RegexOptions {
+            size_limit: 1000,
+            size_limit_policy: SizeLimitPolicy::SwitchToCounted,
+            ..unrolled.clone()
+        };
+        let res = &[
+            "a{2,25}", "a{2,25}?", "a{0,20}b", "[ab]{3,20}b", "(a|ab){2,20}c",
+            "(?:a?){3,25}b", "(a*){2,30}", "(?:(a)|b){2,20}",
+            "x(a{1,20}){2,20}y", "(?:a?|b){25}", "^(?:a{1,20}b){2,20}$",
+            "(a{2,30}?)(a*)", "(a|ab){2,100}c", "(?:(a)|b){2,100}?",
+            "x?(a{1,100}){2,100}b",
+        ];
+        let n = |s: &str, n: usize| ::std::iter::repeat(s).take(n)
+                                                         .collect::<String>();
+        let texts = &[
+            "".to_owned(), "a".to_owned(), "ab abaab aaac".to_owned(),
+            "bbbb".to_owned(), n("a", 22), n("a", 30) + "b",
+            format!("x{}y", n("a", 5)), format!("x{}y", n("a", 45)),
+            n("ab", 15), n("ab", 25) + "c", n("aab", 21),
+        ];
+        for re in res {
+            let p1 = Program::new(re, &counted).unwrap();
+            let p2 = Program::new(re, &unrolled).unwrap();
+            assert!(p1.counters > 0 && p2.counters == 0, "{}", re);
+            for text in texts {
+                let mut caps1 = p1.alloc_captures();
+                let mut caps2 = p2.alloc_captures();
+                assert_eq!(Nfa::exec(&p1, &mut caps1, text, 0, Anchored::No),
+                           Nfa::exec(&p2, &mut caps2, text, 0, Anchored::No));
+                assert_eq!(caps1, caps2, "{} on {:?}", re, text);
+                assert_eq!(p1.is_full_match(text), p2.is_full_match(text),
+                           "{} on {:?}", re, text);
+            }
+        }
+    }
+
+    #[test]
+    fn reverse_no_match() {
+        let rev =
+            Program::new_reverse("ab", &RegexOptions::default()).unwrap();
+        assert_eq!(Nfa::exec_reverse(&rev, "xaby", 4), None);
+        assert_eq!(Nfa::exec_reverse(&rev, "xaby", 3), Some(1));
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use syntax::Expr;
+
+use program::NormalForm;
+
+/// Normalizes every literal in `expr`.
+///
+/// Adjacent literals are joined first, since a combining mark is parsed as
+/// a literal of its own. Classes are left as they are.
+pub fn normalize_expr(expr: Expr, form: NormalForm) -> Expr {
+    match expr {
+        Expr::Literal { chars, casei } => Expr::Literal {
+            chars: normalize_chars(&chars, form),
+            casei: casei,
+        },
+        Expr::Group { e, i, name } => Expr::Group {
+            e: Box::new(normalize_expr(*e, form)),
+            i: i,
+            name: name,
+        },
+        Expr::Repeat { e, r, greedy } => Expr::Repeat {
+            e: Box::new(normalize_expr(*e, form)),
+            r: r,
+            greedy: greedy,
+        },
+        Expr::Alternate(es) => Expr::Alternate(
+            es.into_iter().map(|e| normalize_expr(e, form)).collect()),
+        Expr::Concat(es) => {
+            let mut joined: Vec<Expr> = Vec::with_capacity(es.len());
+            for e in es {
+                let e = normalize_expr(e, form);
+                if let Some(&mut Expr::Literal {
+                    ref mut chars, casei,
+                }) = joined.last_mut() {
+                    if let Expr::Literal { chars: ref more, casei: ci } = e {
+                        if casei == ci {
+                            chars.extend(more.iter().cloned());
+                            *chars = normalize_chars(chars, form);
+                            continue;
+                        }
+                    }
+                }
+                joined.push(e);
+            }
+            Expr::Concat(joined)
+        }
+        e => e,
+    }
+}
+
+fn normalize_chars(chars: &[char], form: NormalForm) -> Vec<char> {
+    let s: String = chars.iter().cloned().collect();
+    normalize_str(&s, form).chars().collect()
+}
+
+/// Text normalized for searching, along with a map back to the positions of
+/// the original text.
+///
+/// The original text is split into chunks that normalize independently of
+/// each other, so that normalizing each chunk gives the same result as
+/// normalizing all of the text. A position in the normalized text that
+/// falls inside a chunk maps back to one of the chunk's boundaries.
+pub struct NormalText {
+    /// The normalized text.
+    pub text: String,
+    /// Pairs of `(original, normalized)` positions of chunk boundaries,
+    /// including both ends of the text.
+    map: Vec<(usize, usize)>,
+}
+
+impl NormalText {
+    pub fn new(text: &str, form: NormalForm) -> NormalText {
+        let mut norm = NormalText {
+            text: String::with_capacity(text.len()),
+            map: vec![],
+        };
+        let mut chunk = 0;
+        for (i, c) in text.char_indices() {
+            if i > chunk && is_boundary(&text[chunk..i], c, form) {
+                norm.push_chunk(chunk, &text[chunk..i], form);
+                chunk = i;
+            }
+        }
+        if chunk < text.len() {
+            norm.push_chunk(chunk, &text[chunk..], form);
+        }
+        norm.map.push((text.len(), norm.text.len()));
+        norm
+    }
+
+    fn push_chunk(&mut self, at: usize, chunk: &str, form: NormalForm) {
+        self.map.push((at, self.text.len()));
+        self.text.push_str(&normalize_str(chunk, form));
+    }
+
+    /// Maps a position in the original text to the normalized text.
+    pub fn to_normal(&self, pos: usize) -> usize {
+        match self.map.binary_search_by(|&(orig, _)| orig.cmp(&pos)) {
+            Ok(i) => self.map[i].1,
+            Err(i) => self.map[i - 1].1,
+        }
+    }
+
+    /// Maps a position in the normalized text back to the original text.
+    ///
+    /// A position inside a chunk is rounded down to the start of the chunk
+    /// if `round_up` is false, or up to its end otherwise.
+    pub fn to_original(&self, pos: usize, round_up: bool) -> usize {
+        match self.map.binary_search_by(|&(_, norm)| norm.cmp(&pos)) {
+            Ok(i) => self.map[i].0,
+            Err(i) if round_up => self.map[i].0,
+            Err(i) => self.map[i - 1].0,
+        }
+    }
+}
+
+/// Returns true if `c` can start a new chunk after `chunk`.
+///
+/// That's the case when `c` doesn't start with a combining mark, which
+/// could be reordered before the chunk's own marks, and doesn't compose
+/// with the end of the chunk. Nothing after `c` can reach back past it.
+fn is_boundary(chunk: &str, c: char, form: NormalForm) -> bool {
+    if (c as u32) < 0x80 {
+        return true;
+    }
+    if !is_starter(c) {
+        return false;
+    }
+    let mut joined = chunk.to_owned();
+    joined.push(c);
+    let mut split = normalize_str(chunk, form);
+    split.push_str(&normalize_str(&joined[chunk.len()..], form));
+    normalize_str(&joined, form) == split
+}
+
+#[cfg(feature = "normalize")]
+fn normalize_str(s: &str, form: NormalForm) -> String {
+    use unicode_normalization::UnicodeNormalization;
+    match form {
+        NormalForm::Nfc => s.nfc().collect(),
+        NormalForm::Nfd => s.nfd().collect(),
+    }
+}
+
+#[cfg(not(feature = "normalize"))]
+fn normalize_str(_: &str, _: NormalForm) -> String {
+    unreachable!("normalization requires the `normalize` feature")
+}
+
+/// Returns true if the canonical decomposition of `c` starts with a
+/// character whose combining class is zero.
+#[cfg(feature = "normalize")]
+fn is_starter(c: char) -> bool {
+    use unicode_normalization::char::{
+        canonical_combining_class, decompose_canonical,
+    };
+    let mut first = None;
+    decompose_canonical(c, |d| if first.is_none() { first = Some(d) });
+    canonical_combining_class(first.unwrap_or(c)) == 0
+}
+
+#[cfg(not(feature = "normalize"))]
+fn is_starter(_: char) -> bool {
+    unreachable!("normalization requires the `normalize` feature")
+}
@@ -10,6 +10,7 @@
 
 use std::cell::RefCell;
 use std::fmt;
+use std::panic::{RefUnwindSafe, UnwindSafe};
 use std::sync::Mutex;
 
 /// A very simple memory pool for managing cached state.
@@ -38,7 +39,10 @@ pub struct Pool<T> {
 }
 
 /// The type of the function used to create resources if none exist.
-pub type CreateFn<T> = Box<Fn() -> T + Send + Sync>;
+///
+/// It's unwind safe so that a regex, which owns pools, is too.
+pub type CreateFn<T> =
+    Box<Fn() -> T + Send + Sync + RefUnwindSafe + UnwindSafe>;
 
 impl<T> Pool<T> {
     /// Create a new pool.
@@ -88,6 +88,32 @@ impl Prefix {
         }
     }
 
+    /// Like `find`, but also returns the index of the prefix alternate that
+    /// was found.
+    ///
+    /// The index corresponds to the order of the prefixes given to
+    /// `Prefix::new`. This is slower than `find` when there are multiple
+    /// single byte prefixes, so it should only be used for debugging.
+    pub fn find_alternate(
+        &self,
+        haystack: &str,
+    ) -> Option<(usize, usize, usize)> {
+        use self::Prefix::*;
+        match *self {
+            Singles { ref chars, ref sparse } => {
+                find_singles(sparse, haystack.as_bytes()).map(|(s, e)| {
+                    let b = haystack.as_bytes()[s];
+                    let i = chars.iter().position(|&c| c == b).unwrap();
+                    (s, e, i)
+                })
+            }
+            Automaton(ref aut) => {
+                aut.find(haystack).next().map(|m| (m.start, m.end, m.pati))
+            }
+            Empty | Single(_) => self.find(haystack).map(|(s, e)| (s, e, 0)),
+        }
+    }
+
     /// Returns true iff this prefix is empty.
     pub fn is_empty(&self) -> bool {
         self.len() == 0
@@ -129,6 +155,78 @@ impl Prefix {
     }
 }
 
+/// Searches text for any of a set of literal strings.
+///
+/// This uses the same machinery that regexes use to find their literal
+/// prefixes: `memchr` for a single byte, a byte map for several single
+/// bytes and an Aho-Corasick automaton for everything else.
+///
+/// # Example
+///
+/// ```rust
+/// # use regex::LiteralSearcher;
+/// let s = LiteralSearcher::new(&["foo", "bar"]);
+/// assert_eq!(s.find("xxbarfoo"), Some((2, 5)));
+/// assert_eq!(s.find("xxx"), None);
+/// ```
+#[derive(Clone, Debug)]
+pub struct LiteralSearcher {
+    prefix: Prefix,
+    /// There's nothing to search for, which `Prefix::Empty` can't express
+    /// because it matches everywhere.
+    never: bool,
+    /// The length of the longest string, in bytes.
+    longest: usize,
+}
+
+impl LiteralSearcher {
+    /// Creates a searcher for the given strings.
+    ///
+    /// If one of the strings is empty, then every search matches at the
+    /// start of the text. If there are no strings, nothing ever matches.
+    pub fn new<S: AsRef<str>>(needles: &[S]) -> LiteralSearcher {
+        let mut needles: Vec<String> =
+            needles.iter().map(|s| s.as_ref().to_owned()).collect();
+        // `Prefix::new` only checks the first string for emptiness.
+        if needles.iter().any(|s| s.is_empty()) {
+            needles = vec![String::new()];
+        }
+        LiteralSearcher {
+            never: needles.is_empty(),
+            longest: needles.iter().map(|s| s.len()).max().unwrap_or(0),
+            prefix: Prefix::new(needles),
+        }
+    }
+
+    /// Returns the start and end byte offsets of the leftmost occurrence of
+    /// any of the strings in `text`.
+    ///
+    /// When strings of different lengths occur at the same offset, it is
+    /// unspecified which one is reported.
+    pub fn find(&self, text: &str) -> Option<(usize, usize)> {
+        if self.never {
+            return None;
+        }
+        let aut = match self.prefix {
+            Prefix::Automaton(ref aut) => aut,
+            _ => return self.prefix.find(text),
+        };
+        // The automaton reports the match that ends first, which need not
+        // start first, like `bc` in `abcd` when searching for `abcd` too.
+        // Once a match ends a whole longest string past the best start, no
+        // later match can start before it.
+        let mut best: Option<(usize, usize)> = None;
+        for m in aut.find_overlapping(text) {
+            match best {
+                Some((s, _)) if m.end >= s + self.longest => break,
+                Some((s, _)) if m.start >= s => {}
+                _ => best = Some((m.start, m.end)),
+            }
+        }
+        best
+    }
+}
+
 /// A very quick scan for multiple single byte prefixes using a sparse map.
 fn find_singles(sparse: &[bool], haystack: &[u8]) -> Option<(usize, usize)> {
     for (hi, &b) in haystack.iter().enumerate() {
@@ -155,3 +253,49 @@ impl fmt::Debug for Prefix {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{LiteralSearcher, Prefix};
+
+    fn prefix(pfxs: &[&str]) -> Prefix {
+        Prefix::new(pfxs.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn find_alternate() {
+        let p = prefix(&["foo", "bar"]);
+        assert_eq!(p.find_alternate("xxbaryy"), Some((2, 5, 1)));
+        assert_eq!(p.find_alternate("foobar"), Some((0, 3, 0)));
+        assert_eq!(p.find_alternate("xxx"), None);
+
+        let p = prefix(&["a", "b", "c"]);
+        assert_eq!(p.find_alternate("xxcb"), Some((2, 3, 2)));
+        assert_eq!(prefix(&["b"]).find_alternate("ab"), Some((1, 2, 0)));
+    }
+
+    #[test]
+    fn literal_searcher() {
+        let s = LiteralSearcher::new(&["foo", "bar"]);
+        assert_eq!(s.find("xxbarfoo"), Some((2, 5)));
+        assert_eq!(s.find("fo"), None);
+
+        let s = LiteralSearcher::new(&["y", "☃"]);
+        assert_eq!(s.find("x☃y"), Some((1, 4)));
+        assert_eq!(LiteralSearcher::new(&["z", "y"]).find("xyz"),
+                   Some((1, 2)));
+        assert_eq!(LiteralSearcher::new(&["z"]).find("xyz"), Some((2, 3)));
+        assert_eq!(LiteralSearcher::new(&["abcd", "bc"]).find("abcd"),
+                   Some((0, 4)));
+        assert_eq!(LiteralSearcher::new(&["abcde", "cd", "x"]).find("xabcde"),
+                   Some((0, 1)));
+        assert_eq!(LiteralSearcher::new(&["abcde", "cd"]).find("abcdabcde"),
+                   Some((2, 4)));
+
+        assert_eq!(LiteralSearcher::new(&["a", ""]).find("xa"),
+                   Some((0, 0)));
+        let none: &[&str] = &[];
+        assert_eq!(LiteralSearcher::new(none).find("xa"), None);
+        assert_eq!(LiteralSearcher::new(none).find(""), None);
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#[cfg(feature = "profile")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use program::MatchEngine;
+
+/// What happened during the most recent search with a regex.
+///
+/// This requires the `profile` feature. See `Regex::last_exec_stats`.
+#[cfg(feature = "profile")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExecStats {
+    /// The matching engine that ran the search.
+    pub engine: MatchEngine,
+    /// The number of instructions executed by the engine. The literal
+    /// engine executes none.
+    pub steps: u64,
+}
+
+/// Counts how many times the matching engines execute each instruction of a
+/// program.
+///
+/// Without the `profile` feature, this is empty and counting does nothing.
+#[cfg(feature = "profile")]
+#[derive(Debug)]
+pub struct InstHits {
+    hits: Vec<AtomicUsize>,
+    /// When true, the counts are reset at the start of every search.
+    pub per_search: bool,
+    /// The engine of the last search, as one more than its index in
+    /// `ENGINES`, or zero before any search.
+    engine: AtomicUsize,
+    /// The number of instructions executed by the last search.
+    steps: AtomicUsize,
+}
+
+#[cfg(feature = "profile")]
+const ENGINES: [MatchEngine; 3] = [
+    MatchEngine::Backtrack, MatchEngine::Nfa, MatchEngine::Literals,
+];
+
+#[cfg(feature = "profile")]
+impl InstHits {
+    pub fn new(len: usize) -> InstHits {
+        InstHits {
+            hits: (0..len).map(|_| AtomicUsize::new(0)).collect(),
+            per_search: false,
+            engine: AtomicUsize::new(0),
+            steps: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn hit(&self, pc: usize) {
+        self.hits[pc].fetch_add(1, Ordering::Relaxed);
+        self.steps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn start_search(&self) {
+        self.steps.store(0, Ordering::Relaxed);
+        if self.per_search {
+            self.reset();
+        }
+    }
+
+    pub fn set_engine(&self, engine: MatchEngine) {
+        let i = ENGINES.iter().position(|&e| e == engine).unwrap();
+        self.engine.store(i + 1, Ordering::Relaxed);
+    }
+
+    pub fn last_stats(&self) -> Option<ExecStats> {
+        match self.engine.load(Ordering::Relaxed) {
+            0 => None,
+            i => Some(ExecStats {
+                engine: ENGINES[i - 1],
+                steps: self.steps.load(Ordering::Relaxed) as u64,
+            }),
+        }
+    }
+
+    pub fn reset(&self) {
+        for hits in &self.hits {
+            hits.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self) -> Vec<u64> {
+        self.hits.iter().map(|h| h.load(Ordering::Relaxed) as u64).collect()
+    }
+}
+
+/// Counts how many times the matching engines execute each instruction of a
+/// program.
+///
+/// Without the `profile` feature, this is empty and counting does nothing.
+#[cfg(not(feature = "profile"))]
+#[derive(Debug)]
+pub struct InstHits;
+
+#[cfg(not(feature = "profile"))]
+impl InstHits {
+    pub fn new(_: usize) -> InstHits {
+        InstHits
+    }
+
+    #[inline(always)]
+    pub fn hit(&self, _: usize) {}
+
+    #[inline(always)]
+    pub fn start_search(&self) {}
+
+    #[inline(always)]
+    pub fn set_engine(&self, _: MatchEngine) {}
+}
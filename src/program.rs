@@ -9,17 +9,27 @@
 // except according to those terms.
 
 use std::cmp::{self, Ordering};
+use std::fmt;
+use std::mem;
+use std::panic::RefUnwindSafe;
+use std::sync::{Arc, Mutex};
 
-use syntax;
+use syntax::{self, Expr, Repeater};
 
 use Error;
 use backtrack::{Backtrack, BackMachine};
 use char::Char;
-use compile::Compiler;
+use compile::{Compiler, inst_heap_size};
+use input::{Input, InputAt};
 use nfa::{Nfa, NfaThreads};
+use normalize::{NormalText, normalize_expr};
 use pool::Pool;
 use prefix::Prefix;
+use profile::InstHits;
+#[cfg(feature = "profile")]
+use profile::ExecStats;
 use re::CaptureIdxs;
+use simplify::simplify_expr;
 
 const NUM_PREFIX_LIMIT: usize = 30;
 const PREFIX_LENGTH_LIMIT: usize = 15;
@@ -27,7 +37,7 @@ const PREFIX_LENGTH_LIMIT: usize = 15;
 pub type InstIdx = usize;
 
 /// An instruction, the underlying unit of a compiled regular expression
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Inst {
     /// A match has occurred.
     /// This is always the last instruction and only occurs in a single spot.
@@ -43,98 +53,223 @@ pub enum Inst {
     /// A zero-width instruction. When this instruction matches, the input
     /// is not advanced.
     EmptyLook(LookInst),
-    /// Match a single possibly case insensitive character.
-    Char(OneChar),
+    /// Match a single case sensitive character.
+    ///
+    /// Case insensitive characters are compiled to `Ranges` instead, so that
+    /// matching this instruction is a single comparison.
+    Char(char),
     /// Match one or more possibly case insensitive character ranges.
     Ranges(CharRanges),
+    /// Record that the top-level alternation branch with the given index
+    /// was taken.
+    BranchMark(usize),
+    /// Run the subroutine starting at the given instruction, then continue
+    /// with the next instruction once it returns. Only the backtracking
+    /// engine supports this.
+    Call(InstIdx),
+    /// Return from the subroutine that was called most recently, restoring
+    /// the capture locations from before the call.
+    Return,
+    /// Set the counter with the given index to zero, before the first
+    /// iteration of a counted repetition.
+    CountReset(usize),
+    /// Start another iteration of a counted repetition or leave it,
+    /// depending on its counter.
+    CountLoop(CountLoop),
+    /// Match the same text that the capture group with the given index last
+    /// matched, or fail if it hasn't matched. Only the backtracking engine
+    /// supports this.
+    Backref(usize),
 }
 
-/// A single character instruction.
-#[derive(Clone, Debug)]
-pub struct OneChar {
-    /// The character.
-    pub c: char,
-    /// True if the character should be matched case insensitively.
-    /// (i.e., The input character will need to be case folded.)
-    pub casei: bool,
+/// The head of a repetition like `e{2,5}` that counts its iterations
+/// instead of being unrolled.
+///
+/// The body of the repetition starts at the next instruction and ends with
+/// a `Jump` back to the head.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CountLoop {
+    /// The counter holding the number of iterations started so far.
+    pub counter: usize,
+    /// The minimum number of iterations.
+    pub min: u32,
+    /// The maximum number of iterations.
+    pub max: u32,
+    /// Whether more iterations are preferred over fewer.
+    pub greedy: bool,
+    /// The instruction after the repetition.
+    pub exit: InstIdx,
 }
 
 /// A multi-range character class instruction.
-#[derive(Clone, Debug)]
+///
+/// Two classes are equal when their ranges are, which are kept in the same
+/// sorted order for the same set of characters.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct CharRanges {
     /// Sorted sequence of non-overlapping ranges.
+    ///
+    /// A `char` is stored as a 4 byte scalar value, so each range takes 8
+    /// bytes, which is as small as a pair of `u32` bounds.
     pub ranges: Vec<(char, char)>,
     /// Whether to match case insensitively.
     pub casei: bool,
+    /// The same class as a bitmap, when all of its ranges are ASCII.
+    pub ascii: Option<AsciiSet>,
+}
+
+/// A set of ASCII characters with one bit per character.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AsciiSet([u64; 2]);
+
+impl AsciiSet {
+    /// Builds a set from ranges that are all ASCII.
+    fn new(ranges: &[(char, char)]) -> AsciiSet {
+        let mut bits = [0u64; 2];
+        for &(s, e) in ranges {
+            for b in (s as usize)..(e as usize + 1) {
+                bits[b / 64] |= 1 << (b % 64);
+            }
+        }
+        AsciiSet(bits)
+    }
+
+    /// Returns true iff the given ASCII byte is in the set.
+    #[inline(always)]
+    pub fn contains(&self, b: u8) -> bool {
+        self.0[(b / 64) as usize] & (1 << (b % 64)) != 0
+    }
 }
 
 /// The set of zero-width match instructions.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum LookInst {
     /// Start of line or input.
     StartLine,
     /// End of line or input.
     EndLine,
+    /// Start of line or input, where `\r`, `\n` and `\r\n` all end a line.
+    StartLineCRLF,
+    /// End of line or input, where `\r`, `\n` and `\r\n` all end a line.
+    EndLineCRLF,
+    /// Start of line or input, where the given character ends a line.
+    StartLineWith(char),
+    /// End of line or input, where the given character ends a line.
+    EndLineWith(char),
     /// Start of input.
     StartText,
     /// End of input.
     EndText,
+    /// End of input, or just before a `\n` that ends the input.
+    EndTextNewline,
     /// Word character on one side and non-word character on other.
     WordBoundary,
     /// Word character on both sides or non-word character on both sides.
     NotWordBoundary,
-}
-
-impl OneChar {
-    /// Tests whether the given input character matches this instruction.
-    #[inline(always)] // About ~5-15% more throughput then `#[inline]`
-    pub fn matches(&self, c: Char) -> bool {
-        self.c == c || (self.casei && self.c == c.case_fold())
-    }
+    /// The boundary of an extended grapheme cluster.
+    GraphemeBoundary,
+    /// Inside of an extended grapheme cluster.
+    NotGraphemeBoundary,
 }
 
 impl CharRanges {
     /// Emits a range specifically for the `.` expression.
     pub fn any() -> CharRanges {
-        CharRanges {
-            ranges: vec![('\x00', '\u{10ffff}')],
-            casei: false,
-        }
+        CharRanges::new(vec![('\x00', '\u{10ffff}')], false)
     }
 
     /// Emits a range specifically for the `(?s).` expression.
     pub fn any_nonl() -> CharRanges {
-        CharRanges {
-            ranges: vec![('\x00', '\x09'), ('\x0B', '\u{10ffff}')],
-            casei: false,
+        CharRanges::any_except('\n')
+    }
+
+    /// Emits a range matching every character except `c`, which is what
+    /// `.` matches without the `s` flag when `c` ends a line instead of
+    /// `\n`. `c` must be ASCII.
+    pub fn any_except(c: char) -> CharRanges {
+        let (before, after) = ((c as u8) as char, (c as u8 + 1) as char);
+        let mut ranges = vec![];
+        if c != '\x00' {
+            ranges.push(('\x00', ((before as u8) - 1) as char));
         }
+        ranges.push((after, '\u{10ffff}'));
+        CharRanges::new(ranges, false)
+    }
+
+    /// Emits a range matching a single case folded character, case
+    /// insensitively.
+    pub fn casei(c: char) -> CharRanges {
+        CharRanges::new(vec![(c, c)], true)
     }
 
     /// Emits a range from the AST character class.
     pub fn from_class(cls: syntax::CharClass) -> CharRanges {
         let casei = cls.is_case_insensitive();
-        CharRanges {
-            ranges: cls.into_iter().map(|r| (r.start, r.end)).collect(),
-            casei: casei,
+        CharRanges::from_ranges(
+            cls.into_iter().map(|r| (r.start, r.end)).collect(),
+            casei,
+        )
+    }
+
+    /// Emits a range from an arbitrary sequence of inclusive ranges.
+    ///
+    /// The ranges are sorted, and overlapping or adjacent ranges are merged,
+    /// so that the instruction's ranges are always sorted and
+    /// non-overlapping.
+    pub fn from_ranges(
+        mut ranges: Vec<(char, char)>,
+        casei: bool,
+    ) -> CharRanges {
+        ranges.sort();
+        let mut merged: Vec<(char, char)> = Vec::with_capacity(ranges.len());
+        for (s, e) in ranges {
+            if let Some(last) = merged.last_mut() {
+                if is_adjacent(last.1, s) || s <= last.1 {
+                    last.1 = cmp::max(last.1, e);
+                    continue;
+                }
+            }
+            merged.push((s, e));
         }
+        // Large classes like `\pL` keep their ranges for the lifetime of the
+        // program, so don't hold on to the space freed by merging.
+        merged.shrink_to_fit();
+        CharRanges::new(merged, casei)
+    }
+
+    /// Emits a range from sorted, non-overlapping ranges. Classes that only
+    /// contain ASCII characters also get a bitmap.
+    fn new(ranges: Vec<(char, char)>, casei: bool) -> CharRanges {
+        let ascii = match ranges.last() {
+            Some(&(_, e)) if e <= '\x7f' => Some(AsciiSet::new(&ranges)),
+            _ => None,
+        };
+        CharRanges { ranges: ranges, casei: casei, ascii: ascii }
     }
 
     /// Tests whether the given input character matches this instruction.
     #[inline(always)] // About ~5-15% more throughput then `#[inline]`
-    pub fn matches(&self, mut c: Char) -> Option<usize> {
+    pub fn matches(&self, mut c: Char) -> bool {
         if self.casei {
             c = c.case_fold();
         }
+        // ASCII classes, like `[0-9]` or `[a-zA-Z_]`, are by far the most
+        // common. Other input falls through to the ranges below.
+        if let Some(ref set) = self.ascii {
+            if let Some(b) = c.as_ascii() {
+                return set.contains(b);
+            }
+        }
         // This speeds up the `match_class_unicode` benchmark by checking
         // some common cases quickly without binary search. e.g., Matching
         // a Unicode class on predominantly ASCII text.
         for i in 0..cmp::min(self.ranges.len(), 4) {
             let r = self.ranges[i];
             if c < r.0 {
-                return None;
+                return false;
             }
             if c <= r.1 {
-                return Some(i);
+                return true;
             }
         }
         self.ranges.binary_search_by(|r| {
@@ -145,20 +280,39 @@ impl CharRanges {
             } else {
                 Ordering::Equal
             }
-        }).ok()
+        }).is_ok()
     }
 }
 
 impl LookInst {
-    /// Tests whether the pair of characters matches this zero-width
-    /// instruction.
-    pub fn matches(&self, c1: Char, c2: Char) -> bool {
+    /// Tests whether this zero-width instruction matches at `at`.
+    ///
+    /// Most only look at the characters on either side of `at`, but
+    /// grapheme cluster boundaries can depend on more of the input.
+    pub fn matches<I: Input>(&self, input: &I, at: InputAt) -> bool {
         use self::LookInst::*;
+        let c1 = input.previous_at(at.pos()).char();
+        let c2 = at.char();
         match *self {
             StartLine => c1.is_none() || c1 == '\n',
             EndLine => c2.is_none() || c2 == '\n',
+            // Neither of these can match between a `\r` and a `\n`.
+            StartLineCRLF => {
+                c1.is_none() || c1 == '\n' || (c1 == '\r' && c2 != '\n')
+            }
+            EndLineCRLF => {
+                c2.is_none() || c2 == '\r' || (c2 == '\n' && c1 != '\r')
+            }
+            StartLineWith(c) => c1.is_none() || c1 == c,
+            EndLineWith(c) => c2.is_none() || c2 == c,
             StartText => c1.is_none(),
             EndText => c2.is_none(),
+            EndTextNewline => {
+                c2.is_none()
+                || (c2 == '\n' && input.at(at.next_pos()).char().is_none())
+            }
+            GraphemeBoundary => input.is_grapheme_boundary(at.pos()),
+            NotGraphemeBoundary => !input.is_grapheme_boundary(at.pos()),
             ref wbty => {
                 let (w1, w2) = (c1.is_word_char(), c2.is_word_char());
                 (*wbty == WordBoundary && w1 ^ w2)
@@ -168,11 +322,354 @@ impl LookInst {
     }
 }
 
+/// The options used to compile a regex program.
+///
+/// These are set by clients through `RegexBuilder`.
+#[derive(Clone, Debug)]
+pub struct RegexOptions {
+    /// The type of matching engine to use.
+    /// When `None` (the default), pick an engine automatically.
+    pub engine: Option<MatchEngine>,
+    /// The size limit of the compiled program, in bytes.
+    pub size_limit: usize,
+    /// The maximum number of literal prefixes, and the length in bytes
+    /// beyond which a prefix is cut off.
+    pub prefix_limits: (usize, usize),
+    /// The maximum nesting depth of the regex.
+    pub depth_limit: usize,
+    /// The maximum number of capture groups, not counting the implicit
+    /// group for the whole match.
+    pub capture_limit: usize,
+    /// When true, record which top-level alternation branch matched.
+    pub branch_marks: bool,
+    /// When true, `\r\n` is treated as a line terminator by `^` and `$` in
+    /// multi-line mode.
+    pub crlf: bool,
+    /// When true, `.` matches a character along with any combining marks,
+    /// emoji modifiers and zero width joined characters that follow it.
+    pub grapheme_dot: bool,
+    /// When true, `.` matches `\n` even without the `s` flag.
+    pub dot_matches_new_line: bool,
+    /// When true, `^` and `$` only match at the beginning and end of the
+    /// text, even in multi-line mode.
+    pub single_line: bool,
+    /// The ASCII character that ends a line for `^`, `$` and `.`, unless
+    /// `crlf` is set.
+    pub line_terminator: u8,
+    /// When true, the `i` flag only matches ASCII letters case
+    /// insensitively.
+    pub ascii_case_insensitive: bool,
+    /// When true, `$` outside of multi-line mode also matches just before
+    /// a `\n` that ends the text, like `\Z`.
+    pub dollar_newline: bool,
+    /// What to do when unrolling a bounded repetition would exceed the
+    /// size limit.
+    pub size_limit_policy: SizeLimitPolicy,
+    /// When true, an unbounded repetition of an expression that can match
+    /// the empty string or is itself an unbounded repetition is an error.
+    pub reject_nested_repeats: bool,
+    /// When true, an unbounded repetition of an unbounded repetition is
+    /// compiled as a single repetition.
+    pub simplify_nested_repeats: bool,
+    /// When true, the regex is simplified with `simplify_expr` before it's
+    /// compiled.
+    pub simplify: bool,
+    /// When set, literals in the regex and the text being searched are
+    /// normalized to this form.
+    pub normalize: Option<NormalForm>,
+    /// Named classes that `\p{name}` can refer to besides the Unicode
+    /// classes, along with their ranges of characters.
+    pub custom_classes: Vec<(String, Vec<(char, char)>)>,
+    /// Called with the matching engine chosen for each search.
+    pub observer: Option<EngineObserver>,
+}
+
+impl Default for RegexOptions {
+    fn default() -> RegexOptions {
+        RegexOptions {
+            engine: None,
+            size_limit: 10 * (1 << 20),
+            prefix_limits: (NUM_PREFIX_LIMIT, PREFIX_LENGTH_LIMIT),
+            depth_limit: 250,
+            capture_limit: 1000,
+            branch_marks: false,
+            crlf: false,
+            grapheme_dot: false,
+            dot_matches_new_line: false,
+            single_line: false,
+            line_terminator: b'\n',
+            ascii_case_insensitive: false,
+            dollar_newline: false,
+            size_limit_policy: SizeLimitPolicy::Error,
+            reject_nested_repeats: false,
+            simplify_nested_repeats: false,
+            simplify: false,
+            normalize: None,
+            custom_classes: vec![],
+            observer: None,
+        }
+    }
+}
+
+impl RegexOptions {
+    /// Returns the options to parse a regex with.
+    fn parser_options(&self) -> syntax::ParserOptions {
+        syntax::ParserOptions::new()
+            .depth_limit(self.depth_limit)
+            .ascii_case_insensitive(self.ascii_case_insensitive)
+            .dollar_newline(self.dollar_newline)
+            .classes(self.custom_classes.clone())
+    }
+}
+
+/// The options a program was compiled with that change how it matches.
+///
+/// Flags like case insensitivity can only be set inside the regex itself,
+/// so together with the original regex string, these determine the
+/// program's behavior.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Flags {
+    /// True when `\r\n` is treated as a line terminator by `^` and `$` in
+    /// multi-line mode.
+    pub crlf: bool,
+    /// True when the program records which top-level alternation branch
+    /// matched.
+    pub branch_marks: bool,
+    /// True when `.` matches a character along with the marks that follow
+    /// it.
+    pub grapheme_dot: bool,
+    /// True when `.` always matches `\n`.
+    pub dot_matches_new_line: bool,
+    /// True when `^` and `$` only match at the beginning and end of the
+    /// text.
+    pub single_line: bool,
+    /// The character that ends a line for `^`, `$` and `.`, unless `crlf`
+    /// is set.
+    pub line_terminator: u8,
+    /// When true, the `i` flag only matches ASCII letters case
+    /// insensitively.
+    pub ascii_case_insensitive: bool,
+    /// When true, `$` outside of multi-line mode also matches just before
+    /// a `\n` that ends the text.
+    pub dollar_newline: bool,
+    /// The Unicode normalization form applied to literals and searched
+    /// text, if any.
+    pub normalize: Option<NormalForm>,
+}
+
+impl Default for Flags {
+    fn default() -> Flags {
+        Flags {
+            crlf: false,
+            branch_marks: false,
+            grapheme_dot: false,
+            dot_matches_new_line: false,
+            single_line: false,
+            line_terminator: b'\n',
+            ascii_case_insensitive: false,
+            dollar_newline: false,
+            normalize: None,
+        }
+    }
+}
+
+/// A Unicode normalization form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NormalForm {
+    /// Canonical composition (NFC).
+    Nfc,
+    /// Canonical decomposition (NFD).
+    Nfd,
+}
+
+/// What the compiler does when unrolling a bounded repetition, like
+/// `a{0,1000000}`, would exceed the size limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SizeLimitPolicy {
+    /// Fail with `Error::CompiledTooBig`.
+    Error,
+    /// Compile the repetition as a loop that counts its iterations instead.
+    /// The matching engines keep track of the counters of each thread or
+    /// state, so searches are slower and may use more memory than with an
+    /// unrolled repetition that fits.
+    SwitchToCounted,
+}
+
+/// A summary of a compiled program, returned by `Program::stats` and
+/// `Program::analyze`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProgramStats {
+    /// The total number of instructions.
+    pub insts: usize,
+    /// The number of bytes used by the instructions, including the ranges
+    /// of character classes. This is what the size limit is checked against.
+    pub size: usize,
+    /// The number of capture groups, including the zeroth capture.
+    pub captures: usize,
+    /// The engine that `is_match` and `find` use, if it doesn't depend on
+    /// the text. This is the case when the engine was forced, when the
+    /// literal prefixes are the whole regex, or when only the backtracking
+    /// engine can run the program.
+    pub engine: Option<MatchEngine>,
+    /// The number of `Char` instructions.
+    pub chars: usize,
+    /// The number of `Ranges` instructions.
+    pub ranges: usize,
+    /// The number of `Split` instructions.
+    pub splits: usize,
+    /// The number of `Save` instructions.
+    pub saves: usize,
+    /// The number of `EmptyLook` instructions.
+    pub empty_looks: usize,
+    /// The number of `Jump` instructions.
+    pub jumps: usize,
+    /// The number of `BranchMark` instructions.
+    pub branch_marks: usize,
+    /// The number of `Call` instructions.
+    pub calls: usize,
+    /// The number of `CountLoop` instructions.
+    pub count_loops: usize,
+    /// The number of `Backref` instructions.
+    pub backrefs: usize,
+    /// The number of literal prefixes extracted from the program.
+    pub prefixes: usize,
+    /// True iff matching a literal prefix indicates a match.
+    pub prefixes_complete: bool,
+    /// True iff the prefixes can be searched without changing which match
+    /// is reported. This and `prefixes_complete` decide whether the
+    /// `Literals` engine is used.
+    pub prefixes_preserve_priority: bool,
+    /// True iff the program is anchored at the beginning.
+    pub anchored_begin: bool,
+    /// True iff the program is anchored at the end.
+    pub anchored_end: bool,
+    /// The length, in bytes, of the longest possible match, if it is
+    /// bounded. Along with a prefix, this lets the backtracking engine run
+    /// on long inputs.
+    pub max_match_len: Option<usize>,
+}
+
+/// Memory for the matching engines that a caller manages itself.
+///
+/// This is created by `Program::new_scratch` and used by
+/// `Program::exec_with_scratch`.
+#[derive(Debug)]
+pub struct Scratch {
+    nfa_threads: NfaThreads,
+    backtrack: BackMachine,
+}
+
+/// Successive non-overlapping matches of a program in a string, with the
+/// capture locations of each written to memory owned by the caller.
+///
+/// This is created by `Program::captures_iter_reuse`. Unlike an
+/// `Iterator`, nothing is allocated per match: each call to `next_into`
+/// overwrites the locations of the previous match, so they are only valid
+/// until the next call. Copy out any locations that must be kept.
+#[derive(Debug)]
+pub struct CapturesIterReuse<'p, 't> {
+    prog: &'p Program,
+    text: &'t str,
+    scratch: Scratch,
+    last_end: usize,
+    last_match: Option<usize>,
+}
+
+impl<'p, 't> CapturesIterReuse<'p, 't> {
+    /// Finds the next match and writes its capture locations to `caps`.
+    /// Returns false when there are no more matches, in which case the
+    /// contents of `caps` are unspecified.
+    ///
+    /// `caps` should be allocated with `Program::alloc_captures`, but it
+    /// may be shorter to skip the locations of later groups. It must have
+    /// room for at least the overall match, i.e., two slots.
+    pub fn next_into(&mut self, caps: &mut CaptureIdxs) -> bool {
+        assert!(caps.len() >= 2, "need at least two capture slots");
+        loop {
+            if self.last_end > self.text.len() {
+                return false;
+            }
+            if !self.prog.exec_with_scratch(
+                &mut self.scratch, caps, self.text, self.last_end,
+            ) {
+                return false;
+            }
+            let (s, e) = (caps[0].unwrap(), caps[1].unwrap());
+            // Don't accept empty matches immediately following a match.
+            if e == s && Some(self.last_end) == self.last_match {
+                if self.last_end >= self.text.len() {
+                    return false;
+                }
+                self.last_end += self.text[self.last_end..].chars()
+                                     .next().unwrap().len_utf8();
+                continue;
+            }
+            self.last_end = e;
+            self.last_match = Some(e);
+            return true;
+        }
+    }
+}
+
+/// A callback that is told which matching engine was chosen for a search,
+/// along with the length of the text being searched.
+///
+/// It must be `RefUnwindSafe` so that a `Regex` that holds one still is.
+#[derive(Clone)]
+pub struct EngineObserver(
+    pub Arc<Fn(MatchEngine, usize) + Send + Sync + RefUnwindSafe>
+);
+
+impl fmt::Debug for EngineObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EngineObserver")
+    }
+}
+
+/// The result of searching for a match that may continue past the end of
+/// the input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartialResult {
+    /// The input contains a match at the given span.
+    Full((usize, usize)),
+    /// There is no match yet, but there may be one if more input is
+    /// appended.
+    Partial,
+    /// There is no match, no matter what input is appended.
+    None,
+}
+
+/// The characters that a match of a program can start with. See
+/// `Program::first_char_set`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FirstSet {
+    /// A match can start with any character.
+    Any,
+    /// A match can only start with a character in one of these sorted,
+    /// non-overlapping ranges.
+    Ranges(Vec<(char, char)>),
+    /// No match starts with a character, because every match is empty.
+    Empty,
+}
+
+/// Where a match must be found, in addition to what the regex requires.
+/// See `Regex::find_anchored`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchored {
+    /// A match may start anywhere at or after the start of the search.
+    No,
+    /// A match must start at the start of the search.
+    Start,
+    /// A match must start at the start of the search and end at the end of
+    /// the text.
+    Both,
+}
+
 /// The matching engines offered by this regex implementation.
 ///
-/// N.B. This is exported for use in testing.
-#[doc(hidden)]
-#[derive(Clone, Copy, Debug)]
+/// The engine used for a search is picked automatically, and can be
+/// observed with `RegexBuilder::engine_observer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum MatchEngine {
     /// A bounded backtracking implementation. About twice as fast as the
     /// NFA, but can only work on small regexes and small input.
@@ -204,40 +701,214 @@ pub struct Program {
     pub prefixes: Prefix,
     /// True iff matching any literal prefix indicates a match.
     pub prefixes_complete: bool,
+    /// The maximum number of literal prefixes, and the length in bytes
+    /// beyond which a prefix is cut off.
+    pub prefix_limits: (usize, usize),
+    /// When there are no literal prefixes, the set of bytes that every
+    /// match must start with, indexed by byte. `None` if every byte can
+    /// start a match, or if the program can match the empty string.
+    pub first_bytes: Option<Vec<bool>>,
+    /// True iff the program can match the empty string somewhere, assuming
+    /// that zero-width assertions hold.
+    pub matches_empty: bool,
+    /// A literal string that every match contains, if one is known.
+    pub required_literal: Option<String>,
     /// True iff program is anchored at the beginning.
     pub anchored_begin: bool,
     /// True iff program is anchored at the end.
     pub anchored_end: bool,
+    /// If the program is `^` followed by nothing but a literal, then that
+    /// literal. A search then only needs to compare it with the start of
+    /// the text.
+    pub anchored_literal: Option<String>,
+    /// True iff the program has `Call` instructions, so it can only be run
+    /// by the backtracking engine.
+    pub recursive: bool,
+    /// The number of counters that `CountLoop` instructions use.
+    pub counters: usize,
+    /// The capture groups that `Backref` instructions refer to, in
+    /// ascending order. A program with backreferences can only be run by
+    /// the backtracking engine.
+    pub backrefs: Vec<usize>,
     /// The type of matching engine to use.
     /// When `None` (the default), pick an engine automatically.
     pub engine: Option<MatchEngine>,
+    /// The capture slot that `BranchMark` instructions write to, if the
+    /// program was compiled with branch marks.
+    pub branch_slot: Option<usize>,
+    /// The options that the program was compiled with.
+    pub flags: Flags,
+    /// The named classes, besides the Unicode classes, that `original` was
+    /// parsed with.
+    pub custom_classes: Vec<(String, Vec<(char, char)>)>,
+    /// The `Split` instructions emitted by quantifiers, in ascending order,
+    /// along with whether each quantifier is greedy.
+    pub quantifiers: Vec<(InstIdx, bool)>,
+    /// Called with the matching engine chosen for each search.
+    pub observer: Option<EngineObserver>,
+    /// How many times each instruction was executed. This only counts with
+    /// the `profile` feature.
+    pub hits: InstHits,
     /// Cached NFA threads.
     pub nfa_threads: Pool<NfaThreads>,
     /// Cached backtracking memory.
     pub backtrack: Pool<BackMachine>,
+    /// The reversed program used by `exec_from_end`, built on first use.
+    pub reverse: ReverseProgram,
+}
+
+/// A reversed copy of a program, compiled the first time it's needed.
+#[derive(Debug)]
+pub struct ReverseProgram {
+    /// The size limit that the reversed program is compiled under, or
+    /// `None` if the program can't be reversed.
+    size_limit: Option<usize>,
+    /// `None` until the first build, then the reversed program, or `None`
+    /// if it couldn't be compiled.
+    prog: Mutex<Option<Option<Arc<Program>>>>,
+}
+
+impl ReverseProgram {
+    /// Creates an empty cache that compiles under `size_limit`, or that
+    /// never yields a program if it's `None`.
+    pub fn new(size_limit: Option<usize>) -> ReverseProgram {
+        ReverseProgram { size_limit: size_limit, prog: Mutex::new(None) }
+    }
+
+    /// Returns the cached program, calling `build` with the size limit to
+    /// compile it if this is the first call.
+    fn get<F>(&self, build: F) -> Option<Arc<Program>>
+            where F: FnOnce(usize) -> Option<Program> {
+        let size_limit = match self.size_limit {
+            None => return None,
+            Some(size_limit) => size_limit,
+        };
+        let mut prog = self.prog.lock().unwrap();
+        if prog.is_none() {
+            *prog = Some(build(size_limit).map(Arc::new));
+        }
+        prog.as_ref().unwrap().clone()
+    }
 }
 
 impl Program {
     /// Compiles a Regex.
-    pub fn new(
-        engine: Option<MatchEngine>,
+    pub fn new(re: &str, opts: &RegexOptions) -> Result<Program, Error> {
+        let expr = try!(Program::parse(re, opts));
+        Program::compile(expr, re.into(), opts)
+    }
+
+    /// Compiles a regex with the default options and the given size limit,
+    /// and returns a summary of the program.
+    ///
+    /// This is meant for tools that check many regexes. The memory that
+    /// the matching engines use is only allocated by the first search, so
+    /// it is never allocated here.
+    pub fn analyze(
+        re: &str,
         size_limit: usize,
+    ) -> Result<ProgramStats, Error> {
+        let opts = RegexOptions {
+            size_limit: size_limit,
+            ..RegexOptions::default()
+        };
+        Program::new(re, &opts).map(|prog| prog.stats())
+    }
+
+    /// Parses a regex with the given options, without compiling it.
+    pub fn parse(
         re: &str,
+        opts: &RegexOptions,
+    ) -> Result<syntax::Expr, Error> {
+        let parsed = opts.parser_options().parse(re);
+        parsed.map_err(|err| match *err.kind() {
+            syntax::ErrorKind::TooDeep(limit) => Error::TooDeep(limit),
+            _ => Error::Syntax(err),
+        })
+    }
+
+    /// Compiles an already parsed regular expression.
+    ///
+    /// This is useful for callers that build or transform an `Expr`
+    /// programmatically, since it skips rendering the expression to a string
+    /// and parsing it again. The `original` string of the program is the
+    /// rendered form of `expr`.
+    pub fn from_expr(
+        expr: syntax::Expr,
+        opts: &RegexOptions,
+    ) -> Result<Program, Error> {
+        let original = expr.to_string();
+        Program::compile(expr, original, opts)
+    }
+
+    fn compile(
+        expr: syntax::Expr,
+        original: String,
+        opts: &RegexOptions,
     ) -> Result<Program, Error> {
-        let expr = try!(syntax::Expr::parse(re));
-        let (insts, cap_names) = try!(Compiler::new(size_limit).compile(expr));
-        let (insts_len, ncaps) = (insts.len(), num_captures(&insts));
-        let create_threads = move || NfaThreads::new(insts_len, ncaps);
+        let expr = if opts.simplify { simplify_expr(expr) } else { expr };
+        let expr = match opts.normalize {
+            None => expr,
+            Some(form) => normalize_expr(expr, form),
+        };
+        // A literal in normalized form may not be in the original text.
+        let required = match opts.normalize {
+            None => required_literal(&expr),
+            Some(_) => None,
+        };
+        let (insts, cap_names, quantifiers) =
+            try!(Compiler::new(opts).compile(expr));
+        let branch_slot = if opts.branch_marks {
+            Some(2 * num_captures(&insts))
+        } else {
+            None
+        };
+        let insts_len = insts.len();
+        let nslots = num_slots(&insts, branch_slot);
+        let matches_empty = matches_empty(&insts);
+        let recursive = insts.iter().any(|inst| match *inst {
+            Inst::Call(_) => true,
+            _ => false,
+        });
+        let counters = num_counters(&insts);
+        let backrefs = backref_groups(&insts);
+        let anchored_literal = anchored_literal(&insts);
+        let create_threads = move || NfaThreads::new(insts_len, nslots);
         let create_backtrack = move || BackMachine::new();
         let mut prog = Program {
-            original: re.into(),
+            original: original,
             insts: insts,
             cap_names: cap_names,
             prefixes: Prefix::Empty,
             prefixes_complete: false,
+            prefix_limits: opts.prefix_limits,
+            first_bytes: None,
+            matches_empty: matches_empty,
+            required_literal: required,
             anchored_begin: false,
             anchored_end: false,
-            engine: engine,
+            anchored_literal: anchored_literal,
+            recursive: recursive,
+            counters: counters,
+            backrefs: backrefs,
+            engine: opts.engine,
+            branch_slot: branch_slot,
+            flags: Flags {
+                crlf: opts.crlf,
+                branch_marks: opts.branch_marks,
+                grapheme_dot: opts.grapheme_dot,
+                dot_matches_new_line: opts.dot_matches_new_line,
+                single_line: opts.single_line,
+                line_terminator: opts.line_terminator,
+                ascii_case_insensitive: opts.ascii_case_insensitive,
+                dollar_newline: opts.dollar_newline,
+                normalize: opts.normalize,
+            },
+            custom_classes: opts.custom_classes.clone(),
+            quantifiers: quantifiers,
+            observer: opts.observer.clone(),
+            hits: InstHits::new(insts_len),
+            reverse: ReverseProgram::new(Some(opts.size_limit)),
             nfa_threads: Pool::new(Box::new(create_threads)),
             backtrack: Pool::new(Box::new(create_backtrack)),
         };
@@ -247,13 +918,309 @@ impl Program {
             Inst::EmptyLook(LookInst::StartText) => true,
             _ => false,
         };
-        prog.anchored_end = match prog.insts[prog.insts.len() - 3] {
-            Inst::EmptyLook(LookInst::EndText) => true,
+        prog.find_first_bytes();
+        // Every path to the final `Save(1)` must go through `$`. An
+        // alternation like `a|b$` ends with `$`, but its first branch jumps
+        // straight to `Save(1)`.
+        let end = prog.insts.len() - 2;
+        prog.anchored_end = match prog.insts[end - 1] {
+            Inst::EmptyLook(LookInst::EndText) => {
+                !prog.insts.iter().any(|inst| match *inst {
+                    Inst::Jump(pc) => pc == end,
+                    Inst::Split(x, y) => x == end || y == end,
+                    _ => false,
+                })
+            }
             _ => false,
         };
         Ok(prog)
     }
 
+    /// Builds a program that matches the given literal string exactly.
+    ///
+    /// This skips the parser and compiler entirely. The program always uses
+    /// the `Literals` matching engine, which scans for the literal as a
+    /// prefix.
+    pub fn literal(needle: &str) -> Program {
+        let mut insts = Vec::with_capacity(needle.len() + 3);
+        insts.push(Inst::Save(0));
+        for c in needle.chars() {
+            insts.push(Inst::Char(c));
+        }
+        insts.push(Inst::Save(1));
+        insts.push(Inst::Match);
+        let (insts_len, nslots) = (insts.len(), num_slots(&insts, None));
+        let matches_empty = needle.is_empty();
+        let create_threads = move || NfaThreads::new(insts_len, nslots);
+        let create_backtrack = move || BackMachine::new();
+        Program {
+            original: syntax::quote(needle),
+            insts: insts,
+            cap_names: vec![None],
+            prefixes: Prefix::new(vec![needle.to_owned()]),
+            prefixes_complete: true,
+            prefix_limits: (NUM_PREFIX_LIMIT, PREFIX_LENGTH_LIMIT),
+            first_bytes: None,
+            matches_empty: matches_empty,
+            required_literal: if needle.is_empty() {
+                None
+            } else {
+                Some(needle.to_owned())
+            },
+            anchored_begin: false,
+            anchored_end: false,
+            anchored_literal: None,
+            recursive: false,
+            counters: 0,
+            backrefs: vec![],
+            engine: Some(MatchEngine::Literals),
+            branch_slot: None,
+            flags: Flags::default(),
+            custom_classes: vec![],
+            quantifiers: vec![],
+            observer: None,
+            hits: InstHits::new(insts_len),
+            reverse: ReverseProgram::new(
+                Some(RegexOptions::default().size_limit)),
+            nfa_threads: Pool::new(Box::new(create_threads)),
+            backtrack: Pool::new(Box::new(create_backtrack)),
+        }
+    }
+
+    /// Compiles a Regex into a program that runs backwards.
+    ///
+    /// The program returned must only be executed with `Nfa::exec_reverse`,
+    /// which finds the start of a match given its end.
+    pub fn new_reverse(
+        re: &str,
+        opts: &RegexOptions,
+    ) -> Result<Program, Error> {
+        let parser_opts = opts.parser_options().depth_limit(::std::usize::MAX);
+        let expr = try!(parser_opts.parse(re));
+        let compiler = Compiler::new(opts);
+        let (insts, cap_names, quantifiers) =
+            try!(compiler.compile_reverse(expr));
+        let (insts_len, nslots) = (insts.len(), num_slots(&insts, None));
+        let matches_empty = matches_empty(&insts);
+        let counters = num_counters(&insts);
+        let backrefs = backref_groups(&insts);
+        let create_threads = move || NfaThreads::new(insts_len, nslots);
+        let create_backtrack = move || BackMachine::new();
+        Ok(Program {
+            original: re.into(),
+            insts: insts,
+            cap_names: cap_names,
+            prefixes: Prefix::Empty,
+            prefixes_complete: false,
+            prefix_limits: opts.prefix_limits,
+            first_bytes: None,
+            matches_empty: matches_empty,
+            required_literal: None,
+            anchored_begin: false,
+            anchored_end: false,
+            anchored_literal: None,
+            recursive: false,
+            counters: counters,
+            backrefs: backrefs,
+            engine: Some(MatchEngine::Nfa),
+            branch_slot: None,
+            flags: Flags::default(),
+            custom_classes: opts.custom_classes.clone(),
+            quantifiers: quantifiers,
+            observer: None,
+            hits: InstHits::new(insts_len),
+            reverse: ReverseProgram::new(Some(opts.size_limit)),
+            nfa_threads: Pool::new(Box::new(create_threads)),
+            backtrack: Pool::new(Box::new(create_backtrack)),
+        })
+    }
+
+    /// Combines `progs` into a single program that keeps the `Match`
+    /// instruction of each, in the same order, so that a search with
+    /// `exec_set` can tell which of them match.
+    ///
+    /// None of `progs` may need the backtracking engine.
+    pub fn new_set(progs: &[&Program]) -> Program {
+        // A chain of `Split`s leads to each program, which are copied in
+        // whole after the chain.
+        let nsplits = if progs.is_empty() { 0 } else { progs.len() - 1 };
+        let mut offs = Vec::with_capacity(progs.len());
+        let mut count_offs = Vec::with_capacity(progs.len());
+        let (mut off, mut counters) = (nsplits, 0);
+        for prog in progs {
+            assert!(!prog.needs_backtrack());
+            offs.push(off);
+            count_offs.push(counters);
+            off += prog.insts.len();
+            counters += prog.counters;
+        }
+        let mut insts = Vec::with_capacity(off);
+        for i in 0..nsplits {
+            let next = if i + 1 == nsplits { offs[i + 1] } else { i + 1 };
+            insts.push(Inst::Split(offs[i], next));
+        }
+        let mut quantifiers = vec![];
+        for (i, prog) in progs.iter().enumerate() {
+            let off = offs[i];
+            for inst in &prog.insts {
+                insts.push(renumber(inst, off, 0, count_offs[i]));
+            }
+            quantifiers.extend(
+                prog.quantifiers.iter().map(|&(pc, g)| (pc + off, g)));
+        }
+        if insts.is_empty() {
+            // This never matches.
+            insts.push(Inst::Ranges(CharRanges::from_ranges(vec![], false)));
+        }
+        let originals: Vec<String> =
+            progs.iter().map(|p| format!("(?:{})", p.original)).collect();
+        let insts_len = insts.len();
+        let nslots = num_slots(&insts, None);
+        let create_threads = move || NfaThreads::new(insts_len, nslots);
+        let create_backtrack = move || BackMachine::new();
+        Program {
+            original: originals.join("|"),
+            cap_names: vec![None; num_captures(&insts)],
+            insts: insts,
+            prefixes: Prefix::Empty,
+            prefixes_complete: false,
+            prefix_limits: (NUM_PREFIX_LIMIT, PREFIX_LENGTH_LIMIT),
+            first_bytes: None,
+            matches_empty: progs.iter().any(|p| p.matches_empty),
+            required_literal: None,
+            anchored_begin: false,
+            anchored_end: false,
+            anchored_literal: None,
+            recursive: false,
+            counters: counters,
+            backrefs: vec![],
+            engine: Some(MatchEngine::Nfa),
+            branch_slot: None,
+            flags: Flags::default(),
+            custom_classes: vec![],
+            quantifiers: quantifiers,
+            observer: None,
+            hits: InstHits::new(insts_len),
+            reverse: ReverseProgram::new(
+                Some(RegexOptions::default().size_limit)),
+            nfa_threads: Pool::new(Box::new(create_threads)),
+            backtrack: Pool::new(Box::new(create_backtrack)),
+        }
+    }
+
+    /// Searches all of `text` with a program built by `new_set`, and
+    /// returns whether each of the programs it was built from matches.
+    pub fn exec_set(&self, text: &str) -> Vec<bool> {
+        Nfa::exec_set(self, text)
+    }
+
+    /// Combines this program and `other` into a single program that matches
+    /// either one, preferring a match of `self`.
+    ///
+    /// The capture groups of `other` are renumbered to follow the capture
+    /// groups of `self`. The result is equivalent to compiling
+    /// `(?:self)|(?:other)`, but without re-parsing either program.
+    pub fn union(&self, other: &Program) -> Program {
+        // Every program starts with `Save(0)` and ends with
+        // `Save(1), Match`. The bodies between those are spliced in behind
+        // a new leading `Split`, and the first body jumps over the second.
+        let (alen, blen) = (self.insts.len(), other.insts.len());
+        let aoff = 1;
+        let boff = alen - 1;
+        let cap_off = 2 * (self.num_captures() - 1);
+        let count_off = self.counters;
+        let end = boff + blen - 2;
+
+        let mut insts = Vec::with_capacity(alen + blen - 1);
+        insts.push(Inst::Save(0));
+        insts.push(Inst::Split(aoff + 1, boff + 1));
+        for inst in &self.insts[1..alen - 2] {
+            insts.push(renumber(inst, aoff, 0, 0));
+        }
+        insts.push(Inst::Jump(end));
+        for inst in &other.insts[1..blen - 2] {
+            insts.push(renumber(inst, boff, cap_off, count_off));
+        }
+        insts.push(Inst::Save(1));
+        insts.push(Inst::Match);
+
+        let mut cap_names = self.cap_names.clone();
+        cap_names.extend(other.cap_names.iter().skip(1).cloned());
+        let engine = match (self.engine, other.engine) {
+            (Some(e1), Some(e2)) if e1 == e2 => Some(e1),
+            _ => None,
+        };
+        let branch_slot = match (self.branch_slot, other.branch_slot) {
+            (None, None) => None,
+            _ => Some(2 * num_captures(&insts)),
+        };
+        let insts_len = insts.len();
+        let nslots = num_slots(&insts, branch_slot);
+        let create_threads = move || NfaThreads::new(insts_len, nslots);
+        let create_backtrack = move || BackMachine::new();
+        let matches_empty = self.matches_empty || other.matches_empty;
+        let flags = Flags {
+            crlf: self.flags.crlf || other.flags.crlf,
+            branch_marks: branch_slot.is_some(),
+            grapheme_dot: self.flags.grapheme_dot || other.flags.grapheme_dot,
+            dot_matches_new_line: self.flags.dot_matches_new_line
+                                  || other.flags.dot_matches_new_line,
+            single_line: self.flags.single_line || other.flags.single_line,
+            line_terminator: self.flags.line_terminator,
+            ascii_case_insensitive: self.flags.ascii_case_insensitive
+                                    || other.flags.ascii_case_insensitive,
+            dollar_newline: self.flags.dollar_newline
+                            || other.flags.dollar_newline,
+            normalize: if self.flags.normalize == other.flags.normalize {
+                self.flags.normalize
+            } else {
+                None
+            },
+        };
+        let quantifiers =
+            self.quantifiers.iter().map(|&(pc, g)| (pc + aoff, g))
+            .chain(other.quantifiers.iter().map(|&(pc, g)| (pc + boff, g)))
+            .collect();
+        // If both programs define a class with the same name, the one from
+        // `self` is used when parsing `original` again.
+        let mut custom_classes = self.custom_classes.clone();
+        custom_classes.extend(other.custom_classes.iter().cloned());
+        let backrefs = backref_groups(&insts);
+        let mut prog = Program {
+            original: format!("(?:{})|(?:{})", self.original, other.original),
+            insts: insts,
+            cap_names: cap_names,
+            prefixes: Prefix::Empty,
+            prefixes_complete: false,
+            prefix_limits: self.prefix_limits,
+            first_bytes: None,
+            matches_empty: matches_empty,
+            required_literal: None,
+            anchored_begin: self.anchored_begin && other.anchored_begin,
+            anchored_end: self.anchored_end && other.anchored_end,
+            anchored_literal: None,
+            recursive: self.recursive || other.recursive,
+            counters: self.counters + other.counters,
+            backrefs: backrefs,
+            engine: engine,
+            branch_slot: branch_slot,
+            flags: flags,
+            custom_classes: custom_classes,
+            quantifiers: quantifiers,
+            observer: self.observer.clone(),
+            hits: InstHits::new(insts_len),
+            // `original` is parsed again to build the reverse program,
+            // but the flags of the two programs were merged above, so it
+            // would no longer match what this program matches.
+            reverse: ReverseProgram::new(None),
+            nfa_threads: Pool::new(Box::new(create_threads)),
+            backtrack: Pool::new(Box::new(create_backtrack)),
+        };
+        prog.find_prefixes();
+        prog.find_first_bytes();
+        prog
+    }
+
     /// Executes a compiled regex program.
     pub fn exec(
         &self,
@@ -261,58 +1228,739 @@ impl Program {
         text: &str,
         start: usize,
     ) -> bool {
-        match self.choose_engine(caps.len(), text) {
-            MatchEngine::Backtrack => Backtrack::exec(self, caps, text, start),
-            MatchEngine::Nfa => Nfa::exec(self, caps, text, start),
-            MatchEngine::Literals => {
-                match self.prefixes.find(&text[start..]) {
-                    None => false,
-                    Some((s, e)) => {
-                        if caps.len() == 2 {
-                            caps[0] = Some(start + s);
-                            caps[1] = Some(start + e);
-                        }
-                        true
-                    }
-                }
-            }
-        }
+        self.exec_(None, None, caps, text, start, Anchored::No)
     }
 
-    fn choose_engine(&self, cap_len: usize, text: &str) -> MatchEngine {
-        // If the engine is already chosen, then we use it.
-        // But that might not be a good idea. e.g., What if `Literals` is
-        // chosen and it can't work? I guess we should probably check whether
-        // the chosen engine is appropriate or not.
-        self.engine.unwrap_or_else(|| {
-            if cap_len <= 2
-               && self.prefixes.preserves_priority()
-               && self.prefixes_complete {
-                MatchEngine::Literals
-            } else if Backtrack::should_exec(self, text) {
-                // We're only here if the input and regex combined are small.
-                MatchEngine::Backtrack
-            } else {
-                MatchEngine::Nfa
-            }
-        })
+    /// Executes a compiled regex program like `exec`, searching `norm`,
+    /// which must have been built from `text` by `normal_text`, instead of
+    /// normalizing `text` again.
+    ///
+    /// This lets a search for successive matches normalize the text once.
+    pub fn exec_normal(
+        &self,
+        norm: &NormalText,
+        caps: &mut CaptureIdxs,
+        text: &str,
+        start: usize,
+    ) -> bool {
+        self.exec_(None, Some(norm), caps, text, start, Anchored::No)
     }
 
-    /// Returns the total number of capture groups in the regular expression.
-    /// This includes the zeroth capture.
-    pub fn num_captures(&self) -> usize {
-        num_captures(&self.insts)
+    /// Returns `text` normalized for searching with `exec_normal`, or
+    /// `None` if this program searches `text` as it is.
+    pub fn normal_text(&self, text: &str) -> Option<NormalText> {
+        match self.flags.normalize {
+            Some(form) if !text.bytes().all(|b| b < 0x80) => {
+                Some(NormalText::new(text, form))
+            }
+            _ => None,
+        }
     }
 
-    /// Allocate new capture groups.
-    pub fn alloc_captures(&self) -> Vec<Option<usize>> {
-        vec![None; 2 * self.num_captures()]
+    /// Executes a compiled regex program like `exec`, where a match must
+    /// also be anchored as given by `anchored`.
+    pub fn exec_anchored(
+        &self,
+        caps: &mut CaptureIdxs,
+        text: &str,
+        start: usize,
+        anchored: Anchored,
+    ) -> bool {
+        self.exec_(None, None, caps, text, start, anchored)
     }
 
-    /// Find and store a prefix machine for the current program.
-    pub fn find_prefixes(&mut self) {
-        use self::Inst::*;
-
+    /// Executes a compiled regex program like `exec`, using the given
+    /// scratch space instead of the memory cached by the program.
+    ///
+    /// This skips the synchronization needed to take cached memory from the
+    /// program and return it, which can matter when searching many short
+    /// strings. `scratch` must have been created by `new_scratch` on this
+    /// program.
+    pub fn exec_with_scratch(
+        &self,
+        scratch: &mut Scratch,
+        caps: &mut CaptureIdxs,
+        text: &str,
+        start: usize,
+    ) -> bool {
+        self.exec_(Some(scratch), None, caps, text, start, Anchored::No)
+    }
+
+    /// Returns a search for successive non-overlapping matches in `text`
+    /// that writes the capture locations of each into a buffer given by
+    /// the caller. See `CapturesIterReuse`.
+    ///
+    /// This is the counterpart to `Regex::captures_iter` that doesn't
+    /// allocate per match.
+    pub fn captures_iter_reuse<'p, 't>(
+        &'p self,
+        text: &'t str,
+    ) -> CapturesIterReuse<'p, 't> {
+        CapturesIterReuse {
+            prog: self,
+            text: text,
+            scratch: self.new_scratch(),
+            last_end: 0,
+            last_match: None,
+        }
+    }
+
+    /// Creates scratch space for `exec_with_scratch`.
+    pub fn new_scratch(&self) -> Scratch {
+        Scratch {
+            nfa_threads: NfaThreads::new(self.insts.len(), self.num_slots()),
+            backtrack: BackMachine::new(),
+        }
+    }
+
+    fn exec_(
+        &self,
+        scratch: Option<&mut Scratch>,
+        norm: Option<&NormalText>,
+        caps: &mut CaptureIdxs,
+        text: &str,
+        start: usize,
+        anchored: Anchored,
+    ) -> bool {
+        // The matching engines only write the slots that participate in a
+        // match, so clear out any locations from a previous search.
+        for slot in caps.iter_mut() {
+            *slot = None;
+        }
+        self.hits.start_search();
+        let owned;
+        let norm = match norm {
+            Some(norm) => Some(norm),
+            None => {
+                owned = self.normal_text(text);
+                owned.as_ref()
+            }
+        };
+        let matched = match norm {
+            Some(norm) => {
+                self.exec_normalized(scratch, caps, norm, start, anchored)
+            }
+            None => self.exec_text(scratch, caps, text, start, anchored),
+        };
+        debug_assert!(!matched || self.valid_captures(caps, text),
+                      "invalid captures {:?} for {:?} in {:?}",
+                      caps, self.original, text);
+        matched
+    }
+
+    /// Returns true if every capture group in `caps` is either unset or
+    /// spans a slice of `text`.
+    fn valid_captures(&self, caps: &CaptureIdxs, text: &str) -> bool {
+        // An odd slot at the end holds the branch mark.
+        let ncaps = caps.len() & !1;
+        caps[..ncaps].chunks(2).all(|pair| match (pair[0], pair[1]) {
+            (None, None) => true,
+            (Some(s), Some(e)) => {
+                s <= e && e <= text.len()
+                && text.is_char_boundary(s) && text.is_char_boundary(e)
+            }
+            _ => false,
+        })
+    }
+
+    fn exec_normalized(
+        &self,
+        scratch: Option<&mut Scratch>,
+        caps: &mut CaptureIdxs,
+        norm: &NormalText,
+        start: usize,
+        anchored: Anchored,
+    ) -> bool {
+        let start = norm.to_normal(start);
+        if !self.exec_text(scratch, caps, &norm.text, start, anchored) {
+            return false;
+        }
+        // Match ends round up, so that a match never shrinks.
+        for (i, slot) in caps.iter_mut().enumerate() {
+            if Some(i) != self.branch_slot {
+                *slot = slot.map(|p| norm.to_original(p, i % 2 == 1));
+            }
+        }
+        true
+    }
+
+    fn exec_text(
+        &self,
+        scratch: Option<&mut Scratch>,
+        caps: &mut CaptureIdxs,
+        text: &str,
+        start: usize,
+        anchored: Anchored,
+    ) -> bool {
+        let mut engine = self.choose_engine(caps.len(), text);
+        if engine == MatchEngine::Literals && anchored != Anchored::No
+           && self.anchored_literal.is_none() {
+            // The literal found first may not be the one that starts at
+            // `start`, or ends at the end of the text.
+            engine = MatchEngine::Nfa;
+        }
+        self.hits.set_engine(engine);
+        if let Some(ref observer) = self.observer {
+            (observer.0)(engine, text.len());
+        }
+        match (engine, scratch) {
+            (MatchEngine::Backtrack, Some(s)) => {
+                Backtrack::exec_with(self, &mut s.backtrack, caps, text, start,
+                                     anchored)
+            }
+            (MatchEngine::Backtrack, None) => {
+                Backtrack::exec(self, caps, text, start, anchored)
+            }
+            (MatchEngine::Nfa, Some(s)) => {
+                Nfa::exec_with(self, &mut s.nfa_threads, caps, text, start,
+                               anchored)
+            }
+            (MatchEngine::Nfa, None) => {
+                Nfa::exec(self, caps, text, start, anchored)
+            }
+            (MatchEngine::Literals, _) => {
+                if let Some(ref lit) = self.anchored_literal {
+                    // `^` only matches at the start of `text`.
+                    if start > 0 || !text.starts_with(&**lit)
+                       || (anchored == Anchored::Both
+                           && lit.len() != text.len()) {
+                        return false;
+                    }
+                    if caps.len() >= 2 {
+                        caps[0] = Some(0);
+                        caps[1] = Some(lit.len());
+                    }
+                    return true;
+                }
+                match self.prefixes.find(&text[start..]) {
+                    None => false,
+                    Some((s, e)) => {
+                        if caps.len() == 2 {
+                            caps[0] = Some(start + s);
+                            caps[1] = Some(start + e);
+                        }
+                        true
+                    }
+                }
+            }
+        }
+    }
+
+    /// Searches `text` for a match like `exec`. If there is no match, then
+    /// this reports whether there could be a match if more input were
+    /// appended to `text`.
+    ///
+    /// This is useful for incremental input, where the caller needs to
+    /// decide whether to wait for more input. It uses the NFA, except for a
+    /// program that only the backtracking engine can run. Then a text that
+    /// doesn't match is always reported as `Partial`.
+    pub fn partial_match(&self, text: &str) -> PartialResult {
+        if self.needs_backtrack() {
+            let mut caps = [None, None];
+            return if self.exec(&mut caps, text, 0) {
+                PartialResult::Full((caps[0].unwrap(), caps[1].unwrap()))
+            } else {
+                PartialResult::Partial
+            };
+        }
+        Nfa::exec_partial(self, text)
+    }
+
+    /// Searches `text` with the NFA like `exec`, where `text` may be
+    /// followed by more input. If the result depends on what follows
+    /// `text`, then the earliest position where a match may still start is
+    /// returned as an error.
+    ///
+    /// This doesn't normalize `text`, so it must not be used with a program
+    /// that normalizes its input.
+    pub fn exec_stream(
+        &self,
+        caps: &mut CaptureIdxs,
+        text: &str,
+        start: usize,
+    ) -> Result<bool, usize> {
+        Nfa::exec_stream(self, caps, text, start)
+    }
+
+    /// Searches `text` for the first match with the NFA and returns, for
+    /// each instruction, whether any thread made progress past it.
+    ///
+    /// The NFA can't run a program that recurses or has backreferences, so
+    /// the returned vector is empty for one.
+    pub fn coverage(&self, text: &str) -> Vec<bool> {
+        if self.needs_backtrack() {
+            return vec![];
+        }
+        Nfa::exec_coverage(self, text)
+    }
+
+    /// Sets the matching engine used by every search. A value of `None`
+    /// means that the engine is automatically selected.
+    ///
+    /// It is the caller's responsibility to pick an engine that works for
+    /// this program. See `Regex::set_default_engine`.
+    pub fn set_engine(&mut self, engine: Option<MatchEngine>) {
+        self.engine = engine;
+    }
+
+    /// Returns the matching engine that a search for the overall match (with
+    /// no sub-captures) in `text` would use.
+    pub fn chosen_engine(&self, text: &str) -> MatchEngine {
+        self.choose_engine(2, text)
+    }
+
+    fn choose_engine(&self, cap_len: usize, text: &str) -> MatchEngine {
+        // If the engine is already chosen, then we use it.
+        // But that might not be a good idea. e.g., What if `Literals` is
+        // chosen and it can't work? I guess we should probably check whether
+        // the chosen engine is appropriate or not.
+        if self.needs_backtrack() {
+            // No other engine can follow `Call` or `Backref` instructions.
+            return MatchEngine::Backtrack;
+        }
+        self.engine.unwrap_or_else(|| {
+            if self.anchored_literal.is_some() {
+                // No searching is needed, whatever captures are requested.
+                MatchEngine::Literals
+            } else if cap_len <= 2
+               && self.prefixes.preserves_priority()
+               && self.prefixes_complete {
+                MatchEngine::Literals
+            } else if Backtrack::should_exec(self, text) {
+                // We're only here if the input and regex combined are small.
+                MatchEngine::Backtrack
+            } else {
+                MatchEngine::Nfa
+            }
+        })
+    }
+
+    /// Returns true iff only the backtracking engine can run this program,
+    /// because it's recursive or has backreferences.
+    pub fn needs_backtrack(&self) -> bool {
+        self.recursive || !self.backrefs.is_empty()
+    }
+
+    /// Returns true iff the program names `c` itself, as a literal or as a
+    /// class range holding just `c`, rather than matching it as part of a
+    /// wider class like `.` or `[^a]`.
+    pub fn names_char(&self, c: char) -> bool {
+        self.insts.iter().any(|inst| match *inst {
+            Inst::Char(d) => d == c,
+            Inst::Ranges(ref rs) => rs.ranges.iter().any(|&r| r == (c, c)),
+            _ => false,
+        })
+    }
+
+    /// Returns the total number of capture groups in the regular expression.
+    /// This includes the zeroth capture.
+    pub fn num_captures(&self) -> usize {
+        num_captures(&self.insts)
+    }
+
+    /// Returns true if this program matches the empty string at every
+    /// position of every input.
+    ///
+    /// This is the case when a `Match` instruction is reachable from the
+    /// start of the program without consuming any input and without passing
+    /// through a zero-width assertion. e.g., `a*` or the empty regex.
+    pub fn is_always_match(&self) -> bool {
+        let mut seen = vec![false; self.insts.len()];
+        let mut stack = vec![0];
+        while let Some(pc) = stack.pop() {
+            if seen[pc] {
+                continue;
+            }
+            seen[pc] = true;
+            match self.insts[pc] {
+                Inst::Match => return true,
+                Inst::Save(_) | Inst::BranchMark(_) => stack.push(pc + 1),
+                Inst::Jump(pc2) => stack.push(pc2),
+                Inst::Split(x, y) => { stack.push(y); stack.push(x); }
+                Inst::CountReset(_) => stack.push(pc + 1),
+                // The loop can only be left right away if it has no
+                // minimum.
+                Inst::CountLoop(ref l) => {
+                    if l.min == 0 {
+                        stack.push(l.exit);
+                    }
+                    stack.push(pc + 1);
+                }
+                Inst::EmptyLook(_) | Inst::Char(_) | Inst::Ranges(_)
+                | Inst::Call(_) | Inst::Return | Inst::Backref(_) => {}
+            }
+        }
+        false
+    }
+
+    /// Returns false if `text` can't contain a match, because it doesn't
+    /// contain the literal that every match contains. Otherwise, returns
+    /// true without searching.
+    pub fn might_match(&self, text: &str) -> bool {
+        match self.required_literal {
+            None => true,
+            Some(ref lit) => text.contains(&**lit),
+        }
+    }
+
+    /// Returns true if this program is anchored at both ends and matches
+    /// exactly one case sensitive literal string. e.g., `^abc$`.
+    pub fn is_anchored_full_literal(&self) -> bool {
+        if !self.anchored_begin || !self.anchored_end {
+            return false;
+        }
+        self.insts[2..self.insts.len() - 3].iter().all(|inst| {
+            match *inst {
+                Inst::Save(_)
+                | Inst::BranchMark(_)
+                | Inst::Char(_) => true,
+                _ => false,
+            }
+        })
+    }
+
+    /// Returns the regex string this program was compiled from.
+    pub fn as_str(&self) -> &str {
+        &self.original
+    }
+
+    /// Returns the options this program was compiled with.
+    ///
+    /// Two programs compiled from the same string with the same flags match
+    /// the same way.
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    /// Returns whether the quantifier that emitted the `Split` instruction
+    /// at `pc` is greedy, or `None` if that instruction wasn't emitted by a
+    /// quantifier.
+    pub fn is_greedy_at(&self, pc: InstIdx) -> Option<bool> {
+        self.quantifiers.binary_search_by(|&(qpc, _)| qpc.cmp(&pc))
+            .ok().map(|i| self.quantifiers[i].1)
+    }
+
+    /// Checks that every `Split` emitted by a quantifier prefers the branch
+    /// its greediness calls for. If one doesn't, then its index is returned.
+    ///
+    /// The repeated expression always starts at the lower of the two
+    /// branches: it either follows the `Split` or, for `+`, is jumped back
+    /// to. So a greedy `Split` must prefer its lower branch and a lazy one
+    /// its higher branch.
+    pub fn check_quantifier_priority(&self) -> Result<(), InstIdx> {
+        for &(pc, greedy) in &self.quantifiers {
+            let ok = match self.insts[pc] {
+                Inst::Split(x, y) => x == y || (x < y) == greedy,
+                _ => false,
+            };
+            if !ok {
+                return Err(pc);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns, for each instruction, how many times the matching engines
+    /// executed it.
+    ///
+    /// The NFA counts an instruction each time a thread reaches it, and the
+    /// backtracking engine each time it steps through it. Counts accumulate
+    /// over every search unless `set_hits_per_search` is enabled. A `Split`
+    /// with a very high count often points to a badly written quantifier.
+    ///
+    /// This requires the `profile` feature. Without it, nothing is counted.
+    #[cfg(feature = "profile")]
+    pub fn instruction_hits(&self) -> Vec<u64> {
+        self.hits.get()
+    }
+
+    /// Resets the counts returned by `instruction_hits` to zero.
+    #[cfg(feature = "profile")]
+    pub fn reset_instruction_hits(&self) {
+        self.hits.reset();
+    }
+
+    /// When `yes` is true, the counts returned by `instruction_hits` are
+    /// reset at the start of every search, so that they describe only the
+    /// most recent search. Otherwise, they accumulate, which is the
+    /// default.
+    #[cfg(feature = "profile")]
+    pub fn set_hits_per_search(&mut self, yes: bool) {
+        self.hits.per_search = yes;
+    }
+
+    /// Returns the matching engine and the number of instructions executed
+    /// by the most recent search, or `None` before the first search.
+    ///
+    /// When the program is searched from several threads at once, the
+    /// counts of their searches are mixed.
+    #[cfg(feature = "profile")]
+    pub fn last_exec_stats(&self) -> Option<ExecStats> {
+        self.hits.last_stats()
+    }
+
+    /// Renders the instructions of this program as a graph in the Graphviz
+    /// DOT language, e.g., to be piped to `dot -Tpng`.
+    ///
+    /// Each instruction is a node labeled with its index. Control flows
+    /// from each instruction to the next one, except that `Jump` has a
+    /// single edge to its target and `Split` has two edges labeled `1` and
+    /// `2` in order of preference.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph program {\n");
+        dot.push_str("    node [shape=box];\n");
+        for (pc, inst) in self.insts.iter().enumerate() {
+            let label = match *inst {
+                Inst::Match => "Match".to_owned(),
+                Inst::Save(slot) => format!("Save({})", slot),
+                Inst::Jump(_) => "Jump".to_owned(),
+                Inst::Split(_, _) => "Split".to_owned(),
+                Inst::EmptyLook(ref look) => format!("{:?}", look),
+                Inst::Char(c) => format!("{:?}", c),
+                Inst::Ranges(ref inst) => {
+                    let mut label = String::new();
+                    if inst.casei {
+                        label.push_str("(?i)");
+                    }
+                    label.push('[');
+                    for &(s, e) in &inst.ranges {
+                        label.extend(s.escape_default());
+                        if s != e {
+                            label.push('-');
+                            label.extend(e.escape_default());
+                        }
+                    }
+                    label.push(']');
+                    label
+                }
+                Inst::BranchMark(i) => format!("BranchMark({})", i),
+                Inst::Call(_) => "Call".to_owned(),
+                Inst::Return => "Return".to_owned(),
+                Inst::CountReset(k) => format!("CountReset({})", k),
+                Inst::Backref(i) => format!("Backref({})", i),
+                Inst::CountLoop(ref l) => {
+                    format!("CountLoop({}, {}, {})", l.counter, l.min, l.max)
+                }
+            };
+            let label = label.replace("\\", "\\\\").replace("\"", "\\\"");
+            dot.push_str(&format!("    {} [label=\"{}: {}\"];\n",
+                                  pc, pc, label));
+            let edges = match *inst {
+                Inst::Match | Inst::Return => vec![],
+                Inst::Call(to) => {
+                    vec![(to, " [label=\"call\"]"), (pc + 1, "")]
+                }
+                Inst::Jump(to) => vec![(to, "")],
+                Inst::Split(x, y) => {
+                    vec![(x, " [label=\"1\"]"), (y, " [label=\"2\"]")]
+                }
+                Inst::CountLoop(ref l) => {
+                    vec![(pc + 1, ""), (l.exit, " [label=\"exit\"]")]
+                }
+                _ => vec![(pc + 1, "")],
+            };
+            for (to, attrs) in edges {
+                dot.push_str(&format!("    {} -> {}{};\n", pc, to, attrs));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Returns a summary of the instructions in this program, along with
+    /// the properties used to choose a matching engine.
+    pub fn stats(&self) -> ProgramStats {
+        let fixed_engine = if self.needs_backtrack() {
+            Some(MatchEngine::Backtrack)
+        } else if self.engine.is_some() {
+            self.engine
+        } else if self.prefixes.preserves_priority()
+                  && self.prefixes_complete {
+            Some(MatchEngine::Literals)
+        } else {
+            None
+        };
+        let mut stats = ProgramStats {
+            insts: self.insts.len(),
+            size: self.insts.iter().fold(0, |n, inst| {
+                n + mem::size_of::<Inst>() + inst_heap_size(inst)
+            }),
+            captures: self.num_captures(),
+            engine: fixed_engine,
+            prefixes: self.prefixes.len(),
+            prefixes_complete: self.prefixes_complete,
+            prefixes_preserve_priority: self.prefixes.preserves_priority(),
+            anchored_begin: self.anchored_begin,
+            anchored_end: self.anchored_end,
+            max_match_len: self.max_match_len(),
+            ..ProgramStats::default()
+        };
+        for inst in &self.insts {
+            match *inst {
+                Inst::Match => {}
+                Inst::Save(_) => stats.saves += 1,
+                Inst::Jump(_) => stats.jumps += 1,
+                Inst::Split(_, _) => stats.splits += 1,
+                Inst::EmptyLook(_) => stats.empty_looks += 1,
+                Inst::Char(_) => stats.chars += 1,
+                Inst::Ranges(_) => stats.ranges += 1,
+                Inst::BranchMark(_) => stats.branch_marks += 1,
+                Inst::Call(_) => stats.calls += 1,
+                Inst::CountLoop(_) => stats.count_loops += 1,
+                Inst::Backref(_) => stats.backrefs += 1,
+                Inst::Return | Inst::CountReset(_) => {}
+            }
+        }
+        stats
+    }
+
+    /// Returns the length, in bytes, of the longest string this program can
+    /// match, or `None` if the length isn't bounded.
+    pub fn max_match_len(&self) -> Option<usize> {
+        // The compiler only ever emits backwards jumps for loops, so a
+        // program without them is acyclic and the longest path can be found
+        // by walking it backwards.
+        let mut longest = vec![0; self.insts.len() + 1];
+        for pc in (0..self.insts.len()).rev() {
+            longest[pc] = match self.insts[pc] {
+                Inst::Match => 0,
+                Inst::Save(_) | Inst::BranchMark(_) | Inst::EmptyLook(_)
+                | Inst::CountReset(_) => longest[pc + 1],
+                Inst::Char(c) => c.len_utf8() + longest[pc + 1],
+                Inst::Ranges(CharRanges { ref ranges, casei, .. }) => {
+                    let last = ranges[ranges.len() - 1].1;
+                    let len = if casei { 4 } else { last.len_utf8() };
+                    len + longest[pc + 1]
+                }
+                Inst::Jump(pc2) if pc2 > pc => longest[pc2],
+                Inst::Split(x, y) if x > pc && y > pc => {
+                    cmp::max(longest[x], longest[y])
+                }
+                // A subroutine may call itself.
+                Inst::Jump(_) | Inst::Split(_, _) | Inst::Call(_)
+                | Inst::Return | Inst::CountLoop(_)
+                | Inst::Backref(_) => return None,
+            };
+        }
+        Some(longest[0])
+    }
+
+    /// Returns true if and only if this program matches all of `text`.
+    ///
+    /// Unlike checking where the match found by `exec` ends, this finds a
+    /// match that spans `text` even if a higher priority match ends sooner.
+    /// e.g., `a|ab` fully matches `ab`.
+    pub fn is_full_match(&self, text: &str) -> bool {
+        if self.needs_backtrack() {
+            // The backtracking engine tries every path, so this finds any
+            // match that spans `text`, as if searching for `\A(?:...)\z`.
+            return self.exec_anchored(&mut [], text, 0, Anchored::Both);
+        }
+        if let Some(form) = self.flags.normalize {
+            let norm = NormalText::new(text, form);
+            return Nfa::exec_longest(self, &norm.text, 0)
+                   == Some(norm.text.len());
+        }
+        Nfa::exec_longest(self, text, 0) == Some(text.len())
+    }
+
+    /// Finds the shortest match at the leftmost position in `text` where a
+    /// match starts, searching from `start`.
+    ///
+    /// This differs from `exec`, which prefers matches the way a
+    /// backtracking engine would, so that `a+` matches all of `aaa` and
+    /// `a|ab` matches `a`. Here, `a+` matches just the first `a` and
+    /// `ab|a` matches `a` too. It also differs from finding where the
+    /// earliest match ends, since that match may not start leftmost. e.g.,
+    /// `\wbc|b` has a match ending at 2 in `abc`, but the span returned is
+    /// `(0, 3)`.
+    ///
+    /// The shortest match is found with the NFA, so `None` is always
+    /// returned for a program that only the backtracking engine can run.
+    pub fn find_earliest(
+        &self,
+        text: &str,
+        start: usize,
+    ) -> Option<(usize, usize)> {
+        if self.needs_backtrack() {
+            return None;
+        }
+        let mut caps = [None, None];
+        if !self.exec(&mut caps, text, start) {
+            return None;
+        }
+        let s = caps[0].unwrap();
+        Nfa::exec_shortest(self, text, s).map(|e| (s, e))
+    }
+
+    /// Searches `text` backwards from its end for the leftmost match.
+    ///
+    /// This only works if the program is anchored at the end, since every
+    /// match then ends at the end of `text`. In that case, the only match
+    /// is found much faster than by a forward search when it is near the
+    /// end of a long text. `None` is returned if the program can't be
+    /// searched backwards, otherwise the result of the search is returned.
+    ///
+    /// The reversed program is compiled on the first call, under the same
+    /// size limit as this program, and reused after that.
+    pub fn exec_from_end(
+        &self,
+        text: &str,
+    ) -> Option<Option<(usize, usize)>> {
+        let crlf = self.insts.iter().any(|inst| match *inst {
+            Inst::EmptyLook(LookInst::StartLineCRLF)
+            | Inst::EmptyLook(LookInst::EndLineCRLF) => true,
+            _ => false,
+        });
+        // The reversed program unrolls counted repetitions, which may not
+        // fit in memory.
+        if !self.anchored_end || crlf || self.flags.normalize.is_some()
+           || self.needs_backtrack() || self.counters > 0 {
+            return None;
+        }
+        let rev = self.reverse.get(|size_limit| {
+            // `original` already parsed within the depth limit, and its
+            // reversal nests no deeper.
+            let opts = RegexOptions {
+                size_limit: size_limit,
+                depth_limit: ::std::usize::MAX,
+                grapheme_dot: self.flags.grapheme_dot,
+                dot_matches_new_line: self.flags.dot_matches_new_line,
+                single_line: self.flags.single_line,
+                line_terminator: self.flags.line_terminator,
+                ascii_case_insensitive: self.flags.ascii_case_insensitive,
+                dollar_newline: self.flags.dollar_newline,
+                custom_classes: self.custom_classes.clone(),
+                ..RegexOptions::default()
+            };
+            Program::new_reverse(&self.original, &opts).ok()
+        });
+        let rev = match rev {
+            Some(rev) => rev,
+            None => return None,
+        };
+        let end = text.len();
+        Some(Nfa::exec_reverse(&rev, text, end).map(|start| (start, end)))
+    }
+
+    /// Allocate new capture groups.
+    ///
+    /// If the program was compiled with branch marks, then an extra slot
+    /// is allocated at the end for the index of the branch that matched.
+    pub fn alloc_captures(&self) -> Vec<Option<usize>> {
+        vec![None; self.num_slots()]
+    }
+
+    /// Returns the number of capture slots used by this program, including
+    /// the branch slot if there is one.
+    pub fn num_slots(&self) -> usize {
+        num_slots(&self.insts, self.branch_slot)
+    }
+
+    /// Find and store a prefix machine for the current program.
+    pub fn find_prefixes(&mut self) {
+        use self::Inst::*;
+
         let (ps, complete) = self.prefixes_from_insts(1);
         if ps.len() > 0 {
             self.prefixes = Prefix::new(ps);
@@ -353,7 +2001,7 @@ impl Program {
             }
             // Arg. We've over-extended ourselves, quit with nothing to
             // show for it.
-            if prefixes.len() > NUM_PREFIX_LIMIT {
+            if prefixes.len() > self.prefix_limits.0 {
                 return;
             }
             if done { break; }
@@ -362,6 +2010,107 @@ impl Program {
         self.prefixes_complete = pcomplete && self.prefixes.len() > 0;
     }
 
+    /// Returns the set of characters that a nonempty match can start with.
+    ///
+    /// Unlike the first bytes used to speed up searches, this covers every
+    /// character, not just the first byte of its encoding. Zero-width
+    /// assertions are assumed to hold, so the set may be larger than
+    /// needed, but never smaller. Empty matches are ignored, so the set for
+    /// a program that can only match the empty string, like `^$`, is
+    /// `FirstSet::Empty`.
+    pub fn first_char_set(&self) -> FirstSet {
+        let mut ranges = vec![];
+        let mut seen = vec![false; self.insts.len()];
+        let mut stack = vec![0];
+        while let Some(pc) = stack.pop() {
+            if seen[pc] {
+                continue;
+            }
+            seen[pc] = true;
+            match self.insts[pc] {
+                Inst::Match => {}
+                Inst::Save(_) | Inst::BranchMark(_) | Inst::EmptyLook(_)
+                | Inst::CountReset(_) => stack.push(pc + 1),
+                Inst::Jump(to) => stack.push(to),
+                Inst::Split(x, y) => {
+                    stack.push(y);
+                    stack.push(x);
+                }
+                Inst::Char(c) => ranges.push((c, c)),
+                Inst::Ranges(ref inst) if inst.casei => {
+                    add_casei_ranges(&mut ranges, inst);
+                }
+                Inst::Ranges(ref inst) => {
+                    ranges.extend(inst.ranges.iter().cloned());
+                }
+                Inst::CountLoop(ref l) => {
+                    stack.push(l.exit);
+                    stack.push(pc + 1);
+                }
+                // A subroutine or a backreference could start with
+                // anything.
+                Inst::Call(_) | Inst::Return | Inst::Backref(_) => {
+                    return FirstSet::Any;
+                }
+            }
+        }
+        let ranges = CharRanges::from_ranges(ranges, false).ranges;
+        if ranges.is_empty() {
+            FirstSet::Empty
+        } else if ranges == CharRanges::any().ranges {
+            FirstSet::Any
+        } else {
+            FirstSet::Ranges(ranges)
+        }
+    }
+
+    /// Computes the set of bytes that a match can start with, for searches
+    /// that can't skip ahead with a literal prefix.
+    ///
+    /// Zero-width assertions are assumed to hold, so the set may be larger
+    /// than needed, but never smaller.
+    fn find_first_bytes(&mut self) {
+        self.first_bytes = None;
+        if !self.prefixes.is_empty() || self.anchored_begin {
+            return;
+        }
+        let mut bytes = vec![false; 256];
+        let mut seen = vec![false; self.insts.len()];
+        let mut stack = vec![0];
+        while let Some(pc) = stack.pop() {
+            if seen[pc] {
+                continue;
+            }
+            seen[pc] = true;
+            match self.insts[pc] {
+                Inst::Match => return,
+                Inst::Save(_) | Inst::BranchMark(_) | Inst::EmptyLook(_) => {
+                    stack.push(pc + 1);
+                }
+                Inst::Jump(to) => stack.push(to),
+                Inst::Split(x, y) => {
+                    stack.push(y);
+                    stack.push(x);
+                }
+                Inst::Char(c) => bytes[first_byte(c)] = true,
+                Inst::Ranges(ref inst) => add_first_bytes(&mut bytes, inst),
+                Inst::CountReset(_) => stack.push(pc + 1),
+                Inst::CountLoop(ref l) => {
+                    stack.push(l.exit);
+                    stack.push(pc + 1);
+                }
+                Inst::Call(_) | Inst::Return | Inst::Backref(_) => return,
+            }
+        }
+        // Bytes in the middle of a character never start one.
+        for b in 0x80..0xC0 {
+            bytes[b] = false;
+        }
+        if !(0..0x80).chain(0xC2..0xF5).all(|b| bytes[b]) {
+            self.first_bytes = Some(bytes);
+        }
+    }
+
     /// Find a prefix starting at the given instruction.
     ///
     /// Returns `true` in the tuple if the end of the prefix leads trivially
@@ -370,6 +2119,7 @@ impl Program {
     fn prefixes_from_insts(&self, mut pc: usize) -> (Vec<String>, bool) {
         use self::Inst::*;
 
+        let (num_limit, len_limit) = self.prefix_limits;
         let mut complete = true;
         let mut alts = vec![String::new()];
         while pc < self.insts.len() {
@@ -379,21 +2129,24 @@ impl Program {
             // it stops. Thus, the prefix alternates grow in lock step, and it
             // suffices to check one of them to see if the prefix limit has been
             // exceeded.
-            if alts[0].len() > PREFIX_LENGTH_LIMIT {
+            if alts[0].len() > len_limit {
                 complete = false;
                 break;
             }
             match *inst {
-                Save(_) => { pc += 1; continue } // completely ignore it
-                Char(OneChar { c, casei: false }) => {
+                // completely ignore these
+                Save(_) | BranchMark(_) => { pc += 1; continue }
+                Char(c) => {
                     for alt in &mut alts {
                         alt.push(c);
                     }
                     pc += 1;
                 }
-                Ranges(CharRanges { ref ranges, casei: false }) => {
+                Ranges(CharRanges { ref ranges, casei: false, .. }) => {
+                    // An empty class, e.g. `[a&&[b]]`, never matches, so
+                    // no prefix can be extended past it.
                     let nchars = num_chars_in_ranges(ranges);
-                    if alts.len() * nchars > NUM_PREFIX_LIMIT {
+                    if nchars == 0 || alts.len() * nchars > num_limit {
                         complete = false;
                         break;
                     }
@@ -401,10 +2154,14 @@ impl Program {
                     let orig = alts;
                     alts = Vec::with_capacity(orig.len());
                     for &(s, e) in ranges {
-                        for c in (s as u32)..(e as u32 + 1){
+                        // Merged ranges can span the surrogate code points,
+                        // which aren't `char`s.
+                        let cs = ((s as u32)..(e as u32 + 1))
+                                 .filter_map(::std::char::from_u32);
+                        for c in cs {
                             for alt in &orig {
                                 let mut alt = alt.clone();
-                                alt.push(::std::char::from_u32(c).unwrap());
+                                alt.push(c);
                                 alts.push(alt);
                             }
                         }
@@ -428,7 +2185,7 @@ impl Program {
         loop {
             match self.insts[pc] {
                 Inst::Match => return true,
-                Inst::Save(_) => pc += 1,
+                Inst::Save(_) | Inst::BranchMark(_) => pc += 1,
                 Inst::Jump(pc2) => pc = pc2,
                 _ => return false,
             }
@@ -438,8 +2195,9 @@ impl Program {
 
 impl Clone for Program {
     fn clone(&self) -> Program {
-        let (insts_len, ncaps) = (self.insts.len(), self.num_captures());
-        let create_threads = move || NfaThreads::new(insts_len, ncaps);
+        let insts_len = self.insts.len();
+        let nslots = num_slots(&self.insts, self.branch_slot);
+        let create_threads = move || NfaThreads::new(insts_len, nslots);
         let create_backtrack = move || BackMachine::new();
         Program {
             original: self.original.clone(),
@@ -447,15 +2205,86 @@ impl Clone for Program {
             cap_names: self.cap_names.clone(),
             prefixes: self.prefixes.clone(),
             prefixes_complete: self.prefixes_complete,
+            prefix_limits: self.prefix_limits,
+            first_bytes: self.first_bytes.clone(),
+            matches_empty: self.matches_empty,
+            required_literal: self.required_literal.clone(),
             anchored_begin: self.anchored_begin,
             anchored_end: self.anchored_end,
+            anchored_literal: self.anchored_literal.clone(),
+            recursive: self.recursive,
+            counters: self.counters,
+            backrefs: self.backrefs.clone(),
             engine: self.engine,
+            branch_slot: self.branch_slot,
+            flags: self.flags,
+            custom_classes: self.custom_classes.clone(),
+            quantifiers: self.quantifiers.clone(),
+            observer: self.observer.clone(),
+            hits: InstHits::new(insts_len),
+            reverse: ReverseProgram::new(self.reverse.size_limit),
             nfa_threads: Pool::new(Box::new(create_threads)),
             backtrack: Pool::new(Box::new(create_backtrack)),
         }
     }
 }
 
+/// Sets `bytes[b]` for every byte `b` that a character matched by `inst` can
+/// start with.
+fn add_first_bytes(bytes: &mut [bool], inst: &CharRanges) {
+    for &(s, e) in &inst.ranges {
+        for b in first_byte(s)..first_byte(e) + 1 {
+            bytes[b] = true;
+        }
+    }
+    if !inst.casei {
+        return;
+    }
+    // Characters outside of ASCII may fold into any range, so allow all of
+    // them. e.g., `K` (Kelvin sign) folds to `k`.
+    for b in 0xC2..0xF5 {
+        bytes[b] = true;
+    }
+    for &(s, e) in inst.ranges.iter().filter(|r| r.0 <= '\x7f') {
+        for b in s as u8..cmp::min(e as u32, 0x7F) as u8 + 1 {
+            if b >= b'a' && b <= b'z' {
+                bytes[(b - 32) as usize] = true;
+            } else if b >= b'A' && b <= b'Z' {
+                bytes[(b + 32) as usize] = true;
+            }
+        }
+    }
+}
+
+/// Adds the characters matched by the case insensitive class `inst` to
+/// `ranges`.
+fn add_casei_ranges(ranges: &mut Vec<(char, char)>, inst: &CharRanges) {
+    // No character above U+1FFFF has a case folding, so those are matched
+    // exactly as they appear in the class.
+    for c in (0..0x20000).filter_map(char::from_u32) {
+        if inst.matches(Char::from(c)) {
+            ranges.push((c, c));
+        }
+    }
+    let high = '\u{20000}';
+    ranges.extend(inst.ranges.iter().filter(|r| r.1 >= high)
+                                    .map(|r| (cmp::max(r.0, high), r.1)));
+}
+
+/// Returns the first byte of the UTF-8 encoding of `c`.
+fn first_byte(c: char) -> usize {
+    let c = c as u32;
+    (if c < 0x80 {
+        c
+    } else if c < 0x800 {
+        0xC0 | (c >> 6)
+    } else if c < 0x10000 {
+        0xE0 | (c >> 12)
+    } else {
+        0xF0 | (c >> 18)
+    }) as usize
+}
+
 /// Return the number of captures in the given sequence of instructions.
 fn num_captures(insts: &[Inst]) -> usize {
     let mut n = 0;
@@ -469,12 +2298,920 @@ fn num_captures(insts: &[Inst]) -> usize {
     n / 2
 }
 
-/// Count the number of characters in the given range.
+/// Returns the number of counters used by the given sequence of
+/// instructions.
+fn num_counters(insts: &[Inst]) -> usize {
+    insts.iter().fold(0, |n, inst| match *inst {
+        Inst::CountReset(k) => cmp::max(n, k + 1),
+        _ => n,
+    })
+}
+
+/// Returns the capture groups that the given sequence of instructions refers
+/// to with backreferences, in ascending order.
+fn backref_groups(insts: &[Inst]) -> Vec<usize> {
+    let mut groups: Vec<usize> = insts.iter().filter_map(|inst| match *inst {
+        Inst::Backref(i) => Some(i),
+        _ => None,
+    }).collect();
+    groups.sort();
+    groups.dedup();
+    groups
+}
+
+/// Returns the literal matched by `insts` if they match `^` followed by
+/// nothing but a literal.
+fn anchored_literal(insts: &[Inst]) -> Option<String> {
+    let n = insts.len();
+    if n < 4 || insts[1] != Inst::EmptyLook(LookInst::StartText)
+       || insts[n - 2] != Inst::Save(1) {
+        return None;
+    }
+    insts[2..n - 2].iter().map(|inst| match *inst {
+        Inst::Char(c) => Some(c),
+        _ => None,
+    }).collect()
+}
+
+/// Return the number of capture slots needed to execute the given sequence
+/// of instructions, including the slot for branch marks, if any.
+fn num_slots(insts: &[Inst], branch_slot: Option<usize>) -> usize {
+    match branch_slot {
+        None => 2 * num_captures(insts),
+        Some(slot) => slot + 1,
+    }
+}
+
+/// Returns a copy of `inst` with its instruction pointers shifted by `off`,
+/// its capture slots shifted by `cap_off`, along with the groups it refers
+/// to, and its counters shifted by `count_off`.
+///
+/// The slots for the zeroth capture group are never shifted.
+fn renumber(
+    inst: &Inst,
+    off: usize,
+    cap_off: usize,
+    count_off: usize,
+) -> Inst {
+    match *inst {
+        Inst::Save(slot) if slot >= 2 => Inst::Save(slot + cap_off),
+        Inst::Jump(pc) => Inst::Jump(pc + off),
+        Inst::Split(x, y) => Inst::Split(x + off, y + off),
+        Inst::Call(pc) => Inst::Call(pc + off),
+        Inst::Backref(i) => Inst::Backref(i + cap_off / 2),
+        Inst::CountReset(k) => Inst::CountReset(k + count_off),
+        Inst::CountLoop(l) => Inst::CountLoop(CountLoop {
+            counter: l.counter + count_off,
+            exit: l.exit + off,
+            ..l
+        }),
+        ref inst => inst.clone(),
+    }
+}
+
+/// Returns true if `c2` is the character immediately following `c1`.
+fn is_adjacent(c1: char, c2: char) -> bool {
+    (c1 as u32) + 1 == c2 as u32 || (c1 == '\u{D7FF}' && c2 == '\u{E000}')
+}
+
+/// Returns true if `Match` can be reached from the start of `insts` without
+/// matching a character, assuming that zero-width assertions hold.
+fn matches_empty(insts: &[Inst]) -> bool {
+    let mut seen = vec![false; insts.len()];
+    let mut stack = vec![0];
+    while let Some(pc) = stack.pop() {
+        if seen[pc] {
+            continue;
+        }
+        seen[pc] = true;
+        match insts[pc] {
+            Inst::Match => return true,
+            Inst::Save(_) | Inst::BranchMark(_) | Inst::EmptyLook(_) => {
+                stack.push(pc + 1);
+            }
+            Inst::Jump(to) => stack.push(to),
+            Inst::Split(x, y) => {
+                stack.push(y);
+                stack.push(x);
+            }
+            Inst::Char(_) | Inst::Ranges(_) => {}
+            // The group may have matched the empty string.
+            Inst::Backref(_) => stack.push(pc + 1),
+            Inst::CountReset(_) => stack.push(pc + 1),
+            // The body may match the empty string however often the loop
+            // must run.
+            Inst::CountLoop(ref l) => {
+                stack.push(l.exit);
+                stack.push(pc + 1);
+            }
+            // The subroutine may match the empty string.
+            Inst::Call(_) | Inst::Return => return true,
+        }
+    }
+    false
+}
+
+/// Returns the longest literal string that every match of `e` contains, if
+/// there is one.
+fn required_literal(e: &Expr) -> Option<String> {
+    let lit = required(e);
+    if lit.is_empty() { None } else { Some(lit) }
+}
+
+/// Returns a literal string that every match of `e` contains, which may be
+/// empty.
+fn required(e: &Expr) -> String {
+    if let Some(lit) = exact(e) {
+        return lit;
+    }
+    match *e {
+        Expr::Group { ref e, .. } => required(e),
+        Expr::Repeat { ref e, r, .. } if min_repeat(r) > 0 => required(e),
+        Expr::Concat(ref es) => {
+            // Exact expressions next to each other form one literal. The
+            // first and last copies of a repeated exact expression are next
+            // to what comes before and after it.
+            let mut best = String::new();
+            let mut run = String::new();
+            for e in es {
+                if let Some(lit) = exact(e) {
+                    run.push_str(&lit);
+                    continue;
+                }
+                if let Some(lit) = repeated_exact(e) {
+                    run.push_str(&lit);
+                    best = longest(best, mem::replace(&mut run, lit));
+                    continue;
+                }
+                best = longest(best, mem::replace(&mut run, String::new()));
+                best = longest(best, required(e));
+            }
+            longest(best, run)
+        }
+        _ => String::new(),
+    }
+}
+
+/// Returns the only string `e` can match, if there's just one. Zero-width
+/// assertions match the empty string.
+fn exact(e: &Expr) -> Option<String> {
+    match *e {
+        Expr::Empty
+        | Expr::StartLine | Expr::EndLine
+        | Expr::StartText | Expr::EndText | Expr::EndTextNewline
+        | Expr::WordBoundary | Expr::NotWordBoundary => Some(String::new()),
+        Expr::Literal { ref chars, casei: false } => {
+            Some(chars.iter().cloned().collect())
+        }
+        Expr::Class(ref cls) if cls.len() == 1 && cls[0].start == cls[0].end
+                                && !cls.is_case_insensitive() => {
+            Some(cls[0].start.to_string())
+        }
+        Expr::Group { ref e, .. } => exact(e),
+        Expr::Concat(ref es) => {
+            let mut lit = String::new();
+            for e in es {
+                match exact(e) {
+                    None => return None,
+                    Some(more) => lit.push_str(&more),
+                }
+            }
+            Some(lit)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the string that `e` repeats at least once, if `e` is a repetition
+/// of an exact expression.
+fn repeated_exact(e: &Expr) -> Option<String> {
+    match *e {
+        Expr::Repeat { ref e, r, .. } if min_repeat(r) > 0 => exact(e),
+        _ => None,
+    }
+}
+
+fn min_repeat(r: Repeater) -> u32 {
+    match r {
+        Repeater::ZeroOrOne | Repeater::ZeroOrMore => 0,
+        Repeater::OneOrMore => 1,
+        Repeater::Range { min, .. } => min,
+    }
+}
+
+fn longest(s1: String, s2: String) -> String {
+    if s2.len() > s1.len() { s2 } else { s1 }
+}
+
+/// Count the number of characters in the given ranges, which excludes any
+/// surrogate code points between their bounds.
 ///
 /// This is useful for pre-emptively limiting the number of prefix literals
 /// we extract from a regex program.
 fn num_chars_in_ranges(ranges: &[(char, char)]) -> usize {
     ranges.iter()
-          .map(|&(s, e)| (e as u32) - (s as u32))
+          .map(|&(s, e)| {
+              let len = (e as u32) - (s as u32) + 1;
+              if s <= '\u{D7FF}' && e >= '\u{E000}' {
+                  len - 0x800
+              } else {
+                  len
+              }
+          })
           .fold(0, |acc, len| acc + len) as usize
 }
+
+#[cfg(test)]
+mod tests {
+    use syntax::{Expr, Repeater};
+
+    use super::{FirstSet, Flags, Inst, MatchEngine, Program, RegexOptions};
+
+    fn prog(re: &str) -> Program {
+        Program::new(re, &RegexOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn backtrack_only() {
+        use super::PartialResult;
+
+        let p = prog(r"(a)\1");
+        assert_eq!(p.coverage("aa"), Vec::<bool>::new());
+        assert_eq!(p.partial_match("xaa"), PartialResult::Full((1, 3)));
+        assert_eq!(p.partial_match("xa"), PartialResult::Partial);
+    }
+
+    #[test]
+    fn names_char() {
+        let names = |re: &str| prog(re).names_char('\u{FFFD}');
+        assert!(names(r"\x{FFFD}"));
+        assert!(names(r"(?i)a\x{FFFD}"));
+        assert!(names(r"a|\x{FFFD}"));
+        assert!(names(r"[a\x{FFFD}]"));
+        assert!(!names(r"."));
+        assert!(!names(r"[^a]"));
+        assert!(!names(r"\W"));
+        assert!(!names(r"é"));
+    }
+
+    #[test]
+    fn exec_from_end_caches_reverse() {
+        let p = prog(r"[a-z]+\d\z");
+        assert_eq!(p.exec_from_end("ab1 cd2"), Some(Some((4, 7))));
+        let rev = p.reverse.prog.lock().unwrap().clone().unwrap().unwrap();
+        assert_eq!(p.exec_from_end("xy9"), Some(Some((0, 3))));
+        let again = p.reverse.prog.lock().unwrap().clone().unwrap().unwrap();
+        assert!(&*rev as *const Program == &*again as *const Program);
+    }
+
+    #[test]
+    fn required_literal() {
+        let lit = |re: &str| prog(re).required_literal;
+        assert_eq!(lit("abc"), Some("abc".to_owned()));
+        assert_eq!(lit(r"\w+ needle\d*"), Some(" needle".to_owned()));
+        assert_eq!(lit(r"a\d(?:bc[d]\be)+f"), Some("bcdef".to_owned()));
+        assert_eq!(lit(r"x+(?:yy|zzz)"), Some("x".to_owned()));
+        assert_eq!(lit(r"(?:ab)+c"), Some("abc".to_owned()));
+        assert_eq!(lit(r"a\d(?:bc){2,}d"), Some("bcd".to_owned()));
+        assert_eq!(lit(r"(?i)abc"), None);
+        assert_eq!(lit(r"a*|b"), None);
+        assert_eq!(lit(r"(?:abc)?d"), Some("d".to_owned()));
+        assert_eq!(lit(""), None);
+    }
+
+    #[test]
+    fn always_match() {
+        assert!(prog("").is_always_match());
+        assert!(prog("a*").is_always_match());
+        assert!(prog("(?:a|b?)").is_always_match());
+        assert!(!prog("abc").is_always_match());
+        assert!(!prog("^a*").is_always_match());
+        assert!(!prog(r"\b").is_always_match());
+    }
+
+    #[test]
+    fn anchored_full_literal() {
+        assert!(prog("^abc$").is_anchored_full_literal());
+        assert!(prog("^(abc)$").is_anchored_full_literal());
+        assert!(!prog("abc").is_anchored_full_literal());
+        assert!(!prog("^abc").is_anchored_full_literal());
+        assert!(!prog("^a.c$").is_anchored_full_literal());
+        assert!(!prog("(?i)^abc$").is_anchored_full_literal());
+    }
+
+    #[test]
+    fn quantifier_priority() {
+        let tests = &[
+            ("a?", vec![(1, true)]),
+            ("a??", vec![(1, false)]),
+            ("a*", vec![(1, true)]),
+            ("a*?", vec![(1, false)]),
+            ("a+", vec![(2, true)]),
+            ("a+?", vec![(2, false)]),
+            ("a{1,2}?b*", vec![(2, false), (4, true)]),
+        ];
+        for &(re, ref greedy) in tests {
+            let p = prog(re);
+            assert_eq!(p.check_quantifier_priority(), Ok(()));
+            for pc in 0..p.insts.len() {
+                let expected = greedy.iter()
+                    .find(|&&(gpc, _)| gpc == pc)
+                    .map(|&(_, g)| g);
+                assert_eq!(p.is_greedy_at(pc), expected, "{} at {}", re, pc);
+            }
+        }
+
+        let p = prog("a*").union(&prog("b+?"));
+        assert_eq!(p.check_quantifier_priority(), Ok(()));
+        assert_eq!(p.is_greedy_at(2), Some(true));
+        assert_eq!(p.is_greedy_at(7), Some(false));
+
+        let mut p = prog("a*");
+        p.insts[1] = Inst::Split(3, 2);
+        assert_eq!(p.check_quantifier_priority(), Err(1));
+    }
+
+    #[test]
+    fn stats() {
+        let stats = prog("(?:ab|cd)e").stats();
+        assert_eq!(stats.insts, 10);
+        assert_eq!(stats.chars, 5);
+        assert_eq!(stats.ranges, 0);
+        assert_eq!(stats.splits, 1);
+        assert_eq!(stats.saves, 2);
+        assert_eq!(stats.empty_looks, 0);
+        assert_eq!(stats.jumps, 1);
+        assert_eq!(stats.branch_marks, 0);
+        assert_eq!(stats.prefixes, 2);
+        assert!(stats.prefixes_complete);
+        assert_eq!(stats.max_match_len, Some(3));
+
+        let stats = prog(r"^[a-z]+\b").stats();
+        assert_eq!((stats.ranges, stats.empty_looks), (1, 2));
+        assert!(stats.anchored_begin);
+        assert_eq!(stats.max_match_len, None);
+    }
+
+    #[test]
+    fn first_char_set() {
+        let first = |re: &str| prog(re).first_char_set();
+        assert_eq!(first(r"[0-9]+|[a-c]x"), FirstSet::Ranges(vec![
+            ('0', '9'), ('a', 'c'),
+        ]));
+        assert_eq!(first(r"\bé?(?:[β-δ]|z)"), FirstSet::Ranges(vec![
+            ('z', 'z'), ('é', 'é'), ('β', 'δ'),
+        ]));
+        assert_eq!(first(r"(?i)k"), FirstSet::Ranges(vec![
+            ('K', 'K'), ('k', 'k'), ('\u{212A}', '\u{212A}'),
+        ]));
+        assert_eq!(first(r"a*b|."), FirstSet::Ranges(vec![
+            ('\0', '\x09'), ('\x0B', '\u{10FFFF}'),
+        ]));
+        assert_eq!(first(r"(?s)."), FirstSet::Any);
+        assert_eq!(first(r"^$"), FirstSet::Empty);
+        assert_eq!(first(r""), FirstSet::Empty);
+        assert_eq!(first(r"(a)\1"), FirstSet::Ranges(vec![('a', 'a')]));
+    }
+
+    #[test]
+    fn anchored_literal() {
+        let p = prog("^foobar");
+        assert_eq!(p.anchored_literal, Some("foobar".to_owned()));
+        assert_eq!(p.chosen_engine("foobarbaz"), MatchEngine::Literals);
+        let mut caps = vec![None; 2];
+        assert!(p.exec(&mut caps, "foobarbaz", 0));
+        assert_eq!(caps, vec![Some(0), Some(6)]);
+        assert!(!p.exec(&mut caps, "foobarbaz", 1));
+        assert!(!p.exec(&mut caps, "xfoobar", 0));
+
+        assert_eq!(prog("^").anchored_literal, Some("".to_owned()));
+        assert_eq!(prog("foobar").anchored_literal, None);
+        assert_eq!(prog("^foo$").anchored_literal, None);
+        assert_eq!(prog("^(foo)").anchored_literal, None);
+        assert_eq!(prog("(?m)^foo").anchored_literal, None);
+    }
+
+    #[test]
+    fn analyze() {
+        use std::mem::size_of;
+        use super::MatchEngine;
+        use Error;
+
+        let stats = Program::analyze("(?:ab|cd)e", 1000).unwrap();
+        assert_eq!(stats, prog("(?:ab|cd)e").stats());
+        assert_eq!(stats.size, 10 * size_of::<Inst>());
+        assert_eq!(stats.captures, 1);
+        assert_eq!(stats.engine, Some(MatchEngine::Literals));
+
+        let stats = Program::analyze(r"(\w+)@([a-z]+)$", 100000).unwrap();
+        assert_eq!(stats.captures, 3);
+        assert_eq!(stats.engine, None);
+        assert!(stats.anchored_end && !stats.anchored_begin);
+        assert!(!stats.prefixes_complete);
+        assert!(stats.size > stats.insts * size_of::<Inst>());
+
+        match Program::analyze(r"\pL", 100) {
+            Err(Error::CompiledTooBig(100)) => {}
+            r => panic!("expected CompiledTooBig, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn factor_alternates() {
+        // Save, Split, 6 chars, Jump, 6 chars, Save, Match without
+        // factoring. Save, 5 chars, Ranges, Save, Match with.
+        let p = prog("(?:foobar|foobaz)");
+        assert_eq!(p.insts.len(), 9);
+        assert!(p.prefixes_complete);
+        // Save, Ranges, Save, Match.
+        let p = prog("a|b|c|d|x");
+        assert_eq!(p.insts.len(), 4);
+        assert!(p.prefixes_complete);
+        assert_eq!(p.prefixes.len(), 5);
+
+        // Branch marks turn factoring off.
+        let unfactored = RegexOptions {
+            branch_marks: true,
+            ..RegexOptions::default()
+        };
+        let tests = &[
+            ("(?:foobar|foobaz)", &["foobar", "xfoobaz", "fooba"][..]),
+            ("foo(a)|foo(b)", &["foob", "fooa", "foo"]),
+            ("xab|(xa)b", &["xab"]),
+            ("a+bc|a*c", &["aaac", "aabc", "bc"]),
+            ("(?i)ab|(?i)ac", &["AC", "aB"]),
+            ("ab(c)|ad(c)", &["adc", "abc"]),
+            ("xa|x-|xb", &["x-", "xb", "xc"]),
+            ("a|c|b|d|e", &["b", "xe", "f"]),
+            ("ab|a|b|c|(?i)d", &["ab", "ac", "D", "e"]),
+            ("[a-c]|-|&|z", &["-", "&&", "b"]),
+        ];
+        for &(re, texts) in tests {
+            let p1 = prog(re);
+            let p2 = Program::new(re, &unfactored).unwrap();
+            // There is one branch mark for each of the two branches.
+            assert!(p1.insts.len() < p2.insts.len() - 2, "{}", re);
+            for text in texts {
+                let mut caps1 = p1.alloc_captures();
+                let mut caps2 = p2.alloc_captures();
+                assert_eq!(p1.exec(&mut caps1, text, 0),
+                           p2.exec(&mut caps2, text, 0));
+                assert_eq!(&caps1[..], &caps2[..caps1.len()],
+                           "{} on {:?}", re, text);
+            }
+        }
+    }
+
+    #[test]
+    fn prefix_limits() {
+        let long = "abcdefghijklmnopqrstuvwxyz";
+        let many: Vec<String> = (0..40).map(|i| format!("x{}", i)).collect();
+        let many = many.join("|");
+        assert!(!prog(long).stats().prefixes_complete);
+        assert_eq!(prog(&many).stats().prefixes, 0);
+
+        let opts = RegexOptions {
+            prefix_limits: (50, 30),
+            ..RegexOptions::default()
+        };
+        let stats = Program::new(long, &opts).unwrap().stats();
+        assert_eq!(stats.prefixes, 1);
+        assert!(stats.prefixes_complete);
+        let stats = Program::new(&many, &opts).unwrap().stats();
+        assert_eq!(stats.prefixes, 40);
+        assert!(stats.prefixes_complete);
+    }
+
+    #[test]
+    fn prefixes_skip_surrogates() {
+        use super::num_chars_in_ranges;
+
+        // The two characters are merged into one range around the
+        // surrogate code points.
+        let re = r"[\x{D7FF}\x{E000}]";
+        let ranges = vec![('\u{D7FF}', '\u{E000}')];
+        assert_eq!(num_chars_in_ranges(&ranges), 2);
+        let opts = RegexOptions {
+            prefix_limits: (5000, 30),
+            ..RegexOptions::default()
+        };
+        let p = Program::new(re, &opts).unwrap();
+        assert_eq!(p.stats().prefixes, 2);
+        assert!(p.stats().prefixes_complete);
+        assert_eq!(p.prefixes.find("x\u{E000}"), Some((1, 4)));
+        assert_eq!(prog(r"[\x{D000}-\x{E0FF}]").stats().prefixes, 0);
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn instruction_hits() {
+        use super::MatchEngine;
+
+        for &engine in &[MatchEngine::Nfa, MatchEngine::Backtrack] {
+            let opts = RegexOptions {
+                engine: Some(engine),
+                ..RegexOptions::default()
+            };
+            let mut p = Program::new("a+b", &opts).unwrap();
+            let match_pc = p.insts.len() - 1;
+            assert!(p.exec(&mut [], "aab", 0));
+            let hits = p.instruction_hits();
+            assert_eq!(hits.len(), p.insts.len());
+            assert_eq!(hits[match_pc], 1);
+
+            assert!(p.exec(&mut [], "aab", 0));
+            let twice: Vec<u64> = hits.iter().map(|&n| 2 * n).collect();
+            assert_eq!(p.instruction_hits(), twice);
+
+            p.set_hits_per_search(true);
+            assert!(p.exec(&mut [], "aab", 0));
+            assert_eq!(p.instruction_hits(), hits);
+
+            p.reset_instruction_hits();
+            assert!(p.instruction_hits().iter().all(|&n| n == 0));
+        }
+    }
+
+    #[test]
+    fn captures_iter_reuse() {
+        let p = prog(r"(\w)(\d)?|x*");
+        let mut caps = p.alloc_captures();
+        let mut it = p.captures_iter_reuse("a1 b");
+        assert!(it.next_into(&mut caps));
+        assert_eq!(caps, vec![Some(0), Some(2), Some(0), Some(1),
+                              Some(1), Some(2)]);
+        assert!(it.next_into(&mut caps));
+        assert_eq!(caps, vec![Some(3), Some(4), Some(3), Some(4), None, None]);
+        assert!(!it.next_into(&mut caps));
+        assert!(!it.next_into(&mut caps));
+
+        let mut caps = [None, None];
+        let mut it = p.captures_iter_reuse(" a");
+        let mut spans = vec![];
+        while it.next_into(&mut caps) {
+            spans.push((caps[0].unwrap(), caps[1].unwrap()));
+        }
+        assert_eq!(spans, vec![(0, 0), (1, 2)]);
+    }
+
+    #[test]
+    fn to_dot() {
+        let dot = prog("ab|c").to_dot();
+        assert!(dot.starts_with("digraph program {\n"));
+        assert!(dot.contains("0 [label=\"0: Save(0)\"]"));
+        assert!(dot.contains("1 [label=\"1: Split\"]"));
+        assert!(dot.contains("[label=\"2: 'a'\"]"));
+        assert!(dot.contains("[label=\"3: 'b'\"]"));
+        assert!(dot.contains("[label=\"5: 'c'\"]"));
+        assert!(dot.contains("[label=\"7: Match\"]"));
+        assert!(dot.contains("    1 -> 2 [label=\"1\"];\n"));
+        assert!(dot.contains("    1 -> 5 [label=\"2\"];\n"));
+        assert_eq!(dot.matches("    1 -> ").count(), 2);
+        assert!(dot.contains("    4 -> 6;\n"));
+
+        let dot = prog(r#"(?i)["\\]"#).to_dot();
+        assert!(dot.contains(r#"[label="1: (?i)[\\\"\\\\]"]"#), "{}", dot);
+    }
+
+    #[test]
+    fn find_earliest() {
+        assert_eq!(prog(r"a+").find_earliest("xaaa", 0), Some((1, 2)));
+        assert_eq!(prog(r"ab|a").find_earliest("ab", 0), Some((0, 1)));
+        assert_eq!(prog(r"\wbc|b").find_earliest("abc", 0), Some((0, 3)));
+        assert_eq!(prog(r"a*").find_earliest("aaa", 0), Some((0, 0)));
+        assert_eq!(prog(r"\d+\b").find_earliest("a 123 45", 0),
+                   Some((2, 5)));
+        assert_eq!(prog(r"\d+").find_earliest("a 123 45", 5), Some((6, 7)));
+        assert_eq!(prog(r"\d").find_earliest("abc", 0), None);
+        assert_eq!(prog(r"(a)\1").find_earliest("aa", 0), None);
+    }
+
+    #[test]
+    fn first_bytes() {
+        let bytes = |re: &str| prog(re).first_bytes.map(|bytes| {
+            (0..256).filter(|&b| bytes[b]).map(|b| b as u8).collect::<Vec<_>>()
+        });
+        let alnum = b"0123456789abcdefghijklmnopqrstuvwxyz".to_vec();
+        assert_eq!(bytes(r"[a-z0-9]x"), Some(alnum.clone()));
+        assert_eq!(bytes(r"foo|[0-9a-z]+"), Some(alnum));
+        assert_eq!(bytes(r"(?i)[a-b]x"), Some(
+            b"ABab".iter().cloned().chain(0xC2..0xF5).collect()));
+        assert_eq!(bytes(r"\b[éa]"), Some(vec![b'a', 0xC3]));
+        // Literal prefixes are used instead.
+        assert_eq!(bytes(r"abc"), None);
+        assert_eq!(bytes(r"[abc]\d+"), None);
+        // These can match anywhere.
+        assert_eq!(bytes(r"[abc]*"), None);
+        assert_eq!(bytes(r"[^a]|a"), None);
+        assert_eq!(bytes(r"^[abc]"), None);
+    }
+
+    #[test]
+    fn exec_with_scratch() {
+        use super::MatchEngine;
+
+        let text = "foo 123 bar 45678";
+        for &engine in &[MatchEngine::Nfa, MatchEngine::Backtrack] {
+            let opts = RegexOptions {
+                engine: Some(engine),
+                ..RegexOptions::default()
+            };
+            let p = Program::new(r"(\d)\d*", &opts).unwrap();
+            let mut scratch = p.new_scratch();
+            for start in 0..text.len() + 1 {
+                let mut caps1 = p.alloc_captures();
+                let mut caps2 = p.alloc_captures();
+                let m1 = p.exec_with_scratch(
+                    &mut scratch, &mut caps1, text, start);
+                let m2 = p.exec(&mut caps2, text, start);
+                assert_eq!((m1, caps1), (m2, caps2));
+            }
+        }
+    }
+
+    #[test]
+    fn flags() {
+        let opts = RegexOptions { crlf: true, ..RegexOptions::default() };
+        let p = Program::new("(?m)^a$", &opts).unwrap();
+        assert_eq!(p.as_str(), "(?m)^a$");
+        assert_eq!(p.flags(), Flags {
+            crlf: true,
+            branch_marks: false,
+            grapheme_dot: false,
+            dot_matches_new_line: false,
+            single_line: false,
+            line_terminator: b'\n',
+            ascii_case_insensitive: false,
+            dollar_newline: false,
+            normalize: None,
+        });
+        assert!(p.flags() != prog("(?m)^a$").flags());
+        assert_eq!(p.clone().flags(), p.flags());
+    }
+
+    #[test]
+    fn anchored_end() {
+        assert!(prog("abc$").anchored_end);
+        assert!(prog("(?:a|bc)$").anchored_end);
+        assert!(!prog("a|b$").anchored_end);
+        assert!(!prog("a$|b$").anchored_end);
+        assert!(!prog("a$b").anchored_end);
+    }
+
+    #[test]
+    fn casei_chars_are_ranges() {
+        let p = prog("a(?i)b[c]");
+        match (&p.insts[1], &p.insts[2], &p.insts[3]) {
+            (&Inst::Char('a'), &Inst::Ranges(ref b), &Inst::Ranges(ref c)) => {
+                assert_eq!((&*b.ranges, b.casei), (&[('b', 'b')][..], true));
+                assert_eq!((&*c.ranges, c.casei), (&[('c', 'c')][..], true));
+            }
+            _ => panic!("unexpected program: {:?}", p.insts),
+        }
+        assert_eq!(exec(&p, "xaBc"), vec![Some(1), Some(4)]);
+    }
+
+    #[test]
+    fn casei_scoped_to_group() {
+        let p = prog("a(?i:b[c])d");
+        match (&p.insts[1], &p.insts[2], &p.insts[3], &p.insts[4]) {
+            (&Inst::Char('a'), &Inst::Ranges(ref b), &Inst::Ranges(ref c),
+             &Inst::Char('d')) => {
+                assert!(b.casei && c.casei);
+            }
+            _ => panic!("unexpected program: {:?}", p.insts),
+        }
+    }
+
+    fn exec(prog: &Program, text: &str) -> Vec<Option<usize>> {
+        let mut caps = prog.alloc_captures();
+        if !prog.exec(&mut caps, text, 0) {
+            return vec![];
+        }
+        caps
+    }
+
+    #[test]
+    fn branch_marks() {
+        use super::MatchEngine::{Backtrack, Nfa};
+
+        for &engine in &[None, Some(Backtrack), Some(Nfa)] {
+            let opts = RegexOptions {
+                engine: engine,
+                branch_marks: true,
+                ..RegexOptions::default()
+            };
+            let p = Program::new(r"(?:a+|(b)|c)", &opts).unwrap();
+            assert_eq!(exec(&p, "xaa").last(), Some(&Some(0)));
+            assert_eq!(exec(&p, "xb").last(), Some(&Some(1)));
+            assert_eq!(exec(&p, "xc").last(), Some(&Some(2)));
+
+            // Only the branch on the matching path is reported.
+            let p = Program::new(r"ab|a", &opts).unwrap();
+            assert_eq!(exec(&p, "ac").last(), Some(&Some(1)));
+        }
+    }
+
+    #[test]
+    fn crlf_line_anchors() {
+        use super::MatchEngine::{Backtrack, Nfa};
+
+        for &engine in &[None, Some(Backtrack), Some(Nfa)] {
+            let opts = RegexOptions {
+                engine: engine,
+                crlf: true,
+                ..RegexOptions::default()
+            };
+            let p = Program::new(r"(?m)a$", &opts).unwrap();
+            assert_eq!(exec(&p, "a\r\nb"), vec![Some(0), Some(1)]);
+            // A bare `\r` ends a line too.
+            let p = Program::new(r"(?m)^b$", &opts).unwrap();
+            assert_eq!(exec(&p, "a\rb\r"), vec![Some(2), Some(3)]);
+            // But there is no line boundary inside of `\r\n`.
+            let p = Program::new(r"(?m)\r$", &opts).unwrap();
+            assert_eq!(exec(&p, "a\r\nb"), vec![]);
+            let p = Program::new(r"(?m)^\n", &opts).unwrap();
+            assert_eq!(exec(&p, "a\r\nb"), vec![]);
+        }
+    }
+
+    #[test]
+    fn ranges_are_merged() {
+        use super::CharRanges;
+
+        let r = CharRanges::from_ranges(vec![('n', 'z'), ('a', 'm')], false);
+        assert_eq!(r.ranges, vec![('a', 'z')]);
+        let r = CharRanges::from_ranges(
+            vec![('x', 'y'), ('a', 'f'), ('c', 'd'), ('e', 'g')], true);
+        assert_eq!(r.ranges, vec![('a', 'g'), ('x', 'y')]);
+        assert!(r.casei);
+        let r = CharRanges::from_ranges(
+            vec![('\u{E000}', '\u{10FFFF}'), ('\0', '\u{D7FF}')], false);
+        assert_eq!(r.ranges, vec![('\0', '\u{10FFFF}')]);
+        assert!(r.matches('b'.into()));
+    }
+
+    #[test]
+    fn ranges_are_compact() {
+        use std::mem::size_of;
+        use super::CharRanges;
+
+        assert_eq!(size_of::<(char, char)>(), 2 * size_of::<u32>());
+        let r = CharRanges::from_ranges(vec![('a', 'c'), ('b', 'd')], false);
+        assert_eq!(r.ranges, vec![('a', 'd')]);
+    }
+
+    #[test]
+    fn ascii_set_matches_ranges() {
+        use super::CharRanges;
+
+        let classes = vec![
+            CharRanges::from_ranges(
+                vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')], false),
+            CharRanges::from_ranges(vec![('\0', '\x7f')], false),
+            CharRanges::from_ranges(vec![('k', 'k'), ('s', 's')], true),
+            CharRanges::any_except('\0'),
+            CharRanges::casei('?'),
+        ];
+        for cls in classes {
+            assert!(cls.ascii.is_some() == (cls.ranges.last().unwrap().1
+                                             <= '\x7f'));
+            let slow = CharRanges {
+                ranges: cls.ranges.clone(),
+                casei: cls.casei,
+                ascii: None,
+            };
+            for c in (0u8..128).map(|b| b as char).chain(vec!['☃', 'K']) {
+                assert_eq!(cls.matches(c.into()), slow.matches(c.into()));
+            }
+        }
+        // The Kelvin sign folds to `k`.
+        let cls = CharRanges::from_ranges(vec![('k', 'k')], true);
+        assert!(cls.ascii.is_some());
+        assert!(cls.matches('\u{212A}'.into()));
+        assert!(!cls.matches('☃'.into()));
+    }
+
+    #[test]
+    fn inst_hash_eq() {
+        use std::collections::HashSet;
+
+        // Each `[a-c]` compiles to an equal `Ranges` instruction.
+        let insts: HashSet<Inst> =
+            prog("[a-c]x[a-c]y[abc]").insts.into_iter().collect();
+        let ranges = insts.iter().filter(|inst| match **inst {
+            Inst::Ranges(_) => true,
+            _ => false,
+        }).count();
+        assert_eq!(ranges, 1);
+        assert!(insts.contains(&Inst::Char('x')));
+        assert!(prog("(?i)a").insts == prog("(?i:a)").insts);
+        assert!(prog("[a-c]").insts != prog("[ac]").insts);
+    }
+
+    #[test]
+    fn from_expr() {
+        // (?i)a(b+)
+        let expr = Expr::Concat(vec![
+            Expr::Literal { chars: vec!['a'], casei: true },
+            Expr::Group {
+                e: Box::new(Expr::Repeat {
+                    e: Box::new(Expr::Literal {
+                        chars: vec!['b'],
+                        casei: false,
+                    }),
+                    r: Repeater::OneOrMore,
+                    greedy: true,
+                }),
+                i: Some(1),
+                name: None,
+            },
+        ]);
+        let p1 = Program::from_expr(expr, &RegexOptions::default()).unwrap();
+        let p2 = prog("(?i:a)(b+)");
+        assert_eq!(p1.num_captures(), 2);
+        for text in &["ab", "xAbbb", "aB", "b", ""] {
+            assert_eq!(exec(&p1, text), exec(&p2, text));
+        }
+        assert!(!p1.original.is_empty());
+    }
+
+    #[test]
+    fn from_expr_options() {
+        use Error;
+        use super::MatchEngine;
+
+        let expr = Expr::parse("a{100}").unwrap();
+        let opts = RegexOptions {
+            engine: Some(MatchEngine::Nfa),
+            ..RegexOptions::default()
+        };
+        let p = Program::from_expr(expr.clone(), &opts).unwrap();
+        assert_eq!(p.engine, Some(MatchEngine::Nfa));
+        assert_eq!(p.original, expr.to_string());
+
+        let opts = RegexOptions { size_limit: 100, ..RegexOptions::default() };
+        match Program::from_expr(expr, &opts) {
+            Err(Error::CompiledTooBig(100)) => {}
+            r => panic!("expected CompiledTooBig, got {:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn perl_whitespace_ranges() {
+        use super::{CharRanges, Inst};
+
+        fn ranges(re: &str) -> Vec<(char, char)> {
+            match prog(re).insts[1] {
+                Inst::Ranges(CharRanges { ref ranges, .. }) => ranges.clone(),
+                ref inst => panic!("expected ranges, got {:?}", inst),
+            }
+        }
+        let h = ranges(r"\h");
+        assert!(h.contains(&('\t', '\t')));
+        assert!(h.contains(&(' ', ' ')));
+        assert!(h.contains(&('\u{2000}', '\u{200a}')));
+        assert!(!h.iter().any(|&(s, e)| s <= '\n' && '\n' <= e));
+        let v = ranges(r"\v");
+        assert!(v.contains(&('\n', '\r')));
+        assert!(v.contains(&('\u{2028}', '\u{2029}')));
+        assert!(!v.iter().any(|&(s, e)| s <= ' ' && ' ' <= e));
+    }
+
+    #[test]
+    fn union() {
+        let p = prog("a(b)").union(&prog("(?P<x>c)(d)"));
+        assert_eq!(p.num_captures(), 4);
+        assert_eq!(p.cap_names, vec![
+            None, None, Some("x".to_owned()), None,
+        ]);
+        assert_eq!(exec(&p, "ab"), vec![
+            Some(0), Some(2), Some(1), Some(2), None, None, None, None,
+        ]);
+        assert_eq!(exec(&p, "xcd"), vec![
+            Some(1), Some(3), None, None, Some(1), Some(2), Some(2), Some(3),
+        ]);
+        assert_eq!(exec(&p, "xyz"), vec![]);
+    }
+
+    #[test]
+    fn union_not_reversed() {
+        let opts = RegexOptions {
+            dot_matches_new_line: true,
+            ..RegexOptions::default()
+        };
+        let a = Program::new(r"a.$", &opts).unwrap();
+        let p = a.union(&prog(r"b.$"));
+        assert_eq!(p.exec_from_end("b\n"), None);
+        assert_eq!(p.clone().exec_from_end("b\n"), None);
+        assert_eq!(exec(&p, "b\n"), vec![]);
+    }
+
+    #[test]
+    fn union_prefers_first() {
+        let p = prog("a").union(&prog("ab"));
+        assert_eq!(exec(&p, "ab"), vec![Some(0), Some(1)]);
+        let p = prog("ab").union(&prog("a"));
+        assert_eq!(exec(&p, "ab"), vec![Some(0), Some(2)]);
+    }
+}
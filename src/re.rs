@@ -9,26 +9,34 @@
 // except according to those terms.
 
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::hash_map::Iter;
+use std::ffi::OsStr;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Range;
+use std::panic::RefUnwindSafe;
+use std::rc::Rc;
 #[cfg(feature = "pattern")]
 use std::str::pattern::{Pattern, Searcher, SearchStep};
-use std::str::FromStr;
-
-use program::{Program, MatchEngine};
+use std::str::{self, FromStr};
+use std::sync::Arc;
+
+use program::{
+    Program, MatchEngine, RegexOptions, EngineObserver, Flags, SizeLimitPolicy,
+    Anchored, CharRanges, FirstSet,
+};
+#[cfg(feature = "normalize")]
+use program::NormalForm;
+#[cfg(feature = "profile")]
+use profile::ExecStats;
+use normalize::NormalText;
+use simplify::simplify_expr;
 use syntax;
 
-const REPLACE_EXPAND: &'static str = r"(?x)
-  (?P<before>^|\b|[^$]) # Ignore `$$name`.
-  \$
-  (?P<name> # Match the actual capture name. Can be...
-    [0-9]+  # A sequence of digits (for indexed captures), or...
-    |
-    [_a-zA-Z][_0-9a-zA-Z]* # A name for named captures.
-  )
-";
-
 /// Type alias for representing capture indices.
 pub type CaptureIdxs = [Option<usize>];
 
@@ -58,6 +66,28 @@ pub fn is_match(regex: &str, text: &str) -> Result<bool, Error> {
     Regex::new(regex).map(|r| r.is_match(text))
 }
 
+/// Rewrites a regular expression into an equivalent one that's no larger,
+/// and returns it as a string.
+///
+/// Repetitions like `a{1}` are removed, classes of one character like `[a]`
+/// become literals, and groups that neither capture nor change the meaning
+/// of the regex are dropped. The result matches exactly the same text, with
+/// the same capture groups, as `regex`. It shows what the regex really
+/// compiles to, without any redundant syntax.
+///
+/// If there was a problem parsing the regular expression, an error is
+/// returned.
+///
+/// # Example
+///
+/// ```rust
+/// assert_eq!(regex::simplify(r"(?:a{1}[b])+c").unwrap(), "(?:ab)+c");
+/// ```
+pub fn simplify(regex: &str) -> Result<String, Error> {
+    let expr = try!(Program::parse(regex, &RegexOptions::default()));
+    Ok(simplify_expr(expr).to_string())
+}
+
 /// An error that occurred during parsing or compiling a regular expression.
 #[derive(Debug)]
 pub enum Error {
@@ -66,6 +96,29 @@ pub enum Error {
     /// The compiled program exceeded the set size limit.
     /// The argument is the size limit imposed.
     CompiledTooBig(usize),
+    /// The regular expression is nested more deeply than the set depth
+    /// limit. The argument is the depth limit imposed.
+    TooDeep(usize),
+    /// The regular expression has more capture groups than the set capture
+    /// limit. The argument is the capture limit imposed.
+    TooManyCaptures(usize),
+    /// The regular expression matches `U+FFFD` as a literal, so it can't be
+    /// matched against text that isn't valid UTF-8, where `U+FFFD` stands
+    /// in for the invalid bytes.
+    NotAscii,
+    /// The regular expression repeats an expression without bound that can
+    /// match the empty string or is repeated without bound itself, like
+    /// `(a*)*` or `(a+)+`. This is only reported when
+    /// `RegexBuilder::reject_nested_repeats` is enabled.
+    NestedUnboundedRepeat,
+    /// The regular expression uses `\X`, which needs the Unicode tables
+    /// provided by the `grapheme` feature, but that feature isn't enabled.
+    NoGraphemeTables,
+    /// A replacement template refers to a capture group that the regular
+    /// expression doesn't have. The argument is the reference as written,
+    /// without the `$` or braces. This is only reported by
+    /// `Regex::compile_replacement` when it's asked to be strict.
+    UnknownGroup(String),
     /// Hints that destructuring should not be exhaustive.
     ///
     /// This enum may grow additional variants, so this makes sure clients
@@ -75,11 +128,37 @@ pub enum Error {
     __Nonexhaustive,
 }
 
+impl Error {
+    /// Returns the byte offsets of the start and end of the character in
+    /// the regex at which a syntax error occurred. This is `None` for any
+    /// other error, since it isn't about a particular part of the regex.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let err = Regex::new("a(").unwrap_err();
+    /// assert_eq!(err.span(), Some((1, 2)));
+    /// ```
+    pub fn span(&self) -> Option<(usize, usize)> {
+        match *self {
+            Error::Syntax(ref err) => Some(err.span()),
+            _ => None,
+        }
+    }
+}
+
 impl ::std::error::Error for Error {
     fn description(&self) -> &str {
         match *self {
             Error::Syntax(ref err) => err.description(),
             Error::CompiledTooBig(_) => "compiled program too big",
+            Error::TooDeep(_) => "regex nested too deeply",
+            Error::TooManyCaptures(_) => "too many capture groups",
+            Error::NotAscii => "regex matches U+FFFD literally",
+            Error::NestedUnboundedRepeat => "nested unbounded repetition",
+            Error::NoGraphemeTables => "grapheme clusters not supported",
+            Error::UnknownGroup(_) => "unknown capture group in replacement",
             Error::__Nonexhaustive => unreachable!(),
         }
     }
@@ -100,6 +179,27 @@ impl fmt::Display for Error {
                 write!(f, "Compiled regex exceeds size limit of {} bytes.",
                        limit)
             }
+            Error::TooDeep(limit) => {
+                write!(f, "Regex exceeds nesting depth limit of {}.", limit)
+            }
+            Error::TooManyCaptures(limit) => {
+                write!(f, "Regex exceeds capture group limit of {}.", limit)
+            }
+            Error::NotAscii => {
+                write!(f, "Regex must not match U+FFFD literally to match \
+                           arbitrary bytes.")
+            }
+            Error::NestedUnboundedRepeat => {
+                write!(f, "Regex contains a nested unbounded repetition.")
+            }
+            Error::NoGraphemeTables => {
+                write!(f, "Regex uses \\X, which requires the `grapheme` \
+                           feature.")
+            }
+            Error::UnknownGroup(ref name) => {
+                write!(f, "Replacement refers to unknown capture group '{}'.",
+                       name)
+            }
             Error::__Nonexhaustive => unreachable!(),
         }
     }
@@ -132,6 +232,11 @@ impl From<syntax::Error> for Error {
 /// methods. All other methods (searching and splitting) return borrowed
 /// pointers into the string given.
 ///
+/// A `Regex` is `Send` and `Sync`, so a single regex can be shared by many
+/// threads and searched concurrently. Each search takes its scratch space
+/// from a pool guarded by a mutex, and returns it when done, so concurrent
+/// searches never share scratch space.
+///
 /// # Examples
 ///
 /// Find the location of a US phone number:
@@ -164,7 +269,11 @@ impl From<syntax::Error> for Error {
 /// assert_eq!(haystack.match_indices(&re).collect::<Vec<_>>(),
 ///            vec![(1, 4), (5, 8)]);
 /// assert_eq!(haystack.split(&re).collect::<Vec<_>>(), vec!["a", "b", "c"]);
+/// assert_eq!(haystack.replace(&re, "#"), "a#b#c");
 /// ```
+///
+/// Regexes can only be searched forwards, so methods that search from the
+/// end of a string, like `rsplit` and `rfind`, can't be used with them.
 #[derive(Clone)]
 pub enum Regex {
     // The representation of `Regex` is exported to support the `regex!`
@@ -186,6 +295,10 @@ pub struct ExNative {
     pub names: &'static &'static [Option<&'static str>],
     #[doc(hidden)]
     pub prog: fn(&mut CaptureIdxs, &str, usize) -> bool,
+    #[doc(hidden)]
+    pub matches_empty: bool,
+    #[doc(hidden)]
+    pub engine: Option<MatchEngine>,
 }
 
 impl Copy for ExNative {}
@@ -210,18 +323,31 @@ impl fmt::Debug for Regex {
     }
 }
 
-/// Equality comparison is based on the original string. It is possible that
-/// different regular expressions have the same matching behavior, but are
-/// still compared unequal. For example, `\d+` and `\d\d*` match the same set
-/// of strings, but are not considered equal.
+/// Equality comparison is based on the original string, along with the
+/// options it was compiled with that change how it matches and the named
+/// classes it was given. Options that don't change the matches, like the
+/// size limit or the matching engine, are ignored.
+///
+/// It is possible that different regular expressions have the same
+/// matching behavior, but are still compared unequal. For example, `\d+`
+/// and `\d\d*` match the same set of strings, but are not considered
+/// equal. A regex compiled with `regex!` is equal to one compiled from the
+/// same string with default options.
 impl PartialEq for Regex {
     fn eq(&self, other: &Regex) -> bool {
-        self.as_str() == other.as_str()
+        self.identity() == other.identity()
     }
 }
 
 impl Eq for Regex {}
 
+/// Hashes the same parts of a regex that equality compares.
+impl Hash for Regex {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state)
+    }
+}
+
 impl FromStr for Regex {
     type Err = Error;
 
@@ -251,6 +377,25 @@ impl Regex {
         Regex::with_engine(None, size, re)
     }
 
+    /// Creates a regular expression that matches the literal string `needle`
+    /// exactly.
+    ///
+    /// This is equivalent to `Regex::new(&regex::quote(needle)).unwrap()`,
+    /// but it never runs the parser and always searches with a plain
+    /// substring search.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let re = Regex::literal("1+1=2");
+    /// assert_eq!(re.as_str(), r"1\+1=2");
+    /// assert_eq!(re.find("does 1+1=2?"), Some((5, 10)));
+    /// ```
+    pub fn literal(needle: &str) -> Regex {
+        Regex::Dynamic(Program::literal(needle))
+    }
+
     /// Compiles a dynamic regular expression and uses given matching engine.
     ///
     /// This is exposed for use in testing and shouldn't be used by clients.
@@ -270,9 +415,151 @@ impl Regex {
         size: usize,
         re: &str,
     ) -> Result<Regex, Error> {
-        Program::new(engine, size, re).map(Regex::Dynamic)
+        let opts = RegexOptions {
+            engine: engine,
+            size_limit: size,
+            ..RegexOptions::default()
+        };
+        Program::new(re, &opts).map(Regex::Dynamic)
+    }
+
+    /// Pins the matching engine used by every search with this regex,
+    /// without recompiling it. A value of `None` restores the automatic
+    /// selection.
+    ///
+    /// This is useful when profiling shows that one engine works best for
+    /// the inputs at hand. Regexes compiled with `regex!` are unaffected.
+    ///
+    /// **WARNING**: It is the caller's responsibility to pick an engine that
+    /// is suitable for the regex. e.g., `MatchEngine::Literals` only works
+    /// when the regex is a set of literals, and `MatchEngine::Backtrack` may
+    /// use a lot of memory on large inputs.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::{MatchEngine, Regex};
+    /// let mut re = Regex::new(r"\d+").unwrap();
+    /// re.set_default_engine(Some(MatchEngine::Nfa));
+    /// assert_eq!(re.chosen_engine("a 123"), Some(MatchEngine::Nfa));
+    /// assert_eq!(re.find("a 123"), Some((2, 5)));
+    /// ```
+    pub fn set_default_engine(&mut self, engine: Option<MatchEngine>) {
+        if let Regex::Dynamic(ref mut prog) = *self {
+            prog.set_engine(engine);
+        }
+    }
+
+    /// Returns the set of characters that a nonempty match can start with,
+    /// or `None` if it could start with any character, like `(?s).*x`.
+    ///
+    /// The set is empty if the regex can only match the empty string. It
+    /// may be larger than needed, since assertions like `\b` are assumed
+    /// to hold, but it is never smaller. This is useful for things like
+    /// autocompletion.
+    ///
+    /// This wraps `Program::first_char_set`, which is only reachable
+    /// through the hidden `internal` module and may change without notice.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let re = Regex::new(r"[0-9]+|x").unwrap();
+    /// let chars = re.first_chars().unwrap();
+    /// assert_eq!(chars.ranges, vec![('0', '9'), ('x', 'x')]);
+    /// ```
+    pub fn first_chars(&self) -> Option<CharRanges> {
+        let set = match *self {
+            Regex::Dynamic(ref prog) => prog.first_char_set(),
+            Regex::Native(ref n) => return native_dynamic(n).first_chars(),
+        };
+        match set {
+            FirstSet::Any => None,
+            FirstSet::Ranges(ranges) => {
+                Some(CharRanges::from_ranges(ranges, false))
+            }
+            FirstSet::Empty => Some(CharRanges::from_ranges(vec![], false)),
+        }
+    }
+
+    /// Returns the matching engine that `find` would use to search `text`.
+    ///
+    /// Regexes compiled with `regex!` return `None`, unless an engine was
+    /// given to it with `engine = ...`.
+    pub fn chosen_engine(&self, text: &str) -> Option<MatchEngine> {
+        match *self {
+            Regex::Native(ref n) => n.engine,
+            Regex::Dynamic(ref prog) => Some(prog.chosen_engine(text)),
+        }
+    }
+
+    /// Returns the matching engine that ran the most recent search with
+    /// this regex and the number of instructions it executed.
+    ///
+    /// A high step count from the NFA suggests that the input is pushing
+    /// the regex onto its slowest path. When the regex is shared between
+    /// threads, the counts of concurrent searches are mixed together.
+    ///
+    /// This requires the `profile` feature, without which nothing is
+    /// recorded. `None` is returned before the first search and for
+    /// regexes compiled with `regex!`.
+    #[cfg(feature = "profile")]
+    pub fn last_exec_stats(&self) -> Option<ExecStats> {
+        match *self {
+            Regex::Native(_) => None,
+            Regex::Dynamic(ref prog) => prog.last_exec_stats(),
+        }
+    }
+
+    /// Returns true if the regex can match the empty string, e.g., `a*` or
+    /// `\b`. Zero-width assertions are assumed to hold, so this is true even
+    /// if the assertions of the regex can never all hold at once.
+    ///
+    /// A regex that can't match the empty string never yields an empty
+    /// match from `find_iter` or `split`, which is decided once for each
+    /// search instead of for every match.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// assert!(Regex::new(r"a*").unwrap().matches_empty());
+    /// assert!(!Regex::new(r"a+").unwrap().matches_empty());
+    /// ```
+    pub fn matches_empty(&self) -> bool {
+        match *self {
+            Regex::Native(ref n) => n.matches_empty,
+            Regex::Dynamic(ref prog) => prog.matches_empty,
+        }
     }
 
+    /// Returns false if the regex definitely doesn't match `text`, and true
+    /// if it might.
+    ///
+    /// This only checks whether `text` contains a literal string that every
+    /// match of the regex contains, like `needle` in `\w+ needle\d*`. It's
+    /// much faster than a search, so it's a cheap way to skip most texts
+    /// that don't match before running a full search on the rest. If no
+    /// such literal is known, or the regex was compiled with `regex!`, this
+    /// always returns true.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let re = Regex::new(r"\w+ needle\d*").unwrap();
+    /// assert!(!re.might_match("a haystack"));
+    /// // This contains the literal, but doesn't match.
+    /// assert!(re.might_match("! needle"));
+    /// assert!(!re.is_match("! needle"));
+    /// ```
+    pub fn might_match(&self, text: &str) -> bool {
+        match *self {
+            Regex::Native(_) => true,
+            Regex::Dynamic(ref prog) => prog.might_match(text),
+        }
+    }
 
     /// Returns true if and only if the regex matches the string given.
     ///
@@ -292,6 +579,60 @@ impl Regex {
         exec(self, &mut [], text, 0)
     }
 
+    /// Returns true if and only if the regex matches all of the string given,
+    /// as if it were wrapped in `\A(?:...)\z`.
+    ///
+    /// This is useful for validating input. Since `\A` and `\z` ignore the
+    /// multi-line flag, a `(?m)` in the regex can't make it match just one
+    /// line of the text.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let re = Regex::new(r"\d+").unwrap();
+    /// assert!(re.is_full_match("123"));
+    /// assert!(!re.is_full_match("123a"));
+    /// ```
+    pub fn is_full_match(&self, text: &str) -> bool {
+        match *self {
+            Regex::Dynamic(ref prog) => prog.is_full_match(text),
+            Regex::Native(ref n) => native_dynamic(n).is_full_match(text),
+        }
+    }
+
+    /// Returns true if and only if the regex matches the OS string given.
+    ///
+    /// This is useful for matching file names and other strings that come
+    /// from the operating system, which need not be valid UTF-8.
+    ///
+    /// On Unix, an `OsStr` is an arbitrary sequence of bytes. Valid UTF-8 is
+    /// matched as usual, while each byte that isn't part of a valid UTF-8
+    /// sequence is matched as a single `U+FFFD` character. It can therefore
+    /// be matched by `.` or a negated class, but never by a literal. To
+    /// keep that guarantee, `Error::NotAscii` is returned if the compiled
+    /// regex matches `U+FFFD` as a literal, like `\x{FFFD}`, or as a class
+    /// that holds it on its own, like `[a\x{FFFD}]`. Other non-ASCII
+    /// literals are fine, since they only match valid UTF-8.
+    ///
+    /// On other platforms, the string is lossily decoded with
+    /// `OsStr::to_string_lossy` and any regex is accepted.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate regex; use regex::Regex;
+    /// # use std::ffi::OsStr;
+    /// # fn main() {
+    /// let re = Regex::new(r"\.rs$").unwrap();
+    /// assert!(re.is_match_os(OsStr::new("lib.rs")).unwrap());
+    /// # }
+    /// ```
+    pub fn is_match_os(&self, text: &OsStr) -> Result<bool, Error> {
+        let text = try!(os_str_text(self, text));
+        Ok(self.is_match(&text))
+    }
+
     /// Returns the start and end byte range of the leftmost-first match in
     /// `text`. If no match exists, then `None` is returned.
     ///
@@ -313,14 +654,145 @@ impl Regex {
     /// # }
     /// ```
     pub fn find(&self, text: &str) -> Option<(usize, usize)> {
+        self.find_at(text, 0)
+    }
+
+    /// Returns the start and end byte range of the leftmost-first match in
+    /// `text` that starts at or after the byte offset `start`.
+    ///
+    /// Unlike searching `&text[start..]`, the text before `start` is still
+    /// seen by assertions like `^` and `\b`, and the locations returned are
+    /// offsets into all of `text`.
+    ///
+    /// If `start` is in the middle of a character, then the search starts
+    /// at the beginning of that character instead of panicking, so a match
+    /// may start up to three bytes before `start`.
+    ///
+    /// # Panics
+    ///
+    /// If `start` is greater than `text.len()`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let re = Regex::new(r"\b\w").unwrap();
+    /// assert_eq!(re.find_at("ab cd", 1), Some((3, 4)));
+    /// // Offset 1 is inside of `é`, which starts at offset 0.
+    /// assert_eq!(re.find_at("éa", 1), Some((0, 2)));
+    /// ```
+    pub fn find_at(&self, text: &str, start: usize) -> Option<(usize, usize)> {
+        let mut caps = [None, None];
+        if exec(self, &mut caps, text, char_start(text, start)) {
+            Some((caps[0].unwrap(), caps[1].unwrap()))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the leftmost-first match in `text` like `find_at`, except
+    /// that the match must also be anchored as given by `anchored`.
+    ///
+    /// With `Anchored::Start`, the match must start at `start`, as if the
+    /// regex began with `^` and the text began at `start`. With
+    /// `Anchored::Both`, the match must also end at the end of `text`. The
+    /// match with the highest priority among those is returned, so `a|ab`
+    /// matches all of `ab` this way.
+    ///
+    /// # Panics
+    ///
+    /// If `start` is greater than `text.len()`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::{Anchored, Regex};
+    /// let re = Regex::new(r"a|ab").unwrap();
+    /// assert_eq!(re.find_anchored("xab", 0, Anchored::No), Some((1, 2)));
+    /// assert_eq!(re.find_anchored("xab", 0, Anchored::Start), None);
+    /// assert_eq!(re.find_anchored("xab", 1, Anchored::Start), Some((1, 2)));
+    /// assert_eq!(re.find_anchored("xab", 1, Anchored::Both), Some((1, 3)));
+    /// ```
+    pub fn find_anchored(
+        &self,
+        text: &str,
+        start: usize,
+        anchored: Anchored,
+    ) -> Option<(usize, usize)> {
+        let start = char_start(text, start);
         let mut caps = [None, None];
-        if exec(self, &mut caps, text, 0) {
+        let matched = match *self {
+            Regex::Dynamic(ref prog) => {
+                prog.exec_anchored(&mut caps, text, start, anchored)
+            }
+            Regex::Native(_) if anchored == Anchored::No => {
+                exec(self, &mut caps, text, start)
+            }
+            // A native regex can't be anchored, so search a dynamic copy.
+            Regex::Native(ref n) => {
+                return native_dynamic(n).find_anchored(text, start, anchored);
+            }
+        };
+        if matched {
             Some((caps[0].unwrap(), caps[1].unwrap()))
         } else {
             None
         }
     }
 
+    /// Returns the leftmost-first match in `text` as a `Match`, which
+    /// provides the matched text along with its location. If no match
+    /// exists, then `None` is returned.
+    ///
+    /// This is like `find`, except the location isn't a bare tuple.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate regex; use regex::Regex;
+    /// # fn main() {
+    /// let text = "I categorically deny having triskaidekaphobia.";
+    /// let m = Regex::new(r"\b\w{13}\b").unwrap().find_match(text).unwrap();
+    /// assert_eq!(m.as_str(), "categorically");
+    /// assert_eq!(m.range(), 2..15);
+    /// # }
+    /// ```
+    pub fn find_match<'t>(&self, text: &'t str) -> Option<Match<'t>> {
+        self.find(text).map(|(s, e)| Match { text: text, start: s, end: e })
+    }
+
+    /// Returns the start and end byte range of the last match yielded by
+    /// `find_iter`. If no match exists, then `None` is returned.
+    ///
+    /// Matches are found from left to right, so this isn't necessarily the
+    /// rightmost match in `text`. When matches overlap, the one that
+    /// `find_iter` skipped over is never returned. e.g., `aa` in `aaa` is
+    /// found at `(0, 2)`, not `(1, 3)`. The matches in between aren't
+    /// collected.
+    ///
+    /// If the regex is anchored at the end of the text with `$`, then the
+    /// text is searched backwards from its end, which is faster than finding
+    /// every match when the text is long.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate regex; use regex::Regex;
+    /// # fn main() {
+    /// let re = Regex::new(r"\d").unwrap();
+    /// assert_eq!(re.find_last("a1b2c3"), Some((5, 6)));
+    /// assert_eq!(Regex::new("aa").unwrap().find_last("aaa"), Some((0, 2)));
+    /// # }
+    /// ```
+    pub fn find_last(&self, text: &str) -> Option<(usize, usize)> {
+        if let Regex::Dynamic(ref prog) = *self {
+            if let Some(m) = prog.exec_from_end(text) {
+                return m;
+            }
+        }
+        self.find_iter(text).last()
+    }
+
     /// Returns an iterator for each successive non-overlapping match in
     /// `text`, returning the start and end byte indices with respect to
     /// `text`.
@@ -344,13 +816,156 @@ impl Regex {
     /// // (45, 58)
     /// # }
     /// ```
+    ///
+    /// Empty matches are reported too, except for an empty match that
+    /// starts where the previous match ended. e.g., `a*` on `"ab"` yields
+    /// `(0, 1)` and `(2, 2)` but not `(1, 1)`. Use `FindMatches::flag_empty`
+    /// to tell empty matches apart from the others.
     pub fn find_iter<'r, 't>(&'r self, text: &'t str) -> FindMatches<'r, 't> {
         FindMatches {
             re: self,
             search: text,
+            norm: normal_text(self, text),
             last_end: 0,
             last_match: None,
+            matches_empty: self.matches_empty(),
+        }
+    }
+
+    /// Returns an iterator over all the non-overlapping matches in the text
+    /// read from `rdr`, as byte offsets from the start of the stream.
+    ///
+    /// These are the same matches that `find_iter` yields on all of the
+    /// text, but the text is read in chunks and only as much of it is kept
+    /// in memory as the search needs. If the length of a match is bounded,
+    /// then that's roughly the longest match. Otherwise, the window grows
+    /// while a match may still be in progress.
+    ///
+    /// The search is done with the NFA engine. A regex compiled by the
+    /// `regex!` macro, or one that normalizes its input, recurses or has
+    /// counted repetitions, reads all of the stream before it's searched.
+    ///
+    /// The iterator yields an error and stops if reading fails or the
+    /// stream isn't valid UTF-8. Matches that need more of the stream to be
+    /// confirmed, such as a match of `a+` that ends right before the error,
+    /// aren't reported.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let re = Regex::new(r"\d+").unwrap();
+    /// let ms: Vec<_> = re.stream_matches(&b"a 12 b 345"[..])
+    ///                    .map(|m| m.unwrap())
+    ///                    .collect();
+    /// assert_eq!(ms, vec![(2, 4), (7, 10)]);
+    /// ```
+    pub fn stream_matches<R: io::Read>(&self, rdr: R) -> StreamMatches<R> {
+        let prog = match *self {
+            Regex::Dynamic(ref prog)
+                if prog.flags.normalize.is_none()
+                   && !prog.needs_backtrack() => Some(prog),
+            _ => None,
+        };
+        StreamMatches {
+            re: self,
+            prog: prog,
+            max_len: prog.and_then(|prog| prog.max_match_len()),
+            rdr: rdr,
+            buf: String::new(),
+            partial: vec![],
+            offset: 0,
+            pos: 0,
+            last_match: None,
+            matches_empty: self.matches_empty(),
+            eof: false,
+            done: false,
+        }
+    }
+
+    /// Returns the number of non-overlapping matches in `text`. These are
+    /// the same matches that `find_iter` yields.
+    ///
+    /// Only the bounds of the overall match are tracked, so no capture
+    /// locations are allocated, and a regex made of literals is counted
+    /// with a literal search.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let re = Regex::new(r"\d+").unwrap();
+    /// assert_eq!(re.count_matches("a1b22c333"), 3);
+    /// ```
+    pub fn count_matches(&self, text: &str) -> usize {
+        let mut caps = [None, None];
+        let (mut count, mut start, mut last_match) = (0, 0, None);
+        while start <= text.len() && exec(self, &mut caps, text, start) {
+            let (s, e) = (caps[0].unwrap(), caps[1].unwrap());
+            // Skip an empty match right after a match, like `find_iter`.
+            if e == s && Some(e) == last_match {
+                match text[e..].chars().next() {
+                    None => break,
+                    Some(c) => start = e + c.len_utf8(),
+                }
+                continue;
+            }
+            count += 1;
+            start = e;
+            last_match = Some(e);
         }
+        count
+    }
+
+    /// Appends the start and end byte offsets of the first `limit`
+    /// non-overlapping matches in `text` to `out`, and returns how many
+    /// were appended.
+    ///
+    /// Nothing already in `out` is removed, so matches from several texts
+    /// can be collected into one buffer, and a buffer that is cleared
+    /// between calls can be reused without allocating. If the returned
+    /// count is equal to `limit`, then there may be more matches after the
+    /// last one.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let re = Regex::new(r"\d+").unwrap();
+    /// let mut out = Vec::with_capacity(3);
+    /// assert_eq!(re.find_all_into("a1b22c333", &mut out, 2), 2);
+    /// assert_eq!(re.find_all_into("4", &mut out, 2), 1);
+    /// assert_eq!(out, vec![(1, 2), (3, 5), (0, 1)]);
+    /// ```
+    pub fn find_all_into(
+        &self,
+        text: &str,
+        out: &mut Vec<(usize, usize)>,
+        limit: usize,
+    ) -> usize {
+        let len = out.len();
+        out.extend(self.find_iter(text).take(limit));
+        out.len() - len
+    }
+
+    /// Returns the matches of this regex in `text`, which can be iterated
+    /// over, counted or searched for the first or last match without
+    /// repeating either argument.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate regex; use regex::Regex;
+    /// # fn main() {
+    /// let re = Regex::new(r"\d+").unwrap();
+    /// let matches = re.matches("a1b22c333");
+    /// assert_eq!(matches.count(), 3);
+    /// assert_eq!(matches.first(), Some((1, 2)));
+    /// assert_eq!(matches.last(), Some((6, 9)));
+    /// # }
+    /// ```
+    pub fn matches<'r, 't>(&'r self, text: &'t str) -> Matches<'r, 't> {
+        Matches { re: self, text: text }
     }
 
     /// Returns the capture groups corresponding to the leftmost-first
@@ -405,8 +1020,25 @@ impl Regex {
     /// The `0`th capture group is always unnamed, so it must always be
     /// accessed with `at(0)`.
     pub fn captures<'t>(&self, text: &'t str) -> Option<Captures<'t>> {
+        self.captures_at(text, 0)
+    }
+
+    /// Returns the capture groups of the leftmost-first match in `text`
+    /// that starts at or after the byte offset `start`.
+    ///
+    /// `start` is handled the same way as in `find_at`, including when it's
+    /// in the middle of a character.
+    ///
+    /// # Panics
+    ///
+    /// If `start` is greater than `text.len()`.
+    pub fn captures_at<'t>(
+        &self,
+        text: &'t str,
+        start: usize,
+    ) -> Option<Captures<'t>> {
         let mut caps = self.alloc_captures();
-        if exec(self, &mut caps, text, 0) {
+        if exec(self, &mut caps, text, char_start(text, start)) {
             Some(Captures::new(self, text, caps))
         } else {
             None
@@ -442,11 +1074,84 @@ impl Regex {
         FindCaptures {
             re: self,
             search: text,
+            norm: normal_text(self, text),
             last_match: None,
             last_end: 0,
         }
     }
 
+    /// Returns the capture groups of every successive non-overlapping match
+    /// in `text`.
+    ///
+    /// This returns the same captures as collecting `captures_iter`, but
+    /// searches with a single buffer of capture locations, so that only the
+    /// locations of each match found are allocated.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate regex; use regex::Regex;
+    /// # fn main() {
+    /// let re = Regex::new(r"(\w)(\d)?").unwrap();
+    /// let all = re.captures_all("a1 b");
+    /// assert_eq!(all.len(), 2);
+    /// assert_eq!(all[0].at(2), Some("1"));
+    /// assert_eq!(all[1].at(2), None);
+    /// # }
+    /// ```
+    pub fn captures_all<'t>(&self, text: &'t str) -> Vec<Captures<'t>> {
+        let mut it = self.captures_iter(text);
+        let mut caps = self.alloc_captures();
+        let mut all = vec![];
+        while it.next_locs(&mut caps) {
+            all.push(Captures::new(self, text, caps.clone()));
+        }
+        all
+    }
+
+    /// Searches for the leftmost-first match in `text` and writes the
+    /// locations of its capture groups into `locs`. Returns true if and only
+    /// if there is a match.
+    ///
+    /// `locs` is resized to hold two slots for every capture group and all
+    /// of its slots are reset before searching. The start and end of capture
+    /// group `i` are written to `locs[2 * i]` and `locs[2 * i + 1]`, so the
+    /// first two slots are always the overall match. If the regex was
+    /// compiled with `RegexBuilder::branch_marks`, then there is one extra
+    /// slot at the end for the index of the branch that matched.
+    ///
+    /// This is the lowest level way to get capture locations. It doesn't
+    /// allocate, once `locs` is big enough, so the same `Vec` can be reused
+    /// for many searches.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate regex; use regex::Regex;
+    /// # fn main() {
+    /// let re = Regex::new(r"(\w+)=(\d+)?").unwrap();
+    /// let mut locs = vec![];
+    /// assert!(re.exec_into("a=1", &mut locs));
+    /// assert_eq!(locs, vec![Some(0), Some(3), Some(0), Some(1),
+    ///                       Some(2), Some(3)]);
+    /// assert!(re.exec_into("b=", &mut locs));
+    /// assert_eq!(locs[4], None);
+    /// # }
+    /// ```
+    pub fn exec_into(
+        &self,
+        text: &str,
+        locs: &mut Vec<Option<usize>>,
+    ) -> bool {
+        let nslots = match *self {
+            Regex::Native(ref n) => 2 * n.names.len(),
+            Regex::Dynamic(ref d) => d.num_slots(),
+        };
+        locs.clear();
+        locs.extend((0..nslots).map(|_| None));
+        exec(self, locs, text, 0)
+    }
+
     /// Returns an iterator of substrings of `text` delimited by a match
     /// of the regular expression.
     /// Namely, each element of the iterator corresponds to text that *isn't*
@@ -473,13 +1178,46 @@ impl Regex {
         }
     }
 
-    /// Returns an iterator of at most `limit` substrings of `text` delimited
-    /// by a match of the regular expression. (A `limit` of `0` will return no
-    /// substrings.)
-    /// Namely, each element of the iterator corresponds to text that *isn't*
-    /// matched by the regular expression.
-    /// The remainder of the string that is not split will be the last element
-    /// in the iterator.
+    /// Returns an iterator of substrings of `text` delimited by a match of
+    /// the regular expression, like `split`, except that the capture groups
+    /// of each delimiting match are yielded after the text preceding it.
+    ///
+    /// Every substring between matches is yielded as `Some`. A capture group
+    /// that didn't participate in the match is yielded as `None`.
+    ///
+    /// This method will *not* copy the text given.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate regex; use regex::Regex;
+    /// # fn main() {
+    /// let re = Regex::new(r"(\d)|(x)").unwrap();
+    /// let fields: Vec<Option<&str>> = re.split_captures("a1bxc").collect();
+    /// assert_eq!(fields, vec![
+    ///     Some("a"), Some("1"), None, Some("b"), None, Some("x"), Some("c"),
+    /// ]);
+    /// # }
+    /// ```
+    pub fn split_captures<'r, 't>(
+        &'r self,
+        text: &'t str,
+    ) -> RegexSplitCaptures<'r, 't> {
+        RegexSplitCaptures {
+            finder: self.captures_iter(text),
+            caps: self.alloc_captures(),
+            last: 0,
+            group: self.captures_len(),
+        }
+    }
+
+    /// Returns an iterator of at most `limit` substrings of `text` delimited
+    /// by a match of the regular expression. (A `limit` of `0` will return no
+    /// substrings.)
+    /// Namely, each element of the iterator corresponds to text that *isn't*
+    /// matched by the regular expression.
+    /// The remainder of the string that is not split will be the last element
+    /// in the iterator.
     ///
     /// This method will *not* copy the text given.
     ///
@@ -592,9 +1330,36 @@ impl Regex {
     /// See the documentation for `replace` for details on how to access
     /// submatches in the replacement string.
     pub fn replacen<R: Replacer>
-                   (&self, text: &str, limit: usize, mut rep: R) -> String {
+                   (&self, text: &str, limit: usize, rep: R) -> String {
+        self.replacen_count(text, limit, rep).0
+    }
+
+    /// Replaces at most `limit` non-overlapping matches in `text` like
+    /// `replacen`, and also returns the number of matches that were
+    /// replaced.
+    ///
+    /// Once `limit` matches are replaced, the rest of `text` is copied as
+    /// it is, including any further matches.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let re = Regex::new(r"\d").unwrap();
+    /// let (new, n) = re.replacen_count("a1b2c3", 2, "#");
+    /// assert_eq!((&*new, n), ("a#b#c3", 2));
+    /// let (new, n) = re.replacen_count("a1", 2, "#");
+    /// assert_eq!((&*new, n), ("a#", 1));
+    /// ```
+    pub fn replacen_count<R: Replacer>(
+        &self,
+        text: &str,
+        limit: usize,
+        mut rep: R,
+    ) -> (String, usize) {
         let mut new = String::with_capacity(text.len());
         let mut last_match = 0;
+        let mut count = 0;
 
         if rep.no_expand().is_some() {
             // borrow checker pains. `rep` is borrowed mutably in the `else`
@@ -607,6 +1372,7 @@ impl Regex {
                 new.push_str(&text[last_match..s]);
                 new.push_str(&rep);
                 last_match = e;
+                count += 1;
             }
         } else {
             for (i, cap) in self.captures_iter(text).enumerate() {
@@ -618,40 +1384,738 @@ impl Regex {
                 new.push_str(&text[last_match..s]);
                 new.push_str(&rep.reg_replace(&cap));
                 last_match = e;
+                count += 1;
             }
         }
         new.push_str(&text[last_match..]);
-        new
+        (new, count)
+    }
+
+    /// Replaces all non-overlapping matches in `text` with the replacement
+    /// provided, writing the result to `out`.
+    ///
+    /// This produces the same output as `replace_all`, but never builds the
+    /// whole result in memory. Unmatched text and expanded replacements are
+    /// written to `out` as each match is found.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate regex; use regex::Regex;
+    /// # fn main() {
+    /// let re = Regex::new(r"(\w+)@").unwrap();
+    /// let mut out = vec![];
+    /// re.replace_all_to("a@ b@", "<$1>", &mut out).unwrap();
+    /// assert_eq!(out, b"<a> <b>");
+    /// # }
+    /// ```
+    pub fn replace_all_to<R: Replacer, W: io::Write>(
+        &self,
+        text: &str,
+        mut rep: R,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let mut last_match = 0;
+        if rep.no_expand().is_some() {
+            let rep = rep.no_expand().unwrap();
+            for (s, e) in self.find_iter(text) {
+                try!(out.write_all(text[last_match..s].as_bytes()));
+                try!(out.write_all(rep.as_bytes()));
+                last_match = e;
+            }
+        } else {
+            for cap in self.captures_iter(text) {
+                let (s, e) = cap.pos(0).unwrap();
+                try!(out.write_all(text[last_match..s].as_bytes()));
+                try!(out.write_all(rep.reg_replace(&cap).as_bytes()));
+                last_match = e;
+            }
+        }
+        out.write_all(text[last_match..].as_bytes())
+    }
+
+    /// Parses the replacement template `template` once, so that it can be
+    /// expanded for many matches without parsing it again.
+    ///
+    /// The template has the same syntax as the one `Captures::expand`
+    /// takes. A reference to a group that this regex doesn't have expands
+    /// to the empty string, as it does with `expand`. If `strict` is true,
+    /// every group reference is checked instead, and an index that's out of
+    /// range or a name that no group has is an error.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate regex; use regex::Regex;
+    /// # fn main() {
+    /// let re = Regex::new(r"(?P<y>\d{4})-(\d{2})").unwrap();
+    /// let rep = re.compile_replacement("$2/$y", true).unwrap();
+    /// assert_eq!(re.replace_all("2015-03 2016-04", &rep), "03/2015 04/2016");
+    /// assert!(re.compile_replacement("$3", true).is_err());
+    /// let rep = re.compile_replacement("$3.", false).unwrap();
+    /// assert_eq!(re.replace_all("2015-03", &rep), ".");
+    /// # }
+    /// ```
+    pub fn compile_replacement(
+        &self,
+        template: &str,
+        strict: bool,
+    ) -> Result<Replacement, Error> {
+        let mut parts = vec![];
+        let mut lit = String::new();
+        let mut rest = template;
+        while let Some(i) = rest.find('$') {
+            lit.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            if rest.starts_with("$") {
+                lit.push('$');
+                rest = &rest[1..];
+                continue;
+            }
+            let (name, len) = match parse_group_ref(rest) {
+                None => {
+                    lit.push('$');
+                    continue;
+                }
+                Some(r) => r,
+            };
+            let group = match name.parse::<usize>() {
+                Ok(i) if i < self.captures_len() => Some(i),
+                Ok(_) => None,
+                Err(_) => self.names_iter().position(|n| {
+                    n.as_ref().map_or(false, |n| n == name)
+                }),
+            };
+            match group {
+                None if strict => {
+                    return Err(Error::UnknownGroup(name.to_owned()));
+                }
+                None => {}
+                Some(i) => {
+                    if !lit.is_empty() {
+                        parts.push(ReplacementPart::Literal(lit));
+                        lit = String::new();
+                    }
+                    parts.push(ReplacementPart::Group(i));
+                }
+            }
+            rest = &rest[len..];
+        }
+        lit.push_str(rest);
+        if !lit.is_empty() {
+            parts.push(ReplacementPart::Literal(lit));
+        }
+        Ok(Replacement { parts: parts })
+    }
+
+    /// Returns, for each instruction of the compiled regex, whether it made
+    /// progress while searching `text` for the first match.
+    ///
+    /// This is a diagnostic for finding the parts of a regex that never
+    /// take part in matching some input, like dead alternatives. It always
+    /// uses the slower NFA engine. Regexes compiled with `regex!` have no
+    /// instructions, and the NFA can't run a regex that recurses or has
+    /// backreferences, so the returned vector is empty for those.
+    pub fn execution_coverage(&self, text: &str) -> Vec<bool> {
+        match *self {
+            Regex::Native(_) => vec![],
+            Regex::Dynamic(ref prog) => prog.coverage(text),
+        }
+    }
+
+    /// Returns the original string of this regex.
+    pub fn as_str<'a>(&'a self) -> &'a str {
+        match *self {
+            Regex::Dynamic(Program { ref original, .. }) => original,
+            Regex::Native(ExNative { ref original, .. }) => original,
+        }
+    }
+
+    /// Returns the parts of this regex that equality and hashing are based
+    /// on.
+    fn identity(&self) -> (&str, Flags, &[(String, Vec<(char, char)>)]) {
+        match *self {
+            Regex::Dynamic(ref prog) => {
+                (&prog.original, prog.flags, &prog.custom_classes)
+            }
+            Regex::Native(ExNative { original, .. }) => {
+                (original, Flags::default(), &[])
+            }
+        }
+    }
+
+    /// Returns the position of the first literal prefix of this regex in
+    /// `text` along with the index of the prefix alternate that was found.
+    ///
+    /// This is only useful for debugging the prefix scan. It returns `None`
+    /// if the regex has no literal prefixes or was compiled with `regex!`.
+    #[doc(hidden)]
+    pub fn find_prefix(&self, text: &str) -> Option<(usize, usize, usize)> {
+        match *self {
+            Regex::Native(_) => None,
+            Regex::Dynamic(ref prog) if prog.prefixes.is_empty() => None,
+            Regex::Dynamic(ref prog) => prog.prefixes.find_alternate(text),
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn names_iter<'a>(&'a self) -> NamesIter<'a> {
+        match *self {
+            Regex::Native(ref n) => NamesIter::Native(n.names.iter()),
+            Regex::Dynamic(ref d) => NamesIter::Dynamic(d.cap_names.iter())
+        }
+    }
+
+    /// Returns the number of capture groups in the regex, including the
+    /// implicit group for the whole match.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::Regex;
+    /// let re = Regex::new(r"(a)(?P<b>b)").unwrap();
+    /// assert_eq!(re.captures_len(), 3);
+    /// ```
+    pub fn captures_len(&self) -> usize {
+        match *self {
+            Regex::Native(ref n) => n.names.len(),
+            Regex::Dynamic(ref d) => d.cap_names.len()
+        }
+    }
+
+    fn alloc_captures(&self) -> Vec<Option<usize>> {
+        match *self {
+            Regex::Native(ref n) => vec![None; 2 * n.names.len()],
+            Regex::Dynamic(ref d) => d.alloc_captures(),
+        }
+    }
+}
+
+/// A configurable builder for a regular expression.
+///
+/// A builder can be used to set options that change how a regular
+/// expression is compiled. `RegexBuilder::new(re).compile()` is equivalent
+/// to `Regex::new(re)`.
+///
+/// # Example
+///
+/// ```rust
+/// # use regex::RegexBuilder;
+/// let re = RegexBuilder::new("[0-9]+").size_limit(1 << 10).compile();
+/// assert!(re.unwrap().is_match("123"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct RegexBuilder {
+    pattern: String,
+    opts: RegexOptions,
+}
+
+impl RegexBuilder {
+    /// Creates a new builder for the regular expression `re` with the
+    /// default options.
+    pub fn new(re: &str) -> RegexBuilder {
+        RegexBuilder {
+            pattern: re.to_owned(),
+            opts: RegexOptions::default(),
+        }
+    }
+
+    /// Compiles the regular expression with the options set on this
+    /// builder.
+    ///
+    /// If an invalid expression is given, then an error is returned.
+    pub fn compile(self) -> Result<Regex, Error> {
+        Program::new(&self.pattern, &self.opts).map(Regex::Dynamic)
+    }
+
+    /// Sets the size limit of the *compiled* regular expression.
+    ///
+    /// The default is 10MB. See `Regex::with_size_limit`.
+    pub fn size_limit(mut self, limit: usize) -> RegexBuilder {
+        self.opts.size_limit = limit;
+        self
+    }
+
+    /// Sets what happens when unrolling a bounded repetition, like
+    /// `a{0,1000000}`, would exceed the size limit.
+    ///
+    /// The default, `SizeLimitPolicy::Error`, fails to compile the regex
+    /// with `Error::CompiledTooBig`. With `SizeLimitPolicy::SwitchToCounted`,
+    /// the repetition is compiled as a loop that counts its iterations
+    /// instead. Searching such a regex is slower, and each thread of the
+    /// NFA engine keeps a copy of every counter.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::{RegexBuilder, SizeLimitPolicy};
+    /// assert!(RegexBuilder::new("a{0,1000000}").compile().is_err());
+    /// let re = RegexBuilder::new("a{0,1000000}")
+    ///     .size_limit_policy(SizeLimitPolicy::SwitchToCounted)
+    ///     .compile().unwrap();
+    /// assert_eq!(re.find("baaa"), Some((0, 0)));
+    /// ```
+    pub fn size_limit_policy(
+        mut self,
+        policy: SizeLimitPolicy,
+    ) -> RegexBuilder {
+        self.opts.size_limit_policy = policy;
+        self
+    }
+
+    /// Sets the limits on the literal prefixes extracted from the regex,
+    /// which let a search skip quickly to where a match can start.
+    ///
+    /// At most `num` prefixes are extracted, and prefixes are cut off after
+    /// `len` bytes. A regex made entirely of literals that fit within these
+    /// limits is searched without running a matching engine at all, so
+    /// raising them can speed up regexes with many or long literals, at the
+    /// cost of more memory. The defaults are `30` and `15`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::{MatchEngine, RegexBuilder};
+    /// let re = RegexBuilder::new(r"a very long and distinctive literal")
+    ///     .prefix_limits(30, 100)
+    ///     .compile().unwrap();
+    /// assert_eq!(re.chosen_engine(""), Some(MatchEngine::Literals));
+    /// ```
+    pub fn prefix_limits(mut self, num: usize, len: usize) -> RegexBuilder {
+        self.opts.prefix_limits = (num, len);
+        self
+    }
+
+    /// Sets the nesting depth limit of the regular expression.
+    ///
+    /// Each group, concatenation, alternation or repetition nested inside
+    /// another counts as one level. Compiling a regex nested more deeply than
+    /// this returns `Error::TooDeep`, which guards against exhausting the
+    /// stack with untrusted expressions like `((((a))))` repeated many times.
+    ///
+    /// The default is 250.
+    pub fn depth_limit(mut self, limit: usize) -> RegexBuilder {
+        self.opts.depth_limit = limit;
+        self
+    }
+
+    /// Sets the maximum number of capture groups in the regular expression,
+    /// not counting the implicit group for the whole match.
+    ///
+    /// Every capture group needs space for its location in each search, so
+    /// compiling a regex with more groups than this returns
+    /// `Error::TooManyCaptures`.
+    ///
+    /// The default is 1000.
+    pub fn capture_limit(mut self, limit: usize) -> RegexBuilder {
+        self.opts.capture_limit = limit;
+        self
+    }
+
+    /// When enabled, each match records which branch of the top-level
+    /// alternation of the regex matched. It is available from
+    /// `Captures::branch`.
+    ///
+    /// Non-capturing groups around the top-level alternation are ignored,
+    /// so `(?:a|b|c)` has three branches. A regex without a top-level
+    /// alternation has a single branch, `0`.
+    ///
+    /// This is disabled by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::RegexBuilder;
+    /// let re = RegexBuilder::new(r"[0-9]+|[a-z]+")
+    ///                       .branch_marks(true)
+    ///                       .compile().unwrap();
+    /// let caps = re.captures("abc").unwrap();
+    /// assert_eq!(caps.branch(), Some(1));
+    /// ```
+    pub fn branch_marks(mut self, yes: bool) -> RegexBuilder {
+        self.opts.branch_marks = yes;
+        self
+    }
+
+    /// When enabled, `^` and `$` in multi-line mode treat `\r\n` as a
+    /// single line terminator. That is, `$` matches before `\r\n` and
+    /// neither `^` nor `$` match between the `\r` and the `\n`. A lone `\r`
+    /// is also treated as a line terminator.
+    ///
+    /// This is disabled by default, in which case only `\n` terminates a
+    /// line.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::RegexBuilder;
+    /// let re = RegexBuilder::new(r"(?m)^\w+$").crlf(true).compile().unwrap();
+    /// let lines: Vec<_> = re.find_iter("foo\r\nbar").collect();
+    /// assert_eq!(lines, vec![(0, 3), (5, 8)]);
+    /// ```
+    pub fn crlf(mut self, yes: bool) -> RegexBuilder {
+        self.opts.crlf = yes;
+        self
+    }
+
+    /// When enabled, compiling a regex that repeats an expression without
+    /// bound, where the expression can match the empty string or is itself
+    /// repeated without bound, returns `Error::NestedUnboundedRepeat`.
+    /// e.g., `(a*)*`, `(a+)+`, `(?:a?)+` and `(?:a|b*){2,}` are rejected.
+    ///
+    /// These are the shapes that make backtracking regex engines take
+    /// exponential time, which is worth guarding against when compiling
+    /// untrusted regexes. If `simplify_nested_repeats` is also enabled,
+    /// then only the regexes that it can't simplify are rejected.
+    ///
+    /// This is disabled by default.
+    pub fn reject_nested_repeats(mut self, yes: bool) -> RegexBuilder {
+        self.opts.reject_nested_repeats = yes;
+        self
+    }
+
+    /// When enabled, an unbounded repetition of an unbounded `*` or `+`
+    /// repetition is compiled as a single repetition that matches the same
+    /// strings. e.g., `(?:a+)+` is compiled as `a+` and `(?:a*)+` as `a*`.
+    ///
+    /// Only repetitions that are both greedy or both lazy are merged, and
+    /// only when no capture group lies between them, so the matches and
+    /// captures found are the same as without this option. `(a+)+` and
+    /// `(?:a+?)+` are compiled as they are.
+    ///
+    /// This is disabled by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::RegexBuilder;
+    /// let re = RegexBuilder::new(r"(?:a+)+b").simplify_nested_repeats(true)
+    ///     .reject_nested_repeats(true);
+    /// assert_eq!(re.compile().unwrap().find("xaaab"), Some((1, 5)));
+    /// ```
+    pub fn simplify_nested_repeats(mut self, yes: bool) -> RegexBuilder {
+        self.opts.simplify_nested_repeats = yes;
+        self
+    }
+
+    /// When enabled, the regex is rewritten as described for
+    /// `regex::simplify` before it's compiled. This never changes what the
+    /// regex matches.
+    ///
+    /// This is disabled by default.
+    pub fn simplify(mut self, yes: bool) -> RegexBuilder {
+        self.opts.simplify = yes;
+        self
+    }
+
+    /// When enabled, `.` matches any character including `\n`, as if the
+    /// `s` flag were set everywhere in the regex. Unlike the flag, this
+    /// can't be cleared with `(?-s)`.
+    ///
+    /// This is disabled by default, in which case the `s` flag decides.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::RegexBuilder;
+    /// let re = RegexBuilder::new(r"a.b").dot_matches_new_line(true);
+    /// assert!(re.compile().unwrap().is_match("a\nb"));
+    /// ```
+    pub fn dot_matches_new_line(mut self, yes: bool) -> RegexBuilder {
+        self.opts.dot_matches_new_line = yes;
+        self
+    }
+
+    /// When enabled, `^` and `$` only match at the beginning and end of the
+    /// text, as if they were `\A` and `\z`, even in multi-line mode. This
+    /// guards against a `(?m)` that was left on by accident.
+    ///
+    /// This is disabled by default, in which case the `m` flag decides.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::RegexBuilder;
+    /// let re = RegexBuilder::new(r"(?m)^b").single_line(true);
+    /// assert!(!re.compile().unwrap().is_match("a\nb"));
+    /// ```
+    pub fn single_line(mut self, yes: bool) -> RegexBuilder {
+        self.opts.single_line = yes;
+        self
+    }
+
+    /// When enabled, `$` outside of multi-line mode also matches just
+    /// before a `\n` that ends the text, as if it were `\Z`. This is how
+    /// `$` behaves in Perl and PCRE.
+    ///
+    /// This is disabled by default, in which case `$` outside of multi-line
+    /// mode only matches at the end of the text, like `\z`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::{Regex, RegexBuilder};
+    /// assert!(!Regex::new(r"foo$").unwrap().is_match("foo\n"));
+    /// let re = RegexBuilder::new(r"foo$").dollar_newline(true);
+    /// assert_eq!(re.compile().unwrap().find("foo\n"), Some((0, 3)));
+    /// ```
+    pub fn dollar_newline(mut self, yes: bool) -> RegexBuilder {
+        self.opts.dollar_newline = yes;
+        self
+    }
+
+    /// Set the character that ends a line. It is used by `^` and `$` in
+    /// multi-line mode, and `.` doesn't match it unless the `s` flag is set.
+    ///
+    /// The default is `\n`. `crlf` and `single_line` take precedence over
+    /// this for `^` and `$`.
+    ///
+    /// # Panics
+    ///
+    /// If `byte` is not ASCII.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::RegexBuilder;
+    /// let re = RegexBuilder::new(r"(?m)^b").line_terminator(b'\x1e');
+    /// assert!(re.compile().unwrap().is_match("a\x1eb"));
+    /// ```
+    pub fn line_terminator(mut self, byte: u8) -> RegexBuilder {
+        assert!(byte < 0x80, "line terminator must be ASCII");
+        self.opts.line_terminator = byte;
+        self
+    }
+
+    /// When enabled, the `i` flag only makes ASCII letters match case
+    /// insensitively. Every other character only matches itself, so `(?i)δ`
+    /// doesn't match `Δ`, and `(?i)k` doesn't match the Kelvin sign.
+    ///
+    /// This is disabled by default, in which case `i` uses simple Unicode
+    /// case folding. ASCII folding is more predictable for ASCII data, and
+    /// faster, since a case insensitive letter is compiled to a small
+    /// class instead of folding every character of the input.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::RegexBuilder;
+    /// let re = RegexBuilder::new(r"(?i)straße")
+    ///     .ascii_case_insensitive(true)
+    ///     .compile().unwrap();
+    /// assert!(re.is_match("STRAße"));
+    /// assert!(!re.is_match("STRAẞE"));
+    /// ```
+    pub fn ascii_case_insensitive(mut self, yes: bool) -> RegexBuilder {
+        self.opts.ascii_case_insensitive = yes;
+        self
+    }
+
+    /// Adds a named class of characters that `\p{name}` and `\P{name}` (or
+    /// `\pN` for a one letter name) can refer to, made of the given
+    /// inclusive ranges. This is useful for classes that the built in Unicode
+    /// tables don't provide, like blocks or the age of characters.
+    ///
+    /// A custom class takes precedence over a Unicode class of the same
+    /// name. Adding a class with the same name twice keeps the first one.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is empty or contains `}`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::RegexBuilder;
+    /// let re = RegexBuilder::new(r"^\p{Block=Cyrillic}+$")
+    ///     .custom_class("Block=Cyrillic", &[('\u{400}', '\u{4FF}')])
+    ///     .compile().unwrap();
+    /// assert!(re.is_match("Привет"));
+    /// assert!(!re.is_match("Hello"));
+    /// ```
+    pub fn custom_class(
+        mut self,
+        name: &str,
+        ranges: &[(char, char)],
+    ) -> RegexBuilder {
+        assert!(!name.is_empty() && !name.contains('}'),
+                "invalid class name: {:?}", name);
+        self.opts.custom_classes.push((name.to_owned(), ranges.to_vec()));
+        self
+    }
+
+    /// When enabled, `.` matches a character along with any combining marks
+    /// that follow it, like `e` followed by U+0301 (combining acute accent).
+    /// Emoji modifiers, like skin tones, and characters joined with U+200D
+    /// (zero width joiner) are included too.
+    ///
+    /// This approximates matching a grapheme cluster, but doesn't implement
+    /// all of the Unicode segmentation rules. Only `.` is affected. With the
+    /// `grapheme` feature, `\X` matches exactly one grapheme cluster.
+    ///
+    /// This is disabled by default, in which case `.` matches a single
+    /// codepoint.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::RegexBuilder;
+    /// let re = RegexBuilder::new(r"^.$").grapheme_dot(true).compile();
+    /// assert!(re.unwrap().is_match("e\u{301}"));
+    /// ```
+    pub fn grapheme_dot(mut self, yes: bool) -> RegexBuilder {
+        self.opts.grapheme_dot = yes;
+        self
+    }
+
+    /// Sets the matching engine used by every search. A value of `None`
+    /// means that the engine is automatically selected, which is the
+    /// default.
+    ///
+    /// See `Regex::set_default_engine` for which engines are suitable.
+    pub fn engine(mut self, engine: Option<MatchEngine>) -> RegexBuilder {
+        self.opts.engine = engine;
+        self
+    }
+
+    /// Normalizes the literals in the regex and the text being searched to
+    /// the Unicode normalization form given.
+    ///
+    /// This lets `café` match whether the `é` is written as one code point
+    /// or as `e` followed by a combining accent. Characters in classes are
+    /// not normalized, so `[é]` still only matches the code point `é`.
+    ///
+    /// Normalizing the text has a cost: every search that isn't on ASCII
+    /// text first builds a normalized copy of all of the text. Iterators
+    /// over matches or captures build it once and reuse it for every match
+    /// they find. Match locations are reported in the original text. A
+    /// match that starts or ends inside a sequence of characters that were
+    /// composed or reordered is widened to cover the whole sequence.
+    ///
+    /// This requires the `normalize` feature.
+    #[cfg(feature = "normalize")]
+    pub fn normalize(mut self, form: NormalForm) -> RegexBuilder {
+        self.opts.normalize = Some(form);
+        self
+    }
+
+    /// Sets a callback that is called for every search with the matching
+    /// engine chosen for it and the length of the text being searched.
+    ///
+    /// This is useful for finding out how a regex is actually executed,
+    /// e.g., whether a regex that looks like a literal really is searched
+    /// with `MatchEngine::Literals`. There is no observer by default.
+    ///
+    /// Regexes compiled with `regex!` never call an observer. The observer
+    /// must be `RefUnwindSafe`, so that a `Regex` can still be used inside
+    /// of `std::panic::catch_unwind`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use regex::{MatchEngine, RegexBuilder};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let seen = Arc::new(Mutex::new(vec![]));
+    /// let seen2 = seen.clone();
+    /// let re = RegexBuilder::new("foo")
+    ///     .engine_observer(move |engine, len| {
+    ///         seen2.lock().unwrap().push((engine, len));
+    ///     })
+    ///     .compile().unwrap();
+    /// assert!(re.is_match("a foo"));
+    /// assert_eq!(*seen.lock().unwrap(), vec![(MatchEngine::Literals, 5)]);
+    /// ```
+    pub fn engine_observer<F>(mut self, observer: F) -> RegexBuilder
+        where F: Fn(MatchEngine, usize)
+                 + Send + Sync + RefUnwindSafe + 'static
+    {
+        self.opts.observer = Some(EngineObserver(Arc::new(observer)));
+        self
     }
+}
 
-    /// Returns the original string of this regex.
-    pub fn as_str<'a>(&'a self) -> &'a str {
-        match *self {
-            Regex::Dynamic(Program { ref original, .. }) => original,
-            Regex::Native(ExNative { ref original, .. }) => original,
-        }
+/// A builder for a regular expression that is an alternation of many
+/// branches, such as a list of keywords.
+///
+/// Each branch is added on its own, so there is no need to join patterns
+/// with `|` by hand or to quote literals. The branches are tried in the
+/// order they were added, and the index of the branch that matched is
+/// available from `Captures::branch`.
+///
+/// Capture groups are numbered across all of the branches, so a
+/// backreference refers to a group by its number in the whole alternation.
+/// e.g., if the first branch is `(a)`, then `(b)\1` in the second branch
+/// doesn't match `bb`. Its group is group 2, and `\1` refers to the group
+/// of the first branch, which hasn't matched.
+///
+/// # Example
+///
+/// ```rust
+/// # use regex::AlternationBuilder;
+/// let re = AlternationBuilder::new()
+///     .add_literal("a.b")
+///     .add(r"[0-9]+").unwrap()
+///     .compile().unwrap();
+/// assert_eq!(re.captures("a.b").unwrap().branch(), Some(0));
+/// assert_eq!(re.captures("axb 42").unwrap().branch(), Some(1));
+/// ```
+#[derive(Clone, Debug)]
+pub struct AlternationBuilder {
+    branches: Vec<String>,
+}
+
+impl AlternationBuilder {
+    /// Creates a new builder with no branches.
+    pub fn new() -> AlternationBuilder {
+        AlternationBuilder { branches: vec![] }
     }
 
-    #[doc(hidden)]
-    pub fn names_iter<'a>(&'a self) -> NamesIter<'a> {
-        match *self {
-            Regex::Native(ref n) => NamesIter::Native(n.names.iter()),
-            Regex::Dynamic(ref d) => NamesIter::Dynamic(d.cap_names.iter())
+    /// Adds a branch that matches the regular expression `re`.
+    ///
+    /// Flags set in `re`, like `(?i)`, only apply to this branch. If `re`
+    /// isn't a valid expression on its own, then an error is returned. It
+    /// is parsed with the default options, so it can't use a class added
+    /// with `RegexBuilder::custom_class`.
+    pub fn add(mut self, re: &str) -> Result<AlternationBuilder, Error> {
+        let expr = try!(syntax::Expr::parse(re));
+        // If the `x` flag is still set at the end of `re`, then a comment
+        // there would run into the `)` after it. A newline ends it, and is
+        // otherwise ignored in that mode, which is checked by parsing `re`
+        // with one.
+        let mut ended = re.to_owned();
+        ended.push('\n');
+        let same = syntax::Expr::parse(&ended).ok().map_or(false, |e| {
+            e == expr
+        });
+        if !same {
+            ended.pop();
         }
+        self.branches.push(format!("(?:{})", ended));
+        Ok(self)
     }
 
-    fn names_len(&self) -> usize {
-        match *self {
-            Regex::Native(ref n) => n.names.len(),
-            Regex::Dynamic(ref d) => d.cap_names.len()
-        }
+    /// Adds a branch that matches `lit` literally.
+    pub fn add_literal(mut self, lit: &str) -> AlternationBuilder {
+        self.branches.push(format!("(?:{})", quote(lit)));
+        self
     }
 
-    fn alloc_captures(&self) -> Vec<Option<usize>> {
-        match *self {
-            Regex::Native(ref n) => vec![None; 2 * n.names.len()],
-            Regex::Dynamic(ref d) => d.alloc_captures(),
-        }
+    /// Returns a `RegexBuilder` for the alternation of every branch, with
+    /// `branch_marks` enabled. This can be used to set other options before
+    /// compiling.
+    ///
+    /// Without any branches, this is the empty regex, which matches
+    /// everywhere.
+    pub fn builder(self) -> RegexBuilder {
+        RegexBuilder::new(&self.branches.join("|")).branch_marks(true)
+    }
+
+    /// Compiles the alternation of every branch with the default options.
+    ///
+    /// If any branch is an invalid expression, then an error is returned.
+    pub fn compile(self) -> Result<Regex, Error> {
+        self.builder().compile()
     }
 }
 
@@ -711,8 +2175,7 @@ impl<'t> Replacer for &'t str {
     }
 
     fn no_expand<'a>(&'a mut self) -> Option<Cow<'a, str>> {
-        let re = Regex::new(REPLACE_EXPAND).unwrap();
-        if !re.is_match(self) {
+        if !self.contains('$') {
             Some((*self).into())
         } else {
             None
@@ -720,6 +2183,55 @@ impl<'t> Replacer for &'t str {
     }
 }
 
+/// A replacement template parsed by `Regex::compile_replacement`.
+///
+/// It can be expanded with `apply`, or passed by reference to `replace` and
+/// the other replacement methods.
+#[derive(Clone, Debug)]
+pub struct Replacement {
+    parts: Vec<ReplacementPart>,
+}
+
+#[derive(Clone, Debug)]
+enum ReplacementPart {
+    Literal(String),
+    Group(usize),
+}
+
+impl Replacement {
+    /// Expands this template with the groups captured in `caps`. A group
+    /// that didn't take part in the match expands to the empty string.
+    pub fn apply(&self, caps: &Captures) -> String {
+        let mut expanded = String::new();
+        for part in &self.parts {
+            match *part {
+                ReplacementPart::Literal(ref s) => expanded.push_str(s),
+                ReplacementPart::Group(i) => {
+                    expanded.push_str(caps.at(i).unwrap_or(""));
+                }
+            }
+        }
+        expanded
+    }
+}
+
+impl<'r> Replacer for &'r Replacement {
+    fn reg_replace<'a>(&'a mut self, caps: &Captures) -> Cow<'a, str> {
+        self.apply(caps).into()
+    }
+
+    fn no_expand<'a>(&'a mut self) -> Option<Cow<'a, str>> {
+        match self.parts.len() {
+            0 => Some("".into()),
+            1 => match self.parts[0] {
+                ReplacementPart::Literal(ref s) => Some((&**s).into()),
+                ReplacementPart::Group(_) => None,
+            },
+            _ => None,
+        }
+    }
+}
+
 impl<F> Replacer for F where F: FnMut(&Captures) -> String {
     fn reg_replace<'a>(&'a mut self, caps: &Captures) -> Cow<'a, str> {
         (*self)(caps).into()
@@ -759,6 +2271,47 @@ impl<'r, 't> Iterator for RegexSplits<'r, 't> {
     }
 }
 
+/// Yields all substrings delimited by a regular expression match, each
+/// followed by the capture groups of the match.
+///
+/// `'r` is the lifetime of the compiled expression and `'t` is the lifetime
+/// of the string being split.
+pub struct RegexSplitCaptures<'r, 't> {
+    finder: FindCaptures<'r, 't>,
+    caps: Vec<Option<usize>>,
+    last: usize,
+    /// The next capture group of the last match to yield.
+    group: usize,
+}
+
+impl<'r, 't> Iterator for RegexSplitCaptures<'r, 't> {
+    type Item = Option<&'t str>;
+
+    fn next(&mut self) -> Option<Option<&'t str>> {
+        let text = self.finder.search;
+        if self.group < self.finder.re.captures_len() {
+            let i = self.group;
+            self.group += 1;
+            return Some(match (self.caps[2 * i], self.caps[2 * i + 1]) {
+                (Some(s), Some(e)) => Some(&text[s..e]),
+                _ => None,
+            });
+        }
+        if !self.finder.next_locs(&mut self.caps) {
+            if self.last >= text.len() {
+                return None;
+            }
+            let s = &text[self.last..];
+            self.last = text.len();
+            return Some(Some(s));
+        }
+        let matched = &text[self.last..self.caps[0].unwrap()];
+        self.last = self.caps[1].unwrap();
+        self.group = 1;
+        Some(Some(matched))
+    }
+}
+
 /// Yields at most `N` substrings delimited by a regular expression match.
 ///
 /// The last substring will be whatever remains after splitting.
@@ -789,6 +2342,38 @@ impl<'r, 't> Iterator for RegexSplitsN<'r, 't> {
     }
 }
 
+/// A single match of a regex in some text.
+///
+/// `'t` is the lifetime of the matched text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Match<'t> {
+    text: &'t str,
+    start: usize,
+    end: usize,
+}
+
+impl<'t> Match<'t> {
+    /// Returns the byte index of the start of the match.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Returns the byte index of the end of the match.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Returns the byte range of the match.
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// Returns the matched text.
+    pub fn as_str(&self) -> &'t str {
+        &self.text[self.start..self.end]
+    }
+}
+
 /// Captures represents a group of captured strings for a single match.
 ///
 /// The 0th capture always corresponds to the entire match. Each subsequent
@@ -804,16 +2389,24 @@ pub struct Captures<'t> {
     text: &'t str,
     locs: Vec<Option<usize>>,
     named: Option<HashMap<String, usize>>,
+    branch: Option<usize>,
 }
 
 impl<'t> Captures<'t> {
     fn new(
         re: &Regex,
         search: &'t str,
-        locs: Vec<Option<usize>>,
+        mut locs: Vec<Option<usize>>,
     ) -> Captures<'t> {
+        // The branch mark, if any, is stored after the capture slots.
+        let branch = match *re {
+            Regex::Dynamic(Program { branch_slot: Some(_), .. }) => {
+                locs.pop().unwrap()
+            }
+            _ => None,
+        };
         let named =
-            if re.names_len() == 0 {
+            if re.captures_len() == 0 {
                 None
             } else {
                 let mut named = HashMap::new();
@@ -828,6 +2421,7 @@ impl<'t> Captures<'t> {
             text: search,
             locs: locs,
             named: named,
+            branch: branch,
         }
     }
 
@@ -838,20 +2432,63 @@ impl<'t> Captures<'t> {
     /// original string matched.
     pub fn pos(&self, i: usize) -> Option<(usize, usize)> {
         let (s, e) = (i * 2, i * 2 + 1);
-        if e >= self.locs.len() || self.locs[s].is_none() {
-            // VM guarantees that each pair of locations are both Some or None.
+        if e >= self.locs.len() {
             return None
         }
-        Some((self.locs[s].unwrap(), self.locs[e].unwrap()))
+        // VM guarantees that each pair of locations are both Some or None.
+        match (self.locs[s], self.locs[e]) {
+            (Some(s), Some(e)) => Some((s, e)),
+            _ => None,
+        }
+    }
+
+    /// Returns the start and end positions spanning the capture groups in
+    /// the range `groups`.
+    ///
+    /// The span starts at the smallest start position and ends at the
+    /// largest end position of the groups in the range that matched.
+    /// Returns `None` if none of them matched.
+    pub fn span_of(&self, groups: Range<usize>) -> Option<(usize, usize)> {
+        groups.filter_map(|i| self.pos(i)).fold(None, |span, (s, e)| {
+            match span {
+                None => Some((s, e)),
+                Some((s2, e2)) => Some((cmp::min(s, s2), cmp::max(e, e2))),
+            }
+        })
+    }
+
+    /// Returns true if the overall match is empty, i.e., if it didn't
+    /// consume any text. e.g., `^` or `\b` matching on their own.
+    pub fn is_empty_match(&self) -> bool {
+        match self.pos(0) {
+            Some((s, e)) => s == e,
+            None => false,
+        }
+    }
+
+    /// Returns the index of the top-level alternation branch that matched.
+    ///
+    /// This is `None` unless the regex was compiled with
+    /// `RegexBuilder::branch_marks` enabled.
+    pub fn branch(&self) -> Option<usize> {
+        self.branch
     }
 
     /// Returns the matched string for the capture group `i`.  If `i` isn't
     /// a valid capture group or didn't match anything, then `None` is
     /// returned.
+    ///
+    /// `None` is also returned if the positions of the group don't span a
+    /// valid slice of the text, which can only happen because of a bug in a
+    /// matching engine.
     pub fn at(&self, i: usize) -> Option<&'t str> {
         match self.pos(i) {
-            None => None,
-            Some((s, e)) => Some(&self.text[s..e])
+            Some((s, e)) if s <= e && e <= self.text.len()
+                            && self.text.is_char_boundary(s)
+                            && self.text.is_char_boundary(e) => {
+                Some(&self.text[s..e])
+            }
+            _ => None,
         }
     }
 
@@ -890,31 +2527,50 @@ impl<'t> Captures<'t> {
         SubCapturesNamed { caps: self, inner: self.named.as_ref().map(|n| n.iter()) }
     }
 
-    /// Expands all instances of `$name` in `text` to the corresponding capture
-    /// group `name`.
+    /// Expands all instances of `$name` and `${name}` in `text` to the
+    /// corresponding capture group `name`.
     ///
     /// `name` may be an integer corresponding to the index of the
     /// capture group (counted by order of opening parenthesis where `0` is the
     /// entire match) or it can be a name (consisting of letters, digits or
-    /// underscores) corresponding to a named capture group.
+    /// underscores, but not starting with a digit) corresponding to a named
+    /// capture group.
+    ///
+    /// Without braces, `name` is as long as possible: `$10` refers to group
+    /// `10` and `$ab` to the group named `ab`, even if there is a group named
+    /// `a`. An index ends at the first character that isn't a digit, so
+    /// `$1a` is group `1` followed by `a`. Use braces to end a name early,
+    /// e.g., `${1}0` is group `1` followed by `0`.
     ///
     /// If `name` isn't a valid capture group (whether the name doesn't exist or
     /// isn't a valid index), then it is replaced with the empty string.
     ///
-    /// To write a literal `$` use `$$`.
+    /// To write a literal `$` use `$$`. A `$` that isn't followed by a name
+    /// or by a name in braces is also written literally.
     pub fn expand(&self, text: &str) -> String {
-        // How evil can you get?
-        let re = Regex::new(REPLACE_EXPAND).unwrap();
-        let text = re.replace_all(text, |refs: &Captures| -> String {
-            let before = refs.name("before").unwrap_or("");
-            let name = refs.name("name").unwrap_or("");
-            format!("{}{}", before, match name.parse::<usize>() {
-                Err(_) => self.name(name).unwrap_or("").to_string(),
-                Ok(i) => self.at(i).unwrap_or("").to_string(),
-            })
-        });
-        let re = Regex::new(r"\$\$").unwrap();
-        re.replace_all(&text, NoExpand("$"))
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(i) = rest.find('$') {
+            expanded.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            if rest.starts_with("$") {
+                expanded.push('$');
+                rest = &rest[1..];
+                continue;
+            }
+            match parse_group_ref(rest) {
+                None => expanded.push('$'),
+                Some((name, len)) => {
+                    expanded.push_str(match name.parse::<usize>() {
+                        Err(_) => self.name(name).unwrap_or(""),
+                        Ok(i) => self.at(i).unwrap_or(""),
+                    });
+                    rest = &rest[len..];
+                }
+            }
+        }
+        expanded.push_str(rest);
+        expanded
     }
 
     /// Returns the number of captured groups.
@@ -926,6 +2582,36 @@ impl<'t> Captures<'t> {
     pub fn is_empty(&self) -> bool { self.len() == 0 }
 }
 
+/// Parses the group reference at the start of `rest`, which follows a `$`
+/// in a replacement string. Returns the name of the group and the length of
+/// the reference, including any braces.
+fn parse_group_ref(rest: &str) -> Option<(&str, usize)> {
+    fn is_name_byte(b: u8) -> bool {
+        b == b'_' || (b as char).is_alphanumeric() && b < 0x80
+    }
+    fn name_len(s: &str) -> usize {
+        let bytes = s.as_bytes();
+        if bytes.is_empty() {
+            0
+        } else if (bytes[0] as char).is_digit(10) {
+            bytes.iter().take_while(|&&b| (b as char).is_digit(10)).count()
+        } else {
+            bytes.iter().take_while(|&&b| is_name_byte(b)).count()
+        }
+    }
+
+    if rest.starts_with("{") {
+        let len = name_len(&rest[1..]);
+        if len > 0 && rest[1 + len..].starts_with("}") {
+            return Some((&rest[1..1 + len], len + 2));
+        }
+        None
+    } else {
+        let len = name_len(rest);
+        if len == 0 { None } else { Some((&rest[..len], len)) }
+    }
+}
+
 /// An iterator over capture groups for a particular match of a regular
 /// expression.
 ///
@@ -1002,21 +2688,28 @@ impl<'t> Iterator for SubCapturesNamed<'t> {
 pub struct FindCaptures<'r, 't> {
     re: &'r Regex,
     search: &'t str,
+    norm: Option<NormalText>,
     last_match: Option<usize>,
     last_end: usize,
 }
 
-impl<'r, 't> Iterator for FindCaptures<'r, 't> {
-    type Item = Captures<'t>;
-
-    fn next(&mut self) -> Option<Captures<'t>> {
+impl<'r, 't> FindCaptures<'r, 't> {
+    /// Finds the next match and writes its capture locations to `caps`,
+    /// which may be reused between calls. Returns false when there are no
+    /// more matches.
+    fn next_locs(&mut self, caps: &mut CaptureIdxs) -> bool {
         if self.last_end > self.search.len() {
-            return None
+            return false
         }
 
-        let mut caps = self.re.alloc_captures();
-        if !exec(self.re, &mut caps, self.search, self.last_end) {
-            return None
+        // The matching engines only write the slots that participate in a
+        // match, so clear out any locations from a previous search.
+        for slot in caps.iter_mut() {
+            *slot = None;
+        }
+        if !exec_iter(self.re, &self.norm, caps, self.search,
+                      self.last_end) {
+            return false
         }
         let (s, e) = (caps[0].unwrap(), caps[1].unwrap());
 
@@ -1024,18 +2717,78 @@ impl<'r, 't> Iterator for FindCaptures<'r, 't> {
         // i.e., no infinite loops please.
         if e == s && Some(self.last_end) == self.last_match {
             if self.last_end >= self.search.len() {
-                return None;
+                return false;
             }
             self.last_end += self.search[self.last_end..].chars()
                                  .next().unwrap().len_utf8();
-            return self.next()
+            return self.next_locs(caps)
         }
         self.last_end = e;
         self.last_match = Some(self.last_end);
+        true
+    }
+}
+
+impl<'r, 't> Iterator for FindCaptures<'r, 't> {
+    type Item = Captures<'t>;
+
+    fn next(&mut self) -> Option<Captures<'t>> {
+        let mut caps = self.re.alloc_captures();
+        if !self.next_locs(&mut caps) {
+            return None
+        }
         Some(Captures::new(self.re, self.search, caps))
     }
 }
 
+/// The non-overlapping matches of a regex in a particular string.
+///
+/// This is created by `Regex::matches`. Every method searches `text` anew,
+/// so it can be used any number of times.
+///
+/// `'r` is the lifetime of the compiled expression and `'t` is the lifetime
+/// of the matched string.
+#[derive(Clone, Copy)]
+pub struct Matches<'r, 't> {
+    re: &'r Regex,
+    text: &'t str,
+}
+
+impl<'r, 't> Matches<'r, 't> {
+    /// Returns an iterator over the start and end byte range of each match.
+    pub fn iter(&self) -> FindMatches<'r, 't> {
+        self.re.find_iter(self.text)
+    }
+
+    /// Returns the number of matches.
+    ///
+    /// Only the bounds of each match are tracked, not its capture groups.
+    /// The end of each match is still needed, since that's where the search
+    /// for the next one starts.
+    pub fn count(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns the first match, like `Regex::find`.
+    pub fn first(&self) -> Option<(usize, usize)> {
+        self.re.find(self.text)
+    }
+
+    /// Returns the last match, like `Regex::find_last`.
+    pub fn last(&self) -> Option<(usize, usize)> {
+        self.re.find_last(self.text)
+    }
+}
+
+impl<'r, 't> IntoIterator for Matches<'r, 't> {
+    type Item = (usize, usize);
+    type IntoIter = FindMatches<'r, 't>;
+
+    fn into_iter(self) -> FindMatches<'r, 't> {
+        self.iter()
+    }
+}
+
 /// An iterator over all non-overlapping matches for a particular string.
 ///
 /// The iterator yields a tuple of integers corresponding to the start and end
@@ -1047,8 +2800,10 @@ impl<'r, 't> Iterator for FindCaptures<'r, 't> {
 pub struct FindMatches<'r, 't> {
     re: &'r Regex,
     search: &'t str,
+    norm: Option<NormalText>,
     last_match: Option<usize>,
     last_end: usize,
+    matches_empty: bool,
 }
 
 impl<'r, 't> Iterator for FindMatches<'r, 't> {
@@ -1060,14 +2815,17 @@ impl<'r, 't> Iterator for FindMatches<'r, 't> {
         }
 
         let mut caps = [None, None];
-        if !exec(self.re, &mut caps, self.search, self.last_end) {
+        if !exec_iter(self.re, &self.norm, &mut caps, self.search,
+                      self.last_end) {
             return None;
         }
         let (s, e) = (caps[0].unwrap(), caps[1].unwrap());
 
         // Don't accept empty matches immediately following a match.
-        // i.e., no infinite loops please.
-        if e == s && Some(self.last_end) == self.last_match {
+        // i.e., no infinite loops please. There are none to skip if the
+        // regex can't match the empty string.
+        if self.matches_empty && e == s
+           && Some(self.last_end) == self.last_match {
             if self.last_end >= self.search.len() {
                 return None;
             }
@@ -1081,6 +2839,199 @@ impl<'r, 't> Iterator for FindMatches<'r, 't> {
     }
 }
 
+impl<'r, 't> FindMatches<'r, 't> {
+    /// Returns an iterator over the same matches as this one, where each
+    /// match is paired with whether it is empty.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate regex; use regex::Regex;
+    /// # fn main() {
+    /// let re = Regex::new(r"a*").unwrap();
+    /// let ms: Vec<_> = re.find_iter("ab").flag_empty().collect();
+    /// assert_eq!(ms, vec![((0, 1), false), ((2, 2), true)]);
+    /// # }
+    /// ```
+    pub fn flag_empty(self) -> FlagEmptyMatches<'r, 't> {
+        FlagEmptyMatches(self)
+    }
+}
+
+/// An iterator over all non-overlapping matches in a stream.
+///
+/// Each item is the start and end of a match as byte offsets from the
+/// start of the stream, or the error that ended the iteration.
+///
+/// `'r` is the lifetime of the compiled expression and `R` is the type of
+/// the stream.
+pub struct StreamMatches<'r, R> {
+    re: &'r Regex,
+    /// The program searched incrementally, if there is one.
+    prog: Option<&'r Program>,
+    max_len: Option<usize>,
+    rdr: R,
+    /// The window of the stream that's being searched.
+    buf: String,
+    /// The bytes at the end of the last read that don't form a whole
+    /// character yet.
+    partial: Vec<u8>,
+    /// The offset in the stream of the start of `buf`.
+    offset: usize,
+    /// Where the next search starts in `buf`.
+    pos: usize,
+    last_match: Option<usize>,
+    matches_empty: bool,
+    eof: bool,
+    done: bool,
+}
+
+impl<'r, R: io::Read> StreamMatches<'r, R> {
+    /// Searches `buf` from `pos`. If more input is needed, then the
+    /// earliest position in `buf` where a match may still start is returned
+    /// as an error.
+    fn search(&self) -> Result<Option<(usize, usize)>, usize> {
+        let mut caps = [None, None];
+        let matched = if self.eof {
+            exec(self.re, &mut caps, &self.buf, self.pos)
+        } else {
+            match self.prog {
+                None => return Err(self.pos),
+                Some(prog) => {
+                    try!(prog.exec_stream(&mut caps, &self.buf, self.pos))
+                }
+            }
+        };
+        if matched {
+            Ok(Some((caps[0].unwrap(), caps[1].unwrap())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Drops the start of the window up to where the next search starts,
+    /// but keeps the character before it, which assertions like `\b` look
+    /// at. Then appends the next chunk of the stream to the window.
+    fn fill(&mut self) -> io::Result<()> {
+        let cut = match self.buf[..self.pos].chars().next_back() {
+            Some(c) => self.pos - c.len_utf8(),
+            None => 0,
+        };
+        if cut > 0 {
+            self.buf = self.buf[cut..].to_owned();
+            self.offset += cut;
+            self.pos -= cut;
+            self.last_match = self.last_match.and_then(|m| m.checked_sub(cut));
+        }
+
+        let mut chunk = [0; 8 * 1024];
+        let n = loop {
+            match self.rdr.read(&mut chunk) {
+                Ok(n) => break n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        };
+        if n == 0 {
+            self.eof = true;
+        }
+        self.partial.extend(chunk[..n].iter().cloned());
+        let valid = match str::from_utf8(&self.partial) {
+            Ok(s) => s.len(),
+            Err(err) => err.valid_up_to(),
+        };
+        // A character is at most 4 bytes long, so anything longer that's
+        // left over can't become valid with more input.
+        if self.partial.len() - valid >= 4
+           || (self.eof && valid < self.partial.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "stream did not contain valid UTF-8"));
+        }
+        self.buf.push_str(str::from_utf8(&self.partial[..valid]).unwrap());
+        self.partial = self.partial[valid..].to_owned();
+        Ok(())
+    }
+}
+
+impl<'r, R: io::Read> Iterator for StreamMatches<'r, R> {
+    type Item = io::Result<(usize, usize)>;
+
+    fn next(&mut self) -> Option<io::Result<(usize, usize)>> {
+        while !self.done {
+            match self.search() {
+                Ok(None) => self.done = true,
+                Ok(Some((s, e))) => {
+                    // Don't accept empty matches immediately following a
+                    // match, just like `FindMatches`.
+                    if self.matches_empty && e == s
+                       && Some(self.pos) == self.last_match {
+                        match self.buf[self.pos..].chars().next() {
+                            Some(c) => {
+                                self.pos += c.len_utf8();
+                                continue;
+                            }
+                            None if self.eof => {
+                                self.done = true;
+                                continue;
+                            }
+                            None => {}
+                        }
+                    } else {
+                        self.pos = e;
+                        self.last_match = Some(e);
+                        return Some(Ok((self.offset + s, self.offset + e)));
+                    }
+                }
+                Err(earliest) => {
+                    // Nothing before the earliest thread still running can
+                    // be part of a match, so it's dropped on the next fill.
+                    if earliest > self.pos {
+                        self.pos = earliest;
+                    }
+                    // No match can start so far back that it's longer than
+                    // the longest match, plus a character of lookahead.
+                    if let Some(len) = self.max_len {
+                        let mut cut = self.buf.len().saturating_sub(len + 4);
+                        if cut > self.pos {
+                            while !self.buf.is_char_boundary(cut) {
+                                cut -= 1;
+                            }
+                            self.pos = cut;
+                        }
+                    }
+                }
+            }
+            if !self.done {
+                if let Err(err) = self.fill() {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over all non-overlapping matches for a particular string,
+/// where each match is paired with whether it is empty.
+///
+/// This is created by `FindMatches::flag_empty`.
+pub struct FlagEmptyMatches<'r, 't>(FindMatches<'r, 't>);
+
+impl<'r, 't> Iterator for FlagEmptyMatches<'r, 't> {
+    type Item = ((usize, usize), bool);
+
+    fn next(&mut self) -> Option<((usize, usize), bool)> {
+        self.0.next().map(|(s, e)| ((s, e), s == e))
+    }
+}
+
+/// A searcher for a regex in a string, for use with the methods on `str`
+/// that accept a `Pattern`.
+///
+/// Regexes can only be searched forwards, so `ReverseSearcher` is not
+/// implemented. Methods like `str::rsplit` or `str::rfind` are therefore a
+/// compile error when given a regex.
 #[cfg(feature = "pattern")]
 pub struct RegexSearcher<'r, 't> {
     it: FindMatches<'r, 't>,
@@ -1138,11 +3089,192 @@ unsafe impl<'r, 't> Searcher<'t> for RegexSearcher<'r, 't> {
             }
         }
     }
+
+    // The default implementation calls `next` until it finds a match, but
+    // the regex engine can jump straight to the next match.
+    #[inline]
+    fn next_match(&mut self) -> Option<(usize, usize)> {
+        let m = match self.next_match.take() {
+            None => self.it.next(),
+            m => m,
+        };
+        self.last_step_end = match m {
+            None => self.haystack().len(),
+            Some((_, e)) => e,
+        };
+        m
+    }
+}
+
+#[cfg(unix)]
+fn os_str_text<'t>(
+    re: &Regex,
+    text: &'t OsStr,
+) -> Result<Cow<'t, str>, Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let names_fffd = match *re {
+        Regex::Dynamic(ref prog) => prog.names_char('\u{FFFD}'),
+        Regex::Native(ref n) => return os_str_text(&native_dynamic(n), text),
+    };
+    if names_fffd {
+        return Err(Error::NotAscii);
+    }
+    let mut bytes = text.as_bytes();
+    let mut decoded = match str::from_utf8(bytes) {
+        Ok(s) => return Ok(Cow::Borrowed(s)),
+        Err(_) => String::with_capacity(bytes.len()),
+    };
+    // Each invalid byte becomes its own replacement character, so that
+    // the number of characters a regex like `..` consumes doesn't depend
+    // on how the invalid bytes happen to be grouped.
+    loop {
+        match str::from_utf8(bytes) {
+            Ok(s) => {
+                decoded.push_str(s);
+                return Ok(Cow::Owned(decoded));
+            }
+            Err(err) => {
+                let valid = err.valid_up_to();
+                decoded.push_str(str::from_utf8(&bytes[..valid]).unwrap());
+                decoded.push('\u{FFFD}');
+                bytes = &bytes[valid + 1..];
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn os_str_text<'t>(
+    _: &Regex,
+    text: &'t OsStr,
+) -> Result<Cow<'t, str>, Error> {
+    Ok(text.to_string_lossy())
+}
+
+/// Returns the offset of the start of the character that contains byte
+/// offset `i` of `text`, or `i` itself if it's already a character boundary.
+fn char_start(text: &str, mut i: usize) -> usize {
+    assert!(i <= text.len(), "start {} is past the end of the text", i);
+    while !text.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+thread_local!(
+    static NATIVE_DYNAMIC: RefCell<HashMap<(&'static str, Option<MatchEngine>),
+                                           Rc<Regex>>> =
+        RefCell::new(HashMap::new())
+);
+
+/// Returns a dynamic copy of a regex compiled with `regex!`, for the methods
+/// that the native code can't run.
+///
+/// The copy is compiled once per thread, with the engine given to `regex!`
+/// and without a size limit, since the plugin has already compiled the
+/// same pattern.
+fn native_dynamic(re: &ExNative) -> Rc<Regex> {
+    NATIVE_DYNAMIC.with(|cache| {
+        let key = (re.original, re.engine);
+        cache.borrow_mut().entry(key).or_insert_with(|| {
+            let size = ::std::usize::MAX;
+            Rc::new(Regex::with_engine(re.engine, size, re.original).unwrap())
+        }).clone()
+    })
 }
 
 fn exec(re: &Regex, caps: &mut CaptureIdxs, text: &str, start: usize) -> bool {
     match *re {
-        Regex::Native(ExNative { ref prog, .. }) => (*prog)(caps, text, start),
+        Regex::Native(ref n) => match n.engine {
+            // The generated code is an NFA, so the other engines search a
+            // dynamic copy that runs them.
+            Some(engine) if engine != MatchEngine::Nfa => {
+                exec(&native_dynamic(n), caps, text, start)
+            }
+            _ => (n.prog)(caps, text, start),
+        },
         Regex::Dynamic(ref prog) => prog.exec(caps, text, start),
     }
 }
+
+/// Returns `text` normalized for `exec_iter`, if `re` normalizes its input.
+fn normal_text(re: &Regex, text: &str) -> Option<NormalText> {
+    match *re {
+        Regex::Native(_) => None,
+        Regex::Dynamic(ref prog) => prog.normal_text(text),
+    }
+}
+
+/// Executes `re` like `exec`, searching `norm` instead if it's given. This
+/// lets iterators normalize the text they search once, not once per match.
+fn exec_iter(
+    re: &Regex,
+    norm: &Option<NormalText>,
+    caps: &mut CaptureIdxs,
+    text: &str,
+    start: usize,
+) -> bool {
+    match (re, norm) {
+        (&Regex::Dynamic(ref prog), &Some(ref norm)) => {
+            prog.exec_normal(norm, caps, text, start)
+        }
+        _ => exec(re, caps, text, start),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Captures;
+
+    fn caps(text: &str, locs: Vec<Option<usize>>) -> Captures {
+        Captures { text: text, locs: locs, named: None, branch: None }
+    }
+
+    #[test]
+    fn stream_window_bounded() {
+        use std::iter::repeat;
+        use super::Regex;
+
+        // A match could be as long as the stream, but no thread lives past
+        // the next space.
+        let re = Regex::new(r"\w+z").unwrap();
+        let text = repeat("abc ").take(100000).collect::<String>();
+        let mut ms = re.stream_matches(text.as_bytes());
+        assert!(ms.next().is_none());
+        assert!(ms.buf.len() < 10000, "{}", ms.buf.len());
+    }
+
+    #[test]
+    fn unwind_safe() {
+        use std::panic::{self, RefUnwindSafe, UnwindSafe};
+        use super::{Regex, RegexBuilder};
+
+        fn assert_unwind_safe<T: UnwindSafe + RefUnwindSafe>() {}
+        assert_unwind_safe::<Regex>();
+
+        let re = RegexBuilder::new("a").engine_observer(|_, _| {})
+                                       .compile().unwrap();
+        assert!(panic::catch_unwind(|| re.is_match("a")).unwrap());
+    }
+
+    #[test]
+    fn corrupt_captures() {
+        let text = "a☃b";
+        let c = caps(text, vec![Some(0), Some(5), Some(1), Some(4)]);
+        assert_eq!(c.at(0), Some("a☃b"));
+        assert_eq!(c.at(1), Some("☃"));
+
+        let c = caps(text, vec![
+            Some(3), Some(1), Some(0), Some(6), Some(0), Some(2),
+            Some(0), None,
+        ]);
+        assert_eq!(c.pos(0), Some((3, 1)));
+        assert_eq!(c.at(0), None);
+        assert_eq!(c.at(1), None);
+        assert_eq!(c.at(2), None);
+        assert_eq!(c.pos(3), None);
+        assert_eq!(c.at(3), None);
+        assert_eq!(c.at(4), None);
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use program::{Program, RegexOptions};
+use re::Error;
+
+/// A set of regular expressions that are searched for at the same time,
+/// reporting every one of them that matches.
+///
+/// Unlike an alternation like `a|b`, which reports the first branch that
+/// matches, a search with a set tells whether each of its regexes matches
+/// anywhere in the text. The regexes are combined into a single program,
+/// so the text is scanned only once.
+///
+/// # Example
+///
+/// ```rust
+/// # use regex::RegexSet;
+/// let set = RegexSet::new(&[r"\d+", r"[a-z]+", r"^bar"]).unwrap();
+/// assert_eq!(set.matches("foo123"), vec![true, true, false]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct RegexSet {
+    patterns: Vec<String>,
+    /// The regexes that the NFA can run, combined with `Program::new_set`.
+    prog: Program,
+    /// The index in `patterns` of each regex in `prog`.
+    nfa: Vec<usize>,
+    /// The regexes that need the backtracking engine, along with their
+    /// indices in `patterns`. These are searched for one at a time.
+    backtrack: Vec<(usize, Program)>,
+}
+
+impl RegexSet {
+    /// Compiles each of `res` with the default options and combines them
+    /// into a set.
+    ///
+    /// If any of them is an invalid expression, then an error is returned.
+    pub fn new(res: &[&str]) -> Result<RegexSet, Error> {
+        let opts = RegexOptions::default();
+        let mut progs = vec![];
+        let mut nfa = vec![];
+        let mut backtrack = vec![];
+        for (i, re) in res.iter().enumerate() {
+            let prog = try!(Program::new(re, &opts));
+            if prog.needs_backtrack() {
+                backtrack.push((i, prog));
+            } else {
+                progs.push(prog);
+                nfa.push(i);
+            }
+        }
+        let prog = {
+            let progs: Vec<&Program> = progs.iter().collect();
+            Program::new_set(&progs)
+        };
+        Ok(RegexSet {
+            patterns: res.iter().map(|&re| re.to_owned()).collect(),
+            prog: prog,
+            nfa: nfa,
+            backtrack: backtrack,
+        })
+    }
+
+    /// Returns, for each regex in the set in the order they were given,
+    /// whether it matches anywhere in `text`.
+    pub fn matches(&self, text: &str) -> Vec<bool> {
+        let mut matched = vec![false; self.patterns.len()];
+        for (&i, m) in self.nfa.iter().zip(self.prog.exec_set(text)) {
+            matched[i] = m;
+        }
+        for &(i, ref prog) in &self.backtrack {
+            matched[i] = prog.exec(&mut [], text, 0);
+        }
+        matched
+    }
+
+    /// Returns true if any regex in the set matches anywhere in `text`.
+    pub fn is_match(&self, text: &str) -> bool {
+        self.matches(text).into_iter().any(|m| m)
+    }
+
+    /// Returns the regexes in the set, in the order they were given.
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+}
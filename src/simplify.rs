@@ -0,0 +1,123 @@
+// Copyright 2014-2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use syntax::{Expr, Repeater};
+
+/// Rewrites `expr` into an expression that matches the same text with the
+/// same captures, but is no larger.
+///
+/// `a{1}` becomes `a`, a class of one character like `[a]` becomes a
+/// literal, adjacent literals are joined and non-capturing groups are
+/// dropped wherever the result still means the same thing. In particular,
+/// the result can be written out with `Display` and parsed again.
+pub fn simplify_expr(expr: Expr) -> Expr {
+    ungroup(simplify(expr), |_| true)
+}
+
+fn simplify(expr: Expr) -> Expr {
+    match expr {
+        Expr::Class(cls) => {
+            if cls.len() == 1 && cls[0].start == cls[0].end
+               && !cls.is_case_insensitive() {
+                Expr::Literal { chars: vec![cls[0].start], casei: false }
+            } else {
+                Expr::Class(cls)
+            }
+        }
+        Expr::Repeat {
+            e,
+            r: Repeater::Range { min: 1, max: Some(1) },
+            ..
+        } => simplify(*e),
+        Expr::Repeat { e, r, greedy } => Expr::Repeat {
+            e: Box::new(ungroup(simplify(*e), is_atom)),
+            r: r,
+            greedy: greedy,
+        },
+        Expr::Group { e, i, name } => Expr::Group {
+            e: Box::new(ungroup(simplify(*e), |_| true)),
+            i: i,
+            name: name,
+        },
+        Expr::Concat(es) => {
+            let mut joined: Vec<Expr> = Vec::with_capacity(es.len());
+            for e in es {
+                // An alternation in a concatenation needs its group.
+                let e = ungroup(simplify(e), |e| match *e {
+                    Expr::Alternate(_) => false,
+                    _ => true,
+                });
+                match e {
+                    Expr::Concat(es) => {
+                        for e in es {
+                            push_joined(&mut joined, e);
+                        }
+                    }
+                    e => push_joined(&mut joined, e),
+                }
+            }
+            if joined.len() == 1 {
+                joined.pop().unwrap()
+            } else {
+                Expr::Concat(joined)
+            }
+        }
+        Expr::Alternate(es) => {
+            let mut branches = Vec::with_capacity(es.len());
+            for e in es {
+                // Branches keep their order, so priorities are unchanged.
+                match ungroup(simplify(e), |_| true) {
+                    Expr::Alternate(es) => branches.extend(es),
+                    e => branches.push(e),
+                }
+            }
+            Expr::Alternate(branches)
+        }
+        e => e,
+    }
+}
+
+/// Returns the expression inside of `e` if `e` is a non-capturing group and
+/// `keep` returns true for it. Otherwise, `e` is returned.
+fn ungroup<F: Fn(&Expr) -> bool>(e: Expr, keep: F) -> Expr {
+    match e {
+        Expr::Group { e, i: None, name: None } => {
+            if keep(&e) {
+                *e
+            } else {
+                Expr::Group { e: e, i: None, name: None }
+            }
+        }
+        e => e,
+    }
+}
+
+/// Returns true if `e` can be repeated without a group around it.
+fn is_atom(e: &Expr) -> bool {
+    match *e {
+        Expr::Empty | Expr::Repeat { .. }
+        | Expr::Concat(_) | Expr::Alternate(_) => false,
+        _ => true,
+    }
+}
+
+/// Pushes `e` onto `es`, joining it with the last expression if both are
+/// literals with the same case sensitivity.
+fn push_joined(es: &mut Vec<Expr>, e: Expr) {
+    if let Some(&mut Expr::Literal { ref mut chars, casei }) = es.last_mut() {
+        if let Expr::Literal { chars: ref more, casei: ci } = e {
+            if casei == ci {
+                chars.extend(more.iter().cloned());
+                return;
+            }
+        }
+    }
+    es.push(e);
+}